@@ -0,0 +1,250 @@
+use std::fs;
+use std::path::{Component, Path};
+use std::process::Command;
+
+use crate::llm_api::{ToolCall, ToolDeclaration};
+use crate::{locatesource, parser, token_budget};
+
+pub const RETRIEVE_ITEM_SOURCE: &str = "retrieve_item_source";
+pub const SEARCH_SYMBOLS: &str = "search_symbols";
+pub const FILE_OUTLINE: &str = "file_outline";
+pub const CARGO_CHECK: &str = "cargo_check";
+
+// Keep tool answers small enough that a few of them fit comfortably in one conversation.
+const MAX_SYMBOL_MATCHES: usize = 50;
+const MAX_TOOL_OUTPUT_TOKENS: usize = 8_000;
+
+/// What running a tool produced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolOutcome {
+    /// Sent back to the model as the function response.
+    pub output: String,
+    /// Source code to keep for the code generation prompt, keyed like `needed_items` entries.
+    pub gathered: Option<(String, String)>,
+}
+
+impl ToolOutcome {
+    fn output(output: String) -> Self {
+        Self { output, gathered: None }
+    }
+}
+
+/// The tools the agent offers while gathering context.
+pub fn tool_declarations() -> Vec<ToolDeclaration> {
+    let string_param = |name: &str, description: &str| {
+        serde_json::json!({
+            "type": "OBJECT",
+            "properties": { name: { "type": "STRING", "description": description } },
+            "required": [name]
+        })
+    };
+    vec![
+        ToolDeclaration {
+            name: RETRIEVE_ITEM_SOURCE.to_string(),
+            description: "Returns the source code of a Rust item (e.g. `my_crate::module::MyStruct`, `serde::Serialize`) or of a file in the project (e.g. `src/lib.rs`). Everything retrieved is included when you write the code changes.".to_string(),
+            parameters: Some(string_param("item", "Fully qualified item path, crate name or project-relative file path.")),
+        },
+        ToolDeclaration {
+            name: SEARCH_SYMBOLS.to_string(),
+            description: "Searches the project's symbols (structs, enums, functions, traits, ...) by case-insensitive substring and lists matching paths with their kind.".to_string(),
+            parameters: Some(string_param("query", "Part of a symbol name or path, e.g. `Session` or `cache::`.")),
+        },
+        ToolDeclaration {
+            name: FILE_OUTLINE.to_string(),
+            description: "Returns a Rust file with all function bodies replaced by `{ ... }`: types, signatures and docs only. Cheaper than retrieving the whole file.".to_string(),
+            parameters: Some(string_param("path", "Project-relative path of a Rust file, e.g. `src/cache.rs`.")),
+        },
+        ToolDeclaration {
+            name: CARGO_CHECK.to_string(),
+            description: "Runs `cargo check` in the project and returns whether it passed, with the compiler messages.".to_string(),
+            parameters: None, // Gemini rejects OBJECT schemas without properties
+        },
+    ]
+}
+
+/// Runs one tool call. Failures are reported to the model as output rather than aborting the agent.
+pub fn execute_tool(project_root: &Path, call: &ToolCall) -> ToolOutcome {
+    let arg = |name: &str| call.args.get(name).and_then(|v| v.as_str()).map(str::trim).unwrap_or("");
+    let outcome = match call.name.as_str() {
+        RETRIEVE_ITEM_SOURCE => {
+            let item = arg("item");
+            if !is_inside_project(item) {
+                return ToolOutcome::output(outside_project(item));
+            }
+            match locatesource::retrieve_item_source(project_root, item) {
+                Ok(source) => ToolOutcome { output: source.clone(), gathered: Some((item.to_string(), source)) },
+                Err(e) => ToolOutcome::output(format!("Error: could not retrieve `{}`: {}", item, e)),
+            }
+        }
+        SEARCH_SYMBOLS => ToolOutcome::output(search_symbols(project_root, arg("query"))),
+        FILE_OUTLINE => ToolOutcome::output(file_outline(project_root, arg("path"))),
+        CARGO_CHECK => ToolOutcome::output(cargo_check(project_root)),
+        other => ToolOutcome::output(format!("Error: unknown tool `{}`.", other)),
+    };
+    ToolOutcome {
+        output: token_budget::truncate_to_tokens(&outcome.output, MAX_TOOL_OUTPUT_TOKENS),
+        ..outcome
+    }
+}
+
+fn search_symbols(project_root: &Path, query: &str) -> String {
+    if query.is_empty() {
+        return "Error: `query` must not be empty.".to_string();
+    }
+    let symbols = match parser::parse_directory(project_root) {
+        Ok(symbols) => symbols,
+        Err(e) => return format!("Error: failed to parse project symbols: {}", e),
+    };
+    let needle = query.to_lowercase();
+    let mut matches: Vec<String> = symbols
+        .iter()
+        .filter(|(path, _)| path.to_lowercase().contains(&needle))
+        .map(|(path, info)| format!("{} ({:?})", path, info.symbol_type))
+        .collect();
+    matches.sort();
+    if matches.is_empty() {
+        return format!("No symbols match `{}`.", query);
+    }
+    let total = matches.len();
+    matches.truncate(MAX_SYMBOL_MATCHES);
+    let mut output = matches.join("\n");
+    if total > MAX_SYMBOL_MATCHES {
+        output.push_str(&format!("\n... and {} more; use a more specific query.", total - MAX_SYMBOL_MATCHES));
+    }
+    output
+}
+
+// Only plain relative paths stay in the project: `Path::join` with an absolute path replaces the
+// root, and `..` climbs out of it. Item paths like `serde::Serialize` pass as one plain component.
+fn is_inside_project(path: &str) -> bool {
+    Path::new(path).components().all(|part| matches!(part, Component::Normal(_) | Component::CurDir))
+}
+
+fn outside_project(path: &str) -> String {
+    format!("Error: `{}` is outside the project.", path)
+}
+
+fn file_outline(project_root: &Path, path: &str) -> String {
+    if !is_inside_project(path) {
+        return outside_project(path);
+    }
+    match fs::read_to_string(project_root.join(path)) {
+        Ok(content) => match syn::parse_file(&content) {
+            Ok(_) => token_budget::summarise_rust_source(&content).unwrap_or(content),
+            Err(e) => format!("Error: `{}` is not valid Rust: {}", path, e),
+        },
+        Err(e) => format!("Error: could not read `{}`: {}", path, e),
+    }
+}
+
+fn cargo_check(project_root: &Path) -> String {
+    match Command::new("cargo")
+        .args(["check", "--quiet", "--message-format=short"])
+        .current_dir(project_root)
+        .output()
+    {
+        Ok(output) => {
+            let messages = String::from_utf8_lossy(&output.stderr);
+            let verdict = if output.status.success() { "cargo check passed." } else { "cargo check failed." };
+            if messages.trim().is_empty() { verdict.to_string() } else { format!("{}\n{}", verdict, messages.trim_end()) }
+        }
+        Err(e) => format!("Error: could not run cargo check: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn call(name: &str, args: serde_json::Value) -> ToolCall {
+        ToolCall { id: None, name: name.to_string(), args }
+    }
+
+    fn project() -> tempfile::TempDir {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("Cargo.toml"), "[package]\nname = \"tool_test\"\nversion = \"0.1.0\"\nedition = \"2021\"\n").unwrap();
+        fs::write(
+            dir.path().join("src/lib.rs"),
+            "pub struct Counter { pub count: u32 }\n\nimpl Counter {\n    pub fn bump(&mut self) -> u32 {\n        self.count += 1;\n        self.count\n    }\n}\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_tool_declarations_have_object_parameters() {
+        let tools = tool_declarations();
+        let names: Vec<&str> = tools.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec![RETRIEVE_ITEM_SOURCE, SEARCH_SYMBOLS, FILE_OUTLINE, CARGO_CHECK]);
+        for parameters in tools.iter().filter_map(|t| t.parameters.as_ref()) {
+            assert_eq!(parameters["type"], "OBJECT");
+            for required in parameters["required"].as_array().into_iter().flatten() {
+                assert!(parameters["properties"].get(required.as_str().unwrap()).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_retrieve_item_source_gathers_file() {
+        let dir = project();
+        let outcome = execute_tool(dir.path(), &call(RETRIEVE_ITEM_SOURCE, serde_json::json!({ "item": "src/lib.rs" })));
+        assert!(outcome.output.contains("self.count += 1"));
+        assert_eq!(outcome.gathered.as_ref().map(|(item, _)| item.as_str()), Some("src/lib.rs"));
+
+        let missing = execute_tool(dir.path(), &call(RETRIEVE_ITEM_SOURCE, serde_json::json!({ "item": "src/missing.rs" })));
+        assert!(missing.output.starts_with("Error:"));
+        assert!(missing.gathered.is_none());
+    }
+
+    #[test]
+    fn test_file_outline_and_unknown_tool() {
+        let dir = project();
+        let outline = execute_tool(dir.path(), &call(FILE_OUTLINE, serde_json::json!({ "path": "src/lib.rs" })));
+        assert!(outline.output.contains("pub fn bump(&mut self) -> u32 { ... }"));
+        assert!(!outline.output.contains("self.count += 1"));
+        assert!(outline.gathered.is_none());
+
+        let escaped = execute_tool(dir.path(), &call(FILE_OUTLINE, serde_json::json!({ "path": "../etc/passwd" })));
+        assert!(escaped.output.contains("outside the project"));
+
+        let unknown = execute_tool(dir.path(), &call("rm_rf", serde_json::json!({})));
+        assert_eq!(unknown.output, "Error: unknown tool `rm_rf`.");
+    }
+
+    #[test]
+    fn test_absolute_paths_are_outside_the_project() {
+        let dir = project();
+        let elsewhere = tempdir().unwrap();
+        let secret = elsewhere.path().join("secret.rs");
+        fs::write(&secret, "pub const SECRET: &str = \"hunter2\";\n").unwrap();
+        let secret = secret.to_str().unwrap();
+
+        let outline = execute_tool(dir.path(), &call(FILE_OUTLINE, serde_json::json!({ "path": secret })));
+        assert_eq!(outline.output, format!("Error: `{}` is outside the project.", secret));
+
+        let retrieved = execute_tool(dir.path(), &call(RETRIEVE_ITEM_SOURCE, serde_json::json!({ "item": secret })));
+        assert_eq!(retrieved.output, format!("Error: `{}` is outside the project.", secret));
+        assert!(retrieved.gathered.is_none());
+
+        let climbed = execute_tool(dir.path(), &call(RETRIEVE_ITEM_SOURCE, serde_json::json!({ "item": "src/../../secret.rs" })));
+        assert!(climbed.output.contains("outside the project"));
+
+        let item = execute_tool(dir.path(), &call(RETRIEVE_ITEM_SOURCE, serde_json::json!({ "item": "tool_test::Counter" })));
+        assert!(!item.output.contains("outside the project"), "{}", item.output);
+    }
+
+    #[test]
+    fn test_search_symbols() {
+        let dir = project();
+        let found = execute_tool(dir.path(), &call(SEARCH_SYMBOLS, serde_json::json!({ "query": "counter" })));
+        assert!(found.output.contains("tool_test::Counter"), "unexpected output: {}", found.output);
+
+        let none = execute_tool(dir.path(), &call(SEARCH_SYMBOLS, serde_json::json!({ "query": "Nonexistent" })));
+        assert_eq!(none.output, "No symbols match `Nonexistent`.");
+
+        let outcome = execute_tool(dir.path(), &call(SEARCH_SYMBOLS, serde_json::json!({})));
+        assert_eq!(outcome.output, "Error: `query` must not be empty.");
+    }
+}
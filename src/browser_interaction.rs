@@ -1,16 +1,375 @@
-use std::error::Error;
+use std::cell::RefCell;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use serde_json::{json, Value};
+
+use crate::cli::CustomCliArgs;
+use crate::llm_api::{answer_from_code_blocks, flatten_conversation, is_cancel_requested, ChatTurn, LLMApi, LLMApiError, ModelReply, JSON_CODE_BLOCK_INSTRUCTION};
+use crate::model_router::route_unset_phases_to;
+use crate::Result;
+
+/// Input field used when `--input` is not given.
+pub const DEFAULT_INPUT_SELECTOR: &str = "textarea";
+/// Code-block elements used when `--codeblock` is not given.
+pub const DEFAULT_CODEBLOCK_SELECTOR: &str = "pre";
+
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(300);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Without a finished selector, the answer counts as done once its code blocks stop changing for this many polls.
+const STABLE_POLLS: usize = 4;
+
+// W3C WebDriver element reference key and key codes (https://www.w3.org/TR/webdriver/#keyboard-actions).
+const ELEMENT_KEY: &str = "element-6066-11e4-a52e-4f735466cecf";
+const KEY_ENTER: char = '\u{E007}';
+const KEY_SHIFT: char = '\u{E008}';
+const KEY_RELEASE_MODIFIERS: char = '\u{E000}';
+
+/// Opens `url` in a browser driven through the WebDriver server at `webdriver_url`, types
+/// `gemini_request` into the input field, waits for the answer and returns the text of every
+/// code block in it.
 pub fn execute_browser_interaction_task(
-    _url: &str,
-    _input_selector: Option<&str>,
-    _codeblock_selector: Option<&str>,
-    _finished_selector: Option<&str>,
-    _gemini_request: &str, // The actual request to "paste"
-) -> Result<Vec<String>, Box<dyn Error>> {
-    // This feature is not yet fully implemented.
-    // In a real implementation, this function would use a browser automation library
-    // (e.g., thirtyfour, puppeteer through wasm-bindgen or a native bridge)
-    // to interact with the specified URL, fill inputs, trigger actions, and extract code.
-    // For now, it returns an error indicating it's unimplemented.
-    Err("Browser interaction feature is not implemented.".into())
+    webdriver_url: &str,
+    url: &str,
+    input_selector: Option<&str>,
+    codeblock_selector: Option<&str>,
+    finished_selector: Option<&str>,
+    gemini_request: &str, // The actual request to "paste"
+) -> Result<Vec<String>> {
+    let selectors = ChatSelectors::new(input_selector, codeblock_selector, finished_selector);
+    let chat = BrowserChat::open(webdriver_url, url, selectors)?;
+    chat.ask(gemini_request)
+}
+
+/// CSS selectors locating the parts of a web chat UI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatSelectors {
+    pub input: String,
+    pub codeblock: String,
+    pub finished: Option<String>, // An element that appears once an answer is complete
+}
+
+impl ChatSelectors {
+    pub fn new(input: Option<&str>, codeblock: Option<&str>, finished: Option<&str>) -> Self {
+        Self {
+            input: input.unwrap_or(DEFAULT_INPUT_SELECTOR).to_string(),
+            codeblock: codeblock.unwrap_or(DEFAULT_CODEBLOCK_SELECTOR).to_string(),
+            finished: finished.map(str::to_string),
+        }
+    }
+}
+
+/// A web chat page open in a WebDriver session. The browser session ends when this is dropped.
+pub struct BrowserChat {
+    driver: WebDriverSession,
+    url: String,
+    selectors: ChatSelectors,
+    timeout: Duration,
+    poll_interval: Duration,
+}
+
+impl BrowserChat {
+    pub fn open(webdriver_url: &str, url: &str, selectors: ChatSelectors) -> Result<Self> {
+        let driver = WebDriverSession::start(webdriver_url)?;
+        driver.navigate(url)?;
+        Ok(Self { driver, url: url.to_string(), selectors, timeout: ANSWER_TIMEOUT, poll_interval: POLL_INTERVAL })
+    }
+
+    /// Loads the chat URL again, which starts a new conversation in most chat UIs.
+    pub fn restart(&self) -> Result<()> {
+        self.driver.navigate(&self.url)
+    }
+
+    /// How long to wait for an answer, and how often to check on it.
+    pub fn with_timing(mut self, timeout: Duration, poll_interval: Duration) -> Self {
+        self.timeout = timeout;
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Sends `prompt` and returns the text of the code blocks the answer added to the page.
+    pub fn ask(&self, prompt: &str) -> Result<Vec<String>> {
+        let blocks_before = loop {
+            if let Some(blocks) = self.code_blocks()? {
+                break blocks.len();
+            }
+            thread::sleep(self.poll_interval);
+        };
+        let finished_before = self.count_finished()?;
+
+        let input = self
+            .driver
+            .find_element(&self.selectors.input)?
+            .ok_or_else(|| format!("No input field matches `{}` on the chat page.", self.selectors.input))?;
+        self.driver.send_keys(&input, &typed_text(prompt))?;
+
+        let started = Instant::now();
+        let mut finished_gone = false;
+        let mut last_blocks: Option<Vec<String>> = None;
+        let mut stable_polls = 0;
+        loop {
+            thread::sleep(self.poll_interval);
+            if is_cancel_requested() {
+                return Err(Box::new(LLMApiError::Cancelled));
+            }
+            if let Some(blocks) = self.code_blocks()? {
+                let new_blocks = blocks.get(blocks_before..).unwrap_or_default().to_vec();
+                match finished_before {
+                    // The marker counts as new once more of them exist, or once it disappeared and came back.
+                    Some(before) => {
+                        let now = self.count_finished()?.unwrap_or(0);
+                        finished_gone |= now == 0;
+                        if now > before || (finished_gone && now > 0) {
+                            return Ok(new_blocks);
+                        }
+                    }
+                    None => {
+                        stable_polls = if !new_blocks.is_empty() && last_blocks.as_ref() == Some(&new_blocks) { stable_polls + 1 } else { 0 };
+                        if stable_polls >= STABLE_POLLS {
+                            return Ok(new_blocks);
+                        }
+                        last_blocks = Some(new_blocks);
+                    }
+                }
+            }
+            if started.elapsed() > self.timeout {
+                return Err(match &self.selectors.finished {
+                    Some(selector) => format!("No `{}` element appeared within {}s of sending the prompt.", selector, self.timeout.as_secs()),
+                    None => format!("No `{}` code block appeared within {}s of sending the prompt.", self.selectors.codeblock, self.timeout.as_secs()),
+                }
+                .into());
+            }
+        }
+    }
+
+    // The text of every code block, or None when the page replaced a block while it was read
+    // (streaming chat UIs rebuild the answer's nodes as it grows); the next poll reads them again.
+    fn code_blocks(&self) -> Result<Option<Vec<String>>> {
+        let texts: Result<Vec<String>> = self.driver.find_elements(&self.selectors.codeblock)?.iter().map(|element| self.driver.element_text(element)).collect();
+        match texts {
+            Err(e) if e.downcast_ref::<WebDriverError>().is_some_and(WebDriverError::is_gone_element) => Ok(None),
+            texts => texts.map(Some),
+        }
+    }
+
+    // How many finished markers the page shows, or None without a finished selector.
+    fn count_finished(&self) -> Result<Option<usize>> {
+        match &self.selectors.finished {
+            Some(selector) => Ok(Some(self.driver.find_elements(selector)?.len())),
+            None => Ok(None),
+        }
+    }
+}
+
+/// `LLMApi` over a web chat driven through WebDriver, so the whole agent runs against a free web UI.
+/// Each prompt is typed into the chat and the answer read back from its code blocks. The chat keeps
+/// its own history: a conversation continuing the previous one (a verification retry) only sends
+/// the new turns, and any other conversation starts a new chat.
+pub struct BrowserLLMApi {
+    webdriver_url: String,
+    url: String,
+    selectors: ChatSelectors,
+    model_name: String,
+    timing: Option<(Duration, Duration)>,
+    chat: RefCell<Option<BrowserChat>>, // Opened on the first call, so runs served from the session cache need no browser
+    history: RefCell<Vec<ChatTurn>>,    // The conversation the chat holds
+}
+
+impl BrowserLLMApi {
+    pub fn new(webdriver_url: &str, url: &str, selectors: ChatSelectors) -> Self {
+        let host = url.split("://").last().unwrap_or(url).split('/').next().filter(|host| !host.is_empty()).unwrap_or(url);
+        Self {
+            webdriver_url: webdriver_url.to_string(),
+            url: url.to_string(),
+            selectors,
+            model_name: format!("browser:{}", host),
+            timing: None,
+            chat: RefCell::new(None),
+            history: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// See `BrowserChat::with_timing`.
+    pub fn with_timing(mut self, timeout: Duration, poll_interval: Duration) -> Self {
+        self.timing = Some((timeout, poll_interval));
+        self
+    }
+
+    /// The name calls are routed and recorded under, e.g. `browser:chat.example.com`.
+    pub fn model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    /// Points every agent phase without a `--model` override at the web chat.
+    pub fn configure_agent_args(&self, args: &mut CustomCliArgs) {
+        route_unset_phases_to(&mut args.model_overrides, &self.model_name);
+    }
+
+    fn ask(&self, prompt: &str, new_chat: bool) -> Result<Vec<String>> {
+        let mut chat = self.chat.borrow_mut();
+        match chat.as_ref() {
+            Some(open) if new_chat => open.restart()?,
+            Some(_) => {}
+            None => {
+                let mut opened = BrowserChat::open(&self.webdriver_url, &self.url, self.selectors.clone())?;
+                if let Some((timeout, poll_interval)) = self.timing {
+                    opened = opened.with_timing(timeout, poll_interval);
+                }
+                *chat = Some(opened);
+            }
+        }
+        chat.as_ref().expect("chat was opened above").ask(prompt)
+    }
+}
+
+impl LLMApi for BrowserLLMApi {
+    fn generate_content(&self, prompt_text: &str, model_name: &str) -> Result<String> {
+        self.generate_conversation(&[ChatTurn::User(prompt_text.to_string())], model_name, None)
+    }
+
+    fn generate_conversation(
+        &self,
+        conversation: &[ChatTurn],
+        _model_name: &str,
+        response_schema: Option<&serde_json::Value>,
+    ) -> Result<String> {
+        let mut history = self.history.borrow_mut();
+        let continued = conversation.strip_prefix(history.as_slice()).filter(|new_turns| !history.is_empty() && !new_turns.is_empty());
+        let mut prompt = flatten_conversation(continued.unwrap_or(conversation));
+        if response_schema.is_some() {
+            prompt.push_str(JSON_CODE_BLOCK_INSTRUCTION);
+        }
+        let blocks = self.ask(&prompt, continued.is_none() && !history.is_empty())?;
+        let answer = answer_from_code_blocks(&blocks)
+            .ok_or_else(|| format!("The chat's answer has no `{}` code block to read.", self.selectors.codeblock))?;
+        *history = conversation.to_vec();
+        history.push(ChatTurn::Model(ModelReply { text: answer.clone(), tool_calls: Vec::new() }));
+        Ok(answer)
+    }
+}
+
+// Typing a newline would send the prompt early in most chat UIs, so lines are joined with Shift+Enter.
+fn typed_text(prompt: &str) -> String {
+    let line_break = format!("{}{}{}", KEY_SHIFT, KEY_ENTER, KEY_RELEASE_MODIFIERS);
+    format!("{}{}", prompt.replace("\r\n", "\n").replace('\n', &line_break), KEY_ENTER)
+}
+
+/// A minimal W3C WebDriver client (chromedriver, geckodriver, ...) over blocking HTTP.
+pub struct WebDriverSession {
+    client: reqwest::blocking::Client,
+    base_url: String, // ".../session/<id>"
+}
+
+impl WebDriverSession {
+    pub fn start(webdriver_url: &str) -> Result<Self> {
+        let client = reqwest::blocking::Client::new();
+        let webdriver_url = webdriver_url.trim_end_matches('/');
+        let body = json!({ "capabilities": { "alwaysMatch": {} } });
+        let response = client.post(format!("{}/session", webdriver_url)).json(&body).send().map_err(|e| {
+            format!(
+                "Cannot reach the WebDriver server at {} ({}). Start chromedriver or geckodriver, or pass its URL with --webdriver.",
+                webdriver_url, e
+            )
+        })?;
+        let value = webdriver_value("new session", response)?;
+        let session_id = value["sessionId"].as_str().ok_or("WebDriver new session: the response has no sessionId")?;
+        Ok(Self { client, base_url: format!("{}/session/{}", webdriver_url, session_id) })
+    }
+
+    pub fn navigate(&self, url: &str) -> Result<()> {
+        self.command("navigate", reqwest::Method::POST, "/url", Some(json!({ "url": url })))?;
+        Ok(())
+    }
+
+    /// The first element matching the CSS selector, or None.
+    pub fn find_element(&self, selector: &str) -> Result<Option<String>> {
+        Ok(self.find_elements(selector)?.into_iter().next())
+    }
+
+    /// Ids of the elements matching the CSS selector, in document order.
+    pub fn find_elements(&self, selector: &str) -> Result<Vec<String>> {
+        let body = json!({ "using": "css selector", "value": selector });
+        let value = self.command("find elements", reqwest::Method::POST, "/elements", Some(body))?;
+        Ok(value
+            .as_array()
+            .map(|elements| elements.iter().filter_map(|element| element[ELEMENT_KEY].as_str().map(str::to_string)).collect())
+            .unwrap_or_default())
+    }
+
+    pub fn send_keys(&self, element: &str, text: &str) -> Result<()> {
+        self.command("send keys", reqwest::Method::POST, &format!("/element/{}/value", element), Some(json!({ "text": text })))?;
+        Ok(())
+    }
+
+    /// The element's rendered text.
+    pub fn element_text(&self, element: &str) -> Result<String> {
+        let value = self.command("get element text", reqwest::Method::GET, &format!("/element/{}/text", element), None)?;
+        Ok(value.as_str().unwrap_or_default().to_string())
+    }
+
+    fn command(&self, name: &str, method: reqwest::Method, path: &str, body: Option<Value>) -> Result<Value> {
+        let mut request = self.client.request(method, format!("{}{}", self.base_url, path));
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().map_err(|e| format!("WebDriver {} failed: {}", name, e))?;
+        webdriver_value(name, response)
+    }
+}
+
+impl Drop for WebDriverSession {
+    fn drop(&mut self) {
+        let _ = self.client.delete(&self.base_url).send();
+    }
+}
+
+/// An error response from the WebDriver server.
+#[derive(Debug)]
+pub struct WebDriverError {
+    pub command: String,
+    pub status: u16,
+    pub error: String, // The W3C error code, e.g. "no such element"
+    pub message: String,
+}
+
+impl WebDriverError {
+    /// Whether the element the command referred to is no longer on the page.
+    pub fn is_gone_element(&self) -> bool {
+        matches!(self.error.as_str(), "stale element reference" | "no such element")
+    }
+}
+
+impl std::fmt::Display for WebDriverError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format!("WebDriver {} failed ({}): {} {}", self.command, self.status, self.error, self.message).trim_end())
+    }
+}
+
+impl std::error::Error for WebDriverError {}
+
+// The `value` of a WebDriver response, or its error as a `WebDriverError`.
+fn webdriver_value(name: &str, response: reqwest::blocking::Response) -> Result<Value> {
+    let status = response.status();
+    let body: Value = response.json().map_err(|e| format!("WebDriver {}: invalid response ({})", name, e))?;
+    let value = body.get("value").cloned().unwrap_or(Value::Null);
+    if !status.is_success() {
+        return Err(Box::new(WebDriverError {
+            command: name.to_string(),
+            status: status.as_u16(),
+            error: value["error"].as_str().unwrap_or("unknown error").to_string(),
+            message: value["message"].as_str().unwrap_or_default().to_string(),
+        }));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_text_joins_lines_with_shift_enter() {
+        assert_eq!(typed_text("fix it"), "fix it\u{E007}");
+        assert_eq!(typed_text("line 1\r\nline 2"), "line 1\u{E008}\u{E007}\u{E000}line 2\u{E007}");
+    }
 }
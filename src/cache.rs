@@ -1,32 +1,89 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::env;
 use std::fs;
-use std::io;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::llm_api::TokenUsage;
+
+/// One LLM call made during the session, as appended to `calls.jsonl`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CallRecord {
+    pub prompt_type: String,
+    pub prompt_hash: String,
+    pub model: String,           // The model that actually answered, after any fallback
+    pub timestamp: u64,          // Seconds since the Unix epoch
+    #[serde(default)]
+    pub usage: Option<TokenUsage>, // None if the backend did not report token counts
+}
+
+/// A compiler suggestion gem applied on its own after a failed verification (or had to skip), as appended to `auto_fixes.jsonl`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AutoFixRecord {
+    pub file: String,
+    pub line: usize,
+    pub message: String,     // The suggestion, e.g. "remove this `mut`"
+    pub replacement: String, // The text that replaced the span
+    pub timestamp: u64,      // Seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>, // Why it was not applied; None for applied suggestions
+}
+
+/// Directory holding one subdirectory per session (`~/.gem/session`).
+pub fn sessions_dir() -> PathBuf {
+    let home_dir = env::var("HOME").unwrap_or_else(|_| {
+        if cfg!(windows) {
+            env::var("USERPROFILE").unwrap_or_else(|_| ".".to_string())
+        } else {
+            ".".to_string()
+        }
+    });
+    PathBuf::from(home_dir).join(".gem").join("session")
+}
+
+/// Reads a session's `calls.jsonl`, skipping lines that do not parse.
+pub fn load_call_records(session_dir: &Path) -> Vec<CallRecord> {
+    load_jsonl(&session_dir.join("calls.jsonl"))
+}
+
+fn load_jsonl<T: DeserializeOwned>(path: &Path) -> Vec<T> {
+    fs::read_to_string(path)
+        .map(|content| {
+            content
+                .lines()
+                .filter_map(|line| serde_json::from_str::<T>(line).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn append_jsonl<T: Serialize>(path: &Path, record: &T) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(record)?)
+}
 
 /// Session manages both caching and persistent state across requests
 pub struct Session {
-    #[allow(dead_code)] // id is planned for future use (e.g., session resumption, logging)
     id: String,
     session_dir: PathBuf,
     pub gathered_data: HashMap<String, String>,
     in_memory_cache: HashMap<String, String>, // Stores prompt hash -> response
     prompts: HashMap<String, String>,         // Stores prompt_type-hash -> prompt text
+    calls: Vec<CallRecord>,                   // Every LLM call made in this session, oldest first
+    auto_fixes: Vec<AutoFixRecord>,           // Compiler suggestions applied without asking the model, oldest first
 }
 
 impl Session {
     pub fn new(session_id: &str) -> Self {
-        let home_dir = env::var("HOME").unwrap_or_else(|_| {
-            if cfg!(windows) {
-                env::var("USERPROFILE").unwrap_or_else(|_| ".".to_string())
-            } else {
-                ".".to_string()
-            }
-        });
-
-        let base_dir = PathBuf::from(home_dir).join(".gem");
-        let session_dir = base_dir.join("session").join(session_id);
+        let session_dir = sessions_dir().join(session_id);
         fs::create_dir_all(&session_dir).expect("Failed to create session directory");
 
         let mut gathered_data = HashMap::new();
@@ -39,6 +96,9 @@ impl Session {
             }
         }
 
+        let calls = load_call_records(&session_dir);
+        let auto_fixes = load_jsonl(&session_dir.join("auto_fixes.jsonl"));
+
         let mut in_memory_cache = HashMap::new();
         let mut prompts = HashMap::new();
 
@@ -78,6 +138,8 @@ impl Session {
             gathered_data,
             in_memory_cache,
             prompts,
+            calls,
+            auto_fixes,
         }
     }
 
@@ -141,6 +203,65 @@ impl Session {
         Ok(())
     }
 
+    /// Record which model answered a prompt and what it cost, appending it to `calls.jsonl`
+    pub fn record_call(&mut self, prompt_type: &str, prompt: &str, model: &str, usage: Option<TokenUsage>) -> io::Result<()> {
+        let record = CallRecord {
+            prompt_type: prompt_type.to_string(),
+            prompt_hash: Self::compute_hash(prompt),
+            model: model.to_string(),
+            timestamp: unix_now(),
+            usage,
+        };
+        append_jsonl(&self.session_dir.join("calls.jsonl"), &record)?;
+        self.calls.push(record);
+        Ok(())
+    }
+
+    /// The session's id, which gem's commits carry as their `Gem-Session:` trailer
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// All LLM calls recorded in this session, oldest first
+    pub fn calls(&self) -> &[CallRecord] {
+        &self.calls
+    }
+
+    /// Record a compiler suggestion applied to `file`, appending it to `auto_fixes.jsonl`
+    pub fn record_auto_fix(&mut self, file: &str, line: usize, message: &str, replacement: &str) -> io::Result<()> {
+        let record = AutoFixRecord {
+            file: file.to_string(),
+            line,
+            message: message.to_string(),
+            replacement: replacement.to_string(),
+            timestamp: unix_now(),
+            skipped: None,
+        };
+        append_jsonl(&self.session_dir.join("auto_fixes.jsonl"), &record)?;
+        self.auto_fixes.push(record);
+        Ok(())
+    }
+
+    /// Record a compiler suggestion for `file` that could not be applied, and why
+    pub fn record_skipped_fix(&mut self, file: &str, line: usize, message: &str, reason: &str) -> io::Result<()> {
+        let record = AutoFixRecord {
+            file: file.to_string(),
+            line,
+            message: message.to_string(),
+            replacement: String::new(),
+            timestamp: unix_now(),
+            skipped: Some(reason.to_string()),
+        };
+        append_jsonl(&self.session_dir.join("auto_fixes.jsonl"), &record)?;
+        self.auto_fixes.push(record);
+        Ok(())
+    }
+
+    /// All compiler suggestions applied (or skipped) in this session, oldest first
+    pub fn auto_fixes(&self) -> &[AutoFixRecord] {
+        &self.auto_fixes
+    }
+
     pub fn load_mock_cache(&mut self, mock_data: HashMap<String, String>) {
         self.in_memory_cache.extend(mock_data);
     }
@@ -263,6 +384,46 @@ mod tests {
         assert_eq!(fs::read_to_string(response_file_path).unwrap(), response_text);
     }
 
+    #[test]
+    #[serial]
+    fn test_record_call_persists_models() {
+        let session_id = "test_record_call_session";
+        let (mut session, session_dir, _temp_dir_guard) = setup_session(session_id);
+
+        let usage = TokenUsage { prompt_tokens: 100, candidate_tokens: 20, thinking_tokens: 5, cached_tokens: 0 };
+        session.record_call("initial", "prompt one", "gemini-2.5-flash", Some(usage)).unwrap();
+        session.record_call("change", "prompt two", "gemini-2.5-pro", None).unwrap();
+
+        assert!(session_dir.join("calls.jsonl").exists());
+        let loaded_session = Session::new(session_id);
+        let models: Vec<(&str, &str)> = loaded_session
+            .calls()
+            .iter()
+            .map(|c| (c.prompt_type.as_str(), c.model.as_str()))
+            .collect();
+        assert_eq!(models, vec![("initial", "gemini-2.5-flash"), ("change", "gemini-2.5-pro")]);
+        assert_eq!(loaded_session.calls()[1].prompt_hash, Session::compute_hash("prompt two"));
+        assert_eq!(loaded_session.calls()[0].usage, Some(usage));
+        assert_eq!(loaded_session.calls()[1].usage, None);
+        assert!(loaded_session.in_memory_cache.is_empty()); // calls.jsonl is not mistaken for a response file
+    }
+
+    #[test]
+    #[serial]
+    fn test_record_auto_fix_persists_fixes() {
+        let session_id = "test_record_auto_fix_session";
+        let (mut session, session_dir, _temp_dir_guard) = setup_session(session_id);
+
+        session.record_auto_fix("src/lib.rs", 3, "remove this `mut`", "").unwrap();
+        session.record_skipped_fix("../other/src/lib.rs", 7, "remove this `mut`", "the file is outside the project").unwrap();
+
+        assert!(session_dir.join("auto_fixes.jsonl").exists());
+        let loaded_session = Session::new(session_id);
+        let fixes: Vec<(&str, usize, Option<&str>)> = loaded_session.auto_fixes().iter().map(|f| (f.file.as_str(), f.line, f.skipped.as_deref())).collect();
+        assert_eq!(fixes, vec![("src/lib.rs", 3, None), ("../other/src/lib.rs", 7, Some("the file is outside the project"))]);
+        assert!(loaded_session.calls().is_empty());
+    }
+
     #[test]
     #[serial]
     fn test_get_cached_response_miss() {
@@ -1,8 +1,19 @@
 use clap::Parser;
+use std::io::{self, Read};
 use std::path::PathBuf;
 
+use crate::model_router::{parse_model_chain_override, ModelChainOverride};
+
 pub const MAX_DATA_GATHERING_ITERATIONS_DEFAULT: usize = 3;
 pub const MAX_VERIFICATION_RETRIES_DEFAULT: usize = 2;
+pub const MAX_API_RETRIES_DEFAULT: usize = 3;
+pub const VERIFY_TIMEOUT_SECS_DEFAULT: u64 = 600;
+pub const DEFAULT_VERIFY_COMMAND: &str = "cargo build";
+pub const CONTEXT_CACHE_TTL_SECS_DEFAULT: u64 = 600;
+pub const CONTEXT_CACHE_MIN_TOKENS_DEFAULT: usize = 4096;
+pub const DEFAULT_LOCAL_MODEL: &str = "google/gemma-3-1b-it";
+pub const DEFAULT_WEBDRIVER_URL: &str = "http://localhost:4444";
+pub const DEFAULT_END_MARKER: &str = "---END---";
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum DebugMode {
@@ -37,12 +48,14 @@ fn parse_debug_mode(s: &str) -> Result<DebugMode, String> {
 pub struct CustomCliArgs {
     /// The user's request in natural language (e.g., "change structs to a SOA architecture in the tests folder")
     /// Can also be read from stdin if not provided.
-    #[arg(name = "USER_REQUEST_PARTS", required_unless_present_any = ["browser", "local"])]
+    #[arg(name = "USER_REQUEST_PARTS", required_unless_present_any = ["browser", "local", "manual", "usage_report"])]
     pub user_request_parts: Vec<String>,
 
-    /// Command to verify the changes (e.g., "cargo test --all-features")
-    #[arg(long, default_value = "cargo build")]
-    pub verify_with: String,
+    /// Command to verify the changes (e.g., "cargo test --all-features"). Repeat it for a pipeline of
+    /// stages run in order; prefix a command with "warn:" to only warn when it fails. Defaults to the
+    /// `[[verify]]` stages of gem.toml, else "cargo build".
+    #[arg(long, value_name = "COMMAND")]
+    pub verify_with: Vec<String>,
 
     /// Do not ask Gemini to generate tests
     #[arg(long)]
@@ -56,7 +69,7 @@ pub struct CustomCliArgs {
     #[arg(long)]
     pub project_file: Option<PathBuf>,
 
-    /// Maximum number of data gathering loops
+    /// Maximum number of data gathering loops (tool calls, when the backend supports tool calling)
     #[arg(long, default_value_t = MAX_DATA_GATHERING_ITERATIONS_DEFAULT)]
     pub max_data_loops: usize,
 
@@ -64,6 +77,27 @@ pub struct CustomCliArgs {
     #[arg(long, default_value_t = MAX_VERIFICATION_RETRIES_DEFAULT)]
     pub max_verify_retries: usize,
 
+    /// Seconds before the verification command, and every process it started, is killed
+    #[arg(long, value_name = "SECS", default_value_t = VERIFY_TIMEOUT_SECS_DEFAULT)]
+    pub verify_timeout: u64,
+
+    /// Stash uncommitted changes to tracked files before starting, instead of refusing to run
+    #[arg(long)]
+    pub stash: bool,
+
+    /// Author of gem's commit, as "Name <email>" (default: your git identity)
+    #[arg(long, value_name = "AUTHOR")]
+    pub commit_author: Option<String>,
+
+    /// Maximum number of retries for rate-limited (429) or overloaded (5xx) LLM API calls
+    #[arg(long, default_value_t = MAX_API_RETRIES_DEFAULT)]
+    pub max_api_retries: usize,
+
+    /// Upper limit for the estimated prompt size in tokens. Defaults to the smallest context window
+    /// among the models a phase may use; gathered items over the limit are summarised or dropped.
+    #[arg(long)]
+    pub max_prompt_tokens: Option<usize>,
+
     /// Debug mode: Runs up to a specific stage and prints information.
     /// Valid stages: initial, sufficient, changes.
     #[arg(long, value_parser = parse_debug_mode)]
@@ -101,9 +135,79 @@ pub struct CustomCliArgs {
     #[arg(long = "finished", requires = "browser")]
     pub finished_selector: Option<String>,
 
-    /// Use a local model (e.g., Gemma) instead of a remote API.
-    #[arg(long = "local")]
-    pub local: bool,
+    /// URL of the WebDriver server (chromedriver, geckodriver, ...) that drives the browser for --browser.
+    #[arg(long = "webdriver", value_name = "URL", default_value = DEFAULT_WEBDRIVER_URL)]
+    pub webdriver_url: String,
+
+    /// Use a local model instead of a remote API: a model id (e.g. "google/gemma-3-1b-it", the default),
+    /// a local model directory or a GGUF file.
+    #[arg(long = "local", value_name = "MODEL", num_args = 0..=1, default_missing_value = DEFAULT_LOCAL_MODEL)]
+    pub local: Option<String>,
+
+    /// Copy-paste mode: show each prompt, paste it into any chat UI and paste the answer back.
+    #[arg(long)]
+    pub manual: bool,
+
+    /// Write each prompt to this file instead of the terminal (requires --manual).
+    #[arg(long, value_name = "PATH", requires = "manual")]
+    pub prompt_file: Option<PathBuf>,
+
+    /// Also copy each prompt to the clipboard (requires --manual).
+    #[arg(long, requires = "manual")]
+    pub clipboard: bool,
+
+    /// Line that ends a pasted answer in --manual mode; EOF (Ctrl-D) ends it too.
+    #[arg(long, value_name = "TEXT", default_value = DEFAULT_END_MARKER)]
+    pub end_marker: String,
+
+    /// Base URL of an OpenAI-compatible chat completions server (e.g., "http://localhost:8080/v1").
+    /// When set, it is used instead of the Gemini API. The bearer token is read from OPENAI_API_KEY.
+    #[arg(long)]
+    pub openai_base_url: Option<String>,
+
+    /// Model name to request from the OpenAI-compatible server (requires --openai-base-url).
+    #[arg(long, requires = "openai_base_url")]
+    pub openai_model: Option<String>,
+
+    /// Ordered model fallback chain for an agent phase, e.g. "change=gemini-2.5-pro,gemini-2.5-flash".
+    /// Phases: initial, sufficient, change, retry. Can be given once per phase.
+    #[arg(long = "model", value_name = "PHASE=MODEL[,MODEL...]", value_parser = parse_model_chain_override)]
+    pub model_overrides: Vec<ModelChainOverride>,
+
+    /// Wait for complete Gemini responses instead of streaming partial output.
+    #[arg(long)]
+    pub no_stream: bool,
+
+    /// Print token usage totals across all sessions, by day and model, and exit.
+    #[arg(long)]
+    pub usage_report: bool,
+
+    /// Gather context through a `needed_items` JSON list instead of native tool calls.
+    #[arg(long)]
+    pub no_tools: bool,
+
+    /// How long the Gemini context cache holding the project context lives, in seconds.
+    /// It is deleted when the run ends; 0 disables caching.
+    #[arg(long, value_name = "SECS", default_value_t = CONTEXT_CACHE_TTL_SECS_DEFAULT)]
+    pub context_cache_ttl: u64,
+
+    /// Smallest project context (estimated tokens) worth caching; smaller contexts are sent with every request.
+    #[arg(long, value_name = "N", default_value_t = CONTEXT_CACHE_MIN_TOKENS_DEFAULT)]
+    pub context_cache_min_tokens: usize,
+}
+
+impl CustomCliArgs {
+    /// Reads the request from `stdin` when none was given as arguments. The agent builds its prompts
+    /// from `user_request_parts`, so a piped request is stored there. Returns whether it was read.
+    pub fn read_request_from_stdin(&mut self, mut stdin: impl Read) -> io::Result<bool> {
+        if !self.user_request_parts.is_empty() {
+            return Ok(false);
+        }
+        let mut request = String::new();
+        stdin.read_to_string(&mut request)?;
+        self.user_request_parts = vec![request.trim().to_string()]; // Trim whitespace, especially trailing newlines
+        Ok(true)
+    }
 }
 
 // The old manual parsing logic (parse_cli_args and print_custom_help) is removed.
@@ -122,7 +226,14 @@ mod tests {
     fn test_clap_basic_parsing() {
         let args = CustomCliArgs::try_parse_from(&["gem", "my", "request"]).unwrap();
         assert_eq!(args.user_request_parts, vec!["my", "request"]);
-        assert_eq!(args.verify_with, "cargo build"); // Default
+        assert!(args.verify_with.is_empty()); // Resolved to gem.toml's stages or DEFAULT_VERIFY_COMMAND
+        assert_eq!(args.verify_timeout, VERIFY_TIMEOUT_SECS_DEFAULT);
+
+        let args = CustomCliArgs::try_parse_from(["gem", "--verify-timeout", "90", "my", "request"]).unwrap();
+        assert_eq!(args.verify_timeout, 90);
+
+        let args = CustomCliArgs::try_parse_from(["gem", "--verify-with", "warn:cargo fmt --check", "--verify-with", "cargo test", "my", "request"]).unwrap();
+        assert_eq!(args.verify_with, vec!["warn:cargo fmt --check", "cargo test"]);
     }
 
     #[test]
@@ -183,9 +294,92 @@ mod tests {
 
     #[test]
     fn test_clap_renamed_local_option() {
-        let args = CustomCliArgs::try_parse_from(&["gem", "--local", "local stuff"]).unwrap();
-        assert!(args.local);
+        let args = CustomCliArgs::try_parse_from(["gem", "--local", "--", "local stuff"]).unwrap();
+        assert_eq!(args.local.as_deref(), Some(DEFAULT_LOCAL_MODEL));
+        assert_eq!(args.user_request_parts, vec!["local stuff"]);
+
+        let args = CustomCliArgs::try_parse_from(["gem", "--local", "models/qwen2.5-coder-1.5b.gguf", "local stuff"]).unwrap();
+        assert_eq!(args.local.as_deref(), Some("models/qwen2.5-coder-1.5b.gguf"));
         assert_eq!(args.user_request_parts, vec!["local stuff"]);
+
+        let args = CustomCliArgs::try_parse_from(["gem", "remote stuff"]).unwrap();
+        assert_eq!(args.local, None);
+    }
+
+    #[test]
+    fn test_clap_manual_options() {
+        let args = CustomCliArgs::try_parse_from(["gem", "--manual", "--prompt-file", "prompt.md", "--clipboard", "--end-marker", "EOF!", "paste task"]).unwrap();
+        assert!(args.manual && args.clipboard);
+        assert_eq!(args.prompt_file, Some(PathBuf::from("prompt.md")));
+        assert_eq!(args.end_marker, "EOF!");
+
+        let args = CustomCliArgs::try_parse_from(["gem", "--manual", "paste task"]).unwrap();
+        assert_eq!((args.prompt_file, args.clipboard, args.end_marker.as_str()), (None, false, DEFAULT_END_MARKER));
+
+        assert!(CustomCliArgs::try_parse_from(["gem", "--clipboard", "paste task"]).is_err());
+        // The request may be piped on stdin instead.
+        assert!(CustomCliArgs::try_parse_from(["gem", "--manual"]).unwrap().user_request_parts.is_empty());
+    }
+
+    #[test]
+    fn test_request_is_read_from_stdin_only_without_arguments() {
+        let mut args = CustomCliArgs::try_parse_from(["gem", "--browser", "https://chat.example.com"]).unwrap();
+        assert!(args.read_request_from_stdin("add a greeting\n".as_bytes()).unwrap());
+        assert_eq!(args.user_request_parts, vec!["add a greeting"]);
+
+        let mut args = CustomCliArgs::try_parse_from(["gem", "my", "request"]).unwrap();
+        assert!(!args.read_request_from_stdin("ignored".as_bytes()).unwrap());
+        assert_eq!(args.user_request_parts, vec!["my", "request"]);
+    }
+
+    #[test]
+    fn test_clap_git_options() {
+        let args = CustomCliArgs::try_parse_from(["gem", "my", "request"]).unwrap();
+        assert_eq!((args.stash, args.commit_author), (false, None));
+
+        let args = CustomCliArgs::try_parse_from(["gem", "--stash", "--commit-author", "gem <gem@example.com>", "my", "request"]).unwrap();
+        assert!(args.stash);
+        assert_eq!(args.commit_author.as_deref(), Some("gem <gem@example.com>"));
+    }
+
+    #[test]
+    fn test_clap_openai_options() {
+        let args = CustomCliArgs::try_parse_from(&[
+            "gem",
+            "--openai-base-url", "http://localhost:8080/v1",
+            "--openai-model", "qwen2.5-coder",
+            "openai task"
+        ]).unwrap();
+        assert_eq!(args.openai_base_url, Some("http://localhost:8080/v1".to_string()));
+        assert_eq!(args.openai_model, Some("qwen2.5-coder".to_string()));
+
+        let result = CustomCliArgs::try_parse_from(&["gem", "--openai-model", "qwen2.5-coder", "task"]);
+        assert!(result.is_err(), "--openai-model should require --openai-base-url");
+    }
+
+    #[test]
+    fn test_clap_model_overrides() {
+        let args = CustomCliArgs::try_parse_from(&[
+            "gem",
+            "--model", "change=gemini-2.5-pro,gemini-2.5-flash",
+            "--model", "initial=gemini-2.5-flash-lite",
+            "routed task"
+        ]).unwrap();
+        assert_eq!(args.model_overrides.len(), 2);
+        assert_eq!(args.model_overrides[0].phase, crate::model_router::AgentPhase::Change);
+        assert_eq!(args.model_overrides[0].models, vec!["gemini-2.5-pro", "gemini-2.5-flash"]);
+        assert_eq!(args.model_overrides[1].models, vec!["gemini-2.5-flash-lite"]);
+
+        assert!(CustomCliArgs::try_parse_from(&["gem", "--model", "gemini-2.5-pro", "task"]).is_err());
+    }
+
+    #[test]
+    fn test_clap_max_prompt_tokens() {
+        let args = CustomCliArgs::try_parse_from(&["gem", "--max-prompt-tokens", "16000", "budgeted task"]).unwrap();
+        assert_eq!(args.max_prompt_tokens, Some(16000));
+
+        let args = CustomCliArgs::try_parse_from(&["gem", "unbudgeted task"]).unwrap();
+        assert_eq!(args.max_prompt_tokens, None);
     }
 
     #[test]
@@ -202,6 +396,10 @@ mod tests {
         assert!(args.no_code);
         assert!(args.no_readme);
         assert!(args.auto_tool_selection);
+        assert!(!args.no_stream);
+        assert!(!args.no_tools);
+        assert_eq!(args.context_cache_ttl, CONTEXT_CACHE_TTL_SECS_DEFAULT);
+        assert_eq!(args.context_cache_min_tokens, CONTEXT_CACHE_MIN_TOKENS_DEFAULT);
         assert_eq!(args.user_request_parts, vec!["task with new flags"]);
     }
 
@@ -230,12 +428,13 @@ mod tests {
         .unwrap();
 
         assert_eq!(args.user_request_parts, vec!["full", "request"]);
-        assert_eq!(args.verify_with, "cargo test");
+        assert_eq!(args.verify_with, vec!["cargo test"]);
         assert!(args.no_test);
         assert_eq!(args.project_root, PathBuf::from("/app"));
         assert_eq!(args.project_file, Some(PathBuf::from("src/lib.rs")));
         assert_eq!(args.max_data_loops, 5);
         assert_eq!(args.max_verify_retries, 3);
+        assert_eq!(args.max_api_retries, MAX_API_RETRIES_DEFAULT);
         assert_eq!(args.debug_mode, Some(DebugMode::Changes));
         assert!(args.no_explanation);
         assert!(args.no_code);
@@ -245,13 +444,13 @@ mod tests {
         assert_eq!(args.input_selector, Some("#chat".to_string()));
         assert_eq!(args.codeblock_selector, Some("pre".to_string()));
         assert_eq!(args.finished_selector, Some(".done".to_string()));
-        assert!(args.local);
+        assert_eq!(args.local.as_deref(), Some(DEFAULT_LOCAL_MODEL));
     }
 
     #[test]
     fn test_clap_missing_user_request_ok_if_local_or_browser() {
         let args_local = CustomCliArgs::try_parse_from(&["gem", "--local"]).unwrap();
-        assert!(args_local.local);
+        assert!(args_local.local.is_some());
         assert!(args_local.user_request_parts.is_empty());
 
         let args_browser = CustomCliArgs::try_parse_from(&["gem", "--browser", "http://example.com"]).unwrap();
@@ -264,4 +463,11 @@ mod tests {
         let result = CustomCliArgs::try_parse_from(&["gem", "--no-test"]); // No request, not local, not browser
         assert!(result.is_err(), "Parsing should fail if user request is missing and not in local or browser mode.");
     }
+
+    #[test]
+    fn test_clap_usage_report_needs_no_request() {
+        let args = CustomCliArgs::try_parse_from(&["gem", "--usage-report"]).unwrap();
+        assert!(args.usage_report);
+        assert!(args.user_request_parts.is_empty());
+    }
 }
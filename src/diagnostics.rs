@@ -0,0 +1,466 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use serde::Deserialize;
+
+use crate::verification::VerificationResult;
+
+// Cargo subcommands that accept `--message-format=json` and report compiler messages with it.
+const JSON_SUBCOMMANDS: &[&str] = &["build", "b", "check", "c", "test", "t", "clippy", "bench", "rustc", "doc"];
+
+/// One line of `cargo --message-format=json` output that carries a compiler message.
+#[derive(Debug, Clone, Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<CompilerMessage>,
+}
+
+/// A rustc diagnostic as emitted in JSON (https://doc.rust-lang.org/rustc/json.html).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CompilerMessage {
+    pub message: String,
+    pub code: Option<DiagnosticCode>,
+    pub level: String,
+    pub spans: Vec<DiagnosticSpan>,
+    #[serde(default)]
+    pub children: Vec<CompilerMessage>,
+    pub rendered: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DiagnosticCode {
+    pub code: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DiagnosticSpan {
+    pub file_name: String,
+    pub byte_start: usize,
+    pub byte_end: usize,
+    pub line_start: usize,
+    pub line_end: usize,
+    pub column_start: usize,
+    pub column_end: usize,
+    pub is_primary: bool,
+    pub suggested_replacement: Option<String>,
+    pub suggestion_applicability: Option<String>,
+}
+
+impl CompilerMessage {
+    pub fn is_error(&self) -> bool {
+        self.level.starts_with("error")
+    }
+
+    pub fn primary_span(&self) -> Option<&DiagnosticSpan> {
+        self.spans.iter().find(|span| span.is_primary).or(self.spans.first())
+    }
+
+    // The same message reported again, e.g. once for the lib and once for its test target.
+    fn same_as(&self, other: &Self) -> bool {
+        self.level == other.level && self.code == other.code && self.message == other.message && self.position() == other.position()
+    }
+
+    fn position(&self) -> Option<(&str, usize, usize, usize)> {
+        self.primary_span().map(|span| (span.file_name.as_str(), span.line_start, span.line_end, span.column_start))
+    }
+}
+
+/// What a cargo command printed on stdout with `--message-format=json`.
+#[derive(Debug, Default)]
+pub struct CargoOutput {
+    pub messages: Vec<CompilerMessage>, // In order, duplicates removed
+    pub other_output: String,           // Lines that are not JSON, such as test results
+    pub saw_json: bool,
+}
+
+impl CargoOutput {
+    pub fn parse(stdout: &str) -> Self {
+        let mut output = CargoOutput::default();
+        for line in stdout.lines() {
+            match serde_json::from_str::<CargoMessage>(line) {
+                Ok(parsed) => {
+                    output.saw_json = true;
+                    if let (true, Some(message)) = (parsed.reason == "compiler-message", parsed.message) {
+                        if !output.messages.iter().any(|seen| seen.same_as(&message)) {
+                            output.messages.push(message);
+                        }
+                    }
+                }
+                Err(_) => {
+                    output.other_output.push_str(line);
+                    output.other_output.push('\n');
+                }
+            }
+        }
+        output
+    }
+
+    /// Errors worth reporting; rustc's closing "aborting due to ..." summary is left out.
+    pub fn errors(&self) -> Vec<&CompilerMessage> {
+        self.messages.iter().filter(|message| message.is_error() && !(message.spans.is_empty() && message.message.starts_with("aborting due to"))).collect()
+    }
+}
+
+/// A compiler suggestion applied to the project's files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppliedFix {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+    pub replacement: String,
+}
+
+// One suggestion whose spans are replaced together.
+struct Suggestion<'a> {
+    message: &'a str,
+    spans: Vec<&'a DiagnosticSpan>,
+}
+
+impl CargoOutput {
+    // The `MachineApplicable` suggestions attached to errors, each distinct fix once.
+    fn machine_applicable_suggestions(&self) -> Vec<Suggestion<'_>> {
+        let mut suggestions: Vec<Suggestion> = Vec::new();
+        for error in self.errors() {
+            for message in std::iter::once(error).chain(&error.children) {
+                let spans: Vec<&DiagnosticSpan> = message
+                    .spans
+                    .iter()
+                    .filter(|span| span.suggestion_applicability.as_deref() == Some("MachineApplicable") && span.suggested_replacement.is_some())
+                    .collect();
+                if !spans.is_empty() && !suggestions.iter().any(|seen| seen.spans == spans) {
+                    suggestions.push(Suggestion { message: &message.message, spans });
+                }
+            }
+        }
+        suggestions
+    }
+}
+
+/// A compiler suggestion gem could not apply; the model gets the error instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedFix {
+    pub file: String, // As the compiler named it, relative to the workspace root
+    pub line: usize,
+    pub message: String,
+    pub reason: String,
+}
+
+/// What `apply_machine_applicable_fixes` did with the suggestions.
+#[derive(Debug, Default)]
+pub struct AutoFixes {
+    pub applied: Vec<AppliedFix>,
+    pub skipped: Vec<SkippedFix>,
+}
+
+/// The root of the cargo workspace `project_root` belongs to, which compiler span file names are
+/// relative to. `project_root` itself when cargo cannot tell.
+pub fn workspace_root(project_root: &Path) -> PathBuf {
+    #[derive(Deserialize)]
+    struct Metadata {
+        workspace_root: PathBuf,
+    }
+    Command::new("cargo")
+        .args(["metadata", "--no-deps", "--format-version=1"])
+        .current_dir(project_root)
+        .stdin(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| serde_json::from_slice::<Metadata>(&output.stdout).ok())
+        .map(|metadata| metadata.workspace_root)
+        .unwrap_or_else(|| project_root.to_path_buf())
+}
+
+/// Applies the `MachineApplicable` suggestions of the errors in `output` to the files under
+/// `project_root`, resolving the compiler's file names against `workspace_root`. Suggestions
+/// overlapping one applied before them are left for the next round; those touching files outside
+/// the project, or files that cannot be read or no longer match the compiler's view, are skipped.
+/// `applied` has one entry per replaced span, with its file relative to the project root.
+pub fn apply_machine_applicable_fixes(project_root: &Path, workspace_root: &Path, output: &CargoOutput) -> AutoFixes {
+    let project_root = fs::canonicalize(project_root).unwrap_or_else(|_| project_root.to_path_buf());
+    let workspace_root = fs::canonicalize(workspace_root).unwrap_or_else(|_| workspace_root.to_path_buf());
+    let project_file = |file_name: &str| -> Option<String> {
+        let plain = Path::new(file_name).components().all(|part| matches!(part, Component::Normal(_) | Component::CurDir));
+        let relative = workspace_root.join(file_name).strip_prefix(&project_root).ok()?.to_string_lossy().into_owned();
+        plain.then_some(relative)
+    };
+    let skip = |span: &DiagnosticSpan, message: &str, reason: String| SkippedFix { file: span.file_name.clone(), line: span.line_start, message: message.to_string(), reason };
+
+    let mut fixes = AutoFixes::default();
+    let mut edits: BTreeMap<String, Vec<(&DiagnosticSpan, &str)>> = BTreeMap::new();
+    for suggestion in output.machine_applicable_suggestions() {
+        let files: Option<Vec<String>> = suggestion.spans.iter().map(|span| project_file(&span.file_name)).collect();
+        let Some(files) = files else {
+            fixes.skipped.push(skip(suggestion.spans[0], suggestion.message, "the file is outside the project".to_string()));
+            continue;
+        };
+        let overlapping = suggestion.spans.iter().zip(&files).any(|(span, file)| {
+            let overlaps = |(other, _): &(&DiagnosticSpan, &str)| other.byte_start == span.byte_start || (other.byte_start < span.byte_end && span.byte_start < other.byte_end);
+            edits.get(file).is_some_and(|file_edits| file_edits.iter().any(overlaps))
+        });
+        if !overlapping {
+            for (span, file) in suggestion.spans.into_iter().zip(files) {
+                edits.entry(file).or_default().push((span, suggestion.message));
+            }
+        }
+    }
+
+    for (file, mut file_edits) in edits {
+        let path = project_root.join(&file);
+        let mut content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                fixes.skipped.extend(file_edits.into_iter().map(|(span, message)| skip(span, message, format!("cannot read {}: {}", path.display(), e))));
+                continue;
+            }
+        };
+        // Back to front, so earlier byte offsets stay valid.
+        file_edits.sort_by_key(|(span, _)| std::cmp::Reverse(span.byte_start));
+        let mut applied = Vec::new();
+        for (span, message) in file_edits {
+            let replacement = span.suggested_replacement.as_deref().unwrap_or_default();
+            if span.byte_end > content.len() || !content.is_char_boundary(span.byte_start) || !content.is_char_boundary(span.byte_end) {
+                fixes.skipped.push(skip(span, message, "the file no longer matches what the compiler saw".to_string()));
+                continue;
+            }
+            content.replace_range(span.byte_start..span.byte_end, replacement);
+            applied.push((span, AppliedFix { file: file.clone(), line: span.line_start, message: message.to_string(), replacement: replacement.to_string() }));
+        }
+        match fs::write(&path, content) {
+            Ok(()) => fixes.applied.extend(applied.into_iter().map(|(_, fix)| fix)),
+            Err(e) => fixes.skipped.extend(applied.into_iter().map(|(span, fix)| skip(span, &fix.message, format!("cannot write {}: {}", path.display(), e)))),
+        }
+    }
+    fixes.applied.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    fixes
+}
+
+/// Tells the model which suggestions gem applied itself, since its view of the files predates them.
+pub fn describe_applied_fixes(fixes: &[AppliedFix]) -> String {
+    let mut description = "gem applied these compiler suggestions before the run above; the files already contain them:".to_string();
+    for fix in fixes {
+        description.push_str(&format!("\n- {} line {}: {}", fix.file, fix.line, fix.message));
+    }
+    description
+}
+
+/// `command` with `--message-format=json` added after its cargo subcommand, or None when it is not
+/// a single cargo command that supports it (or already picks a message format).
+pub fn with_json_message_format(command: &str) -> Option<String> {
+    if command.contains("--message-format") || command.contains(['&', '|', ';', '>', '<', '`', '$']) {
+        return None;
+    }
+    let words: Vec<&str> = command.split_whitespace().collect();
+    if words.first() != Some(&"cargo") {
+        return None;
+    }
+    // Skip toolchain (`+nightly`) and global flags (`--locked`) before the subcommand.
+    let subcommand = words.iter().skip(1).position(|word| !word.starts_with(['-', '+'])).map(|i| i + 1)?;
+    if !JSON_SUBCOMMANDS.contains(&words[subcommand]) {
+        return None;
+    }
+    let mut rewritten = words[..=subcommand].to_vec();
+    rewritten.push("--message-format=json");
+    rewritten.extend(&words[subcommand + 1..]);
+    Some(rewritten.join(" "))
+}
+
+/// The verification result as shown to the user and fed back to the model. For JSON cargo output,
+/// the compiler errors are listed once each with their location, code and rendered snippet, and the
+/// remaining stdout is kept as text; any other output is reported as is.
+pub fn verification_report(result: &VerificationResult) -> String {
+    let cargo = CargoOutput::parse(&result.stdout);
+    if !cargo.saw_json {
+        return result.report();
+    }
+    let report = VerificationResult { stdout: cargo.other_output.clone(), ..result.clone() }.report();
+    let errors = cargo.errors();
+    if errors.is_empty() {
+        return report;
+    }
+    format!("{}\n\n{}", report, format_errors(&errors))
+}
+
+fn format_errors(errors: &[&CompilerMessage]) -> String {
+    let mut formatted = format!("Compiler errors ({}, duplicates removed):", errors.len());
+    for (i, error) in errors.iter().enumerate() {
+        let code = error.code.as_ref().map(|code| format!("[{}]", code.code)).unwrap_or_default();
+        let location = match error.primary_span() {
+            Some(span) if span.line_start == span.line_end => format!(" in {} line {}", span.file_name, span.line_start),
+            Some(span) => format!(" in {} lines {}-{}", span.file_name, span.line_start, span.line_end),
+            None => String::new(),
+        };
+        formatted.push_str(&format!("\n\n{}. {}{}{}: {}", i + 1, error.level, code, location, error.message));
+        if let Some(rendered) = &error.rendered {
+            formatted.push_str(&format!("\n{}", rendered.trim_end()));
+        }
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn compiler_message(level: &str, code: Option<&str>, message: &str, file: &str, line: usize) -> String {
+        let rendered = format!("{}: {}\n --> {}:{}:5\n", level, message, file, line);
+        json!({
+            "reason": "compiler-message",
+            "package_id": "test_project 0.1.0",
+            "message": {
+                "message": message,
+                "code": code.map(|code| json!({ "code": code, "explanation": null })),
+                "level": level,
+                "spans": [{
+                    "file_name": file, "byte_start": 10, "byte_end": 15, "line_start": line, "line_end": line,
+                    "column_start": 5, "column_end": 10, "is_primary": true, "text": [], "label": null,
+                    "suggested_replacement": null, "suggestion_applicability": null, "expansion": null
+                }],
+                "children": [],
+                "rendered": rendered
+            }
+        })
+        .to_string()
+    }
+
+    fn failed_run(stdout: String) -> VerificationResult {
+        VerificationResult {
+            command: "cargo test --message-format=json".to_string(),
+            exit_code: Some(101),
+            timed_out: false,
+            duration: Duration::from_millis(1500),
+            stdout,
+            stderr: "error: could not compile `test_project`\n".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_adds_json_message_format_to_cargo_commands() {
+        assert_eq!(with_json_message_format("cargo check").as_deref(), Some("cargo check --message-format=json"));
+        assert_eq!(with_json_message_format("cargo +nightly --locked test --all-features -- --nocapture").as_deref(), Some("cargo +nightly --locked test --message-format=json --all-features -- --nocapture"));
+        assert_eq!(with_json_message_format("cargo clippy -- -D warnings").as_deref(), Some("cargo clippy --message-format=json -- -D warnings"));
+        assert_eq!(with_json_message_format("cargo fmt --check"), None);
+        assert_eq!(with_json_message_format("cargo build --message-format=short"), None);
+        assert_eq!(with_json_message_format("cargo build && ./run-checks.sh"), None);
+        assert_eq!(with_json_message_format("make test"), None);
+    }
+
+    #[test]
+    fn test_report_lists_each_error_once() {
+        let stdout = [
+            compiler_message("warning", None, "unused variable: `x`", "src/lib.rs", 2),
+            compiler_message("error", Some("E0308"), "mismatched types", "src/lib.rs", 4),
+            r#"{"reason":"compiler-artifact","package_id":"dep 1.0.0"}"#.to_string(),
+            compiler_message("error", Some("E0308"), "mismatched types", "src/lib.rs", 4), // Again for the test target
+            compiler_message("error", None, "cannot find value `y` in this scope", "src/main.rs", 7),
+            json!({
+                "reason": "compiler-message",
+                "message": { "message": "aborting due to 2 previous errors", "code": null, "level": "error", "spans": [], "children": [], "rendered": "error: aborting due to 2 previous errors\n" }
+            })
+            .to_string(),
+            "test result: FAILED. 0 passed; 1 failed".to_string(),
+        ]
+        .join("\n");
+
+        let report = verification_report(&failed_run(stdout));
+
+        assert_eq!(
+            report,
+            "`cargo test --message-format=json` exited with status 101 after 1.5s.\n\
+             --- stderr ---\nerror: could not compile `test_project`\n\
+             --- stdout ---\ntest result: FAILED. 0 passed; 1 failed\n\n\
+             Compiler errors (2, duplicates removed):\n\n\
+             1. error[E0308] in src/lib.rs line 4: mismatched types\nerror: mismatched types\n --> src/lib.rs:4:5\n\n\
+             2. error in src/main.rs line 7: cannot find value `y` in this scope\nerror: cannot find value `y` in this scope\n --> src/main.rs:7:5"
+        );
+    }
+
+    fn error_with_suggestions(file: &str, suggestions: &[(&str, usize, usize, &str, &str)]) -> String {
+        let children: Vec<serde_json::Value> = suggestions
+            .iter()
+            .map(|(message, start, end, replacement, applicability)| {
+                json!({
+                    "message": message, "code": null, "level": "help", "children": [], "rendered": null,
+                    "spans": [{
+                        "file_name": file, "byte_start": start, "byte_end": end, "line_start": 2, "line_end": 2,
+                        "column_start": 1, "column_end": 1, "is_primary": true,
+                        "suggested_replacement": replacement, "suggestion_applicability": applicability
+                    }]
+                })
+            })
+            .collect();
+        let mut line: serde_json::Value = serde_json::from_str(&compiler_message("error", None, "variable does not need to be mutable", file, 2)).unwrap();
+        line["message"]["children"] = json!(children);
+        line.to_string()
+    }
+
+    #[test]
+    fn test_applies_machine_applicable_suggestions_of_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        let source = "pub fn value() -> i32 {\n    let mut x = 2;\n    x\n}\n";
+        std::fs::write(dir.path().join("src/lib.rs"), source).unwrap();
+        let mut_at = source.find("mut ").unwrap();
+        let stdout = [
+            error_with_suggestions("src/lib.rs", &[
+                ("remove this `mut`", mut_at, mut_at + 4, "", "MachineApplicable"),
+                ("consider renaming", mut_at + 4, mut_at + 5, "y", "MaybeIncorrect"),
+            ]),
+            // The same error for the test target, an overlapping fix and one outside the project.
+            error_with_suggestions("src/lib.rs", &[("remove this `mut`", mut_at, mut_at + 4, "", "MachineApplicable")]),
+            error_with_suggestions("src/lib.rs", &[("make it immutable", mut_at + 1, mut_at + 3, "", "MachineApplicable")]),
+            error_with_suggestions("../other/src/lib.rs", &[("remove this `mut`", 0, 4, "", "MachineApplicable")]),
+            compiler_message("warning", None, "unused import", "src/lib.rs", 1),
+        ]
+        .join("\n");
+
+        let fixes = apply_machine_applicable_fixes(dir.path(), dir.path(), &CargoOutput::parse(&stdout));
+        let applied = fixes.applied;
+
+        assert_eq!(fixes.skipped.iter().map(|fix| (fix.file.as_str(), fix.reason.as_str())).collect::<Vec<_>>(), vec![("../other/src/lib.rs", "the file is outside the project")]);
+        assert_eq!(applied, vec![AppliedFix { file: "src/lib.rs".to_string(), line: 2, message: "remove this `mut`".to_string(), replacement: String::new() }]);
+        assert_eq!(std::fs::read_to_string(dir.path().join("src/lib.rs")).unwrap(), "pub fn value() -> i32 {\n    let x = 2;\n    x\n}\n");
+        assert_eq!(describe_applied_fixes(&applied), "gem applied these compiler suggestions before the run above; the files already contain them:\n- src/lib.rs line 2: remove this `mut`");
+    }
+
+    #[test]
+    fn test_suggestions_of_a_workspace_member_are_resolved_against_the_workspace_root() {
+        let workspace = tempfile::tempdir().unwrap();
+        std::fs::write(workspace.path().join("Cargo.toml"), "[workspace]\nmembers = [\"app\", \"other\"]\nresolver = \"2\"\n").unwrap();
+        let source = "pub fn value() -> i32 {\n    let mut x = 2;\n    x\n}\n";
+        for member in ["app", "other"] {
+            std::fs::create_dir_all(workspace.path().join(member).join("src")).unwrap();
+            std::fs::write(workspace.path().join(member).join("Cargo.toml"), format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n", member)).unwrap();
+            std::fs::write(workspace.path().join(member).join("src/lib.rs"), source).unwrap();
+        }
+        let project_root = workspace.path().join("app");
+        let mut_at = source.find("mut ").unwrap();
+        let remove_mut = |file: &str| error_with_suggestions(file, &[("remove this `mut`", mut_at, mut_at + 4, "", "MachineApplicable")]);
+        // Cargo names files relative to the workspace root, not the member being verified.
+        let stdout = [remove_mut("app/src/lib.rs"), remove_mut("app/src/gone.rs"), remove_mut("other/src/lib.rs")].join("\n");
+
+        let workspace_root = workspace_root(&project_root);
+        assert_eq!(std::fs::canonicalize(&workspace_root).unwrap(), std::fs::canonicalize(workspace.path()).unwrap());
+        let fixes = apply_machine_applicable_fixes(&project_root, &workspace_root, &CargoOutput::parse(&stdout));
+
+        assert_eq!(fixes.applied.iter().map(|fix| fix.file.as_str()).collect::<Vec<_>>(), vec!["src/lib.rs"]);
+        assert_eq!(std::fs::read_to_string(project_root.join("src/lib.rs")).unwrap(), "pub fn value() -> i32 {\n    let x = 2;\n    x\n}\n");
+        assert_eq!(std::fs::read_to_string(workspace.path().join("other/src/lib.rs")).unwrap(), source);
+        let skipped: Vec<(&str, &str)> = fixes.skipped.iter().map(|fix| (fix.file.as_str(), fix.reason.as_str())).collect();
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(skipped[0].0, "other/src/lib.rs");
+        assert_eq!(skipped[0].1, "the file is outside the project");
+        assert_eq!(skipped[1].0, "app/src/gone.rs");
+        assert!(skipped[1].1.starts_with("cannot read "), "{}", skipped[1].1);
+    }
+
+    #[test]
+    fn test_output_that_is_not_json_is_reported_as_is() {
+        let result = VerificationResult { stdout: "running 1 test\ntest it_works ... FAILED\n".to_string(), ..failed_run(String::new()) };
+        assert_eq!(verification_report(&result), result.report());
+    }
+}
@@ -1,10 +1,1055 @@
-use std::error::Error;
-
-pub fn run_local_gemma(_prompt: &str) -> Result<String, Box<dyn Error>> {
-    // This feature is not yet fully implemented.
-    // A production implementation would require integrating with a Gemma-compatible
-    // local inference library or process.
-    // For now, it returns an error indicating it's unimplemented.
-    println!("Local Gemma model support is not fully implemented."); // Keep a message for users trying this path.
-    Err("Local Gemma model functionality is not implemented.".into())
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use regex::Regex;
+
+use crate::cli::DEFAULT_LOCAL_MODEL;
+use crate::gguf::{GgufFile, GgufValue, TensorInfo};
+use crate::json_constraint::JsonConstraint;
+use crate::llm_api::{is_cancel_requested, LLMApiError};
+use crate::local_llm::{model_id_candidates, models_dir, LocalGeneration, LocalGenerator, LocalModelSource, DEFAULT_LOCAL_MAX_TOKENS};
+use crate::Result;
+
+const PREFILL_CHUNK: usize = 64; // Prompt tokens processed per forward pass
+const SPM_SPACE: char = '\u{2581}'; // '▁', SentencePiece's stand-in for a space
+
+// `tokenizer.ggml.token_type` values.
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_BYTE: i32 = 6;
+
+// Models loaded so far, by file, so each is read only once per process.
+static LOADED_MODELS: Mutex<Vec<(PathBuf, Arc<LocalModel>)>> = Mutex::new(Vec::new());
+
+/// Answers `prompt` with the default local model (`DEFAULT_LOCAL_MODEL`), on the CPU.
+pub fn run_local_gemma(prompt: &str) -> Result<String> {
+    let model = load_cached(DEFAULT_LOCAL_MODEL)?;
+    model.as_ref().generate(prompt, DEFAULT_LOCAL_MAX_TOKENS)
+}
+
+/// Loads the model a `--local` value refers to, or returns the copy loaded earlier.
+pub fn load_cached(spec: &str) -> Result<Arc<LocalModel>> {
+    let path = resolve_gguf(spec)?;
+    let path = path.canonicalize().unwrap_or(path);
+    let mut loaded = LOADED_MODELS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some((_, model)) = loaded.iter().find(|(loaded_path, _)| *loaded_path == path) {
+        return Ok(model.clone());
+    }
+    let model = Arc::new(LocalModel::load(&path)?);
+    loaded.push((path, model.clone()));
+    Ok(model)
+}
+
+/// The GGUF file a `--local` value refers to: the file itself, the only `.gguf` file in a directory,
+/// or for a model id, its file or directory in `~/.gem/models`.
+pub fn resolve_gguf(spec: &str) -> Result<PathBuf> {
+    resolve_gguf_in(spec, &models_dir())
+}
+
+fn resolve_gguf_in(spec: &str, models_dir: &Path) -> Result<PathBuf> {
+    match LocalModelSource::parse(spec)? {
+        LocalModelSource::GgufFile(path) => Ok(path),
+        LocalModelSource::Directory(dir) => single_gguf_in(&dir),
+        LocalModelSource::ModelId(id) => {
+            let candidates = model_id_candidates(models_dir, &id);
+            if let Some(found) = candidates.iter().find(|candidate| candidate.exists()) {
+                return if found.is_dir() { single_gguf_in(found) } else { Ok(found.clone()) };
+            }
+            let listed: Vec<String> = candidates.iter().map(|candidate| candidate.display().to_string()).collect();
+            Err(format!(
+                "Local model `{}` not found; looked for {}. Download a GGUF build of it (e.g. {}-Q4_K_M.gguf from Hugging Face) to one of these paths, or pass the path of a .gguf file or of a directory containing one to --local.",
+                id,
+                listed.join(", "),
+                id.rsplit('/').next().unwrap_or(&id)
+            )
+            .into())
+        }
+    }
+}
+
+fn single_gguf_in(dir: &Path) -> Result<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| format!("Cannot read local model directory {}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().and_then(|ext| ext.to_str()) == Some("gguf"))
+        .collect();
+    files.sort();
+    match files.len() {
+        1 => Ok(files.remove(0)),
+        0 => Err(format!(
+            "No .gguf file in local model directory {}. The CPU backend only reads GGUF models; directories with safetensors weights need the mistral_integration feature.",
+            dir.display()
+        )
+        .into()),
+        _ => {
+            let listed: Vec<String> = files.iter().map(|file| file.display().to_string()).collect();
+            Err(format!("Local model directory {} holds several .gguf files; pass one of them to --local: {}", dir.display(), listed.join(", ")).into())
+        }
+    }
+}
+
+/// A Gemma or Llama-family chat model loaded from a GGUF file, run on the CPU with greedy decoding.
+pub struct LocalModel {
+    name: String, // The file name without `.gguf`
+    gguf: GgufFile,
+    config: ModelConfig,
+    tokenizer: Tokenizer,
+    token_embd: TensorInfo,
+    output: TensorInfo, // `token_embd` when the embeddings are tied
+    output_norm: Vec<f32>,
+    layers: Vec<Layer>,
+    stop_tokens: Vec<u32>,
+}
+
+struct ModelConfig {
+    architecture: String,
+    embedding_length: usize,
+    head_count: usize,
+    head_count_kv: usize,
+    head_dim: usize,
+    rms_epsilon: f32,
+    rope_dims: usize,
+    rope_base: f32,
+    rope_base_local: f32,    // Sliding-window layers of Gemma 3 use a shorter base
+    rope_neox: bool,         // Rotate dimension pairs (i, i + d/2) rather than (2i, 2i + 1)
+    context_length: usize,
+    sliding_window: Option<usize>,
+    attn_softcap: Option<f32>,
+    final_softcap: Option<f32>,
+    embedding_scale: f32,
+    gelu: bool, // Gemma's feed-forward uses GELU, Llama's SiLU
+}
+
+struct Layer {
+    attn_norm: Vec<f32>,
+    attn_q: TensorInfo,
+    attn_k: TensorInfo,
+    attn_v: TensorInfo,
+    attn_output: TensorInfo,
+    attn_q_norm: Option<Vec<f32>>,
+    attn_k_norm: Option<Vec<f32>>,
+    post_attention_norm: Option<Vec<f32>>,
+    ffn_norm: Vec<f32>,
+    ffn_gate: TensorInfo,
+    ffn_up: TensorInfo,
+    ffn_down: TensorInfo,
+    post_ffw_norm: Option<Vec<f32>>,
+    sliding_window: bool,
+}
+
+// Keys and values of every layer seen so far, one `Vec` per layer.
+struct KvCache {
+    keys: Vec<Vec<f32>>,
+    values: Vec<Vec<f32>>,
+    len: usize,
+}
+
+impl LocalModel {
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.is_file() {
+            return Err(format!("Local model file {} not found.", path.display()).into());
+        }
+        let gguf = GgufFile::open(path)?;
+        let name = path.file_stem().map_or_else(|| "local-model".to_string(), |stem| stem.to_string_lossy().into_owned());
+        Self::from_gguf(gguf, name).map_err(|e| format!("Cannot load local model {}: {}", path.display(), e).into())
+    }
+
+    fn from_gguf(gguf: GgufFile, name: String) -> Result<Self> {
+        let architecture = gguf.get("general.architecture").and_then(GgufValue::as_str).ok_or("missing general.architecture")?.to_string();
+        let arch_u64 = |key: &str| gguf.get(&format!("{}.{}", architecture, key)).and_then(GgufValue::as_u64).map(|v| v as usize);
+        let arch_f32 = |key: &str| gguf.get(&format!("{}.{}", architecture, key)).and_then(GgufValue::as_f32);
+        let is_gemma = architecture.starts_with("gemma");
+
+        let embedding_length = arch_u64("embedding_length").ok_or("missing embedding_length")?;
+        let block_count = arch_u64("block_count").ok_or("missing block_count")?;
+        let head_count = arch_u64("attention.head_count").ok_or("missing attention.head_count")?;
+        let head_count_kv = arch_u64("attention.head_count_kv").unwrap_or(head_count);
+        if embedding_length == 0 || head_count == 0 || head_count_kv == 0 {
+            return Err("embedding_length, attention.head_count and attention.head_count_kv must not be 0".into());
+        }
+        let head_dim = arch_u64("attention.key_length").unwrap_or(embedding_length / head_count);
+        if head_count % head_count_kv != 0 {
+            return Err(format!("{} attention heads cannot share {} key/value heads", head_count, head_count_kv).into());
+        }
+        let rope_dims = arch_u64("rope.dimension_count").unwrap_or(head_dim);
+        if head_dim == 0 || rope_dims > head_dim {
+            return Err(format!("heads of {} values cannot rotate {} of them", head_dim, rope_dims).into());
+        }
+        let rope_base = arch_f32("rope.freq_base").unwrap_or(10_000.0);
+        let config = ModelConfig {
+            embedding_length,
+            head_count,
+            head_count_kv,
+            head_dim,
+            rms_epsilon: arch_f32("attention.layer_norm_rms_epsilon").unwrap_or(if is_gemma { 1e-6 } else { 1e-5 }),
+            rope_dims,
+            rope_base,
+            rope_base_local: if architecture == "gemma3" { 10_000.0 } else { rope_base },
+            rope_neox: is_gemma || ["qwen2", "qwen3", "phi3", "stablelm"].contains(&architecture.as_str()),
+            context_length: arch_u64("context_length").unwrap_or(4096),
+            sliding_window: arch_u64("attention.sliding_window"),
+            attn_softcap: arch_f32("attn_logit_softcapping"),
+            final_softcap: arch_f32("final_logit_softcapping"),
+            embedding_scale: if is_gemma { (embedding_length as f32).sqrt() } else { 1.0 },
+            gelu: is_gemma,
+            architecture,
+        };
+
+        let tensor = |name: &str| gguf.tensor(name).cloned().ok_or_else(|| format!("missing tensor `{}`", name));
+        let norm = |name: &str| gguf.tensor(name).map(|info| gguf.dequantize(info));
+        let mut layers = Vec::with_capacity(block_count);
+        for i in 0..block_count {
+            let name = |suffix: &str| format!("blk.{}.{}.weight", i, suffix);
+            layers.push(Layer {
+                attn_norm: norm(&name("attn_norm")).ok_or_else(|| format!("missing tensor `{}`", name("attn_norm")))?,
+                attn_q: tensor(&name("attn_q"))?,
+                attn_k: tensor(&name("attn_k"))?,
+                attn_v: tensor(&name("attn_v"))?,
+                attn_output: tensor(&name("attn_output"))?,
+                attn_q_norm: norm(&name("attn_q_norm")),
+                attn_k_norm: norm(&name("attn_k_norm")),
+                post_attention_norm: norm(&name("post_attention_norm")),
+                ffn_norm: norm(&name("ffn_norm")).ok_or_else(|| format!("missing tensor `{}`", name("ffn_norm")))?,
+                ffn_gate: tensor(&name("ffn_gate"))?,
+                ffn_up: tensor(&name("ffn_up"))?,
+                ffn_down: tensor(&name("ffn_down"))?,
+                post_ffw_norm: norm(&name("post_ffw_norm")),
+                sliding_window: config.sliding_window.is_some()
+                    && match config.architecture.as_str() {
+                        "gemma2" => i % 2 == 0,
+                        "gemma3" => (i + 1) % 6 != 0,
+                        _ => true,
+                    },
+            });
+        }
+        let token_embd = tensor("token_embd.weight")?;
+        let output = gguf.tensor("output.weight").cloned().unwrap_or_else(|| token_embd.clone());
+        let output_norm = norm("output_norm.weight").ok_or("missing tensor `output_norm.weight`")?;
+        if token_embd.row_len() != embedding_length {
+            return Err(format!("token_embd has rows of {} values, expected {}", token_embd.row_len(), embedding_length).into());
+        }
+        let (q_dim, kv_dim) = (head_count * head_dim, head_count_kv * head_dim);
+        for (i, layer) in layers.iter().enumerate() {
+            check_shape(&layer.attn_q, embedding_length, q_dim)?;
+            check_shape(&layer.attn_k, embedding_length, kv_dim)?;
+            check_shape(&layer.attn_v, embedding_length, kv_dim)?;
+            check_shape(&layer.attn_output, q_dim, embedding_length)?;
+            check_shape(&layer.ffn_gate, embedding_length, layer.ffn_up.rows())?;
+            check_shape(&layer.ffn_up, embedding_length, layer.ffn_gate.rows())?;
+            check_shape(&layer.ffn_down, layer.ffn_gate.rows(), embedding_length)?;
+            let norms = [
+                ("attn_norm", Some(&layer.attn_norm), embedding_length),
+                ("ffn_norm", Some(&layer.ffn_norm), embedding_length),
+                ("post_attention_norm", layer.post_attention_norm.as_ref(), embedding_length),
+                ("post_ffw_norm", layer.post_ffw_norm.as_ref(), embedding_length),
+                ("attn_q_norm", layer.attn_q_norm.as_ref(), head_dim),
+                ("attn_k_norm", layer.attn_k_norm.as_ref(), head_dim),
+            ];
+            for (name, norm, len) in norms {
+                if let Some(norm) = norm.filter(|norm| norm.len() != len) {
+                    return Err(format!("tensor `blk.{}.{}.weight` has {} values, expected {}", i, name, norm.len(), len).into());
+                }
+            }
+        }
+        if output.row_len() != embedding_length || output.rows() > token_embd.rows() {
+            return Err(format!("output has {} rows of {} values, expected at most {} rows of {}", output.rows(), output.row_len(), token_embd.rows(), embedding_length).into());
+        }
+        if output_norm.len() != embedding_length {
+            return Err(format!("tensor `output_norm.weight` has {} values, expected {}", output_norm.len(), embedding_length).into());
+        }
+
+        let tokenizer = Tokenizer::from_gguf(&gguf)?;
+        if tokenizer.tokens.len() > token_embd.rows() || tokenizer.bos.is_some_and(|bos| bos as usize >= token_embd.rows()) {
+            return Err(format!("the tokenizer has {} tokens, but token_embd only {} rows", tokenizer.tokens.len(), token_embd.rows()).into());
+        }
+        let mut stop_tokens: Vec<u32> = tokenizer.eos.into_iter().collect();
+        stop_tokens.extend(gguf.get("tokenizer.ggml.eot_token_id").and_then(GgufValue::as_u64).map(|id| id as u32));
+        stop_tokens.extend(["<end_of_turn>", "<|eot_id|>", "<|im_end|>", "<|end|>"].iter().filter_map(|piece| tokenizer.token_id(piece)));
+
+        Ok(Self { name, gguf, config, tokenizer, token_embd, output, output_norm, layers, stop_tokens })
+    }
+
+    pub fn architecture(&self) -> &str {
+        &self.config.architecture
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// Generates at most `max_tokens` tokens answering `prompt` as a single user turn.
+    pub fn generate(&self, prompt: &str, max_tokens: usize) -> Result<String> {
+        Ok(self.complete(prompt, max_tokens)?.text)
+    }
+
+    /// Like `generate`, but also reports token counts and whether `max_tokens` cut the answer off.
+    pub fn complete(&self, prompt: &str, max_tokens: usize) -> Result<LocalGeneration> {
+        self.complete_constrained(prompt, max_tokens, None)
+    }
+
+    /// Like `complete`, but only picks tokens that keep the answer valid JSON matching `schema`
+    /// (a Gemini response schema), so a finished answer always parses.
+    pub fn complete_json(&self, prompt: &str, max_tokens: usize, schema: &serde_json::Value) -> Result<LocalGeneration> {
+        self.complete_constrained(prompt, max_tokens, Some(JsonConstraint::new(schema)))
+    }
+
+    /// Like `complete_json`, but the answer continues `partial`: a `complete_json` answer that was
+    /// cut off at `max_tokens`, plus any continuations of it.
+    pub fn complete_json_continuation(&self, prompt: &str, max_tokens: usize, schema: &serde_json::Value, partial: &str) -> Result<LocalGeneration> {
+        let mut constraint = JsonConstraint::new(schema);
+        if !constraint.push_bytes(partial.as_bytes()) {
+            return Err("The cut-off JSON answer cannot be continued: it does not match the response schema.".into());
+        }
+        self.complete_constrained(prompt, max_tokens, Some(constraint))
+    }
+
+    fn complete_constrained(&self, prompt: &str, max_tokens: usize, constraint: Option<JsonConstraint>) -> Result<LocalGeneration> {
+        let prompt_tokens = self.prompt_tokens(prompt);
+        let (generated, stopped) = self.generate_tokens(&prompt_tokens, max_tokens, constraint)?;
+        Ok(LocalGeneration {
+            text: self.tokenizer.decode(&generated),
+            prompt_tokens: prompt_tokens.len(),
+            generated_tokens: generated.len(),
+            hit_token_limit: !stopped,
+        })
+    }
+
+    /// `prompt` wrapped in the model's chat template, as token ids.
+    pub fn prompt_tokens(&self, prompt: &str) -> Vec<u32> {
+        let special = |piece: &str| self.tokenizer.token_id(piece);
+        let segments: Vec<Segment> = if let (Some(start), Some(end)) = (special("<start_of_turn>"), special("<end_of_turn>")) {
+            vec![
+                Segment::Special(start),
+                Segment::Text(format!("user\n{}", prompt)),
+                Segment::Special(end),
+                Segment::Text("\n".to_string()),
+                Segment::Special(start),
+                Segment::Text("model\n".to_string()),
+            ]
+        } else if let (Some(start), Some(end), Some(eot)) = (special("<|start_header_id|>"), special("<|end_header_id|>"), special("<|eot_id|>")) {
+            vec![
+                Segment::Special(start),
+                Segment::Text("user".to_string()),
+                Segment::Special(end),
+                Segment::Text(format!("\n\n{}", prompt)),
+                Segment::Special(eot),
+                Segment::Special(start),
+                Segment::Text("assistant".to_string()),
+                Segment::Special(end),
+                Segment::Text("\n\n".to_string()),
+            ]
+        } else if let (Some(start), Some(end)) = (special("<|im_start|>"), special("<|im_end|>")) {
+            vec![
+                Segment::Special(start),
+                Segment::Text(format!("user\n{}", prompt)),
+                Segment::Special(end),
+                Segment::Text("\n".to_string()),
+                Segment::Special(start),
+                Segment::Text("assistant\n".to_string()),
+            ]
+        } else {
+            vec![Segment::Text(prompt.to_string())]
+        };
+
+        let mut tokens: Vec<u32> = self.tokenizer.bos.filter(|_| self.tokenizer.add_bos).into_iter().collect();
+        for (i, segment) in segments.iter().enumerate() {
+            match segment {
+                Segment::Special(id) => tokens.push(*id),
+                Segment::Text(text) => tokens.extend(self.tokenizer.encode_fragment(text, i == 0)),
+            }
+        }
+        tokens
+    }
+
+    // The generated tokens and whether generation ended at a stop token (rather than at `max_tokens`).
+    fn generate_tokens(&self, prompt_tokens: &[u32], max_tokens: usize, mut constraint: Option<JsonConstraint>) -> Result<(Vec<u32>, bool)> {
+        if prompt_tokens.len() >= self.config.context_length {
+            return Err(format!(
+                "The prompt has {} tokens, but the local model's context holds only {}.",
+                prompt_tokens.len(),
+                self.config.context_length
+            )
+            .into());
+        }
+        let max_tokens = max_tokens.min(self.config.context_length - prompt_tokens.len());
+        let mut cache = KvCache { keys: vec![Vec::new(); self.layers.len()], values: vec![Vec::new(); self.layers.len()], len: 0 };
+        let mut logits = Vec::new();
+        for chunk in prompt_tokens.chunks(PREFILL_CHUNK) {
+            check_cancel()?;
+            logits = self.forward(chunk, &mut cache);
+        }
+
+        let mut generated = Vec::new();
+        while generated.len() < max_tokens && !logits.is_empty() {
+            let next = match constraint.as_mut() {
+                Some(constraint) => self.constrained_token(&logits, constraint)?,
+                None => argmax(&logits) as u32,
+            };
+            if self.stop_tokens.contains(&next) {
+                return Ok((generated, true));
+            }
+            generated.push(next);
+            if generated.len() < max_tokens {
+                check_cancel()?;
+                logits = self.forward(&[next], &mut cache);
+            }
+        }
+        Ok((generated, false))
+    }
+
+    // The most likely token that keeps the answer valid (a stop token only once it is complete), fed to `constraint`.
+    fn constrained_token(&self, logits: &[f32], constraint: &mut JsonConstraint) -> Result<u32> {
+        let mut candidates: Vec<u32> = (0..logits.len() as u32).collect();
+        candidates.sort_unstable_by(|a, b| logits[*b as usize].total_cmp(&logits[*a as usize]));
+        let mut bytes = Vec::new();
+        for id in candidates {
+            if self.stop_tokens.contains(&id) {
+                if constraint.is_complete() {
+                    return Ok(id);
+                }
+                continue;
+            }
+            bytes.clear();
+            self.tokenizer.token_bytes(id, &mut bytes);
+            if !bytes.is_empty() && constraint.push_bytes(&bytes) {
+                return Ok(id);
+            }
+        }
+        Err("No token of the local model continues its JSON answer.".into())
+    }
+
+    // Runs `tokens` through the model after the ones already in `cache` and returns the logits of the last one.
+    fn forward(&self, tokens: &[u32], cache: &mut KvCache) -> Vec<f32> {
+        let config = &self.config;
+        let (dim, n) = (config.embedding_length, tokens.len());
+        let (q_dim, kv_dim) = (config.head_count * config.head_dim, config.head_count_kv * config.head_dim);
+
+        let mut x = vec![0.0; n * dim];
+        for (token, row) in tokens.iter().zip(x.chunks_mut(dim)) {
+            self.gguf.dequantize_row(&self.token_embd, *token as usize, row);
+            row.iter_mut().for_each(|v| *v *= config.embedding_scale);
+        }
+
+        for (i, layer) in self.layers.iter().enumerate() {
+            let mut h = x.clone();
+            rms_norm(&mut h, &layer.attn_norm, config.rms_epsilon);
+            let mut q = self.matmul(&layer.attn_q, &h, n);
+            let mut k = self.matmul(&layer.attn_k, &h, n);
+            let v = self.matmul(&layer.attn_v, &h, n);
+            if let Some(weight) = &layer.attn_q_norm {
+                rms_norm(&mut q, weight, config.rms_epsilon);
+            }
+            if let Some(weight) = &layer.attn_k_norm {
+                rms_norm(&mut k, weight, config.rms_epsilon);
+            }
+            let rope_base = if layer.sliding_window { config.rope_base_local } else { config.rope_base };
+            self.rope(&mut q, q_dim, cache.len, rope_base);
+            self.rope(&mut k, kv_dim, cache.len, rope_base);
+            cache.keys[i].extend_from_slice(&k);
+            cache.values[i].extend_from_slice(&v);
+
+            let attention = self.attention(&q, &cache.keys[i], &cache.values[i], cache.len, n, layer.sliding_window);
+            let mut attn_out = self.matmul(&layer.attn_output, &attention, n);
+            if let Some(weight) = &layer.post_attention_norm {
+                rms_norm(&mut attn_out, weight, config.rms_epsilon);
+            }
+            x.iter_mut().zip(&attn_out).for_each(|(x, o)| *x += o);
+
+            let mut h = x.clone();
+            rms_norm(&mut h, &layer.ffn_norm, config.rms_epsilon);
+            let mut gate = self.matmul(&layer.ffn_gate, &h, n);
+            let up = self.matmul(&layer.ffn_up, &h, n);
+            for (g, u) in gate.iter_mut().zip(&up) {
+                *g = if config.gelu { gelu(*g) } else { silu(*g) } * u;
+            }
+            let mut ffn_out = self.matmul(&layer.ffn_down, &gate, n);
+            if let Some(weight) = &layer.post_ffw_norm {
+                rms_norm(&mut ffn_out, weight, config.rms_epsilon);
+            }
+            x.iter_mut().zip(&ffn_out).for_each(|(x, o)| *x += o);
+        }
+        cache.len += n;
+
+        let mut last = x[(n - 1) * dim..].to_vec();
+        rms_norm(&mut last, &self.output_norm, config.rms_epsilon);
+        let mut logits = self.matmul(&self.output, &last, 1);
+        if let Some(cap) = config.final_softcap {
+            logits.iter_mut().for_each(|l| *l = cap * (*l / cap).tanh());
+        }
+        logits
+    }
+
+    // `n` input rows times the transposed weight matrix, i.e. one output value per weight row and input row.
+    // Each thread dequantizes a slice of the weight rows once and applies them to all `n` inputs.
+    fn matmul(&self, weight: &TensorInfo, input: &[f32], n: usize) -> Vec<f32> {
+        let (rows, cols) = (weight.rows(), weight.row_len());
+        let threads = thread::available_parallelism().map_or(1, |p| p.get()).min(rows).max(1);
+        let rows_per_thread = rows.div_ceil(threads);
+        let parts: Vec<(usize, Vec<f32>)> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..rows)
+                .step_by(rows_per_thread)
+                .map(|start| {
+                    scope.spawn(move || {
+                        let end = (start + rows_per_thread).min(rows);
+                        let mut row = vec![0.0; cols];
+                        let mut part = vec![0.0; (end - start) * n];
+                        for r in start..end {
+                            self.gguf.dequantize_row(weight, r, &mut row);
+                            for (t, x) in input.chunks(cols).enumerate() {
+                                part[(r - start) * n + t] = row.iter().zip(x).map(|(w, x)| w * x).sum();
+                            }
+                        }
+                        (start, part)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().expect("matmul worker panicked")).collect()
+        });
+
+        let mut output = vec![0.0; n * rows];
+        for (start, part) in parts {
+            for (i, value) in part.into_iter().enumerate() {
+                let (r, t) = (start + i / n, i % n);
+                output[t * rows + r] = value;
+            }
+        }
+        output
+    }
+
+    // Rotary position embedding of `values`: consecutive rows of `row_dim`, the first at position `start`.
+    fn rope(&self, values: &mut [f32], row_dim: usize, start: usize, base: f32) {
+        let (head_dim, rope_dims) = (self.config.head_dim, self.config.rope_dims);
+        for (t, row) in values.chunks_mut(row_dim).enumerate() {
+            let position = (start + t) as f32;
+            for head in row.chunks_mut(head_dim) {
+                for i in 0..rope_dims / 2 {
+                    let angle = position * base.powf(-2.0 * i as f32 / rope_dims as f32);
+                    let (sin, cos) = angle.sin_cos();
+                    let (a, b) = if self.config.rope_neox { (i, i + rope_dims / 2) } else { (2 * i, 2 * i + 1) };
+                    let (x0, x1) = (head[a], head[b]);
+                    head[a] = x0 * cos - x1 * sin;
+                    head[b] = x0 * sin + x1 * cos;
+                }
+            }
+        }
+    }
+
+    // Causal attention of the `n` new queries (positions `start..start + n`) over every cached key.
+    fn attention(&self, q: &[f32], keys: &[f32], values: &[f32], start: usize, n: usize, sliding_window: bool) -> Vec<f32> {
+        let config = &self.config;
+        let (head_dim, kv_dim) = (config.head_dim, config.head_count_kv * config.head_dim);
+        let group = config.head_count / config.head_count_kv;
+        let scale = 1.0 / (head_dim as f32).sqrt();
+        let mut output = vec![0.0; n * config.head_count * head_dim];
+        for t in 0..n {
+            let position = start + t;
+            let first = match config.sliding_window {
+                Some(window) if sliding_window => (position + 1).saturating_sub(window),
+                _ => 0,
+            };
+            for head in 0..config.head_count {
+                let query = &q[(t * config.head_count + head) * head_dim..][..head_dim];
+                let kv_offset = (head / group) * head_dim;
+                let mut scores: Vec<f32> = (first..=position)
+                    .map(|p| {
+                        let key = &keys[p * kv_dim + kv_offset..][..head_dim];
+                        let score = query.iter().zip(key).map(|(q, k)| q * k).sum::<f32>() * scale;
+                        config.attn_softcap.map_or(score, |cap| cap * (score / cap).tanh())
+                    })
+                    .collect();
+                softmax(&mut scores);
+                let out = &mut output[(t * config.head_count + head) * head_dim..][..head_dim];
+                for (p, weight) in (first..=position).zip(&scores) {
+                    let value = &values[p * kv_dim + kv_offset..][..head_dim];
+                    out.iter_mut().zip(value).for_each(|(o, v)| *o += weight * v);
+                }
+            }
+        }
+        output
+    }
+}
+
+impl LocalGenerator for LocalModel {
+    fn model_name(&self) -> &str {
+        &self.name
+    }
+
+    fn context_window(&self) -> usize {
+        self.config.context_length
+    }
+
+    fn generate(&self, prompt: &str, max_tokens: usize) -> Result<LocalGeneration> {
+        self.complete(prompt, max_tokens)
+    }
+
+    fn generate_json(&self, prompt: &str, max_tokens: usize, schema: &serde_json::Value) -> Result<LocalGeneration> {
+        self.complete_json(prompt, max_tokens, schema)
+    }
+
+    fn generate_json_continuation(&self, prompt: &str, max_tokens: usize, schema: &serde_json::Value, partial: &str) -> Result<LocalGeneration> {
+        self.complete_json_continuation(prompt, max_tokens, schema, partial)
+    }
+}
+
+// Checks that `tensor` maps `inputs` values to `outputs` values, as `LocalModel::matmul` expects.
+fn check_shape(tensor: &TensorInfo, inputs: usize, outputs: usize) -> Result<()> {
+    if tensor.row_len() != inputs || tensor.rows() != outputs {
+        return Err(format!("tensor `{}` has {} rows of {} values, expected {} rows of {}", tensor.name, tensor.rows(), tensor.row_len(), outputs, inputs).into());
+    }
+    Ok(())
+}
+
+// Lets Ctrl-C interrupt a long generation between forward passes.
+fn check_cancel() -> Result<()> {
+    if is_cancel_requested() {
+        return Err(Box::new(LLMApiError::Cancelled));
+    }
+    Ok(())
+}
+
+enum Segment {
+    Special(u32),
+    Text(String),
+}
+
+// Normalises every `weight.len()`-sized chunk of `values` in place.
+fn rms_norm(values: &mut [f32], weight: &[f32], epsilon: f32) {
+    for chunk in values.chunks_mut(weight.len()) {
+        let mean_square = chunk.iter().map(|v| v * v).sum::<f32>() / chunk.len() as f32;
+        let scale = 1.0 / (mean_square + epsilon).sqrt();
+        chunk.iter_mut().zip(weight).for_each(|(v, w)| *v = *v * scale * w);
+    }
+}
+
+fn softmax(values: &mut [f32]) {
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.0;
+    for v in values.iter_mut() {
+        *v = (*v - max).exp();
+        sum += *v;
+    }
+    values.iter_mut().for_each(|v| *v /= sum);
+}
+
+fn silu(x: f32) -> f32 {
+    x / (1.0 + (-x).exp())
+}
+
+// The tanh approximation, as in Gemma's reference implementation.
+fn gelu(x: f32) -> f32 {
+    0.5 * x * (1.0 + ((2.0 / std::f32::consts::PI).sqrt() * (x + 0.044715 * x * x * x)).tanh())
+}
+
+fn argmax(values: &[f32]) -> usize {
+    values.iter().enumerate().fold(0, |best, (i, v)| if *v > values[best] { i } else { best })
+}
+
+enum TokenizerKind {
+    /// SentencePiece (`tokenizer.ggml.model = "llama"`): score-ordered merges with byte fallback.
+    SentencePiece { add_space_prefix: bool },
+    /// Byte-level BPE (`"gpt2"`), as used by Llama 3 and Qwen.
+    BytePairs { merge_ranks: HashMap<(String, String), usize>, pre_split: Regex },
+}
+
+/// The tokenizer stored in a GGUF file's `tokenizer.ggml.*` metadata.
+pub struct Tokenizer {
+    kind: TokenizerKind,
+    tokens: Vec<String>,
+    scores: Vec<f32>,
+    token_types: Vec<i32>,
+    ids: HashMap<String, u32>,
+    bos: Option<u32>,
+    eos: Option<u32>,
+    add_bos: bool,
+}
+
+impl Tokenizer {
+    fn from_gguf(gguf: &GgufFile) -> Result<Self> {
+        let array = |key: &str| gguf.get(key).and_then(GgufValue::as_array).unwrap_or_default();
+        let tokens: Vec<String> = array("tokenizer.ggml.tokens").iter().filter_map(|t| t.as_str().map(str::to_string)).collect();
+        if tokens.is_empty() {
+            return Err("missing tokenizer.ggml.tokens".into());
+        }
+        let scores = array("tokenizer.ggml.scores").iter().map(|s| s.as_f32().unwrap_or(0.0)).collect();
+        let token_types = array("tokenizer.ggml.token_type").iter().map(|t| t.as_u64().unwrap_or(1) as i32).collect();
+        let ids = tokens.iter().enumerate().map(|(id, token)| (token.clone(), id as u32)).collect();
+        let token_id = |key: &str| gguf.get(key).and_then(GgufValue::as_u64).map(|id| id as u32);
+        let flag = |key: &str| gguf.get(key).and_then(GgufValue::as_bool);
+
+        let model = gguf.get("tokenizer.ggml.model").and_then(GgufValue::as_str).unwrap_or("llama");
+        let kind = match model {
+            "llama" => TokenizerKind::SentencePiece { add_space_prefix: flag("tokenizer.ggml.add_space_prefix").unwrap_or(true) },
+            "gpt2" => {
+                let merge_ranks = array("tokenizer.ggml.merges")
+                    .iter()
+                    .filter_map(|merge| merge.as_str()?.split_once(' ').map(|(a, b)| (a.to_string(), b.to_string())))
+                    .enumerate()
+                    .map(|(rank, pair)| (pair, rank))
+                    .collect();
+                let pre_split = Regex::new(r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+").expect("valid pre-tokenizer regex");
+                TokenizerKind::BytePairs { merge_ranks, pre_split }
+            }
+            other => return Err(format!("unsupported tokenizer model `{}`", other).into()),
+        };
+        Ok(Self {
+            add_bos: flag("tokenizer.ggml.add_bos_token").unwrap_or(matches!(kind, TokenizerKind::SentencePiece { .. })),
+            kind,
+            tokens,
+            scores,
+            token_types,
+            ids,
+            bos: token_id("tokenizer.ggml.bos_token_id"),
+            eos: token_id("tokenizer.ggml.eos_token_id"),
+        })
+    }
+
+    pub fn token_id(&self, piece: &str) -> Option<u32> {
+        self.ids.get(piece).copied()
+    }
+
+    /// `text` as token ids, without BOS and without treating special-token names in it as special.
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        self.encode_fragment(text, true)
+    }
+
+    // `at_start`: the fragment begins the text, so SentencePiece adds its space prefix.
+    fn encode_fragment(&self, text: &str, at_start: bool) -> Vec<u32> {
+        if text.is_empty() {
+            return Vec::new();
+        }
+        match &self.kind {
+            TokenizerKind::SentencePiece { add_space_prefix } => {
+                let prefix = if *add_space_prefix && at_start { " " } else { "" };
+                self.encode_sentencepiece(&format!("{}{}", prefix, text).replace(' ', &SPM_SPACE.to_string()))
+            }
+            TokenizerKind::BytePairs { merge_ranks, pre_split } => pre_split
+                .find_iter(text)
+                .flat_map(|word| self.encode_byte_pairs(word.as_str(), merge_ranks))
+                .collect(),
+        }
+    }
+
+    // Repeatedly merges the adjacent pair forming the highest-scoring vocabulary piece (leftmost on ties),
+    // then falls back to `<0xXX>` byte tokens for pieces not in the vocabulary.
+    fn encode_sentencepiece(&self, text: &str) -> Vec<u32> {
+        #[derive(PartialEq)]
+        struct Bigram {
+            score: f32,
+            left: usize,
+            len: usize,
+        }
+        impl Eq for Bigram {}
+        impl Ord for Bigram {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.score.total_cmp(&other.score).then(other.left.cmp(&self.left))
+            }
+        }
+        impl PartialOrd for Bigram {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        // Symbols as byte ranges of `text`, linked to their neighbours; merged-away symbols get length 0.
+        let starts: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+        let mut lens: Vec<usize> = text.chars().map(char::len_utf8).collect();
+        let mut prev: Vec<Option<usize>> = (0..starts.len()).map(|i| i.checked_sub(1)).collect();
+        let mut next: Vec<Option<usize>> = (0..starts.len()).map(|i| Some(i + 1).filter(|n| *n < starts.len())).collect();
+
+        let mut queue = BinaryHeap::new();
+        let push_bigram = |queue: &mut BinaryHeap<Bigram>, starts: &[usize], lens: &[usize], left: usize, right: usize| {
+            let len = lens[left] + lens[right];
+            if let Some(id) = self.token_id(&text[starts[left]..starts[left] + len]) {
+                queue.push(Bigram { score: self.scores.get(id as usize).copied().unwrap_or(0.0), left, len });
+            }
+        };
+        for i in 1..starts.len() {
+            push_bigram(&mut queue, &starts, &lens, i - 1, i);
+        }
+        while let Some(bigram) = queue.pop() {
+            let Some(right) = next[bigram.left] else { continue };
+            // Skip pairs made stale by an earlier merge.
+            if lens[bigram.left] == 0 || lens[bigram.left] + lens[right] != bigram.len {
+                continue;
+            }
+            lens[bigram.left] = bigram.len;
+            lens[right] = 0;
+            next[bigram.left] = next[right];
+            if let Some(after) = next[right] {
+                prev[after] = Some(bigram.left);
+                push_bigram(&mut queue, &starts, &lens, bigram.left, after);
+            }
+            if let Some(before) = prev[bigram.left] {
+                push_bigram(&mut queue, &starts, &lens, before, bigram.left);
+            }
+        }
+
+        let mut ids = Vec::new();
+        let mut symbol = Some(0);
+        while let Some(i) = symbol {
+            let piece = &text[starts[i]..starts[i] + lens[i]];
+            match self.token_id(piece) {
+                Some(id) => ids.push(id),
+                None => ids.extend(piece.bytes().filter_map(|b| self.token_id(&format!("<0x{:02X}>", b)))),
+            }
+            symbol = next[i];
+        }
+        ids
+    }
+
+    fn encode_byte_pairs(&self, word: &str, merge_ranks: &HashMap<(String, String), usize>) -> Vec<u32> {
+        let mut symbols: Vec<String> = word.bytes().map(|b| byte_to_unicode(b).to_string()).collect();
+        loop {
+            let best = symbols
+                .windows(2)
+                .enumerate()
+                .filter_map(|(i, pair)| merge_ranks.get(&(pair[0].clone(), pair[1].clone())).map(|rank| (*rank, i)))
+                .min();
+            let Some((_, i)) = best else { break };
+            let right = symbols.remove(i + 1);
+            symbols[i].push_str(&right);
+        }
+        symbols
+            .iter()
+            .flat_map(|symbol| match self.token_id(symbol) {
+                Some(id) => vec![id],
+                None => symbol.chars().filter_map(|c| self.token_id(&c.to_string())).collect(),
+            })
+            .collect()
+    }
+
+    /// Text of `ids`, leaving out control tokens such as BOS and EOS.
+    pub fn decode(&self, ids: &[u32]) -> String {
+        let mut bytes = Vec::new();
+        for &id in ids {
+            self.token_bytes(id, &mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    // Appends the bytes token `id` stands for (none for control tokens) to `bytes`.
+    fn token_bytes(&self, id: u32, bytes: &mut Vec<u8>) {
+        let Some(piece) = self.tokens.get(id as usize) else { return };
+        match self.token_types.get(id as usize).copied().unwrap_or(1) {
+            TOKEN_TYPE_CONTROL => {}
+            TOKEN_TYPE_BYTE => bytes.extend(u8::from_str_radix(piece.trim_start_matches("<0x").trim_end_matches('>'), 16).ok()),
+            _ => match self.kind {
+                TokenizerKind::SentencePiece { .. } => bytes.extend(piece.replace(SPM_SPACE, " ").bytes()),
+                TokenizerKind::BytePairs { .. } => {
+                    for c in piece.chars() {
+                        match unicode_to_byte(c) {
+                            Some(b) => bytes.push(b),
+                            None => bytes.extend(c.to_string().bytes()),
+                        }
+                    }
+                }
+            },
+        }
+    }
+}
+
+// GPT-2's reversible byte-to-character map: printable Latin-1 bytes map to themselves, the rest to U+0100 onwards.
+fn byte_to_unicode(b: u8) -> char {
+    let printable = |b: u8| (b'!'..=b'~').contains(&b) || (0xa1..=0xac).contains(&b) || b >= 0xae;
+    if printable(b) {
+        return b as char;
+    }
+    let shifted = (0..b).filter(|c| !printable(*c)).count() as u32;
+    char::from_u32(256 + shifted).expect("valid code point")
+}
+
+// `byte_to_unicode` maps onto U+0021..=U+0143, so the inverse is a table indexed from U+0000.
+fn unicode_to_byte(c: char) -> Option<u8> {
+    static TABLE: OnceLock<[Option<u8>; 324]> = OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let mut table = [None; 324];
+        for b in 0..=255u8 {
+            table[byte_to_unicode(b) as usize] = Some(b);
+        }
+        table
+    });
+    table.get(c as usize).copied().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join("local_models").join(name)
+    }
+
+    fn expected(model: &str) -> serde_json::Value {
+        let json = std::fs::read_to_string(fixture("expected.json")).unwrap();
+        serde_json::from_str::<serde_json::Value>(&json).unwrap()[model].clone()
+    }
+
+    fn ids(value: &serde_json::Value) -> Vec<u32> {
+        value.as_array().unwrap().iter().map(|id| id.as_u64().unwrap() as u32).collect()
+    }
+
+    // Compares the model against the reference implementation in `tests/fixtures/local_models/generate.py`.
+    fn assert_matches_reference(file: &str, architecture: &str) {
+        let model = LocalModel::load(&fixture(file)).unwrap();
+        let expected = expected(file);
+        assert_eq!(model.architecture(), architecture);
+        let prompt = expected["prompt"].as_str().unwrap();
+        let prompt_tokens = model.prompt_tokens(prompt);
+        assert_eq!(prompt_tokens, ids(&expected["prompt_tokens"]));
+
+        let mut cache = KvCache { keys: vec![Vec::new(); model.layers.len()], values: vec![Vec::new(); model.layers.len()], len: 0 };
+        let logits = model.forward(&prompt_tokens, &mut cache);
+        let expected_logits: Vec<f64> = expected["logits"].as_array().unwrap().iter().map(|l| l.as_f64().unwrap()).collect();
+        assert_eq!(logits.len(), expected_logits.len());
+        for (i, (actual, expected)) in logits.iter().zip(&expected_logits).enumerate() {
+            assert!((*actual as f64 - expected).abs() < 1e-3, "logit {}: {} vs {}", i, actual, expected);
+        }
+
+        let max_tokens = expected["max_tokens"].as_u64().unwrap() as usize;
+        assert_eq!(model.generate_tokens(&prompt_tokens, max_tokens, None).unwrap().0, ids(&expected["generated_tokens"]));
+        assert_eq!(model.generate(prompt, max_tokens).unwrap(), expected["generated_text"].as_str().unwrap());
+    }
+
+    #[test]
+    fn test_tiny_llama_matches_reference() {
+        assert_matches_reference("tiny-llama.gguf", "llama");
+    }
+
+    #[test]
+    fn test_tiny_gemma3_matches_reference() {
+        assert_matches_reference("tiny-gemma3.gguf", "gemma3");
+    }
+
+    #[test]
+    fn test_generate_stops_at_max_tokens() {
+        let model = LocalModel::load(&fixture("tiny-llama.gguf")).unwrap();
+        let expected = expected("tiny-llama.gguf");
+        let prompt = expected["prompt"].as_str().unwrap();
+        let prompt_tokens = model.prompt_tokens(prompt);
+        assert_eq!(model.generate_tokens(&prompt_tokens, 3, None).unwrap(), (ids(&expected["generated_tokens"])[..3].to_vec(), false));
+        assert!(model.generate_tokens(&prompt_tokens, 0, None).unwrap().0.is_empty());
+        let generation = model.complete(prompt, 3).unwrap();
+        assert_eq!((generation.prompt_tokens, generation.generated_tokens, generation.hit_token_limit), (prompt_tokens.len(), 3, true));
+    }
+
+    #[test]
+    fn test_sentencepiece_round_trip_with_byte_fallback() {
+        let model = LocalModel::load(&fixture("tiny-llama.gguf")).unwrap();
+        let tokenizer = model.tokenizer();
+        let ids = tokenizer.encode("hello world");
+        assert_eq!(ids, vec![tokenizer.token_id("▁hello").unwrap(), tokenizer.token_id("▁world").unwrap()]);
+
+        let text = "fn main() { println!(\"héllo\"); }";
+        assert_eq!(tokenizer.decode(&tokenizer.encode(text)), format!(" {}", text));
+    }
+
+    #[test]
+    fn test_byte_to_unicode_is_reversible() {
+        assert_eq!(byte_to_unicode(b'a'), 'a');
+        assert_eq!(byte_to_unicode(b' '), 'Ġ');
+        assert_eq!(byte_to_unicode(b'\n'), 'Ċ');
+        assert!((0..=255u8).all(|b| unicode_to_byte(byte_to_unicode(b)) == Some(b)));
+    }
+
+    #[test]
+    fn test_json_constrained_answers_parse() {
+        use crate::llm_api::{CodeChangeAction, ResponseSchema};
+
+        let schema = serde_json::json!({
+            "type": "OBJECT",
+            "properties": { "sufficient": { "type": "BOOLEAN" }, "action": CodeChangeAction::response_schema() },
+            "required": ["sufficient", "action"]
+        });
+        for name in ["tiny-llama.gguf", "tiny-gemma3.gguf"] {
+            let model = LocalModel::load(&fixture(name)).unwrap();
+            // Unconstrained, the random models answer with gibberish.
+            assert!(serde_json::from_str::<serde_json::Value>(&model.generate("hello", 20).unwrap()).is_err());
+
+            let generation = model.complete_json("hello", 100, &schema).unwrap();
+            assert!(!generation.hit_token_limit, "{}: {:?}", name, generation.text);
+            let answer: serde_json::Value = serde_json::from_str(&generation.text).unwrap();
+            assert!(answer["sufficient"].is_boolean(), "{}: {}", name, answer);
+            serde_json::from_value::<CodeChangeAction>(answer["action"].clone()).unwrap();
+
+            // An answer cut off at the token limit is continued under the same constraint.
+            let cut_off = model.complete_json("hello", 4, &schema).unwrap();
+            assert!(cut_off.hit_token_limit, "{}: {:?}", name, cut_off.text);
+            let rest = model.complete_json_continuation("hello, continue", 100, &schema, &cut_off.text).unwrap();
+            assert!(!rest.hit_token_limit, "{}: {:?}", name, rest.text);
+            let answer: serde_json::Value = serde_json::from_str(&format!("{}{}", cut_off.text, rest.text)).unwrap();
+            assert!(answer["sufficient"].is_boolean(), "{}: {}", name, answer);
+            assert!(model.complete_json_continuation("hello", 100, &schema, "{\"reason\"").is_err());
+        }
+    }
+
+    #[test]
+    fn test_corrupt_metadata_is_rejected_without_panicking() {
+        let load = |corrupt: &dyn Fn(&mut GgufFile)| {
+            let mut gguf = GgufFile::open(&fixture("tiny-llama.gguf")).unwrap();
+            corrupt(&mut gguf);
+            LocalModel::from_gguf(gguf, "corrupt".to_string()).err().map(|e| e.to_string())
+        };
+        assert_eq!(load(&|_| {}), None);
+
+        let set = |key: &'static str, value: u32| move |gguf: &mut GgufFile| _ = gguf.metadata.insert(key.to_string(), GgufValue::U32(value));
+        let zero_heads = "embedding_length, attention.head_count and attention.head_count_kv must not be 0";
+        assert_eq!(load(&set("llama.attention.head_count", 0)).as_deref(), Some(zero_heads));
+        assert_eq!(load(&set("llama.attention.head_count_kv", 0)).as_deref(), Some(zero_heads));
+        assert!(load(&set("llama.attention.key_length", 0)).unwrap().contains("cannot rotate"));
+
+        // Tensors that no longer match the config, each one row short.
+        let shrink = |name: &'static str| move |gguf: &mut GgufFile| gguf.tensors.get_mut(name).unwrap().dims[1] -= 1;
+        for name in ["blk.0.attn_q.weight", "blk.0.attn_k.weight", "blk.0.attn_output.weight", "blk.0.ffn_gate.weight", "blk.0.ffn_down.weight"] {
+            let error = load(&shrink(name)).unwrap();
+            assert!(error.starts_with(&format!("tensor `{}` has", name)), "{}", error);
+        }
+        let error = load(&|gguf: &mut GgufFile| gguf.tensors.get_mut("blk.0.attn_norm.weight").unwrap().dims[0] -= 1).unwrap();
+        assert!(error.starts_with("tensor `blk.0.attn_norm.weight` has"), "{}", error);
+        let error = load(&shrink("token_embd.weight")).unwrap();
+        assert_eq!(error, "output has 357 rows of 32 values, expected at most 356 rows of 32");
+        let tied_and_short = |gguf: &mut GgufFile| {
+            gguf.tensors.remove("output.weight");
+            gguf.tensors.get_mut("token_embd.weight").unwrap().dims[1] -= 1;
+        };
+        assert_eq!(load(&tied_and_short).unwrap(), "the tokenizer has 357 tokens, but token_embd only 356 rows");
+    }
+
+    #[test]
+    fn test_missing_model_file_names_the_path() {
+        let error = LocalModel::load(Path::new("/nonexistent/model.gguf")).err().unwrap().to_string();
+        assert_eq!(error, "Local model file /nonexistent/model.gguf not found.");
+    }
+
+    #[test]
+    fn test_resolve_gguf_by_path_directory_and_model_id() {
+        let models_dir = tempfile::tempdir().unwrap();
+        let single = models_dir.path().join("acme").join("tiny-llama");
+        fs::create_dir_all(&single).unwrap();
+        fs::copy(fixture("tiny-llama.gguf"), single.join("tiny-llama-Q8_0.gguf")).unwrap();
+        fs::copy(fixture("tiny-gemma3.gguf"), models_dir.path().join("tiny-gemma3.gguf")).unwrap();
+
+        let file = fixture("tiny-llama.gguf");
+        assert_eq!(resolve_gguf_in(file.to_str().unwrap(), models_dir.path()).unwrap(), file);
+        assert_eq!(resolve_gguf_in(single.to_str().unwrap(), models_dir.path()).unwrap(), single.join("tiny-llama-Q8_0.gguf"));
+        assert_eq!(resolve_gguf_in("acme/tiny-llama", models_dir.path()).unwrap(), single.join("tiny-llama-Q8_0.gguf"));
+        assert_eq!(resolve_gguf_in("google/tiny-gemma3", models_dir.path()).unwrap(), models_dir.path().join("tiny-gemma3.gguf"));
+
+        let error = resolve_gguf_in(fixture("").to_str().unwrap(), models_dir.path()).unwrap_err().to_string();
+        assert!(error.contains("holds several .gguf files; pass one of them to --local"), "{}", error);
+        let error = resolve_gguf_in("google/gemma-3-1b-it", models_dir.path()).unwrap_err().to_string();
+        assert!(error.starts_with("Local model `google/gemma-3-1b-it` not found; looked for "), "{}", error);
+        assert!(error.contains(&models_dir.path().join("gemma-3-1b-it.gguf").display().to_string()), "{}", error);
+    }
+
+    #[test]
+    fn test_load_cached_reuses_loaded_models() {
+        let path = fixture("tiny-gemma3.gguf");
+        let first = load_cached(path.to_str().unwrap()).unwrap();
+        let second = load_cached(path.to_str().unwrap()).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.model_name(), "tiny-gemma3");
+    }
 }
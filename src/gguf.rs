@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::Result;
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const DEFAULT_ALIGNMENT: u64 = 32;
+// Smallest encodings, used to reject counts a file of its size cannot hold before allocating for them.
+const MIN_METADATA_ENTRY_BYTES: usize = 12; // Empty key (8-byte length) and value type
+const MIN_TENSOR_INFO_BYTES: usize = 24; // Empty name, dimension count, type and offset
+
+/// A metadata value of a GGUF file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+}
+
+impl GgufValue {
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            GgufValue::U8(v) => Some(v as u64),
+            GgufValue::U16(v) => Some(v as u64),
+            GgufValue::U32(v) => Some(v as u64),
+            GgufValue::U64(v) => Some(v),
+            GgufValue::I8(v) if v >= 0 => Some(v as u64),
+            GgufValue::I16(v) if v >= 0 => Some(v as u64),
+            GgufValue::I32(v) if v >= 0 => Some(v as u64),
+            GgufValue::I64(v) if v >= 0 => Some(v as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            GgufValue::F32(v) => Some(v),
+            GgufValue::F64(v) => Some(v as f32),
+            _ => self.as_u64().map(|v| v as f32),
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            GgufValue::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            GgufValue::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+/// Tensor element encodings (`ggml_type`). Only the ones `gem` can dequantize are listed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GgmlType {
+    F32,
+    F16,
+    BF16,
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    Q4K,
+    Q5K,
+    Q6K,
+}
+
+impl GgmlType {
+    fn from_id(id: u32) -> Option<Self> {
+        Some(match id {
+            0 => GgmlType::F32,
+            1 => GgmlType::F16,
+            2 => GgmlType::Q4_0,
+            3 => GgmlType::Q4_1,
+            6 => GgmlType::Q5_0,
+            7 => GgmlType::Q5_1,
+            8 => GgmlType::Q8_0,
+            12 => GgmlType::Q4K,
+            13 => GgmlType::Q5K,
+            14 => GgmlType::Q6K,
+            30 => GgmlType::BF16,
+            _ => return None,
+        })
+    }
+
+    /// (elements per block, bytes per block)
+    fn block_layout(self) -> (usize, usize) {
+        match self {
+            GgmlType::F32 => (1, 4),
+            GgmlType::F16 | GgmlType::BF16 => (1, 2),
+            GgmlType::Q4_0 => (32, 18),
+            GgmlType::Q4_1 => (32, 20),
+            GgmlType::Q5_0 => (32, 22),
+            GgmlType::Q5_1 => (32, 24),
+            GgmlType::Q8_0 => (32, 34),
+            GgmlType::Q4K => (256, 144),
+            GgmlType::Q5K => (256, 176),
+            GgmlType::Q6K => (256, 210),
+        }
+    }
+
+    /// Bytes taken by `elements` values; `elements` must be a whole number of blocks.
+    pub fn byte_size(self, elements: usize) -> usize {
+        let (block_elements, block_bytes) = self.block_layout();
+        elements / block_elements * block_bytes
+    }
+}
+
+/// Where a tensor's data lives in the file. `dims[0]` is the row length (GGUF lists the fastest-varying dimension first).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorInfo {
+    pub name: String,
+    pub dims: Vec<usize>,
+    pub ggml_type: GgmlType,
+    pub offset: usize, // Absolute offset into `GgufFile::data`
+}
+
+impl TensorInfo {
+    pub fn elements(&self) -> usize {
+        self.dims.iter().product()
+    }
+
+    pub fn row_len(&self) -> usize {
+        self.dims[0]
+    }
+
+    pub fn rows(&self) -> usize {
+        self.elements() / self.row_len()
+    }
+}
+
+/// A GGUF model file (the format llama.cpp uses), read into memory.
+pub struct GgufFile {
+    pub version: u32,
+    pub metadata: HashMap<String, GgufValue>,
+    pub tensors: HashMap<String, TensorInfo>,
+    data: Vec<u8>,
+}
+
+impl GgufFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = fs::read(path).map_err(|e| format!("Cannot read GGUF file {}: {}", path.display(), e))?;
+        Self::parse(data).map_err(|e| format!("Invalid GGUF file {}: {}", path.display(), e).into())
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        let mut reader = Reader { data: &data, pos: 0 };
+        if reader.bytes(4)? != GGUF_MAGIC {
+            return Err("not a GGUF file (bad magic)".into());
+        }
+        let version = reader.u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(format!("unsupported GGUF version {}", version).into());
+        }
+        let tensor_count = reader.count(MIN_TENSOR_INFO_BYTES)?;
+        let metadata_count = reader.count(MIN_METADATA_ENTRY_BYTES)?;
+
+        let mut metadata = HashMap::new();
+        for _ in 0..metadata_count {
+            let key = reader.string()?;
+            let value_type = reader.u32()?;
+            metadata.insert(key, reader.value(value_type)?);
+        }
+
+        let mut infos = Vec::with_capacity(tensor_count);
+        for _ in 0..tensor_count {
+            let name = reader.string()?;
+            let n_dims = reader.u32()? as usize;
+            if n_dims > reader.remaining() / 8 {
+                return Err("unexpected end of file".into());
+            }
+            let dims = (0..n_dims).map(|_| reader.u64().map(|d| d as usize)).collect::<Result<Vec<_>>>()?;
+            let type_id = reader.u32()?;
+            let ggml_type = GgmlType::from_id(type_id).ok_or_else(|| format!("tensor `{}` has unsupported type {}", name, type_id))?;
+            let offset = reader.u64()? as usize;
+            infos.push(TensorInfo { name, dims, ggml_type, offset });
+        }
+
+        let alignment = metadata.get("general.alignment").and_then(GgufValue::as_u64).unwrap_or(DEFAULT_ALIGNMENT) as usize;
+        if alignment == 0 {
+            return Err("general.alignment must not be 0".into());
+        }
+        let data_start = reader.pos.div_ceil(alignment).checked_mul(alignment).ok_or("general.alignment is too large")?;
+        let mut tensors = HashMap::new();
+        for mut info in infos {
+            let (block_elements, block_bytes) = info.ggml_type.block_layout();
+            if info.dims.is_empty() || info.row_len() == 0 || info.row_len() % block_elements != 0 {
+                return Err(format!("tensor `{}` has rows that are not a whole number of {:?} blocks", info.name, info.ggml_type).into());
+            }
+            // Checked, since wrapped sums would pass the bounds check and make reading rows panic.
+            let end = info.dims.iter().try_fold(1usize, |elements, &dim| elements.checked_mul(dim))
+                .and_then(|elements| (elements / block_elements).checked_mul(block_bytes))
+                .zip(info.offset.checked_add(data_start))
+                .and_then(|(byte_size, offset)| Some((offset, offset.checked_add(byte_size)?)));
+            match end {
+                Some((offset, end)) if end <= data.len() => info.offset = offset,
+                _ => return Err(format!("tensor `{}` extends past the end of the file", info.name).into()),
+            }
+            tensors.insert(info.name.clone(), info);
+        }
+        Ok(Self { version, metadata, tensors, data })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata.get(key)
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<&TensorInfo> {
+        self.tensors.get(name)
+    }
+
+    /// Dequantizes row `row` of `tensor` into `out` (length `tensor.row_len()`).
+    pub fn dequantize_row(&self, tensor: &TensorInfo, row: usize, out: &mut [f32]) {
+        let row_bytes = tensor.ggml_type.byte_size(tensor.row_len());
+        let start = tensor.offset + row * row_bytes;
+        dequantize(tensor.ggml_type, &self.data[start..start + row_bytes], out);
+    }
+
+    /// The whole tensor as f32 values, row after row.
+    pub fn dequantize(&self, tensor: &TensorInfo) -> Vec<f32> {
+        let mut values = vec![0.0; tensor.elements()];
+        for (row, out) in values.chunks_mut(tensor.row_len()).enumerate() {
+            self.dequantize_row(tensor, row, out);
+        }
+        values
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8]> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len()).ok_or("unexpected end of file")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    // A u64 count of items taking at least `min_item_bytes` each, checked against what is left of the file.
+    fn count(&mut self, min_item_bytes: usize) -> Result<usize> {
+        let count = self.u64()?;
+        if count > (self.remaining() / min_item_bytes) as u64 {
+            return Err(format!("count {} is more than the rest of the file can hold", count).into());
+        }
+        Ok(count as usize)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().expect("slice has the requested length"))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u64()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+
+    fn value(&mut self, value_type: u32) -> Result<GgufValue> {
+        Ok(match value_type {
+            0 => GgufValue::U8(self.array::<1>()?[0]),
+            1 => GgufValue::I8(self.array::<1>()?[0] as i8),
+            2 => GgufValue::U16(u16::from_le_bytes(self.array()?)),
+            3 => GgufValue::I16(i16::from_le_bytes(self.array()?)),
+            4 => GgufValue::U32(self.u32()?),
+            5 => GgufValue::I32(i32::from_le_bytes(self.array()?)),
+            6 => GgufValue::F32(f32::from_le_bytes(self.array()?)),
+            7 => GgufValue::Bool(self.array::<1>()?[0] != 0),
+            8 => GgufValue::String(self.string()?),
+            9 => {
+                let element_type = self.u32()?;
+                let len = self.count(1)?;
+                GgufValue::Array((0..len).map(|_| self.value(element_type)).collect::<Result<Vec<_>>>()?)
+            }
+            10 => GgufValue::U64(self.u64()?),
+            11 => GgufValue::I64(i64::from_le_bytes(self.array()?)),
+            12 => GgufValue::F64(f64::from_le_bytes(self.array()?)),
+            other => return Err(format!("unknown metadata value type {}", other).into()),
+        })
+    }
+}
+
+pub fn f16_to_f32(bits: u16) -> f32 {
+    let sign = ((bits >> 15) as u32) << 31;
+    let exponent = ((bits >> 10) & 0x1f) as u32;
+    let mantissa = (bits & 0x3ff) as u32;
+    let magnitude = match (exponent, mantissa) {
+        (0, 0) => 0,
+        (0, _) => {
+            // Subnormal: renormalise into an f32 exponent.
+            let shift = mantissa.leading_zeros() - 21;
+            ((113 - shift) << 23) | ((mantissa << shift) & 0x3ff) << 13
+        }
+        (0x1f, _) => 0x7f80_0000 | (mantissa << 13),
+        _ => ((exponent + 112) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(sign | magnitude)
+}
+
+fn f16_at(bytes: &[u8], at: usize) -> f32 {
+    f16_to_f32(u16::from_le_bytes([bytes[at], bytes[at + 1]]))
+}
+
+// The 6-bit scale and min of sub-block `j` in a Q4_K/Q5_K block's packed `scales`.
+fn scale_min_k4(j: usize, scales: &[u8]) -> (f32, f32) {
+    if j < 4 {
+        ((scales[j] & 63) as f32, (scales[j + 4] & 63) as f32)
+    } else {
+        (
+            ((scales[j + 4] & 0x0f) | ((scales[j - 4] >> 6) << 4)) as f32,
+            ((scales[j + 4] >> 4) | ((scales[j] >> 6) << 4)) as f32,
+        )
+    }
+}
+
+// Block layouts and formulas follow ggml's reference dequantization (ggml-quants.c).
+fn dequantize(ggml_type: GgmlType, bytes: &[u8], out: &mut [f32]) {
+    let (block_elements, block_bytes) = ggml_type.block_layout();
+    for (block, y) in bytes.chunks_exact(block_bytes).zip(out.chunks_exact_mut(block_elements)) {
+        match ggml_type {
+            GgmlType::F32 => y[0] = f32::from_le_bytes([block[0], block[1], block[2], block[3]]),
+            GgmlType::F16 => y[0] = f16_at(block, 0),
+            GgmlType::BF16 => y[0] = f32::from_bits((u16::from_le_bytes([block[0], block[1]]) as u32) << 16),
+            GgmlType::Q4_0 => {
+                let d = f16_at(block, 0);
+                for (j, q) in block[2..18].iter().enumerate() {
+                    y[j] = ((q & 0x0f) as i32 - 8) as f32 * d;
+                    y[j + 16] = ((q >> 4) as i32 - 8) as f32 * d;
+                }
+            }
+            GgmlType::Q4_1 => {
+                let (d, m) = (f16_at(block, 0), f16_at(block, 2));
+                for (j, q) in block[4..20].iter().enumerate() {
+                    y[j] = (q & 0x0f) as f32 * d + m;
+                    y[j + 16] = (q >> 4) as f32 * d + m;
+                }
+            }
+            GgmlType::Q5_0 | GgmlType::Q5_1 => {
+                let (d, m, qh_at) = if ggml_type == GgmlType::Q5_0 { (f16_at(block, 0), None, 2) } else { (f16_at(block, 0), Some(f16_at(block, 2)), 4) };
+                let qh = u32::from_le_bytes([block[qh_at], block[qh_at + 1], block[qh_at + 2], block[qh_at + 3]]);
+                for (j, q) in block[qh_at + 4..qh_at + 20].iter().enumerate() {
+                    let high0 = (((qh >> j) << 4) & 0x10) as u8;
+                    let high1 = ((qh >> (j + 12)) & 0x10) as u8;
+                    let (x0, x1) = ((q & 0x0f) | high0, (q >> 4) | high1);
+                    match m {
+                        None => {
+                            y[j] = (x0 as i32 - 16) as f32 * d;
+                            y[j + 16] = (x1 as i32 - 16) as f32 * d;
+                        }
+                        Some(m) => {
+                            y[j] = x0 as f32 * d + m;
+                            y[j + 16] = x1 as f32 * d + m;
+                        }
+                    }
+                }
+            }
+            GgmlType::Q8_0 => {
+                let d = f16_at(block, 0);
+                for (j, q) in block[2..34].iter().enumerate() {
+                    y[j] = *q as i8 as f32 * d;
+                }
+            }
+            GgmlType::Q4K | GgmlType::Q5K => {
+                let (d, dmin) = (f16_at(block, 0), f16_at(block, 2));
+                let scales = &block[4..16];
+                let (qh, qs) = if ggml_type == GgmlType::Q5K { (Some(&block[16..48]), &block[48..176]) } else { (None, &block[16..144]) };
+                for chunk in 0..4 {
+                    let (sc1, m1) = scale_min_k4(2 * chunk, scales);
+                    let (sc2, m2) = scale_min_k4(2 * chunk + 1, scales);
+                    let q = &qs[chunk * 32..chunk * 32 + 32];
+                    for l in 0..32 {
+                        let (high1, high2) = match qh {
+                            Some(qh) => ((qh[l] >> (2 * chunk)) & 1, (qh[l] >> (2 * chunk + 1)) & 1),
+                            None => (0, 0),
+                        };
+                        y[chunk * 64 + l] = d * sc1 * ((q[l] & 0x0f) + 16 * high1) as f32 - dmin * m1;
+                        y[chunk * 64 + 32 + l] = d * sc2 * ((q[l] >> 4) + 16 * high2) as f32 - dmin * m2;
+                    }
+                }
+            }
+            GgmlType::Q6K => {
+                let (ql, qh, scales) = (&block[0..128], &block[128..192], &block[192..208]);
+                let d = f16_at(block, 208);
+                for half in 0..2 {
+                    let (ql, qh, sc, y) = (&ql[half * 64..], &qh[half * 32..], &scales[half * 8..], &mut y[half * 128..]);
+                    for l in 0..32 {
+                        let is = l / 16;
+                        let q1 = ((ql[l] & 0x0f) | ((qh[l] & 3) << 4)) as i32 - 32;
+                        let q2 = ((ql[l + 32] & 0x0f) | (((qh[l] >> 2) & 3) << 4)) as i32 - 32;
+                        let q3 = ((ql[l] >> 4) | (((qh[l] >> 4) & 3) << 4)) as i32 - 32;
+                        let q4 = ((ql[l + 32] >> 4) | (((qh[l] >> 6) & 3) << 4)) as i32 - 32;
+                        y[l] = d * (sc[is] as i8) as f32 * q1 as f32;
+                        y[l + 32] = d * (sc[is + 2] as i8) as f32 * q2 as f32;
+                        y[l + 64] = d * (sc[is + 4] as i8) as f32 * q3 as f32;
+                        y[l + 96] = d * (sc[is + 6] as i8) as f32 * q4 as f32;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_f16_to_f32() {
+        assert_eq!(f16_to_f32(0x3c00), 1.0);
+        assert_eq!(f16_to_f32(0xc000), -2.0);
+        assert_eq!(f16_to_f32(0x3555), 0.33325195);
+        assert_eq!(f16_to_f32(0x0001), 5.9604645e-8); // Smallest subnormal
+        assert!(f16_to_f32(0x7c00).is_infinite());
+    }
+
+    #[test]
+    fn test_dequantize_q8_0_and_q4_0() {
+        let mut q8 = vec![0x00, 0x38]; // d = 0.5
+        q8.extend((0..32).map(|i| (i as i8 - 16) as u8));
+        let mut out = [0.0; 32];
+        dequantize(GgmlType::Q8_0, &q8, &mut out);
+        assert_eq!(out[0], -8.0);
+        assert_eq!(out[31], 7.5);
+
+        let mut q4 = vec![0x00, 0x40]; // d = 2.0
+        q4.extend([0x9f; 16]); // low nibble 15, high nibble 9
+        dequantize(GgmlType::Q4_0, &q4, &mut out);
+        assert_eq!((out[0], out[16]), (14.0, 2.0));
+    }
+
+    #[test]
+    fn test_dequantize_q4_k_scales() {
+        let mut block = vec![0x00, 0x3c, 0x00, 0x38]; // d = 1.0, dmin = 0.5
+        block.extend([2, 3, 4, 5, 1, 1, 1, 1, 0, 0, 0, 0]); // scales 2..5 (sub-blocks 0-3), mins 1
+        block.extend([0x21; 128]); // low nibble 1, high nibble 2
+        let mut out = [0.0; 256];
+        dequantize(GgmlType::Q4K, &block, &mut out);
+        assert_eq!(out[0], 2.0 * 1.0 - 0.5); // Sub-block 0: scale 2, min 1
+        assert_eq!(out[32], 3.0 * 2.0 - 0.5); // Sub-block 1: scale 3, high nibbles
+        assert_eq!(out[64], 4.0 * 1.0 - 0.5);
+        assert_eq!(out[255], 0.0 * 2.0 - 0.0); // Sub-blocks 4-7 have zero scales and mins
+    }
+
+    #[test]
+    fn test_parse_rejects_non_gguf() {
+        assert!(GgufFile::parse(b"GGML\x03\x00\x00\x00".to_vec()).err().unwrap().to_string().contains("bad magic"));
+        assert!(GgufFile::parse(b"GGUF\x03\x00\x00\x00".to_vec()).err().unwrap().to_string().contains("unexpected end of file"));
+    }
+
+    fn string_bytes(s: &str) -> Vec<u8> {
+        [&(s.len() as u64).to_le_bytes()[..], s.as_bytes()].concat()
+    }
+
+    // A GGUF v3 file: the counts as given, then `body` (metadata and tensor infos) and 64 bytes of data.
+    fn gguf_file(tensor_count: u64, metadata_count: u64, body: &[u8]) -> Vec<u8> {
+        let mut file = GGUF_MAGIC.to_vec();
+        file.extend(3u32.to_le_bytes());
+        file.extend(tensor_count.to_le_bytes());
+        file.extend(metadata_count.to_le_bytes());
+        file.extend(body);
+        file.resize(file.len().div_ceil(32) * 32 + 64, 0);
+        file
+    }
+
+    fn f32_tensor_info(dims: &[u64], offset: u64) -> Vec<u8> {
+        let mut info = string_bytes("weights");
+        info.extend((dims.len() as u32).to_le_bytes());
+        dims.iter().for_each(|dim| info.extend(dim.to_le_bytes()));
+        info.extend(0u32.to_le_bytes()); // F32
+        info.extend(offset.to_le_bytes());
+        info
+    }
+
+    fn parse_error(file: Vec<u8>) -> String {
+        GgufFile::parse(file).err().expect("corrupt file was accepted").to_string()
+    }
+
+    #[test]
+    fn test_parse_reads_a_tensor() {
+        let mut file = gguf_file(1, 0, &f32_tensor_info(&[4, 2], 0));
+        let data_start = file.len() - 64;
+        file[data_start..data_start + 4].copy_from_slice(&1.5f32.to_le_bytes());
+        let gguf = GgufFile::parse(file).unwrap();
+        let tensor = gguf.tensor("weights").unwrap();
+        assert_eq!((tensor.rows(), tensor.offset), (2, data_start));
+        assert_eq!(gguf.dequantize(tensor)[..2], [1.5, 0.0]);
+    }
+
+    #[test]
+    fn test_parse_rejects_corrupt_headers_without_panicking() {
+        assert!(parse_error(gguf_file(u64::MAX, 0, &[])).contains("more than the rest of the file can hold"));
+        assert!(parse_error(gguf_file(0, u64::MAX, &[])).contains("more than the rest of the file can hold"));
+
+        let mut huge_array = string_bytes("tokenizer.ggml.tokens");
+        huge_array.extend(9u32.to_le_bytes()); // Array
+        huge_array.extend(8u32.to_le_bytes()); // of strings
+        huge_array.extend(u64::MAX.to_le_bytes());
+        assert!(parse_error(gguf_file(0, 1, &huge_array)).contains("more than the rest of the file can hold"));
+
+        let mut zero_alignment = string_bytes("general.alignment");
+        zero_alignment.extend(4u32.to_le_bytes()); // U32
+        zero_alignment.extend(0u32.to_le_bytes());
+        assert_eq!(parse_error(gguf_file(0, 1, &zero_alignment)), "general.alignment must not be 0");
+
+        let mut many_dims = string_bytes("weights");
+        many_dims.extend(u32::MAX.to_le_bytes());
+        assert!(parse_error(gguf_file(1, 0, &many_dims)).contains("unexpected end of file"));
+
+        for (dims, offset) in [(&[4u64, 2][..], u64::MAX - 8), (&[4, u64::MAX / 2], 0), (&[4, 2], 64)] {
+            assert_eq!(parse_error(gguf_file(1, 0, &f32_tensor_info(dims, offset))), "tensor `weights` extends past the end of the file");
+        }
+        assert!(parse_error(gguf_file(1, 0, &f32_tensor_info(&[0, 2], 0))).contains("not a whole number of F32 blocks"));
+    }
+}
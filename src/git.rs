@@ -0,0 +1,182 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use crate::Result;
+
+/// Trailer naming the gem session a commit came from.
+pub const SESSION_TRAILER: &str = "Gem-Session";
+
+/// The git work tree a project lives in. All commands run in the project directory, so paths are
+/// relative to the project root even when it is a subdirectory of the repository.
+pub struct GitRepo {
+    dir: PathBuf,
+}
+
+impl GitRepo {
+    /// The repository containing `project_root`, or `None` when it is not in a git work tree or git
+    /// is not installed.
+    pub fn open(project_root: &Path) -> Option<Self> {
+        let output = Command::new("git").args(["rev-parse", "--is-inside-work-tree"]).current_dir(project_root).stdin(Stdio::null()).output().ok()?;
+        (output.status.success() && String::from_utf8_lossy(&output.stdout).trim() == "true").then(|| Self { dir: project_root.to_path_buf() })
+    }
+
+    /// Tracked files with uncommitted (staged or unstaged) changes, as `git status --porcelain` lines.
+    /// Untracked files are left out: gem neither commits nor overwrites them unasked.
+    pub fn uncommitted_changes(&self) -> Result<Vec<String>> {
+        let status = self.run(&["status", "--porcelain", "--untracked-files=no"])?;
+        Ok(status.lines().filter(|line| !line.trim().is_empty()).map(str::to_string).collect())
+    }
+
+    /// Moves the uncommitted changes to tracked files onto the stash.
+    pub fn stash(&self, message: &str) -> Result<()> {
+        self.run(&["stash", "push", "--message", message])?;
+        Ok(())
+    }
+
+    /// Commits exactly `paths` (created, modified or deleted; others staged by the user stay staged
+    /// and out of the commit) and returns the new commit's hash, or `None` when none of them changed.
+    /// `author` is `"Name <email>"`; without it git's configured identity is used.
+    pub fn commit_paths(&self, paths: &[String], message: &str, author: Option<&str>) -> Result<Option<String>> {
+        // A file that was created and deleted again without ever being committed is not a change.
+        let mut pathspecs = Vec::new();
+        for path in paths {
+            if self.dir.join(path).exists() || !self.run(&["ls-files", "--", path])?.trim().is_empty() {
+                pathspecs.push(path.as_str());
+            }
+        }
+        if pathspecs.is_empty() {
+            return Ok(None);
+        }
+
+        self.run(&[&["add", "--all", "--"][..], &pathspecs].concat())?;
+        let staged = self.output(&[&["diff", "--cached", "--quiet", "--"][..], &pathspecs].concat())?;
+        if staged.status.success() {
+            return Ok(None); // The files are back to their committed content
+        }
+
+        let author = author.map(|author| format!("--author={}", author));
+        let mut commit = vec!["commit", "--only", "--message", message];
+        commit.extend(author.as_deref());
+        commit.push("--");
+        commit.extend(&pathspecs);
+        self.run(&commit)?;
+        Ok(Some(self.run(&["rev-parse", "HEAD"])?.trim().to_string()))
+    }
+
+    // Paths come from the model, so git must not read `*`, `[...]` or `:(magic)` in them as patterns.
+    fn output(&self, args: &[&str]) -> Result<Output> {
+        Command::new("git")
+            .args(args)
+            .current_dir(&self.dir)
+            .env("GIT_LITERAL_PATHSPECS", "1")
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Failed to run `git {}`: {}", args.join(" "), e).into())
+    }
+
+    fn run(&self, args: &[&str]) -> Result<String> {
+        let output = self.output(args)?;
+        if !output.status.success() {
+            return Err(format!("`git {}` failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// The message for gem's commit: the request as subject, the model's explanation as body and the
+/// session as trailer.
+pub fn commit_message(user_request: &str, explanation: &str, session_id: &str) -> String {
+    let mut message = format!("gem: Automated change for \"{}\"", user_request.trim());
+    if !explanation.trim().is_empty() {
+        message.push_str(&format!("\n\n{}", explanation.trim()));
+    }
+    message.push_str(&format!("\n\n{}: {}\n", SESSION_TRAILER, session_id));
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git").args(args).current_dir(dir).output().unwrap();
+        assert!(output.status.success(), "git {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    fn repo_with_commit() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "--quiet"]);
+        git(dir.path(), &["config", "user.name", "Dev"]);
+        git(dir.path(), &["config", "user.email", "dev@example.com"]);
+        fs::write(dir.path().join("kept.txt"), "kept\n").unwrap();
+        fs::write(dir.path().join("gone.txt"), "gone\n").unwrap();
+        git(dir.path(), &["add", "."]);
+        git(dir.path(), &["commit", "--quiet", "-m", "initial"]);
+        dir
+    }
+
+    #[test]
+    fn test_only_tracked_changes_make_the_tree_dirty() {
+        let dir = repo_with_commit();
+        let repo = GitRepo::open(dir.path()).unwrap();
+        fs::write(dir.path().join("untracked.txt"), "new").unwrap();
+        assert!(repo.uncommitted_changes().unwrap().is_empty());
+
+        fs::write(dir.path().join("kept.txt"), "edited\n").unwrap();
+        assert_eq!(repo.uncommitted_changes().unwrap(), vec![" M kept.txt"]);
+
+        repo.stash("gem: before test").unwrap();
+        assert!(repo.uncommitted_changes().unwrap().is_empty());
+        assert_eq!(fs::read_to_string(dir.path().join("kept.txt")).unwrap(), "kept\n");
+        assert!(git(dir.path(), &["stash", "list"]).contains("gem: before test"));
+
+        assert!(GitRepo::open(tempfile::tempdir().unwrap().path()).is_none());
+    }
+
+    #[test]
+    fn test_commits_only_the_given_paths_with_author_and_trailer() {
+        let dir = repo_with_commit();
+        let repo = GitRepo::open(dir.path()).unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/new.rs"), "fn new() {}\n").unwrap();
+        fs::remove_file(dir.path().join("gone.txt")).unwrap();
+        fs::write(dir.path().join("kept.txt"), "the user's own edit\n").unwrap();
+        fs::write(dir.path().join("scratch.txt"), "not gem's").unwrap();
+
+        let message = commit_message("add new", "Added `new`.", "abc123");
+        let paths = ["src/new.rs".to_string(), "gone.txt".to_string(), "never-existed.rs".to_string()];
+        let hash = repo.commit_paths(&paths, &message, Some("gem <gem@example.com>")).unwrap().unwrap();
+
+        assert_eq!(git(dir.path(), &["rev-parse", "HEAD"]).trim(), hash);
+        assert_eq!(git(dir.path(), &["show", "--name-status", "--format=", "HEAD"]), "D\tgone.txt\nA\tsrc/new.rs\n");
+        assert_eq!(git(dir.path(), &["log", "-1", "--format=%an <%ae>|%cn"]).trim(), "gem <gem@example.com>|Dev");
+        assert_eq!(git(dir.path(), &["log", "-1", "--format=%B"]).trim(), "gem: Automated change for \"add new\"\n\nAdded `new`.\n\nGem-Session: abc123");
+        assert_eq!(git(dir.path(), &["log", "-1", &format!("--format=%(trailers:key={},valueonly)", SESSION_TRAILER)]).trim(), "abc123");
+        assert_eq!(repo.uncommitted_changes().unwrap(), vec![" M kept.txt"]);
+
+        // Nothing left to commit for these paths.
+        assert_eq!(repo.commit_paths(&paths, &message, None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_paths_are_not_read_as_pathspec_patterns() {
+        let dir = repo_with_commit();
+        let repo = GitRepo::open(dir.path()).unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        for file in ["src/[id].rs", "src/i.rs", "src/d.rs"] {
+            fs::write(dir.path().join(file), "fn page() {}\n").unwrap();
+        }
+        git(dir.path(), &["add", "src"]);
+        git(dir.path(), &["commit", "--quiet", "-m", "pages"]);
+        for file in ["src/[id].rs", "src/i.rs", "src/d.rs", "kept.txt"] {
+            fs::write(dir.path().join(file), "edited\n").unwrap();
+        }
+
+        let paths = ["src/[id].rs".to_string(), ":(top)kept.txt".to_string()];
+        repo.commit_paths(&paths, "edit [id]", None).unwrap().unwrap();
+        assert_eq!(git(dir.path(), &["show", "--name-only", "--format=", "HEAD"]), "src/[id].rs\n");
+        assert_eq!(repo.uncommitted_changes().unwrap(), vec![" M kept.txt", " M src/d.rs", " M src/i.rs"]);
+    }
+}
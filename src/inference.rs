@@ -1,64 +1,124 @@
 #![cfg(feature = "mistral_integration")]
 
 use anyhow::Result;
-// Assuming Model, TextMessageRole, TextModelBuilder, TextMessages are directly available from mistralrs
+// Assuming Model, TextMessageRole, TextModelBuilder, RequestBuilder are directly available from mistralrs
 // and that Model is the type returned by TextModelBuilder.build()
-use mistralrs::{Model, TextMessageRole, TextModelBuilder, TextMessages};
-use once_cell::sync::OnceCell;
+use mistralrs::{Constraint, GgufModelBuilder, Model, RequestBuilder, TextMessageRole, TextModelBuilder};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
 use std::sync::Arc;
+use tokio::sync::Mutex;
 
-// This will store the pipeline object returned by TextModelBuilder
-static PIPELINE_ENGINE: OnceCell<Arc<Model>> = OnceCell::new();
+use crate::llm_api::to_json_schema;
+use crate::local_llm::{LocalGeneration, LocalGenerator, LocalModelSource};
+use crate::token_budget::ModelLimits;
+
+// Pipelines built so far, by `--local` value, so each model is loaded only once per process.
+static PIPELINES: Lazy<Mutex<HashMap<String, Arc<Model>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
 pub struct InferenceEngine;
 
 impl InferenceEngine {
-    pub async fn initialize() -> Result<()> {
-        if PIPELINE_ENGINE.get().is_some() {
-            return Ok(());
+    /// Builds (or returns the already built) pipeline for a model id, model directory or GGUF file.
+    pub async fn initialize(spec: &str) -> Result<Arc<Model>> {
+        let mut pipelines = PIPELINES.lock().await;
+        if let Some(pipeline) = pipelines.get(spec) {
+            return Ok(pipeline.clone());
         }
-        println!("Initializing InferenceEngine with model google/gemma-3-1b-it using mistralrs tag v0.6.0 (pipeline approach)...");
-
-        let model_builder = TextModelBuilder::new(
-            "google/gemma-3-1b-it".to_string(),
-        )
-        .with_logging(); // Enable logging
-
-        match model_builder.build().await {
-            Ok(pipeline) => { // pipeline is likely of type mistralrs::Model
-                PIPELINE_ENGINE.set(Arc::new(pipeline)).map_err(|_| {
-                    anyhow::anyhow!("Failed to set PIPELINE_ENGINE in OnceCell")
-                })?;
+        println!("Initializing InferenceEngine with model {} using mistralrs tag v0.6.0 (pipeline approach)...", spec);
+
+        let source = LocalModelSource::parse(spec).map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        let built = match source {
+            LocalModelSource::ModelId(id) => TextModelBuilder::new(id).with_logging().build().await,
+            LocalModelSource::Directory(dir) if dir.join("config.json").is_file() => {
+                TextModelBuilder::new(dir.display().to_string()).with_logging().build().await
+            }
+            LocalModelSource::Directory(_) | LocalModelSource::GgufFile(_) => {
+                let file = crate::gemma::resolve_gguf(spec).map_err(|e| anyhow::anyhow!(e.to_string()))?;
+                let dir = file.parent().map(|dir| dir.display().to_string()).unwrap_or_else(|| ".".to_string());
+                let name = file.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                GgufModelBuilder::new(dir, vec![name]).with_logging().build().await
+            }
+        };
+
+        match built {
+            Ok(pipeline) => {
+                let pipeline = Arc::new(pipeline);
+                pipelines.insert(spec.to_string(), pipeline.clone());
                 println!("PipelineEngine initialized successfully.");
-                Ok(())
+                Ok(pipeline)
             }
             Err(e) => {
-                let err_msg = format!("Failed to build model pipeline: {:?}", e);
+                let err_msg = format!(
+                    "Failed to build model pipeline for {}: {:?}. Model ids are fetched from Hugging Face; local directories need config.json, tokenizer.json and safetensors weights, or a single .gguf file.",
+                    spec, e
+                );
                 eprintln!("{}", err_msg);
                 Err(anyhow::anyhow!(err_msg))
             }
         }
     }
 
-    pub async fn generate_text(prompt: &str, _max_tokens: usize) -> Result<String> {
-        let pipeline = PIPELINE_ENGINE
-            .get()
-            .ok_or_else(|| anyhow::anyhow!("Pipeline engine not initialized. Call initialize() first."))?;
+    pub async fn generate_text(pipeline: &Model, prompt: &str, max_tokens: usize) -> Result<String> {
+        Ok(Self::generate(pipeline, prompt, max_tokens, None).await?.text)
+    }
 
-        let messages = TextMessages::new()
-            .add_message(TextMessageRole::User, prompt.to_string());
+    /// Answers `prompt` with at most `max_tokens` tokens, reporting token counts and whether the limit was hit.
+    /// With a (Gemini) response schema, decoding is constrained to JSON matching it.
+    pub async fn generate(pipeline: &Model, prompt: &str, max_tokens: usize, schema: Option<&serde_json::Value>) -> Result<LocalGeneration> {
+        let mut request = RequestBuilder::new()
+            .add_message(TextMessageRole::User, prompt.to_string())
+            .set_sampler_max_len(max_tokens);
+        if let Some(schema) = schema {
+            request = request.set_constraint(Constraint::JsonSchema(to_json_schema(schema)));
+        }
 
-        // Using send_chat_request as per user's example.
-        // _max_tokens is unused if this call doesn't support per-request sampling_params.
-        match pipeline.send_chat_request(messages).await {
+        match pipeline.send_chat_request(request).await {
             Ok(response) => {
-                if let Some(choice) = response.choices.first() {
-                    Ok(choice.message.content.as_ref().cloned().unwrap_or_default())
-                } else {
-                    Err(anyhow::anyhow!("No choices returned in chat response"))
-                }
+                let choice = response.choices.first().ok_or_else(|| anyhow::anyhow!("No choices returned in chat response"))?;
+                Ok(LocalGeneration {
+                    text: choice.message.content.clone().unwrap_or_default(),
+                    prompt_tokens: response.usage.prompt_tokens,
+                    generated_tokens: response.usage.completion_tokens,
+                    hit_token_limit: choice.finish_reason == "length",
+                })
             }
             Err(e) => Err(anyhow::anyhow!("Error during send_chat_request: {:?}", e)),
         }
     }
 }
+
+/// `LocalGenerator` over a mistral.rs pipeline. Calls must come from a thread that may block
+/// on the Tokio runtime (e.g. inside `block_in_place`).
+pub struct MistralGenerator {
+    model: String,
+    pipeline: Arc<Model>,
+}
+
+impl MistralGenerator {
+    pub async fn new(spec: &str) -> Result<Self> {
+        Ok(Self { model: spec.to_string(), pipeline: InferenceEngine::initialize(spec).await? })
+    }
+}
+
+impl LocalGenerator for MistralGenerator {
+    fn model_name(&self) -> &str {
+        &self.model
+    }
+
+    fn context_window(&self) -> usize {
+        ModelLimits::for_model(&self.model).context_window
+    }
+
+    fn generate(&self, prompt: &str, max_tokens: usize) -> crate::Result<LocalGeneration> {
+        tokio::runtime::Handle::current()
+            .block_on(InferenceEngine::generate(&self.pipeline, prompt, max_tokens, None))
+            .map_err(|e| e.to_string().into())
+    }
+
+    fn generate_json(&self, prompt: &str, max_tokens: usize, schema: &serde_json::Value) -> crate::Result<LocalGeneration> {
+        tokio::runtime::Handle::current()
+            .block_on(InferenceEngine::generate(&self.pipeline, prompt, max_tokens, Some(schema)))
+            .map_err(|e| e.to_string().into())
+    }
+}
@@ -0,0 +1,409 @@
+use serde_json::{Map, Value};
+
+// Whitespace bytes allowed in a row between JSON tokens, so a small model cannot pad an answer forever.
+const MAX_WHITESPACE_RUN: usize = 16;
+
+static ANY: Value = Value::Null;
+
+/// A JSON answer being written byte by byte, checked against a Gemini response schema
+/// (see `llm_api::ResponseSchema`; lowercase JSON Schema types are accepted too).
+/// Local backends use it to pick only tokens that keep the answer a valid prefix,
+/// so the finished answer always deserializes into the response struct.
+#[derive(Debug, Clone)]
+pub struct JsonConstraint<'a> {
+    stack: Vec<Frame<'a>>,
+    whitespace_run: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Frame<'a> {
+    Value(&'a Value), // A value matching the schema is expected next
+    Object { properties: Option<&'a Map<String, Value>>, required: Vec<&'a str>, seen: Vec<&'a str>, value_schema: &'a Value, state: ObjectState },
+    Array { items: &'a Value, state: ArrayState },
+    Str { escape: Escape }, // A free string; its text is not kept, as it may hold a whole file
+    Choice { allowed: Vec<&'a str>, text: Vec<u8> }, // An enum value or property name, matched as it is read
+    Literal(&'static [u8]), // The rest of `true`, `false` or `null`
+    Number { state: NumberState, integer: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ObjectState {
+    Open,       // After `{`: a key or `}`
+    Key,        // After `,`: a key
+    InKey,      // Reading a key
+    Colon,      // After a key
+    InValue,    // Reading a value
+    AfterValue, // `,` or `}`
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArrayState {
+    Open,       // After `[`: a value or `]`
+    Item,       // After `,`: a value
+    InValue,    // Reading a value
+    AfterValue, // `,` or `]`
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    Backslash,
+    Unicode(u8), // Hex digits still expected
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumberState {
+    Minus,
+    Zero,
+    Int,
+    Dot,
+    Fraction,
+    Exponent,
+    ExponentSign,
+    ExponentDigits,
+}
+
+impl NumberState {
+    fn accepting(self) -> bool {
+        matches!(self, NumberState::Zero | NumberState::Int | NumberState::Fraction | NumberState::ExponentDigits)
+    }
+}
+
+enum Step<'a> {
+    Reject,
+    Whitespace,
+    Consumed,
+    Push(Frame<'a>),         // Consumed; `Frame` reads what follows
+    PushUnconsumed(Frame<'a>), // `Frame` reads this byte
+    Done,                    // Consumed and completed the value
+    DoneUnconsumed,          // Completed the value; the parent reads this byte
+}
+
+impl<'a> JsonConstraint<'a> {
+    /// Starts an answer that must be a JSON value matching `schema`.
+    pub fn new(schema: &'a Value) -> Self {
+        Self { stack: vec![Frame::Value(schema)], whitespace_run: 0 }
+    }
+
+    /// Appends `byte` if the answer stays a valid prefix; otherwise leaves it unchanged and returns false.
+    pub fn push(&mut self, byte: u8) -> bool {
+        let mut next = self.clone();
+        if !next.advance(byte) {
+            return false;
+        }
+        *self = next;
+        true
+    }
+
+    /// Appends all of `bytes`, or none of them if any would make the answer invalid.
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> bool {
+        let mut next = self.clone();
+        if !bytes.iter().all(|b| next.advance(*b)) {
+            return false;
+        }
+        *self = next;
+        true
+    }
+
+    /// Whether the answer so far is a complete value matching the schema.
+    pub fn is_complete(&self) -> bool {
+        match self.stack.as_slice() {
+            [] => true,
+            [Frame::Number { state, .. }] => state.accepting(),
+            _ => false,
+        }
+    }
+
+    // Like `push`, but may leave `self` half-updated when it returns false.
+    fn advance(&mut self, byte: u8) -> bool {
+        loop {
+            let Some(top) = self.stack.last_mut() else { return false };
+            match step(top, byte) {
+                Step::Reject => return false,
+                Step::Whitespace => {
+                    self.whitespace_run += 1;
+                    return self.whitespace_run <= MAX_WHITESPACE_RUN;
+                }
+                Step::Consumed => break,
+                Step::Push(frame) => {
+                    self.stack.push(frame);
+                    break;
+                }
+                Step::PushUnconsumed(frame) => self.stack.push(frame),
+                Step::Done => {
+                    self.finish_value();
+                    break;
+                }
+                Step::DoneUnconsumed => self.finish_value(),
+            }
+        }
+        self.whitespace_run = 0;
+        true
+    }
+
+    // Pops the completed value on top and moves its parent on.
+    fn finish_value(&mut self) {
+        let key = match self.stack.pop() {
+            Some(Frame::Choice { text, .. }) => text,
+            _ => Vec::new(),
+        };
+        match self.stack.last_mut() {
+            Some(Frame::Object { properties, seen, value_schema, state, .. }) => match *state {
+                ObjectState::InKey => {
+                    let key = String::from_utf8_lossy(&key);
+                    if let Some((name, schema)) = properties.and_then(|props| props.get_key_value(key.as_ref())) {
+                        seen.push(name.as_str());
+                        *value_schema = schema;
+                    }
+                    *state = ObjectState::Colon;
+                }
+                _ => *state = ObjectState::AfterValue,
+            },
+            Some(Frame::Array { state, .. }) => *state = ArrayState::AfterValue,
+            _ => {}
+        }
+    }
+}
+
+fn step<'a>(frame: &mut Frame<'a>, byte: u8) -> Step<'a> {
+    let whitespace = matches!(byte, b' ' | b'\n' | b'\r' | b'\t');
+    match frame {
+        Frame::Value(schema) => {
+            if whitespace {
+                return Step::Whitespace;
+            }
+            match start_value(schema, byte) {
+                Some(started) => {
+                    *frame = started;
+                    Step::Consumed
+                }
+                None => Step::Reject,
+            }
+        }
+        Frame::Object { properties, required, seen, value_schema, state } => {
+            if whitespace && *state != ObjectState::InKey && *state != ObjectState::InValue {
+                return Step::Whitespace;
+            }
+            match (*state, byte) {
+                (ObjectState::Open | ObjectState::Key, b'"') => {
+                    let key = match properties {
+                        Some(props) => {
+                            let allowed: Vec<&str> = props.keys().map(String::as_str).filter(|key| !seen.contains(key)).collect();
+                            if allowed.is_empty() {
+                                return Step::Reject;
+                            }
+                            Frame::Choice { allowed, text: Vec::new() }
+                        }
+                        None => Frame::Str { escape: Escape::None },
+                    };
+                    *state = ObjectState::InKey;
+                    Step::Push(key)
+                }
+                (ObjectState::Colon, b':') => {
+                    *state = ObjectState::InValue;
+                    Step::Push(Frame::Value(value_schema))
+                }
+                (ObjectState::AfterValue, b',') => {
+                    let keys_left = properties.is_none_or(|props| props.len() > seen.len());
+                    if !keys_left {
+                        return Step::Reject;
+                    }
+                    *state = ObjectState::Key;
+                    Step::Consumed
+                }
+                (ObjectState::Open | ObjectState::AfterValue, b'}') if required.iter().all(|key| seen.contains(key)) => Step::Done,
+                _ => Step::Reject,
+            }
+        }
+        Frame::Array { items, state } => {
+            if whitespace && *state != ArrayState::InValue {
+                return Step::Whitespace;
+            }
+            match (*state, byte) {
+                (ArrayState::Open | ArrayState::AfterValue, b']') => Step::Done,
+                (ArrayState::AfterValue, b',') => {
+                    *state = ArrayState::Item;
+                    Step::Consumed
+                }
+                (ArrayState::Open | ArrayState::Item, _) => {
+                    *state = ArrayState::InValue;
+                    Step::PushUnconsumed(Frame::Value(items))
+                }
+                _ => Step::Reject,
+            }
+        }
+        Frame::Str { escape } => step_string(escape, byte),
+        Frame::Choice { allowed, text } => {
+            // Enum values and property names are matched literally; none of them needs escaping.
+            if byte == b'"' {
+                return if allowed.iter().any(|value| value.as_bytes() == text.as_slice()) { Step::Done } else { Step::Reject };
+            }
+            text.push(byte);
+            if allowed.iter().any(|value| value.as_bytes().starts_with(text)) { Step::Consumed } else { Step::Reject }
+        }
+        Frame::Literal(rest) => match rest.split_first() {
+            Some((expected, remaining)) if *expected == byte => {
+                *rest = remaining;
+                if remaining.is_empty() { Step::Done } else { Step::Consumed }
+            }
+            _ => Step::Reject,
+        },
+        Frame::Number { state, integer } => {
+            let next = match (*state, byte) {
+                (NumberState::Minus, b'0') => NumberState::Zero,
+                (NumberState::Minus, b'1'..=b'9') => NumberState::Int,
+                (NumberState::Int, b'0'..=b'9') => NumberState::Int,
+                (NumberState::Zero | NumberState::Int, b'.') if !*integer => NumberState::Dot,
+                (NumberState::Dot | NumberState::Fraction, b'0'..=b'9') => NumberState::Fraction,
+                (NumberState::Zero | NumberState::Int | NumberState::Fraction, b'e' | b'E') if !*integer => NumberState::Exponent,
+                (NumberState::Exponent, b'+' | b'-') => NumberState::ExponentSign,
+                (NumberState::Exponent | NumberState::ExponentSign | NumberState::ExponentDigits, b'0'..=b'9') => NumberState::ExponentDigits,
+                (current, _) if current.accepting() => return Step::DoneUnconsumed,
+                _ => return Step::Reject,
+            };
+            *state = next;
+            Step::Consumed
+        }
+    }
+}
+
+fn step_string<'a>(escape: &mut Escape, byte: u8) -> Step<'a> {
+    match (*escape, byte) {
+        (Escape::None, b'"') => Step::Done,
+        (Escape::None, b'\\') => {
+            *escape = Escape::Backslash;
+            Step::Consumed
+        }
+        (Escape::None, 0x00..=0x1f) => Step::Reject, // Raw control characters, e.g. unescaped newlines
+        (Escape::None, _) => Step::Consumed,
+        (Escape::Backslash, b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => {
+            *escape = Escape::None;
+            Step::Consumed
+        }
+        (Escape::Backslash, b'u') => {
+            *escape = Escape::Unicode(4);
+            Step::Consumed
+        }
+        (Escape::Unicode(left), b'0'..=b'9' | b'a'..=b'f' | b'A'..=b'F') => {
+            *escape = if left == 1 { Escape::None } else { Escape::Unicode(left - 1) };
+            Step::Consumed
+        }
+        _ => Step::Reject,
+    }
+}
+
+// The frame reading the rest of a value that starts with `byte`, if the schema allows such a value.
+fn start_value<'a>(schema: &'a Value, byte: u8) -> Option<Frame<'a>> {
+    let types = schema_types(schema);
+    let allows = |ty: &str| types.is_empty() || types.iter().any(|t| t == ty);
+    let started = match byte {
+        b'{' if allows("object") => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            let required = schema.get("required").and_then(Value::as_array).map(|keys| keys.iter().filter_map(Value::as_str).collect()).unwrap_or_default();
+            Frame::Object { properties, required, seen: Vec::new(), value_schema: &ANY, state: ObjectState::Open }
+        }
+        b'[' if allows("array") => Frame::Array { items: schema.get("items").unwrap_or(&ANY), state: ArrayState::Open },
+        b'"' if allows("string") => {
+            match schema.get("enum").and_then(Value::as_array) {
+                Some(values) => Frame::Choice { allowed: values.iter().filter_map(Value::as_str).collect(), text: Vec::new() },
+                None => Frame::Str { escape: Escape::None },
+            }
+        }
+        b't' if allows("boolean") => Frame::Literal(b"rue"),
+        b'f' if allows("boolean") => Frame::Literal(b"alse"),
+        b'n' if allows("null") => Frame::Literal(b"ull"),
+        b'-' | b'0'..=b'9' if allows("number") || allows("integer") => {
+            let integer = !allows("number");
+            let state = match byte {
+                b'-' => NumberState::Minus,
+                b'0' => NumberState::Zero,
+                _ => NumberState::Int,
+            };
+            Frame::Number { state, integer }
+        }
+        _ => return None,
+    };
+    Some(started)
+}
+
+// Lowercase type names the schema allows, `null` included if it is nullable; empty for any type.
+fn schema_types(schema: &Value) -> Vec<String> {
+    let mut types: Vec<String> = match schema.get("type") {
+        Some(Value::String(ty)) => vec![ty.to_lowercase()],
+        Some(Value::Array(tys)) => tys.iter().filter_map(Value::as_str).map(str::to_lowercase).collect(),
+        _ => return Vec::new(),
+    };
+    if schema.get("nullable").and_then(Value::as_bool) == Some(true) {
+        types.push("null".to_string());
+    }
+    types
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_api::{CodeChange, CodeChangeAction, GeminiCodeGenerationResponse, GeminiSufficiencyResponse, ResponseSchema, TestChange};
+
+    fn accepts(schema: &Value, answer: &str) -> bool {
+        let mut constraint = JsonConstraint::new(schema);
+        constraint.push_bytes(answer.as_bytes()) && constraint.is_complete()
+    }
+
+    #[test]
+    fn test_serialized_responses_are_accepted() {
+        let response = GeminiCodeGenerationResponse {
+            changes: vec![CodeChange {
+                file_path: "src/lib.rs".to_string(),
+                action: CodeChangeAction::ReplaceItemInSection,
+                content: Some("pub fn answer() -> u32 {\n    \"42\\u00e9\".len() as u32 // ü\n}".to_string()),
+            }],
+            tests: Some(vec![TestChange {
+                file_path: "tests/answer.rs".to_string(),
+                action: "CreateFile".to_string(),
+                content: "#[test]\nfn answer() {}".to_string(),
+                test_name: None,
+            }]),
+            explanation: "Adds `answer`.".to_string(),
+        };
+        let schema = GeminiCodeGenerationResponse::response_schema();
+        assert!(accepts(&schema, &serde_json::to_string(&response).unwrap()));
+        assert!(accepts(&schema, &serde_json::to_string_pretty(&response).unwrap()));
+
+        let schema = GeminiSufficiencyResponse::response_schema();
+        assert!(accepts(&schema, "{\"sufficient\": false, \"needed_items\": [\"fn main\", \"struct Config\"]}"));
+        assert!(accepts(&schema, "{\"needed_items\":[],\"sufficient\":true}"));
+        assert!(accepts(&serde_json::json!({ "type": "number" }), "-12.5e+3"));
+    }
+
+    #[test]
+    fn test_almost_valid_answers_are_rejected() {
+        let schema = GeminiSufficiencyResponse::response_schema();
+        assert!(!accepts(&schema, "```json\n{\"sufficient\": true}"));
+        assert!(!accepts(&schema, "{\"needed_items\": []}")); // `sufficient` is required
+        assert!(!accepts(&schema, "{\"sufficient\": true,}"));
+        assert!(!accepts(&schema, "{\"sufficient\": \"yes\"}"));
+        assert!(!accepts(&schema, "{\"sufficient\": true, \"reason\": \"done\"}"));
+        assert!(!accepts(&schema, "{\"sufficient\": true, \"sufficient\": false}"));
+        assert!(!accepts(&schema, "{\"sufficient\": true} trailing"));
+        assert!(!accepts(&schema, "{\"sufficient\": tru}"));
+        assert!(!accepts(&schema, &format!("{{{}\"sufficient\": true}}", " ".repeat(MAX_WHITESPACE_RUN + 1))));
+
+        let schema = CodeChange::response_schema();
+        assert!(!accepts(&schema, "{\"file_path\": \"a.rs\", \"action\": \"Rewrite\"}"));
+        assert!(!accepts(&schema, "{\"file_path\": \"a.rs\", \"action\": \"CreateFile\", \"content\": \"fn a() {\n}\"}"));
+        assert!(!accepts(&schema, "{\"file_path\": \"a\\q.rs\", \"action\": \"CreateFile\"}"));
+        assert!(accepts(&schema, "{\"file_path\": \"a.rs\", \"action\": \"DeleteFile\", \"content\": null}"));
+    }
+
+    #[test]
+    fn test_rejected_bytes_leave_the_constraint_unchanged() {
+        let schema = GeminiSufficiencyResponse::response_schema();
+        let mut constraint = JsonConstraint::new(&schema);
+        assert!(constraint.push_bytes(b"{\"suff"));
+        assert!(!constraint.push_bytes(b"icient\": 1"));
+        assert!(!constraint.push(b'x'));
+        assert!(constraint.push_bytes(b"icient\": true}"));
+        assert!(constraint.is_complete());
+        assert!(!constraint.push(b' '));
+    }
+}
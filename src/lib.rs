@@ -1,15 +1,30 @@
 // Declare modules that are part of the library
 pub mod llm_api;
+pub mod agent_tools;
 pub mod cache;
 pub mod cli;
+pub mod diagnostics;
 pub mod parser;
 pub mod locatesource;
 pub mod browser_interaction;
 pub mod gemma;
+pub mod gguf;
+pub mod git;
+pub mod inference;
+pub mod json_constraint;
+pub mod local_llm;
 pub mod llm_response_parser;
+pub mod llm_replay;
+pub mod llm_mock;
+pub mod manual_llm;
+pub mod model_router;
+pub mod project_config;
+pub mod token_budget;
+pub mod usage;
+pub mod verification;
 
 // Standard library imports needed by moved functions
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs; // Added for file operations
 use std::path::{Path, PathBuf};
 use std::process::Command;
@@ -19,7 +34,9 @@ use std::time::Duration; // For ProgressBar and potentially for reqwest timeout
 // Crate-local imports (modules defined above)
 use cache::Session;
 use cli::CustomCliArgs; // Used for structuring command line arguments.
-use llm_api::LLMApi; // Use the trait
+use git::GitRepo;
+use llm_api::{ChatTurn, LLMApi, ModelReply, ResponseSchema}; // Use the trait
+use model_router::{AgentPhase, ModelRouter};
 
 // Re-export types needed for integration tests and by the binary crate
 pub use llm_api::{
@@ -30,6 +47,7 @@ pub use llm_api::{
     CodeChangeAction,
     TestChange,
     RealLLMApi, // Re-exporting for main
+    OpenAICompatibleLLMApi,
     MockLLMApi // Re-exporting for tests
 };
 // No need to re-export LLMApi trait if it's only used internally by run_gem_agent's signature here
@@ -44,13 +62,6 @@ pub use cli::CustomCliArgs as ExtCustomCliArgs;
 use ignore::WalkBuilder;
 use indicatif::{ProgressBar, ProgressStyle}; // If run_gem_agent handles ProgressBar
 
-// --- Global Constants (moved from main.rs) ---
-// These might need to be public if prompts use them and prompts are constructed outside.
-// For now, assuming prompts are constructed within this lib.
-const FLASH_MODEL_NAME: &str = "gemini-2.5-flash-preview-04-17";
-const THINKING_MODEL_NAME: &str = "gemini-2.5-flash-preview-04-17";
-
-
 // --- Error Type ---
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -61,8 +72,27 @@ pub fn run_gem_agent(
     llm_api: Box<dyn LLMApi>, // Use the trait from llm_api.rs
     is_interactive: bool,
     project_root: PathBuf,
+) -> Result<()> {
+    // The session may hold calls from earlier runs; only this run's calls go into the summary.
+    let calls_before = session.calls().len();
+    let result = run_agent_phases(args, session, llm_api.as_ref(), is_interactive, project_root);
+    llm_api.release_shared_prefix(); // Don't leave the project context cached (and billed) until its TTL runs out
+    let run_calls = &session.calls()[calls_before..];
+    if !run_calls.is_empty() {
+        println!("{}", usage::format_run_summary(run_calls));
+    }
+    result
+}
+
+fn run_agent_phases(
+    args: CustomCliArgs,
+    session: &mut Session,
+    llm_api: &dyn LLMApi,
+    is_interactive: bool,
+    project_root: PathBuf,
 ) -> Result<()> {
     let mut pb: Option<ProgressBar> = None;
+    let model_router = ModelRouter::with_overrides(&args.model_overrides);
 
     if is_interactive {
         pb = Some(ProgressBar::new_spinner());
@@ -73,13 +103,25 @@ pub fn run_gem_agent(
     check_dependencies(&project_root)?;
     if let Some(p) = &pb { p.finish_with_message("Dependencies OK."); }
 
+    let git_repo = match GitRepo::open(&project_root) {
+        Some(repo) if args.debug_mode.is_none() => {
+            prepare_work_tree(&repo, &args)?;
+            Some(repo)
+        }
+        Some(_) => None, // Debug runs stop before changing anything
+        None => {
+            eprintln!("gem: WARN: {} is not in a git work tree; the changes will not be committed.", project_root.display());
+            None
+        }
+    };
+
     if is_interactive {
         pb = Some(ProgressBar::new_spinner());
         pb.as_ref().unwrap().set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
         pb.as_ref().unwrap().set_message("Phase 1: Initial Information Gathering...");
         pb.as_ref().unwrap().enable_steady_tick(Duration::from_millis(100));
     }
-    let initial_context = gather_initial_project_info(&project_root)?;
+    let mut initial_context = gather_initial_project_info(&project_root)?;
     if let Some(p) = &pb { p.finish_with_message("Initial information gathered."); }
 
     // Construct user_request from user_request_parts
@@ -87,146 +129,207 @@ pub fn run_gem_agent(
     // Note: user_request_str is constructed here. If CustomCliArgs were to have a processed
     // `user_request: String` field, this could be simplified. For now, this approach is fine.
 
-    let first_prompt = construct_first_gemini_prompt(&user_request_str, &initial_context);
+    // With tool support the model fetches context itself; otherwise it lists `needed_items` for us to fetch.
+    let use_tools = llm_api.supports_tools() && !args.no_tools;
+    let construct_first_prompt = if use_tools { construct_tool_gathering_prompt } else { construct_first_gemini_prompt };
+
+    // The symbol dump and cargo tree grow with the crate; cut them down if they would not fit.
+    // Every phase carries the project context, so it may take at most half of the smallest phase budget
+    // and leave the rest for the gathered items.
+    let first_prompt = construct_first_prompt(&user_request_str, args.max_data_loops);
+    let initial_budget = token_budget::prompt_budget_for_models(model_router.chain(AgentPhase::Initial), args.max_prompt_tokens);
+    let smallest_budget = [AgentPhase::Sufficient, AgentPhase::Change, AgentPhase::Retry]
+        .into_iter()
+        .map(|phase| token_budget::prompt_budget_for_models(model_router.chain(phase), args.max_prompt_tokens))
+        .fold(initial_budget, usize::min);
+    let mut bare_context = initial_context.clone();
+    for key in INITIAL_CONTEXT_TRIM_ORDER { bare_context.insert(key.to_string(), String::new()); }
+    let initial_overhead = token_budget::estimate_tokens(&construct_project_context_prompt(&bare_context)) + token_budget::estimate_tokens(&first_prompt);
+    let context_budget = initial_budget.saturating_sub(initial_overhead).min(smallest_budget / 2);
+    let trimmed = token_budget::fit_context_entries(&mut initial_context, &INITIAL_CONTEXT_TRIM_ORDER, context_budget);
+    report_trimmed_context(AgentPhase::Initial, initial_budget, &trimmed, pb.as_ref());
+
+    let project_context = ChatTurn::User(construct_project_context_prompt(&initial_context));
+    let project_context_tokens = token_budget::estimate_tokens(&llm_api::flatten_conversation(std::slice::from_ref(&project_context)));
+    let first_conversation = with_project_context(&project_context, &first_prompt);
 
     if args.debug_mode == Some(crate::cli::DebugMode::Initial) { // Assuming cli::DebugMode is accessible
         println!("\n--- DEBUG: INITIAL PROMPT ---");
-        println!("{}", first_prompt);
+        println!("{}", llm_api::flatten_conversation(&first_conversation));
         println!("--- END DEBUG: INITIAL PROMPT ---");
         return Ok(());
     }
 
-    if is_interactive {
-        pb = Some(ProgressBar::new_spinner());
-        pb.as_ref().unwrap().set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
-        pb.as_ref().unwrap().set_message("Asking Gemini what information it needs...");
-        pb.as_ref().unwrap().enable_steady_tick(Duration::from_millis(100));
-    }
-    session.overwrite_prompt("initial", &first_prompt)?;
-    let gemini_response_str_result = call_gemini_api_with_session(
-        session,
-        llm_api.as_ref(),
-        "initial",
-        &first_prompt,
-        FLASH_MODEL_NAME,
-    );
-    if let Some(p) = &pb { p.finish_and_clear(); }
-    let gemini_response_str = clean_gemini_api_json(gemini_response_str_result?);
-
-    let needed_items_response: GeminiNeededItemsResponse =
-        serde_json::from_str(&gemini_response_str)?;
-    let mut current_needed_items = needed_items_response.needed_items;
-
     let mut gathered_data_for_gemini: HashMap<String, String> = session.gathered_data.clone();
-    let mut data_gathering_iterations = 0;
+    let verification_stages = verification::verification_stages(&args.verify_with, &project_root)?;
     let mut verification_attempt = 0;
-    let _code_change_attempt = 0;
-
-    loop { // Sufficiency Loop
-        if data_gathering_iterations >= args.max_data_loops {
-            eprintln!("gem: ERROR: Exceeded maximum data gathering iterations ({}). Giving up.", args.max_data_loops);
-            return Err("Max data gathering iterations reached.".into());
-        }
-        data_gathering_iterations += 1;
-
-        if !current_needed_items.is_empty() {
-            if is_interactive {
-                pb = Some(ProgressBar::new_spinner());
-                pb.as_ref().unwrap().set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
-                pb.as_ref().unwrap().set_message(format!("Extracting {} item(s)...", current_needed_items.len()));
-                pb.as_ref().unwrap().enable_steady_tick(Duration::from_millis(100));
-            }
-        }
+    llm_api.set_shared_prefix(std::slice::from_ref(&project_context));
 
-        for item_path_or_qname in &current_needed_items {
-            if !gathered_data_for_gemini.contains_key(item_path_or_qname) {
-                if let Some(p) = &pb { p.set_message(format!("Extracting: {}...", item_path_or_qname)); }
-                match query_rust_analyzer_for_item_definition(&project_root, item_path_or_qname) {
-                    Ok(content) => {
-                        gathered_data_for_gemini.insert(item_path_or_qname.clone(), content.clone());
-                        session.add_data(item_path_or_qname, &content);
-                    }
-                    Err(e) => {
-                        let error_msg = format!("// GEM_NOTE: Error querying for {}: {}", item_path_or_qname, e);
-                        if let Some(p) = &pb { p.println(format!("gem: ERROR: {}", error_msg)); }
-                        else { eprintln!("gem: ERROR: {}", error_msg); }
-                        gathered_data_for_gemini.insert(item_path_or_qname.clone(), error_msg.clone());
-                        session.add_data(item_path_or_qname, &error_msg);
-                    }
-                }
-            }
-        }
-        if let Some(p) = &pb {
-            if !current_needed_items.is_empty() {
-                p.finish_with_message("Items extracted.");
-            } else {
-                p.finish_and_clear();
-            }
+    if use_tools {
+        if is_interactive {
+            pb = Some(ProgressBar::new_spinner());
+            pb.as_ref().unwrap().set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
+            pb.as_ref().unwrap().enable_steady_tick(Duration::from_millis(100));
         }
-        // pb = None; // Reset progress bar after this section // This line is removed as pb is reassigned or goes out of scope
-
-        session.save()?;
-        current_needed_items.clear();
-
-        let user_request_str = args.user_request_parts.join(" "); // Reconstruct here too or pass around
-        let sufficiency_prompt = construct_sufficiency_check_prompt(&user_request_str, &gathered_data_for_gemini);
-        session.append_to_prompt("sufficient", &sufficiency_prompt)?;
-
+        let gather_result = gather_context_with_tools(session, llm_api, &model_router, &args, &project_root, first_conversation, &mut gathered_data_for_gemini, pb.as_ref());
+        if let Some(p) = &pb { p.finish_and_clear(); }
+        gather_result?;
         if args.debug_mode == Some(crate::cli::DebugMode::Sufficient) {
-            println!("\n--- DEBUG: SUFFICIENCY PROMPT (Iteration {}) ---", data_gathering_iterations);
-            println!("{}", sufficiency_prompt);
-            println!("--- END DEBUG: SUFFICIENCY PROMPT ---");
+            println!("\n--- DEBUG: GATHERED ITEMS ---");
+            for (item, content) in &gathered_data_for_gemini { println!("// Item: {}\n{}\n", item, content); }
+            println!("--- END DEBUG: GATHERED ITEMS ---");
             return Ok(());
         }
-
+    } else {
         if is_interactive {
             pb = Some(ProgressBar::new_spinner());
             pb.as_ref().unwrap().set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
-            pb.as_ref().unwrap().set_message("Asking Gemini if gathered data is sufficient...");
+            pb.as_ref().unwrap().set_message("Asking Gemini what information it needs...");
             pb.as_ref().unwrap().enable_steady_tick(Duration::from_millis(100));
         }
+        session.overwrite_prompt("initial", &llm_api::flatten_conversation(&first_conversation))?;
+        let gemini_response_str_result = call_llm_conversation_with_session(
+            session,
+            llm_api,
+            &model_router,
+            AgentPhase::Initial,
+            "initial",
+            &first_conversation,
+            Some(&GeminiNeededItemsResponse::response_schema()),
+            pb.as_ref(),
+        );
+        if let Some(p) = &pb { p.finish_and_clear(); }
+        let gemini_response_str = clean_gemini_api_json(gemini_response_str_result?);
 
-        let gemini_sufficiency_response_str_result = if let Some(cached_response) =
-            session.get_cached_response("sufficient", &sufficiency_prompt)
-        {
-            if is_interactive { if let Some(p) = &pb {p.println("gem: Using cached sufficiency response");} }
-            else { println!("Using cached sufficiency response"); }
-            Ok(cached_response)
-        } else {
-            let res = call_gemini_api_with_session(
-                session,
-                llm_api.as_ref(),
-                "sufficient",
-                &sufficiency_prompt,
-                FLASH_MODEL_NAME,
-            );
-            res
-        };
+        let needed_items_response: GeminiNeededItemsResponse =
+            serde_json::from_str(&gemini_response_str)?;
+        let mut current_needed_items = needed_items_response.needed_items;
 
-        if let Some(p) = &pb { p.finish_and_clear(); }
-        let cleaned_response = clean_gemini_api_json(gemini_sufficiency_response_str_result?);
-        let sufficiency_response: GeminiSufficiencyResponse = serde_json::from_str(&cleaned_response)?;
-
-        if sufficiency_response.sufficient { break; }
-        else {
-            if !sufficiency_response.needed_items.is_empty() {
-                current_needed_items.extend(sufficiency_response.needed_items.into_iter().filter(|item| !gathered_data_for_gemini.contains_key(item)));
-                if current_needed_items.is_empty() && !gathered_data_for_gemini.is_empty() {
-                    if is_interactive { if let Some(p) = &pb {p.println("gem: All newly requested items were already gathered. Proceeding to check sufficiency again.");} else {println!("gem: All newly requested items were already gathered. Proceeding to check sufficiency again.");} }
-                     else { println!("gem: All newly requested items were already gathered. Proceeding to check sufficiency again."); }
+        let mut data_gathering_iterations = 0;
+
+        loop { // Sufficiency Loop
+            check_cancelled()?;
+            if data_gathering_iterations >= args.max_data_loops {
+                eprintln!("gem: ERROR: Exceeded maximum data gathering iterations ({}). Giving up.", args.max_data_loops);
+                return Err("Max data gathering iterations reached.".into());
+            }
+            data_gathering_iterations += 1;
+
+            if !current_needed_items.is_empty() {
+                if is_interactive {
+                    pb = Some(ProgressBar::new_spinner());
+                    pb.as_ref().unwrap().set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
+                    pb.as_ref().unwrap().set_message(format!("Extracting {} item(s)...", current_needed_items.len()));
+                    pb.as_ref().unwrap().enable_steady_tick(Duration::from_millis(100));
+                }
+            }
+
+            for item_path_or_qname in &current_needed_items {
+                if !gathered_data_for_gemini.contains_key(item_path_or_qname) {
+                    if let Some(p) = &pb { p.set_message(format!("Extracting: {}...", item_path_or_qname)); }
+                    match query_rust_analyzer_for_item_definition(&project_root, item_path_or_qname) {
+                        Ok(content) => {
+                            gathered_data_for_gemini.insert(item_path_or_qname.clone(), content.clone());
+                            session.add_data(item_path_or_qname, &content);
+                        }
+                        Err(e) => {
+                            let error_msg = format!("{} for {}: {}", token_budget::GATHER_ERROR_PREFIX, item_path_or_qname, e);
+                            if let Some(p) = &pb { p.println(format!("gem: ERROR: {}", error_msg)); }
+                            else { eprintln!("gem: ERROR: {}", error_msg); }
+                            gathered_data_for_gemini.insert(item_path_or_qname.clone(), error_msg.clone());
+                            session.add_data(item_path_or_qname, &error_msg);
+                        }
+                    }
+                }
+            }
+            if let Some(p) = &pb {
+                if !current_needed_items.is_empty() {
+                    p.finish_with_message("Items extracted.");
+                } else {
+                    p.finish_and_clear();
                 }
+            }
+            // pb = None; // Reset progress bar after this section // This line is removed as pb is reassigned or goes out of scope
+
+            session.save()?;
+            current_needed_items.clear();
+
+            let user_request_str = args.user_request_parts.join(" "); // Reconstruct here too or pass around
+            let sufficiency_budget = token_budget::prompt_budget_for_models(model_router.chain(AgentPhase::Sufficient), args.max_prompt_tokens);
+            let sufficiency_overhead = project_context_tokens + token_budget::estimate_tokens(&construct_sufficiency_check_prompt(&user_request_str, &HashMap::new()));
+            let (prompt_data, trimmed) = token_budget::fit_gathered_data(&gathered_data_for_gemini, sufficiency_budget.saturating_sub(sufficiency_overhead));
+            report_trimmed_context(AgentPhase::Sufficient, sufficiency_budget, &trimmed, pb.as_ref());
+            let sufficiency_conversation = with_project_context(&project_context, &construct_sufficiency_check_prompt(&user_request_str, &prompt_data));
+            let sufficiency_prompt = llm_api::flatten_conversation(&sufficiency_conversation);
+            session.append_to_prompt("sufficient", &sufficiency_prompt)?;
+
+            if args.debug_mode == Some(crate::cli::DebugMode::Sufficient) {
+                println!("\n--- DEBUG: SUFFICIENCY PROMPT (Iteration {}) ---", data_gathering_iterations);
+                println!("{}", sufficiency_prompt);
+                println!("--- END DEBUG: SUFFICIENCY PROMPT ---");
+                return Ok(());
+            }
+
+            if is_interactive {
+                pb = Some(ProgressBar::new_spinner());
+                pb.as_ref().unwrap().set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
+                pb.as_ref().unwrap().set_message("Asking Gemini if gathered data is sufficient...");
+                pb.as_ref().unwrap().enable_steady_tick(Duration::from_millis(100));
+            }
+
+            let gemini_sufficiency_response_str_result = if let Some(cached_response) =
+                session.get_cached_response("sufficient", &sufficiency_prompt)
+            {
+                if is_interactive { if let Some(p) = &pb {p.println("gem: Using cached sufficiency response");} }
+                else { println!("Using cached sufficiency response"); }
+                Ok(cached_response)
             } else {
-                eprintln!("gem: ERROR: Gemini reported data is not sufficient but did not specify what's needed. Giving up.");
-                return Err("Gemini insufficient without item list.".into());
+                let res = call_llm_conversation_with_session(
+                    session,
+                    llm_api,
+                    &model_router,
+                    AgentPhase::Sufficient,
+                    "sufficient",
+                    &sufficiency_conversation,
+                    Some(&GeminiSufficiencyResponse::response_schema()),
+                    pb.as_ref(),
+                );
+                res
+            };
+
+            if let Some(p) = &pb { p.finish_and_clear(); }
+            let cleaned_response = clean_gemini_api_json(gemini_sufficiency_response_str_result?);
+            let sufficiency_response: GeminiSufficiencyResponse = serde_json::from_str(&cleaned_response)?;
+
+            if sufficiency_response.sufficient { break; }
+            else {
+                if !sufficiency_response.needed_items.is_empty() {
+                    current_needed_items.extend(sufficiency_response.needed_items.into_iter().filter(|item| !gathered_data_for_gemini.contains_key(item)));
+                    if current_needed_items.is_empty() && !gathered_data_for_gemini.is_empty() {
+                        if is_interactive { if let Some(p) = &pb {p.println("gem: All newly requested items were already gathered. Proceeding to check sufficiency again.");} else {println!("gem: All newly requested items were already gathered. Proceeding to check sufficiency again.");} }
+                         else { println!("gem: All newly requested items were already gathered. Proceeding to check sufficiency again."); }
+                    }
+                } else {
+                    eprintln!("gem: ERROR: Gemini reported data is not sufficient but did not specify what's needed. Giving up.");
+                    return Err("Gemini insufficient without item list.".into());
+                }
             }
         }
     }
 
     let mut verification_failures_context = String::new();
+    let mut failed_stage = String::new(); // The stage whose output is in verification_failures_context
+    // Code generation is one conversation: each retry adds the previous answer and the verification
+    // failure as follow-up turns instead of re-sending the whole context.
+    let mut code_gen_conversation: Vec<ChatTurn> = Vec::new();
+    let mut code_gen_leading_turns = 0;
+    // Every file any attempt touched. Only the attempt that passes verification is committed, so
+    // the retries end up as one commit, which must also cover files only earlier attempts changed.
+    let mut touched_files: BTreeSet<String> = BTreeSet::new();
     loop { // Code Generation Loop
+        check_cancelled()?;
         if verification_attempt >= args.max_verify_retries + 1 {
             eprintln!("gem: ERROR: Exceeded maximum verification retries ({}). Giving up.", args.max_verify_retries);
-            eprintln!("gem: The last unverified changes might be committed. Please review your git history.");
             return Err("Max verification retries reached.".into());
         }
         verification_attempt += 1;
@@ -235,14 +338,38 @@ pub fn run_gem_agent(
         }
 
         let user_request_str = args.user_request_parts.join(" "); // Reconstruct here too or pass around
-        let code_gen_prompt = construct_code_generation_prompt(&user_request_str, &gathered_data_for_gemini, !args.no_test, if verification_attempt > 1 { Some(&verification_failures_context) } else { None }, &args.verify_with);
-        session.append_to_prompt("change", &code_gen_prompt)?;
-
-        if args.debug_mode == Some(crate::cli::DebugMode::Changes) && verification_attempt == 1 {
-            println!("\n--- DEBUG: CODE GENERATION PROMPT (Attempt 1) ---");
-            println!("{}", code_gen_prompt);
-            println!("--- END DEBUG: CODE GENERATION PROMPT ---");
-            return Ok(());
+        let code_gen_phase = if verification_attempt > 1 { AgentPhase::Retry } else { AgentPhase::Change };
+        let code_gen_budget = token_budget::prompt_budget_for_models(model_router.chain(code_gen_phase), args.max_prompt_tokens);
+
+        let mut conversation_fits = false;
+        if !code_gen_conversation.is_empty() {
+            let feedback = construct_verification_feedback_prompt(&failed_stage, &verification_failures_context);
+            session.append_to_prompt("change", &feedback)?;
+            code_gen_conversation.push(ChatTurn::User(feedback));
+            let (dropped, fits) = token_budget::fit_conversation(&mut code_gen_conversation, code_gen_leading_turns, code_gen_budget);
+            if dropped > 0 && fits {
+                let msg = format!("gem: WARN: The {} conversation exceeds its ~{}-token budget; dropped the {} oldest failed attempt(s).", code_gen_phase, code_gen_budget, dropped);
+                if let Some(p) = &pb { p.println(msg); } else { eprintln!("{}", msg); }
+            }
+            conversation_fits = fits;
+        }
+        if !conversation_fits {
+            // First attempt, or a retry whose conversation outgrew the budget: start over with one prompt.
+            let failure_context = if verification_attempt > 1 { Some(verification_failures_context.as_str()) } else { None };
+            let code_gen_overhead = project_context_tokens + token_budget::estimate_tokens(&construct_code_generation_prompt(&user_request_str, &HashMap::new(), !args.no_test, failure_context, &failed_stage));
+            let (prompt_data, trimmed) = token_budget::fit_gathered_data(&gathered_data_for_gemini, code_gen_budget.saturating_sub(code_gen_overhead));
+            report_trimmed_context(code_gen_phase, code_gen_budget, &trimmed, pb.as_ref());
+            let code_gen_prompt = construct_code_generation_prompt(&user_request_str, &prompt_data, !args.no_test, failure_context, &failed_stage);
+            code_gen_conversation = with_project_context(&project_context, &code_gen_prompt);
+            session.append_to_prompt("change", &llm_api::flatten_conversation(&code_gen_conversation))?;
+
+            if args.debug_mode == Some(crate::cli::DebugMode::Changes) && verification_attempt == 1 {
+                println!("\n--- DEBUG: CODE GENERATION PROMPT (Attempt 1) ---");
+                println!("{}", llm_api::flatten_conversation(&code_gen_conversation));
+                println!("--- END DEBUG: CODE GENERATION PROMPT ---");
+                return Ok(());
+            }
+            code_gen_leading_turns = code_gen_conversation.len();
         }
 
         if is_interactive {
@@ -252,22 +379,28 @@ pub fn run_gem_agent(
             pb.as_ref().unwrap().enable_steady_tick(Duration::from_millis(100));
         }
 
-        let gemini_code_gen_response_str_result = if let Some(cached_response) = session.get_cached_response("change", &code_gen_prompt) {
+        let gemini_code_gen_response_str_result = if let Some(cached_response) = session.get_cached_response("change", &llm_api::flatten_conversation(&code_gen_conversation)) {
              if is_interactive { if let Some(p) = &pb {p.println("gem: Using cached code generation response");} }
              else { println!("Using cached code generation response"); }
             Ok(cached_response)
         } else {
-            let res = call_gemini_api_with_session(
+            let res = call_llm_conversation_with_session(
                 session,
-                llm_api.as_ref(),
+                llm_api,
+                &model_router,
+                code_gen_phase,
                 "change",
-                &code_gen_prompt,
-                THINKING_MODEL_NAME,
+                &code_gen_conversation,
+                Some(&GeminiCodeGenerationResponse::response_schema()),
+                pb.as_ref(),
             );
             res
         };
         if let Some(p) = &pb { p.finish_and_clear(); }
-        let mut code_gen_response: GeminiCodeGenerationResponse = serde_json::from_str(&gemini_code_gen_response_str_result?)?;
+        let gemini_code_gen_response_str = gemini_code_gen_response_str_result?;
+        let cleaned_code_gen_response = clean_gemini_api_json(gemini_code_gen_response_str.clone());
+        let mut code_gen_response: GeminiCodeGenerationResponse = serde_json::from_str(&cleaned_code_gen_response)?;
+        code_gen_conversation.push(ChatTurn::Model(ModelReply { text: gemini_code_gen_response_str, tool_calls: Vec::new() }));
 
         // If ProcessMarkdownAndApplyChanges is used, extract explanation from the markdown content.
         for change in &code_gen_response.changes {
@@ -307,6 +440,7 @@ pub fn run_gem_agent(
                 // Optionally, provide more context or attempt rollback if applicable
                 e
             })?;
+        touched_files.extend(changed_files(&code_gen_response.changes));
         if let Some(p) = &pb { p.finish_with_message("Code changes applied."); }
 
         if !args.no_test {
@@ -322,38 +456,110 @@ pub fn run_gem_agent(
             }
         }
 
-        if is_interactive {
-            pb = Some(ProgressBar::new_spinner());
-            pb.as_ref().unwrap().set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
-            pb.as_ref().unwrap().set_message(format!("Verifying changes with: \"{}\" (attempt {})...", args.verify_with, verification_attempt));
-            pb.as_ref().unwrap().enable_steady_tick(Duration::from_millis(100));
+        let mut failure = None;
+        for (index, stage) in verification_stages.iter().enumerate() {
+            let stage_name = verification::describe_stage(stage, index, verification_stages.len());
+            if is_interactive {
+                pb = Some(ProgressBar::new_spinner());
+                pb.as_ref().unwrap().set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap());
+                pb.as_ref().unwrap().set_message(format!("Running {} (attempt {})...", stage_name, verification_attempt));
+                pb.as_ref().unwrap().enable_steady_tick(Duration::from_millis(100));
+            }
+
+            // Cargo commands report compiler messages as JSON, so retries get a clean list of errors.
+            let verify_command = diagnostics::with_json_message_format(&stage.command).unwrap_or_else(|| stage.command.clone());
+            let timeout = Duration::from_secs(args.verify_timeout);
+            let (verification, auto_fixes) = match stage.on_failure {
+                verification::OnFailure::Stop => verify_with_auto_fixes(&project_root, &verify_command, timeout, session, pb.as_ref())?,
+                verification::OnFailure::Warn => (verification::run_verification(&project_root, &verify_command, timeout)?, Vec::new()),
+            };
+            touched_files.extend(auto_fixes.iter().map(|fix| fix.file.clone()));
+            let mut report = diagnostics::verification_report(&verification);
+            if verification.success() {
+                if let Some(p) = &pb { p.finish_with_message(format!("Passed {}.", stage_name)); }
+                else { println!("Passed {}.", stage_name); }
+                println!("Output:\n{}", report);
+                continue;
+            }
+            if stage.on_failure == verification::OnFailure::Warn {
+                if let Some(p) = &pb { p.finish_and_clear(); }
+                eprintln!("gem: WARN: {} failed; it only warns, so verification goes on.\n{}", stage_name, report);
+                continue;
+            }
+            if !auto_fixes.is_empty() {
+                report.push_str(&format!("\n\n{}", diagnostics::describe_applied_fixes(&auto_fixes)));
+            }
+            failure = Some((stage_name, report));
+            break; // Later stages would run against code that is about to change
         }
 
-        match execute_verification_command_mock( &mut verification_attempt, &project_root, &args.verify_with) {
-            Ok(output) => {
-                if let Some(p) = &pb { p.finish_with_message("Verification successful!"); }
-                else { println!("Verification successful!"); }
-                println!("Output:\n{}", output);
+        let Some((stage_name, report)) = failure else {
+            if let Some(p) = &pb { p.finish_with_message("Verification successful!"); }
+            else { println!("Verification successful!"); }
 
-                let user_request_str = args.user_request_parts.join(" "); // Reconstruct here too or pass around
-                let commit_message = format!("gem: Automated change for \"{}\"\n\n{}\n\n", user_request_str, code_gen_response.explanation);
-                git_commit_mock(&project_root, &commit_message, verification_attempt > 1)?;
-                println!("\ngem: Task completed successfully.");
-                return Ok(());
-            }
-            Err(e) => {
-                if let Some(p) = &pb { p.finish_with_message("Verification failed."); }
-                else { println!("Verification failed."); }
-                verification_failures_context = e.to_string();
-                eprintln!("Error Output:\n{}", verification_failures_context);
-
-                if verification_attempt >= args.max_verify_retries + 1 {
-                     eprintln!("gem: Max verification retries reached. The last (failed) attempt is committed (amended). Please review and fix manually.");
-                    return Err(format!("Verification failed after max retries: {}", verification_failures_context).into());
+            if let Some(repo) = &git_repo {
+                let commit_message = git::commit_message(&user_request_str, &code_gen_response.explanation, session.id());
+                let files: Vec<String> = touched_files.into_iter().collect();
+                match repo.commit_paths(&files, &commit_message, args.commit_author.as_deref())? {
+                    Some(hash) => println!("gem: Committed the changes as {}.", &hash[..hash.len().min(12)]),
+                    None => println!("gem: Nothing to commit; the files gem touched are unchanged."),
                 }
             }
+            println!("\ngem: Task completed successfully.");
+            return Ok(());
+        };
+
+        if let Some(p) = &pb { p.finish_with_message(format!("Verification failed at {}.", stage_name)); }
+        else { println!("Verification failed at {}.", stage_name); }
+        verification_failures_context = report;
+        failed_stage = stage_name;
+        eprintln!("Error Output:\n{}", verification_failures_context);
+
+        if verification_attempt >= args.max_verify_retries + 1 {
+            eprintln!("gem: Max verification retries reached. The last (failed) attempt is left in the working tree. Please review and fix manually.");
+            return Err(format!("Verification failed after max retries: {}", verification_failures_context).into());
+        }
+    }
+}
+
+// Compiler suggestions may uncover further ones; give up on them after this many rounds.
+const MAX_AUTO_FIX_ROUNDS: usize = 3;
+
+// Runs the verification command. While it fails with machine-applicable compiler suggestions,
+// applies them, records them in the session and runs it again, sparing a round-trip to the model.
+fn verify_with_auto_fixes(
+    project_root: &Path,
+    command: &str,
+    timeout: Duration,
+    session: &mut Session,
+    pb: Option<&ProgressBar>,
+) -> Result<(verification::VerificationResult, Vec<diagnostics::AppliedFix>)> {
+    let mut verification = verification::run_verification(project_root, command, timeout)?;
+    let mut applied = Vec::new();
+    let mut workspace_root = None;
+    for _ in 0..MAX_AUTO_FIX_ROUNDS {
+        if verification.success() {
+            break;
+        }
+        let workspace_root = workspace_root.get_or_insert_with(|| diagnostics::workspace_root(project_root));
+        let diagnostics::AutoFixes { applied: fixes, skipped } = diagnostics::apply_machine_applicable_fixes(project_root, workspace_root, &diagnostics::CargoOutput::parse(&verification.stdout));
+        for skipped in &skipped {
+            session.record_skipped_fix(&skipped.file, skipped.line, &skipped.message, &skipped.reason)?;
+            let msg = format!("gem: WARN: Skipped the compiler suggestion \"{}\" for {} line {}: {}.", skipped.message, skipped.file, skipped.line, skipped.reason);
+            if let Some(p) = pb { p.println(msg); } else { eprintln!("{}", msg); }
         }
+        if fixes.is_empty() {
+            break;
+        }
+        for fix in &fixes {
+            session.record_auto_fix(&fix.file, fix.line, &fix.message, &fix.replacement)?;
+        }
+        let msg = format!("gem: Applied {} machine-applicable compiler suggestion(s); verifying again.", fixes.len());
+        if let Some(p) = pb { p.println(msg); } else { println!("{}", msg); }
+        applied.extend(fixes);
+        verification = verification::run_verification(project_root, command, timeout)?;
     }
+    Ok((verification, applied))
 }
 
 // --- Helper Functions (moved from main.rs, now public for tests) ---
@@ -440,10 +646,11 @@ fn get_cargo_metadata_dependencies(project_root: &Path) -> Result<String> {
     Ok(String::from_utf8(output.stdout)?)
 }
 
-pub fn construct_first_gemini_prompt(user_request: &str, context: &HashMap<String, String>) -> String {
+// The environment and project overview. Every phase's conversation starts with it (see `with_project_context`),
+// so it is identical across calls and can be cached by the backend.
+pub fn construct_project_context_prompt(context: &HashMap<String, String>) -> String {
     format!(
-        include_str!("prompts/initial.txt"), // Assuming prompts are in src/prompts/ relative to lib.rs
-        user_request,
+        include_str!("prompts/context.txt"), // Assuming prompts are in src/prompts/ relative to lib.rs
         context.get("rustc_version").unwrap_or(&"N/A".to_string()),
         context.get("cargo_version").unwrap_or(&"N/A".to_string()),
         context.get("rust_analyzer_version").unwrap_or(&"N/A".to_string()),
@@ -454,45 +661,194 @@ pub fn construct_first_gemini_prompt(user_request: &str, context: &HashMap<Strin
     )
 }
 
+pub fn construct_first_gemini_prompt(user_request: &str, _max_tool_calls: usize) -> String {
+    format!(include_str!("prompts/initial.txt"), user_request)
+}
+
+// Like the initial prompt, but the model gathers what it needs through tool calls.
+pub fn construct_tool_gathering_prompt(user_request: &str, max_tool_calls: usize) -> String {
+    format!(include_str!("prompts/tools.txt"), user_request, max_tool_calls)
+}
+
+// Splits a phase prompt into its turns and puts the shared project context turn in front of the user prompt.
+fn with_project_context(project_context: &ChatTurn, prompt: &str) -> Vec<ChatTurn> {
+    let mut conversation = llm_api::split_system_prompt(prompt);
+    let system_turns = conversation.iter().take_while(|turn| matches!(turn, ChatTurn::System(_))).count();
+    conversation.insert(system_turns, project_context.clone());
+    conversation
+}
+
 pub fn construct_sufficiency_check_prompt(user_request: &str, gathered_data: &HashMap<String, String>) -> String {
     let mut data_str = String::new();
     for (item, content) in gathered_data { data_str.push_str(&format!("// Item: {}\n// Extracted Code:\n{}\n\n", item, content)); }
     format!(include_str!("prompts/sufficient.txt"), user_request, data_str)
 }
 
-pub fn construct_code_generation_prompt(user_request: &str, gathered_data: &HashMap<String, String>, generate_tests: bool, failure_context: Option<&str>, verify_command: &str) -> String {
+pub fn construct_code_generation_prompt(user_request: &str, gathered_data: &HashMap<String, String>, generate_tests: bool, failure_context: Option<&str>, failed_stage: &str) -> String {
     let mut data_str = String::new();
     for (item, content) in gathered_data { data_str.push_str(&format!("// Item: {}\n// Extracted Code:\n{}\n\n", item, content)); }
     let test_instruction = if generate_tests { "You should also generate relevant unit tests for the changes." } else { "Test generation is disabled for this request." };
     let failure_prompt_addition = if let Some(ctx) = failure_context { format!(r#"
 Previous Attempt Feedback:
-The verification command "{}" failed.
+Your previous changes failed {}.
 Build/Test Output (JSON messages or raw output):
 ```
 {}
 ```
 Please analyze the errors and provide a corrected set of changes and tests.
-"#, verify_command, ctx) } else { String::new() };
+"#, failed_stage, ctx) } else { String::new() };
     format!(include_str!("prompts/change.txt"), test_instruction, user_request, data_str, failure_prompt_addition, test_instruction)
 }
 
-// This is the refactored version from the previous step.
-fn call_gemini_api_with_session(
+// Follow-up turn after a failed verification; the conversation already holds the context and the answer.
+pub fn construct_verification_feedback_prompt(failed_stage: &str, failure_output: &str) -> String {
+    format!(r#"Your changes have been applied, but they failed {}.
+Build/Test Output (JSON messages or raw output):
+```
+{}
+```
+Please analyze the errors and provide a corrected set of changes and tests, as a single JSON object with the same structure as before.
+"#, failed_stage, failure_output)
+}
+
+// Runs one phase conversation through the model router. The flattened conversation is the cache key.
+#[allow(clippy::too_many_arguments)]
+fn call_llm_conversation_with_session(
+    session: &mut Session,
+    llm_api: &dyn LLMApi,
+    model_router: &ModelRouter,
+    phase: AgentPhase,
+    prompt_type: &str,
+    conversation: &[ChatTurn],
+    response_schema: Option<&serde_json::Value>,
+    pb: Option<&ProgressBar>,
+) -> Result<String> {
+    let prompt_text = llm_api::flatten_conversation(conversation);
+    cached_llm_call(session, llm_api, prompt_type, &prompt_text, pb, || {
+        model_router.route(phase, |model| llm_api.generate_conversation(conversation, model, response_schema))
+    })
+}
+
+// Serves `prompt_text` from the session cache, or runs `call` and caches and records its answer.
+// `call` returns the response together with the model that produced it.
+fn cached_llm_call(
     session: &mut Session,
     llm_api: &dyn LLMApi,
     prompt_type: &str,
     prompt_text: &str,
-    model_name: &str,
+    pb: Option<&ProgressBar>,
+    call: impl FnOnce() -> Result<(String, String)>,
 ) -> Result<String> {
     if let Some(cached_response) = session.get_cached_response(prompt_type, prompt_text) {
         return Ok(cached_response);
     }
-    let response = llm_api.generate_content(prompt_text, model_name)?;
+    check_cancelled()?;
+    llm_api.set_progress_bar(pb.cloned());
+    llm_api.take_last_usage(); // Don't attribute a stale count to this call
+    let response = call();
+    llm_api.set_progress_bar(None);
+    let response = response.inspect_err(|_| {
+        // Keep what was gathered so far; re-running the same request resumes from the session.
+        if let Err(save_err) = session.save() {
+            eprintln!("gem: WARN: Failed to save session after API error: {}", save_err);
+        }
+    })?;
+    let (response, model_used) = response;
     session.save_prompt_and_response(prompt_type, prompt_text, &response)
         .map_err(|e| format!("Failed to save {} prompt and response: {}", prompt_type, e))?;
+    session.record_call(prompt_type, prompt_text, &model_used, llm_api.take_last_usage())
+        .map_err(|e| format!("Failed to record {} call: {}", prompt_type, e))?;
     Ok(response)
 }
 
+// Lets the model gather its own context through tool calls, all within one conversation.
+// Retrieved sources end up in `gathered` (and the session) for the code generation prompt.
+// At most `args.max_data_loops` tool calls are executed; after that the model gets no more turns.
+#[allow(clippy::too_many_arguments)]
+fn gather_context_with_tools(
+    session: &mut Session,
+    llm_api: &dyn LLMApi,
+    model_router: &ModelRouter,
+    args: &CustomCliArgs,
+    project_root: &Path,
+    first_conversation: Vec<ChatTurn>,
+    gathered: &mut HashMap<String, String>,
+    pb: Option<&ProgressBar>,
+) -> Result<()> {
+    let tools = agent_tools::tool_declarations();
+    let first_turns = first_conversation.len();
+    let mut conversation = first_conversation;
+    let mut tool_calls_made = 0;
+
+    loop {
+        check_cancelled()?;
+        // The first turn sees the request cold; later turns only judge whether they know enough.
+        let phase = if conversation.len() == first_turns { AgentPhase::Initial } else { AgentPhase::Sufficient };
+        if let Some(p) = pb { p.set_message(format!("Gathering context with tools ({} call(s) so far)...", tool_calls_made)); }
+
+        // The whole conversation is the cache key, so a resumed session replays the same tool calls.
+        let conversation_json = serde_json::to_string(&conversation)?;
+        let reply_json = cached_llm_call(session, llm_api, "tools", &conversation_json, pb, || {
+            let (reply, model) = model_router.route(phase, |model| llm_api.generate_with_tools(&conversation, &tools, model))?;
+            Ok((serde_json::to_string(&reply)?, model))
+        })?;
+        let reply: ModelReply = serde_json::from_str(&reply_json)?;
+
+        if reply.tool_calls.is_empty() {
+            if !reply.text.trim().is_empty() {
+                let msg = format!("gem: Context gathered: {}", reply.text.trim());
+                if let Some(p) = pb { p.println(msg); } else { println!("{}", msg); }
+            }
+            return Ok(());
+        }
+        if tool_calls_made >= args.max_data_loops {
+            eprintln!("gem: WARN: Reached the maximum of {} tool calls; continuing with the context gathered so far.", args.max_data_loops);
+            return Ok(());
+        }
+
+        let mut results = Vec::new();
+        for call in &reply.tool_calls {
+            if tool_calls_made >= args.max_data_loops {
+                // Every call needs an answer (OpenAI rejects the conversation otherwise), so refuse the extras.
+                results.push(llm_api::ToolResult { call: call.clone(), output: "Error: tool call limit reached.".to_string() });
+                continue;
+            }
+            tool_calls_made += 1;
+            let msg = format!("gem: Tool call {}/{}: {}({})", tool_calls_made, args.max_data_loops, call.name, call.args);
+            if let Some(p) = pb { p.println(msg); } else { println!("{}", msg); }
+
+            let outcome = agent_tools::execute_tool(project_root, call);
+            if let Some((item, source)) = &outcome.gathered {
+                gathered.insert(item.clone(), source.clone());
+                session.add_data(item, source);
+            }
+            results.push(llm_api::ToolResult { call: call.clone(), output: outcome.output });
+        }
+        session.save().map_err(|e| format!("Failed to save session after tool calls: {}", e))?;
+
+        conversation.push(ChatTurn::Model(reply));
+        conversation.push(ChatTurn::ToolResults(results));
+    }
+}
+
+// Parts of the initial context that may be truncated to fit the budget, cut in this order.
+const INITIAL_CONTEXT_TRIM_ORDER: [&str; 3] = ["project_symbols", "dependencies", "src_tree"];
+
+fn report_trimmed_context(phase: AgentPhase, budget: usize, trimmed: &[token_budget::TrimAction], pb: Option<&ProgressBar>) {
+    if trimmed.is_empty() { return; }
+    let cuts: Vec<String> = trimmed.iter().map(|t| t.to_string()).collect();
+    let msg = format!("gem: WARN: The {} prompt exceeds its ~{}-token budget; {}.", phase, budget, cuts.join(", "));
+    if let Some(p) = pb { p.println(msg); } else { eprintln!("{}", msg); }
+}
+
+// Ctrl-C sets a flag instead of killing the process, so the session can be saved first.
+fn check_cancelled() -> Result<()> {
+    if llm_api::is_cancel_requested() {
+        return Err(Box::new(llm_api::LLMApiError::Cancelled));
+    }
+    Ok(())
+}
+
 fn clean_gemini_api_json(s: String) -> String {
     let s = if s.trim().starts_with("```json") { s.replacen("```json", "", 1) } else { s };
     let mut t = &s[..];
@@ -510,6 +866,37 @@ fn query_rust_analyzer_for_item_definition(project_root: &Path, item_qname_or_pa
     }
 }
 
+// Refuses to start on uncommitted changes to tracked files, or stashes them with `--stash`, so
+// the user's work neither ends up in gem's commit nor gets overwritten by it.
+fn prepare_work_tree(repo: &GitRepo, args: &CustomCliArgs) -> Result<()> {
+    let changes = repo.uncommitted_changes()?;
+    if changes.is_empty() {
+        return Ok(());
+    }
+    if !args.stash {
+        return Err(format!("The working tree has uncommitted changes:\n{}\nCommit them, or run again with --stash to stash them first.", changes.join("\n")).into());
+    }
+    repo.stash(&format!("gem: before \"{}\"", args.user_request_parts.join(" ")))?;
+    println!("gem: Stashed {} uncommitted change(s); restore them with `git stash pop`.", changes.len());
+    Ok(())
+}
+
+/// Project-relative paths of the files `changes` create, modify or delete.
+pub fn changed_files(changes: &[CodeChange]) -> Vec<String> {
+    let mut files = Vec::new();
+    for change in changes {
+        match change.action {
+            CodeChangeAction::ReplaceItemInSection => files.push(change.file_path.split("::").next().unwrap_or_default().to_string()),
+            CodeChangeAction::ProcessMarkdownAndApplyChanges => {
+                let blocks = parser::extract_file_code_blocks_from_markdown(change.content.as_deref().unwrap_or_default()).unwrap_or_default();
+                files.extend(blocks.into_iter().map(|(file_path, _)| file_path));
+            }
+            _ => files.push(change.file_path.clone()),
+        }
+    }
+    files
+}
+
 // --- Real Code Change Application ---
 pub fn apply_code_changes(project_root: &Path, changes: &[CodeChange]) -> Result<()> {
     for change in changes {
@@ -673,20 +1060,6 @@ fn apply_tests_mock(_project_root: &Path, tests: &[TestChange]) -> Result<()> {
     Ok(())
 }
 
-fn git_commit_mock(_project_root: &Path, _message: &str, _amend: bool) -> Result<()> {
-    // println!("MOCK: git commit --message \"{}\" {}", message, if amend { "--amend" } else { "" });
-    Ok(())
-}
-
-fn execute_verification_command_mock(attempt: &mut usize, _project_root: &Path, command_str: &str) -> Result<String> {
-    *attempt += 1; // This mock behavior might need to be controllable for tests
-    if *attempt == 1 && command_str.contains("cargo") {
-        Err("Mock: Verification FAILED (first attempt)".into())
-    } else {
-        Ok("Mock: Build successful! All checks passed.".to_string())
-    }
-}
-
 // The call_gemini_api_mock function was here, but has been removed as it was unused
 // and marked as problematic for library use. Mocking is now primarily handled by
 // MockLLMApi in tests.
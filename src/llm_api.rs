@@ -1,13 +1,39 @@
+use indicatif::ProgressBar;
 use serde::{Deserialize, Serialize};
-use std::time::Duration; // Required for reqwest timeout
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::BufRead;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant}; // Required for reqwest timeout and retry backoff
 
 // Type alias for Results that might be errors from this module
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 // --- Gemini API Request/Response Structures (moved from main.rs) ---
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GeminiFunctionCall {
+    pub name: String,
+    #[serde(default)]
+    pub args: serde_json::Value,
+}
+
 #[derive(Serialize, Debug)]
+pub struct GeminiFunctionResponse {
+    name: String,
+    response: serde_json::Value,
+}
+
+// A part carries exactly one of text, a function call (model turns) or a function response.
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
 pub struct GeminiRequestPart {
-    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_call: Option<GeminiFunctionCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_response: Option<GeminiFunctionResponse>,
 }
 
 #[derive(Serialize, Debug)]
@@ -18,32 +44,393 @@ pub struct GeminiRequestContent {
 }
 
 #[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiGenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_schema: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiFunctionDeclaration {
+    name: String,
+    description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameters: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiTool {
+    function_declarations: Vec<GeminiFunctionDeclaration>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct GeminiRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<GeminiRequestContent>,
+    contents: Vec<GeminiRequestContent>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<GeminiTool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GeminiGenerationConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cached_content: Option<String>, // "cachedContents/..." holding the leading turns (and tools)
+    // Can add safetySettings etc. here if needed
+}
+
+impl GeminiRequest {
+    pub fn single_turn(prompt_text: &str) -> Self {
+        Self::from_conversation(&[ChatTurn::User(prompt_text.to_string())])
+    }
+
+    pub fn from_conversation(conversation: &[ChatTurn]) -> Self {
+        // Gemini takes system turns separately from the contents.
+        let system_parts: Vec<GeminiRequestPart> = conversation
+            .iter()
+            .filter_map(|turn| match turn {
+                ChatTurn::System(text) => Some(GeminiRequestPart { text: Some(text.clone()), ..Default::default() }),
+                _ => None,
+            })
+            .collect();
+        let system_instruction = if system_parts.is_empty() { None } else { Some(GeminiRequestContent { parts: system_parts, role: None }) };
+        let contents = conversation
+            .iter()
+            .filter_map(|turn| match turn {
+                ChatTurn::System(_) => None,
+                ChatTurn::User(text) => Some(GeminiRequestContent {
+                    parts: vec![GeminiRequestPart { text: Some(text.clone()), ..Default::default() }],
+                    role: Some("user".to_string()),
+                }),
+                ChatTurn::Model(reply) => {
+                    let mut parts = Vec::new();
+                    if !reply.text.is_empty() {
+                        parts.push(GeminiRequestPart { text: Some(reply.text.clone()), ..Default::default() });
+                    }
+                    parts.extend(reply.tool_calls.iter().map(|call| GeminiRequestPart {
+                        function_call: Some(GeminiFunctionCall { name: call.name.clone(), args: call.args.clone() }),
+                        ..Default::default()
+                    }));
+                    Some(GeminiRequestContent { parts, role: Some("model".to_string()) })
+                }
+                ChatTurn::ToolResults(results) => Some(GeminiRequestContent {
+                    parts: results
+                        .iter()
+                        .map(|result| GeminiRequestPart {
+                            function_response: Some(GeminiFunctionResponse {
+                                name: result.call.name.clone(),
+                                response: serde_json::json!({ "output": result.output }),
+                            }),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    role: Some("user".to_string()),
+                }),
+            })
+            .collect();
+        Self { system_instruction, contents, tools: Vec::new(), generation_config: None, cached_content: None }
+    }
+
+    /// Declares functions the model may call instead of answering directly.
+    pub fn with_tools(mut self, tools: &[ToolDeclaration]) -> Self {
+        self.tools = vec![GeminiTool {
+            function_declarations: tools
+                .iter()
+                .map(|tool| GeminiFunctionDeclaration {
+                    name: tool.name.clone(),
+                    description: tool.description.clone(),
+                    parameters: tool.parameters.clone(),
+                })
+                .collect(),
+        }];
+        self
+    }
+
+    /// Refers to a `cachedContents` entry that holds the leading turns and the tools, which the
+    /// request then must not repeat. Gemini rejects a system instruction next to a cache, so it
+    /// goes in as the first user turn instead.
+    pub fn with_cached_content(mut self, cache_name: &str) -> Self {
+        if let Some(system) = self.system_instruction.take() {
+            let text: Vec<&str> = system.parts.iter().filter_map(|part| part.text.as_deref()).collect();
+            self.contents.insert(0, GeminiRequestContent {
+                parts: vec![GeminiRequestPart { text: Some(format!("{}{}", SYSTEM_PROMPT_HEADER, text.join("\n\n"))), ..Default::default() }],
+                role: Some("user".to_string()),
+            });
+        }
+        self.tools.clear();
+        self.cached_content = Some(cache_name.to_string());
+        self
+    }
+
+    /// Asks Gemini for JSON matching `schema` (see `ResponseSchema`) instead of free text.
+    pub fn with_response_schema(mut self, schema: &serde_json::Value) -> Self {
+        self.generation_config = Some(GeminiGenerationConfig {
+            response_mime_type: Some("application/json".to_string()),
+            response_schema: Some(schema.clone()),
+        });
+        self
+    }
+}
+
+// Body of `POST cachedContents`.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiCachedContentRequest {
+    model: String, // "models/<name>"; a cache only serves the model it was created for
     contents: Vec<GeminiRequestContent>,
-    // Can add generationConfig, safetySettings etc. here if needed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<GeminiTool>,
+    ttl: String, // e.g. "600s"
+    display_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GeminiCachedContent {
+    pub name: String,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct GeminiResponsePart {
+    #[serde(default)]
     pub text: String, // Made public
+    #[serde(default, rename = "functionCall")]
+    pub function_call: Option<GeminiFunctionCall>,
 }
 
-#[derive(Deserialize, Debug)]
+// Streamed chunks may carry only a finishReason, so content is optional on the wire.
+#[derive(Deserialize, Debug, Default)]
 pub struct GeminiResponseContent {
+    #[serde(default)]
     pub parts: Vec<GeminiResponsePart>, // Made public
+    #[serde(default)]
     pub role: String,                   // Made public
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct GeminiSafetyRating {
+    pub category: String,
+    #[serde(default)]
+    pub probability: String,
+    #[serde(default)]
+    pub blocked: bool,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct GeminiResponseCandidate {
+    #[serde(default)]
     pub content: GeminiResponseContent, // Made public
     #[serde(alias = "finishReason")]
     pub finish_reason: Option<String>, // Made public
+    #[serde(default, rename = "safetyRatings")]
+    pub safety_ratings: Vec<GeminiSafetyRating>,
+}
+
+// Present when the prompt itself was blocked; there are no candidates then.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiPromptFeedback {
+    pub block_reason: Option<String>,
+    #[serde(default)]
+    pub safety_ratings: Vec<GeminiSafetyRating>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiUsageMetadata {
+    #[serde(default)]
+    pub prompt_token_count: u64,
+    #[serde(default)]
+    pub candidates_token_count: u64,
+    #[serde(default)]
+    pub thoughts_token_count: u64,
+    #[serde(default)]
+    pub cached_content_token_count: u64,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct GeminiResponse {
+    #[serde(default)]
     pub candidates: Vec<GeminiResponseCandidate>, // Made public
+    #[serde(default, rename = "usageMetadata")]
+    pub usage_metadata: Option<GeminiUsageMetadata>,
+    #[serde(default, rename = "promptFeedback")]
+    pub prompt_feedback: Option<GeminiPromptFeedback>,
+}
+
+/// Token counts the backend reported for one call.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub candidate_tokens: u64,  // Visible output, excluding thinking
+    #[serde(default)]
+    pub thinking_tokens: u64,
+    #[serde(default)]
+    pub cached_tokens: u64, // Part of prompt_tokens served from a context cache, billed at a reduced rate
+}
+
+impl TokenUsage {
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.candidate_tokens + self.thinking_tokens
+    }
+
+    pub fn add(&mut self, other: &TokenUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.candidate_tokens += other.candidate_tokens;
+        self.thinking_tokens += other.thinking_tokens;
+        self.cached_tokens += other.cached_tokens;
+    }
+}
+
+impl From<&GeminiUsageMetadata> for TokenUsage {
+    fn from(usage: &GeminiUsageMetadata) -> Self {
+        Self {
+            prompt_tokens: usage.prompt_token_count,
+            candidate_tokens: usage.candidates_token_count,
+            thinking_tokens: usage.thoughts_token_count,
+            cached_tokens: usage.cached_content_token_count,
+        }
+    }
+}
+
+
+// --- OpenAI-compatible Chat Completions Request/Response Structures ---
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OpenAIFunctionCall {
+    pub name: String,
+    pub arguments: String, // JSON-encoded arguments
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OpenAIToolCall {
+    #[serde(default)]
+    pub id: String,
+    #[serde(rename = "type", default)]
+    pub kind: String, // Always "function"
+    pub function: OpenAIFunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpenAIChatMessage {
+    pub role: String, // "system", "user", "assistant" or "tool"
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<OpenAIToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>, // Set on "tool" messages
+}
+
+impl OpenAIChatMessage {
+    fn text(role: &str, content: &str) -> Self {
+        Self { role: role.to_string(), content: Some(content.to_string()), tool_calls: Vec::new(), tool_call_id: None }
+    }
+
+    fn from_conversation(conversation: &[ChatTurn]) -> Vec<Self> {
+        let mut messages = Vec::new();
+        for turn in conversation {
+            match turn {
+                ChatTurn::System(text) => messages.push(Self::text("system", text)),
+                ChatTurn::User(text) => messages.push(Self::text("user", text)),
+                ChatTurn::Model(reply) => messages.push(Self {
+                    role: "assistant".to_string(),
+                    content: if reply.text.is_empty() { None } else { Some(reply.text.clone()) },
+                    tool_calls: reply
+                        .tool_calls
+                        .iter()
+                        .enumerate()
+                        .map(|(i, call)| OpenAIToolCall {
+                            id: openai_tool_call_id(call, i),
+                            kind: "function".to_string(),
+                            function: OpenAIFunctionCall { name: call.name.clone(), arguments: call.args.to_string() },
+                        })
+                        .collect(),
+                    tool_call_id: None,
+                }),
+                ChatTurn::ToolResults(results) => {
+                    messages.extend(results.iter().enumerate().map(|(i, result)| Self {
+                        role: "tool".to_string(),
+                        content: Some(result.output.clone()),
+                        tool_calls: Vec::new(),
+                        tool_call_id: Some(openai_tool_call_id(&result.call, i)),
+                    }));
+                }
+            }
+        }
+        messages
+    }
+}
+
+// Servers that omit ids still need the assistant's calls and the tool messages to line up.
+fn openai_tool_call_id(call: &ToolCall, index: usize) -> String {
+    call.id.clone().unwrap_or_else(|| format!("call_{}", index))
+}
+
+#[derive(Serialize, Debug)]
+pub struct OpenAITool {
+    #[serde(rename = "type")]
+    kind: String,
+    function: serde_json::Value, // {name, description, parameters}
+}
+
+#[derive(Serialize, Debug)]
+pub struct OpenAIChatRequest {
+    model: String,
+    messages: Vec<OpenAIChatMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<OpenAITool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<serde_json::Value>, // {"type": "json_schema", "json_schema": {...}}
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OpenAIChatChoice {
+    pub message: OpenAIChatMessage,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct OpenAICompletionTokensDetails {
+    #[serde(default)]
+    pub reasoning_tokens: u64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct OpenAIPromptTokensDetails {
+    #[serde(default)]
+    pub cached_tokens: u64, // Served from the server's automatic prompt cache
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OpenAIUsage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64, // Includes reasoning tokens
+    #[serde(default)]
+    pub completion_tokens_details: Option<OpenAICompletionTokensDetails>,
+    #[serde(default)]
+    pub prompt_tokens_details: Option<OpenAIPromptTokensDetails>,
+}
+
+impl From<&OpenAIUsage> for TokenUsage {
+    fn from(usage: &OpenAIUsage) -> Self {
+        let reasoning = usage.completion_tokens_details.as_ref().map_or(0, |d| d.reasoning_tokens);
+        Self {
+            prompt_tokens: usage.prompt_tokens,
+            candidate_tokens: usage.completion_tokens.saturating_sub(reasoning),
+            thinking_tokens: reasoning,
+            cached_tokens: usage.prompt_tokens_details.as_ref().map_or(0, |d| d.cached_tokens),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OpenAIChatResponse {
+    pub choices: Vec<OpenAIChatChoice>,
+    #[serde(default)]
+    pub usage: Option<OpenAIUsage>,
 }
 
 
@@ -96,6 +483,229 @@ pub struct GeminiCodeGenerationResponse {
 }
 
 
+// --- Response Schemas ---
+// Gemini `responseSchema` definitions (an OpenAPI 3.0 subset) for the structs above.
+// Keep them in sync with the serde field names; the tests below check that they match.
+pub trait ResponseSchema {
+    fn response_schema() -> serde_json::Value;
+}
+
+impl ResponseSchema for GeminiNeededItemsResponse {
+    fn response_schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "OBJECT",
+            "properties": {
+                "needed_items": { "type": "ARRAY", "items": { "type": "STRING" } }
+            },
+            "required": ["needed_items"]
+        })
+    }
+}
+
+impl ResponseSchema for GeminiSufficiencyResponse {
+    fn response_schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "OBJECT",
+            "properties": {
+                "sufficient": { "type": "BOOLEAN" },
+                "needed_items": { "type": "ARRAY", "items": { "type": "STRING" } }
+            },
+            "required": ["sufficient"]
+        })
+    }
+}
+
+impl ResponseSchema for CodeChangeAction {
+    fn response_schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "STRING",
+            "enum": [
+                "ReplaceContent",
+                "CreateFile",
+                "DeleteFile",
+                "ApplyDiff",
+                "ReplaceLines",
+                "InsertAfterLine",
+                "ReplaceItemInSection",
+                "ProcessMarkdownAndApplyChanges"
+            ]
+        })
+    }
+}
+
+impl ResponseSchema for CodeChange {
+    fn response_schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "OBJECT",
+            "properties": {
+                "file_path": { "type": "STRING" },
+                "action": CodeChangeAction::response_schema(),
+                "content": { "type": "STRING", "nullable": true }
+            },
+            "required": ["file_path", "action"]
+        })
+    }
+}
+
+impl ResponseSchema for TestChange {
+    fn response_schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "OBJECT",
+            "properties": {
+                "file_path": { "type": "STRING" },
+                "action": { "type": "STRING" },
+                "content": { "type": "STRING" },
+                "test_name": { "type": "STRING", "nullable": true }
+            },
+            "required": ["file_path", "action", "content"]
+        })
+    }
+}
+
+impl ResponseSchema for GeminiCodeGenerationResponse {
+    fn response_schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "OBJECT",
+            "properties": {
+                "changes": { "type": "ARRAY", "items": CodeChange::response_schema() },
+                "tests": { "type": "ARRAY", "items": TestChange::response_schema(), "nullable": true },
+                "explanation": { "type": "STRING" }
+            },
+            "required": ["changes", "explanation"]
+        })
+    }
+}
+
+
+/// Converts a Gemini schema (uppercase types, `nullable`) into standard JSON Schema,
+/// for backends such as OpenAI-compatible servers that expect the latter.
+pub fn to_json_schema(gemini_schema: &serde_json::Value) -> serde_json::Value {
+    match gemini_schema {
+        serde_json::Value::Object(map) => {
+            let nullable = map.get("nullable").and_then(|n| n.as_bool()).unwrap_or(false);
+            let mut converted = serde_json::Map::new();
+            for (key, value) in map {
+                match key.as_str() {
+                    "nullable" => {}
+                    "type" => {
+                        let ty = serde_json::Value::String(value.as_str().unwrap_or_default().to_lowercase());
+                        converted.insert(key.clone(), if nullable { serde_json::json!([ty, "null"]) } else { ty });
+                    }
+                    "properties" => {
+                        let properties = value
+                            .as_object()
+                            .map(|props| props.iter().map(|(name, schema)| (name.clone(), to_json_schema(schema))).collect())
+                            .unwrap_or_default();
+                        converted.insert(key.clone(), serde_json::Value::Object(properties));
+                    }
+                    "items" => { converted.insert(key.clone(), to_json_schema(value)); }
+                    _ => { converted.insert(key.clone(), value.clone()); }
+                }
+            }
+            serde_json::Value::Object(converted)
+        }
+        other => other.clone(),
+    }
+}
+
+
+// --- Tool Calling ---
+
+/// A function the model may call. `parameters` is a Gemini schema (see `ResponseSchema`), or None for no arguments.
+#[derive(Debug, Clone)]
+pub struct ToolDeclaration {
+    pub name: String,
+    pub description: String,
+    pub parameters: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    #[serde(default)]
+    pub id: Option<String>, // OpenAI call id; Gemini matches results by name and order
+    pub name: String,
+    pub args: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ToolResult {
+    pub call: ToolCall,
+    pub output: String,
+}
+
+/// One model turn: text, tool calls, or both.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ModelReply {
+    pub text: String,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ChatTurn {
+    System(String), // Instructions that frame the whole conversation
+    User(String),
+    Model(ModelReply),
+    ToolResults(Vec<ToolResult>),
+}
+
+// Section headers of the prompt files, also used to render a conversation as one prompt.
+const SYSTEM_PROMPT_HEADER: &str = "SYSTEM PROMPT:\n";
+const USER_PROMPT_HEADER: &str = "USER PROMPT:\n";
+
+/// Splits a prompt written as `SYSTEM PROMPT: ... USER PROMPT: ...` into a system and a user turn.
+/// Prompts without that layout become a single user turn.
+pub fn split_system_prompt(prompt_text: &str) -> Vec<ChatTurn> {
+    let separator = format!("\n\n{}", USER_PROMPT_HEADER);
+    if let Some(rest) = prompt_text.strip_prefix(SYSTEM_PROMPT_HEADER) {
+        if let Some((system, user)) = rest.split_once(&separator) {
+            return vec![ChatTurn::System(system.to_string()), ChatTurn::User(user.to_string())];
+        }
+    }
+    vec![ChatTurn::User(prompt_text.to_string())]
+}
+
+/// Renders a conversation as a single prompt, for backends that only take one.
+/// A system turn followed by a user turn renders back to the prompt `split_system_prompt` split.
+pub fn flatten_conversation(conversation: &[ChatTurn]) -> String {
+    if let [ChatTurn::User(text)] = conversation {
+        return text.clone();
+    }
+    let sections: Vec<String> = conversation
+        .iter()
+        .map(|turn| match turn {
+            ChatTurn::System(text) => format!("{}{}", SYSTEM_PROMPT_HEADER, text),
+            ChatTurn::User(text) => format!("{}{}", USER_PROMPT_HEADER, text),
+            ChatTurn::Model(reply) => {
+                let mut lines = Vec::new();
+                if !reply.text.is_empty() { lines.push(reply.text.clone()); }
+                lines.extend(reply.tool_calls.iter().map(|call| format!("[called {}({})]", call.name, call.args)));
+                format!("MODEL RESPONSE:\n{}", lines.join("\n"))
+            }
+            ChatTurn::ToolResults(results) => {
+                let outputs: Vec<String> = results.iter().map(|r| format!("[{} returned]\n{}", r.call.name, r.output)).collect();
+                format!("TOOL RESULTS:\n{}", outputs.join("\n"))
+            }
+        })
+        .collect();
+    sections.join("\n\n")
+}
+
+
+/// Appended to prompts for chat UIs that expect JSON, so the answer arrives in a code block.
+pub const JSON_CODE_BLOCK_INSTRUCTION: &str = "\n\nReply with the JSON in a single code block.";
+
+/// The answer held by a chat UI's code blocks: the last one holding JSON (earlier ones are usually
+/// examples or partial attempts), else all of them. None without code blocks.
+pub fn answer_from_code_blocks(blocks: &[String]) -> Option<String> {
+    if blocks.is_empty() {
+        return None;
+    }
+    match blocks.iter().rev().find(|block| serde_json::from_str::<serde_json::Value>(block).is_ok()) {
+        Some(json) => Some(json.clone()),
+        None => Some(blocks.join("\n\n")),
+    }
+}
+
 // --- LLMApi Trait Definition ---
 pub trait LLMApi {
     fn generate_content(
@@ -103,16 +713,409 @@ pub trait LLMApi {
         prompt_text: &str,
         model_name: &str,
     ) -> Result<String>;
+
+    /// Like `generate_content`, but asks the backend to return JSON matching `response_schema`.
+    /// Backends without schema support fall back to plain generation; callers still parse the text.
+    fn generate_structured(
+        &self,
+        prompt_text: &str,
+        model_name: &str,
+        _response_schema: &serde_json::Value,
+    ) -> Result<String> {
+        self.generate_content(prompt_text, model_name)
+    }
+
+    /// Continues `conversation` (system, user and model turns) with the model's next answer,
+    /// as JSON matching `response_schema` if one is given.
+    /// Backends without a message-list API get the conversation flattened into one prompt.
+    fn generate_conversation(
+        &self,
+        conversation: &[ChatTurn],
+        model_name: &str,
+        response_schema: Option<&serde_json::Value>,
+    ) -> Result<String> {
+        let prompt_text = flatten_conversation(conversation);
+        match response_schema {
+            Some(schema) => self.generate_structured(&prompt_text, model_name, schema),
+            None => self.generate_content(&prompt_text, model_name),
+        }
+    }
+
+    /// Declares the leading turns every later conversation of the session starts with (the project
+    /// context), so backends that support it can cache them server-side instead of re-sending them.
+    fn set_shared_prefix(&self, _prefix: &[ChatTurn]) {}
+
+    /// Frees whatever `set_shared_prefix` set up. Called when the agent session ends.
+    fn release_shared_prefix(&self) {}
+
+    /// Whether `generate_with_tools` is implemented. Without it the agent asks for context via `needed_items`.
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
+    /// Continues `conversation` with the model's next turn, which may call any of `tools`.
+    fn generate_with_tools(
+        &self,
+        _conversation: &[ChatTurn],
+        _tools: &[ToolDeclaration],
+        _model_name: &str,
+    ) -> Result<ModelReply> {
+        Err("This LLM backend does not support tool calling.".into())
+    }
+
+    /// Token usage of the last successful call, if the backend reports it. Taking it clears it.
+    fn take_last_usage(&self) -> Option<TokenUsage> {
+        None
+    }
+
+    /// Spinner to report partial output on while a response is being generated.
+    /// Backends that cannot stream ignore it.
+    fn set_progress_bar(&self, _pb: Option<ProgressBar>) {}
+}
+
+// --- Cancellation (Ctrl-C) ---
+// Set from the binary's signal handler; long-running requests check it and bail out.
+static CANCEL_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub fn request_cancel() {
+    CANCEL_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn clear_cancel_request() {
+    CANCEL_REQUESTED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_cancel_requested() -> bool {
+    CANCEL_REQUESTED.load(Ordering::SeqCst)
 }
 
+// --- Error Type ---
+// Classified API failures, so callers can decide between retrying, falling back and failing fast.
+#[derive(Debug)]
+pub enum LLMApiError {
+    /// 429 / RESOURCE_EXHAUSTED: rate limit or quota hit.
+    Quota { status: u16, message: String, retry_after: Option<Duration> },
+    /// 500, 502, 503, 504: the service is overloaded or temporarily unavailable.
+    Overloaded { status: u16, message: String, retry_after: Option<Duration> },
+    /// 401, 403: missing or invalid API key.
+    Auth { status: u16, message: String },
+    /// 400, 404 and other 4xx: the request itself is wrong (bad payload, unknown model, ...).
+    BadRequest { status: u16, message: String },
+    /// Connection failures and timeouts before a response arrived.
+    Network(String),
+    Cancelled,
+    /// The answer hit the output token limit (`MAX_TOKENS`, OpenAI `length`). Carries what arrived.
+    Truncated { partial: String, usage: Option<TokenUsage> },
+    /// The prompt or the answer was blocked (`SAFETY`, `RECITATION`, `BLOCKLIST`, ...).
+    Blocked { reason: String, in_prompt: bool, details: String },
+}
+
+impl LLMApiError {
+    pub fn from_status(status: u16, retry_after: Option<Duration>, body: String) -> Self {
+        match status {
+            429 => LLMApiError::Quota { status, message: body, retry_after },
+            500 | 502 | 503 | 504 => LLMApiError::Overloaded { status, message: body, retry_after },
+            401 | 403 => LLMApiError::Auth { status, message: body },
+            _ => LLMApiError::BadRequest { status, message: body },
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, LLMApiError::Quota { .. } | LLMApiError::Overloaded { .. } | LLMApiError::Network(_))
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            LLMApiError::Quota { retry_after, .. } | LLMApiError::Overloaded { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LLMApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LLMApiError::Quota { status, message, .. } => write!(f, "LLM API quota or rate limit exceeded ({}): {}", status, message),
+            LLMApiError::Overloaded { status, message, .. } => write!(f, "LLM API overloaded or unavailable ({}): {}", status, message),
+            LLMApiError::Auth { status, message } => write!(f, "LLM API authentication failed ({}): {}. Check your API key.", status, message),
+            LLMApiError::BadRequest { status, message } => write!(f, "LLM API rejected the request ({}): {}", status, message),
+            LLMApiError::Network(msg) => write!(f, "LLM API network error: {}", msg),
+            LLMApiError::Cancelled => write!(f, "Request cancelled by user (Ctrl-C)."),
+            LLMApiError::Truncated { partial, .. } => write!(
+                f,
+                "LLM response was cut off at the output token limit (MAX_TOKENS) after {} characters; the answer is incomplete.",
+                partial.len()
+            ),
+            LLMApiError::Blocked { reason, in_prompt, details } => {
+                let what = if *in_prompt { "the prompt" } else { "the response" };
+                write!(f, "LLM API blocked {} ({}", what, reason)?;
+                if !details.is_empty() {
+                    write!(f, ": {}", details)?;
+                }
+                write!(f, "). {}", block_explanation(reason))
+            }
+        }
+    }
+}
+
+// What a block reason means for the user, and what they can do about it.
+fn block_explanation(reason: &str) -> &'static str {
+    match reason {
+        "SAFETY" | "IMAGE_SAFETY" | "content_filter" => "The safety filters flagged it. Rephrase the request, or leave out the gathered files that may have triggered the filter.",
+        "RECITATION" => "The answer was reproducing existing material (e.g. licensed code or documentation) too closely. Ask for smaller changes written from scratch rather than copies of existing code.",
+        "BLOCKLIST" => "It contained terms from the API's blocklist. Remove them from the request or the gathered files.",
+        "PROHIBITED_CONTENT" => "It was flagged as prohibited content. Rephrase the request.",
+        "SPII" => "It contained sensitive personal information. Remove personal data (names, keys, addresses) from the request or the gathered files.",
+        _ => "Rephrase the request or try a different model.",
+    }
+}
+
+impl std::error::Error for LLMApiError {}
+
+// Reads the wait hint from a `Retry-After: <seconds>` header, or from the `retryDelay: "32s"`
+// field Gemini puts in the RetryInfo detail of RESOURCE_EXHAUSTED errors.
+fn parse_retry_after(header: Option<&str>, body: &str) -> Option<Duration> {
+    if let Some(secs) = header.and_then(|h| h.trim().parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(secs.max(0.0)));
+    }
+    let body_json: serde_json::Value = serde_json::from_str(body).ok()?;
+    body_json["error"]["details"]
+        .as_array()?
+        .iter()
+        .filter_map(|detail| detail["retryDelay"].as_str())
+        .find_map(|delay| delay.trim_end_matches('s').parse::<f64>().ok())
+        .map(|secs| Duration::from_secs_f64(secs.max(0.0)))
+}
+
+// --- Retry Policy ---
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: usize, // Retries after the first attempt; 0 disables retrying
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn with_max_retries(max_retries: usize) -> Self {
+        Self { max_retries, ..Self::default() }
+    }
+
+    /// Exponential backoff for the given retry (0-based); a server-provided `Retry-After` wins.
+    pub fn backoff(&self, retry: usize, retry_after: Option<Duration>) -> Duration {
+        let delay = retry_after.unwrap_or_else(|| {
+            self.initial_backoff.saturating_mul(2u32.saturating_pow(retry.min(16) as u32))
+        });
+        delay.min(self.max_backoff)
+    }
+}
+
+// Sleeps in small steps so Ctrl-C is noticed while waiting.
+fn sleep_unless_cancelled(duration: Duration) -> std::result::Result<(), LLMApiError> {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        if is_cancel_requested() {
+            return Err(LLMApiError::Cancelled);
+        }
+        std::thread::sleep((deadline - Instant::now()).min(Duration::from_millis(100)));
+    }
+    Ok(())
+}
+
+/// Sends the request built by `build_request`, retrying quota, overload and network failures
+/// according to `policy`. Other errors are returned immediately.
+fn send_with_retry(
+    build_request: impl Fn() -> reqwest::blocking::RequestBuilder,
+    policy: &RetryPolicy,
+    progress: Option<&ProgressBar>,
+) -> Result<reqwest::blocking::Response> {
+    let mut retry = 0;
+    loop {
+        if is_cancel_requested() {
+            return Err(Box::new(LLMApiError::Cancelled));
+        }
+        let error = match build_request().send() {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) => {
+                let status = response.status().as_u16();
+                let retry_after_header = response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                let body = response
+                    .text()
+                    .unwrap_or_else(|_| "Could not read error body".to_string());
+                let retry_after = parse_retry_after(retry_after_header.as_deref(), &body);
+                LLMApiError::from_status(status, retry_after, body)
+            }
+            Err(e) if e.is_timeout() || e.is_connect() => LLMApiError::Network(e.to_string()),
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        if !error.is_retryable() || retry >= policy.max_retries {
+            return Err(Box::new(error));
+        }
+        let delay = policy.backoff(retry, error.retry_after());
+        retry += 1;
+        let note = format!("gem: WARN: {} Retrying in {:.1}s (retry {}/{}).", error, delay.as_secs_f64(), retry, policy.max_retries);
+        if let Some(pb) = progress { pb.println(note); } else { eprintln!("{}", note); }
+        sleep_unless_cancelled(delay)?;
+    }
+}
+
+pub const GEMINI_API_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
 // --- RealLLMApi Implementation ---
+/// When and for how long `RealLLMApi` caches the shared conversation prefix (`cachedContents`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContextCacheConfig {
+    pub ttl: Duration, // Outlives the session unless it is deleted first
+    pub min_tokens: usize, // Smaller prefixes are sent as usual; Gemini rejects tiny caches anyway
+}
+
 pub struct RealLLMApi {
     api_key: String,
+    base_url: String,
+    stream: bool, // Use streamGenerateContent (SSE) instead of a single generateContent call
+    retry_policy: RetryPolicy,
+    progress: RefCell<Option<ProgressBar>>,
+    last_usage: Cell<Option<TokenUsage>>,
+    context_cache: Option<ContextCacheConfig>,
+    shared_prefix: RefCell<Vec<ChatTurn>>,
+    // One cache per model and tool set, created on first use. None if creating it failed.
+    prefix_caches: RefCell<HashMap<String, Option<String>>>,
 }
 
 impl RealLLMApi {
     pub fn new(api_key: String) -> Self {
-        Self { api_key }
+        Self {
+            api_key,
+            base_url: GEMINI_API_BASE_URL.to_string(),
+            stream: false,
+            retry_policy: RetryPolicy::default(),
+            progress: RefCell::new(None),
+            last_usage: Cell::new(None),
+            context_cache: None,
+            shared_prefix: RefCell::new(Vec::new()),
+            prefix_caches: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Points the client at a different endpoint, e.g. a proxy or a stand-in server in tests.
+    pub fn with_base_url(mut self, base_url: String) -> Self {
+        self.base_url = base_url;
+        self
+    }
+
+    pub fn with_streaming(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Caches the prefix declared with `set_shared_prefix` in Gemini `cachedContents`.
+    pub fn with_context_cache(mut self, config: ContextCacheConfig) -> Self {
+        self.context_cache = Some(config);
+        self
+    }
+}
+
+impl RealLLMApi {
+    // The cache holding the start of `conversation` for this model and tool set, and how many
+    // turns it covers. Creates the cache on first use.
+    fn prefix_cache(&self, model_name: &str, conversation: &[ChatTurn], tools: &[ToolDeclaration]) -> Option<(String, String, usize)> {
+        let config = self.context_cache?;
+        let prefix = self.shared_prefix.borrow();
+        let mut leading = conversation.iter().filter(|turn| !matches!(turn, ChatTurn::System(_)));
+        if prefix.is_empty() || !prefix.iter().all(|turn| leading.next() == Some(turn)) {
+            return None;
+        }
+        let tool_names: Vec<&str> = tools.iter().map(|tool| tool.name.as_str()).collect();
+        let key = format!("{}|{}", model_name, tool_names.join(","));
+        if let Some(entry) = self.prefix_caches.borrow().get(&key) {
+            return entry.clone().map(|name| (key, name, prefix.len()));
+        }
+
+        let entry = match create_gemini_cached_content(&self.base_url, &self.api_key, model_name, &prefix, tools, config.ttl, &self.retry_policy) {
+            Ok(name) => Some(name),
+            Err(e) => {
+                eprintln!("gem: WARN: Could not cache the project context for {} ({}); sending it with every request.", model_name, e);
+                None
+            }
+        };
+        self.prefix_caches.borrow_mut().insert(key.clone(), entry.clone());
+        entry.map(|name| (key, name, prefix.len()))
+    }
+
+    // Builds the request for `conversation`, referring to the prefix cache when there is one.
+    // Returns the cache key too, so a cache that expired early can be dropped.
+    fn gemini_request(&self, model_name: &str, conversation: &[ChatTurn], tools: &[ToolDeclaration]) -> (GeminiRequest, Option<String>) {
+        match self.prefix_cache(model_name, conversation, tools) {
+            Some((key, cache_name, covered)) => {
+                let rest = without_leading_turns(conversation, covered);
+                (GeminiRequest::from_conversation(&rest).with_tools(tools).with_cached_content(&cache_name), Some(key))
+            }
+            None if tools.is_empty() => (GeminiRequest::from_conversation(conversation), None),
+            None => (GeminiRequest::from_conversation(conversation).with_tools(tools), None),
+        }
+    }
+
+    // Runs `call` with the request for `conversation`. If Gemini no longer knows the cache it refers
+    // to (expired or deleted), forgets the cache and sends the conversation in full instead.
+    fn with_gemini_request<T>(
+        &self,
+        model_name: &str,
+        conversation: &[ChatTurn],
+        tools: &[ToolDeclaration],
+        mut call: impl FnMut(GeminiRequest) -> Result<T>,
+    ) -> Result<T> {
+        let (request_payload, cache_key) = self.gemini_request(model_name, conversation, tools);
+        let result = call(request_payload);
+        match (&result, cache_key) {
+            // Gemini answers 403 or 404 naming the cache for one it no longer has. Other errors (a bad
+            // payload, an invalid key) are not the cache's fault.
+            (Err(e), Some(key)) if e.downcast_ref::<LLMApiError>().is_some_and(is_missing_cache_error) => {
+                eprintln!("gem: WARN: The Gemini context cache for {} is gone ({}); sending the full context.", model_name, e);
+                self.prefix_caches.borrow_mut().insert(key, None);
+                let (request_payload, _) = self.gemini_request(model_name, conversation, tools);
+                call(request_payload)
+            }
+            _ => result,
+        }
+    }
+
+    // Sends the conversation, continuing answers that hit the output token limit.
+    fn send(&self, model_name: &str, conversation: &[ChatTurn], response_schema: Option<&serde_json::Value>) -> Result<String> {
+        let progress = self.progress.borrow();
+        let (text, usage) = generate_with_continuations(conversation, progress.as_ref(), |conversation, continuing| {
+            self.with_gemini_request(model_name, conversation, &[], |mut request_payload| {
+                // Under a schema the model would start a fresh JSON document instead of finishing the cut-off one.
+                if let (Some(schema), false) = (response_schema, continuing) {
+                    request_payload = request_payload.with_response_schema(schema);
+                }
+                if self.stream {
+                    call_real_gemini_api_streaming(&self.base_url, &self.api_key, model_name, &request_payload, &self.retry_policy, progress.as_ref())
+                } else {
+                    call_real_gemini_api(&self.base_url, &self.api_key, model_name, &request_payload, &self.retry_policy, progress.as_ref())
+                }
+            })
+        })?;
+        self.last_usage.set(usage);
+        Ok(text)
     }
 }
 
@@ -122,7 +1125,69 @@ impl LLMApi for RealLLMApi {
         prompt_text: &str,
         model_name: &str,
     ) -> Result<String> {
-        call_real_gemini_api(&self.api_key, prompt_text, model_name)
+        self.send(model_name, &[ChatTurn::User(prompt_text.to_string())], None)
+    }
+
+    fn generate_structured(
+        &self,
+        prompt_text: &str,
+        model_name: &str,
+        response_schema: &serde_json::Value,
+    ) -> Result<String> {
+        self.send(model_name, &[ChatTurn::User(prompt_text.to_string())], Some(response_schema))
+    }
+
+    fn generate_conversation(
+        &self,
+        conversation: &[ChatTurn],
+        model_name: &str,
+        response_schema: Option<&serde_json::Value>,
+    ) -> Result<String> {
+        self.send(model_name, conversation, response_schema)
+    }
+
+    fn set_shared_prefix(&self, prefix: &[ChatTurn]) {
+        let Some(config) = self.context_cache else { return };
+        self.release_shared_prefix(); // Caches of an earlier prefix are of no use anymore
+        if crate::token_budget::estimate_tokens(&flatten_conversation(prefix)) >= config.min_tokens {
+            *self.shared_prefix.borrow_mut() = prefix.to_vec();
+        }
+    }
+
+    fn release_shared_prefix(&self) {
+        self.shared_prefix.borrow_mut().clear();
+        for cache_name in self.prefix_caches.borrow_mut().drain().filter_map(|(_, name)| name) {
+            if let Err(e) = delete_gemini_cached_content(&self.base_url, &self.api_key, &cache_name) {
+                eprintln!("gem: WARN: Failed to delete Gemini context cache {} ({}); it expires after its TTL.", cache_name, e);
+            }
+        }
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    // Tool turns are short, so they are not streamed.
+    fn generate_with_tools(
+        &self,
+        conversation: &[ChatTurn],
+        tools: &[ToolDeclaration],
+        model_name: &str,
+    ) -> Result<ModelReply> {
+        let progress = self.progress.borrow();
+        let (reply, usage) = self.with_gemini_request(model_name, conversation, tools, |request_payload| {
+            call_real_gemini_api_with_tools(&self.base_url, &self.api_key, model_name, &request_payload, &self.retry_policy, progress.as_ref())
+        })?;
+        self.last_usage.set(usage);
+        Ok(reply)
+    }
+
+    fn take_last_usage(&self) -> Option<TokenUsage> {
+        self.last_usage.take()
+    }
+
+    fn set_progress_bar(&self, pb: Option<ProgressBar>) {
+        *self.progress.borrow_mut() = pb;
     }
 }
 
@@ -171,48 +1236,782 @@ impl LLMApi for MockLLMApi {
     }
 }
 
+// --- OpenAICompatibleLLMApi Implementation ---
+// Speaks the `/v1/chat/completions` protocol used by OpenAI, llama.cpp server, vLLM and LiteLLM.
+pub struct OpenAICompatibleLLMApi {
+    base_url: String,        // e.g. "http://localhost:8080/v1", without the "/chat/completions" suffix
+    model: Option<String>,   // Overrides the model name requested by the agent, if set
+    api_key: Option<String>, // Sent as a bearer token, if set
+    retry_policy: RetryPolicy,
+    last_usage: Cell<Option<TokenUsage>>,
+    json_schema_rejected: Cell<bool>, // The server answered a `json_schema` response format with 400
+}
+
+impl OpenAICompatibleLLMApi {
+    pub fn new(base_url: String, model: Option<String>, api_key: Option<String>) -> Self {
+        Self { base_url, model, api_key, retry_policy: RetryPolicy::default(), last_usage: Cell::new(None), json_schema_rejected: Cell::new(false) }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+}
+
+impl LLMApi for OpenAICompatibleLLMApi {
+    fn generate_content(
+        &self,
+        prompt_text: &str,
+        model_name: &str,
+    ) -> Result<String> {
+        self.generate_conversation(&[ChatTurn::User(prompt_text.to_string())], model_name, None)
+    }
+
+    fn generate_structured(
+        &self,
+        prompt_text: &str,
+        model_name: &str,
+        response_schema: &serde_json::Value,
+    ) -> Result<String> {
+        self.generate_conversation(&[ChatTurn::User(prompt_text.to_string())], model_name, Some(response_schema))
+    }
+
+    fn generate_conversation(
+        &self,
+        conversation: &[ChatTurn],
+        model_name: &str,
+        response_schema: Option<&serde_json::Value>,
+    ) -> Result<String> {
+        let model = self.model.as_deref().unwrap_or(model_name);
+        let (text, usage) = generate_with_continuations(conversation, None, |conversation, continuing| {
+            // As with Gemini, a continuation must finish the cut-off document rather than start a new one.
+            let response_schema = response_schema.filter(|_| !continuing && !self.json_schema_rejected.get());
+            let result = call_openai_chat_conversation(&self.base_url, self.api_key.as_deref(), conversation, model, response_schema, &self.retry_policy);
+            match result {
+                // Older llama.cpp and vLLM builds and many LiteLLM routes reject `json_schema`. Their
+                // answers are parsed as text like before.
+                Err(e) if response_schema.is_some() && matches!(e.downcast_ref::<LLMApiError>(), Some(LLMApiError::BadRequest { .. })) => {
+                    eprintln!("gem: WARN: {} does not accept a JSON schema response format ({}); parsing answers as text.", self.base_url, e);
+                    self.json_schema_rejected.set(true);
+                    call_openai_chat_conversation(&self.base_url, self.api_key.as_deref(), conversation, model, None, &self.retry_policy)
+                }
+                result => result,
+            }
+        })?;
+        self.last_usage.set(usage);
+        Ok(text)
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    fn generate_with_tools(
+        &self,
+        conversation: &[ChatTurn],
+        tools: &[ToolDeclaration],
+        model_name: &str,
+    ) -> Result<ModelReply> {
+        let model = self.model.as_deref().unwrap_or(model_name);
+        let (reply, usage) = call_openai_chat_with_tools(&self.base_url, self.api_key.as_deref(), conversation, tools, model, &self.retry_policy)?;
+        self.last_usage.set(usage);
+        Ok(reply)
+    }
+
+    fn take_last_usage(&self) -> Option<TokenUsage> {
+        self.last_usage.take()
+    }
+}
+
+// Checks a finished candidate. `MAX_TOKENS` becomes `LLMApiError::Truncated` carrying `text`,
+// block reasons become `LLMApiError::Blocked`, other early stops a plain error.
+fn check_finish_reason(reason: Option<&str>, text: &str, usage: Option<TokenUsage>, safety_ratings: &[GeminiSafetyRating]) -> Result<()> {
+    match reason {
+        None | Some("STOP") | Some("FINISH_REASON_UNSPECIFIED") => Ok(()),
+        Some("MAX_TOKENS") => Err(Box::new(LLMApiError::Truncated { partial: text.to_string(), usage })),
+        Some(reason @ ("SAFETY" | "IMAGE_SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII")) => Err(Box::new(LLMApiError::Blocked {
+            reason: reason.to_string(),
+            in_prompt: false,
+            details: describe_safety_ratings(safety_ratings),
+        })),
+        Some(reason) => Err(format!(
+            "Gemini stopped early (finishReason: {}) after {} characters; the response is incomplete.",
+            reason, text.len()
+        ).into()),
+    }
+}
+
+// A response without candidates may be a blocked prompt rather than a malformed answer.
+fn check_prompt_feedback(response: &GeminiResponse) -> Result<()> {
+    if let Some(GeminiPromptFeedback { block_reason: Some(reason), safety_ratings }) = &response.prompt_feedback {
+        return Err(Box::new(LLMApiError::Blocked {
+            reason: reason.clone(),
+            in_prompt: true,
+            details: describe_safety_ratings(safety_ratings),
+        }));
+    }
+    Ok(())
+}
+
+// Only the categories that caused (or came close to causing) the block.
+fn describe_safety_ratings(ratings: &[GeminiSafetyRating]) -> String {
+    ratings
+        .iter()
+        .filter(|r| r.blocked || matches!(r.probability.as_str(), "MEDIUM" | "HIGH"))
+        .map(|r| format!("{} {}", r.category, r.probability))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Continuations of an answer that hit the output limit, before giving up.
+const MAX_CONTINUATIONS: usize = 3;
+const CONTINUE_PROMPT: &str = "Your previous answer was cut off at the output limit. Continue exactly where it stopped: do not repeat anything, do not add commentary and do not wrap the rest in a code fence.";
+
+/// Runs `call` on `conversation` and, while the answer comes back `Truncated`, asks the model to
+/// continue it and stitches the pieces together. `call` gets `true` for the continuation requests.
+pub fn generate_with_continuations(
+    conversation: &[ChatTurn],
+    progress: Option<&ProgressBar>,
+    mut call: impl FnMut(&[ChatTurn], bool) -> Result<(String, Option<TokenUsage>)>,
+) -> Result<(String, Option<TokenUsage>)> {
+    let mut conversation = conversation.to_vec();
+    let mut text = String::new();
+    let mut total_usage: Option<TokenUsage> = None;
+    let mut add_usage = |usage: Option<TokenUsage>| {
+        if let Some(usage) = usage {
+            total_usage.get_or_insert_with(TokenUsage::default).add(&usage);
+        }
+    };
+
+    let mut continuations = 0;
+    loop {
+        let error = match call(&conversation, continuations > 0) {
+            Ok((part, usage)) => {
+                text.push_str(&part);
+                add_usage(usage);
+                return Ok((text, total_usage));
+            }
+            Err(error) => error,
+        };
+        let (partial, usage) = match error.downcast::<LLMApiError>() {
+            Ok(api_error) => match *api_error {
+                LLMApiError::Truncated { partial, usage } => (partial, usage),
+                other => return Err(Box::new(other)),
+            },
+            Err(error) => return Err(error),
+        };
+        text.push_str(&partial);
+        add_usage(usage);
+        if continuations == MAX_CONTINUATIONS {
+            return Err(Box::new(LLMApiError::Truncated { partial: text, usage: total_usage }));
+        }
+        continuations += 1;
+        let msg = format!("gem: Response hit the output token limit after {} characters; asking the model to continue ({}/{}).", text.len(), continuations, MAX_CONTINUATIONS);
+        if let Some(pb) = progress { pb.println(msg); } else { eprintln!("{}", msg); }
+        conversation.push(ChatTurn::Model(ModelReply { text: partial, tool_calls: Vec::new() }));
+        conversation.push(ChatTurn::User(CONTINUE_PROMPT.to_string()));
+    }
+}
+
+// Whether `error` is Gemini reporting that a `cachedContents` entry is gone.
+fn is_missing_cache_error(error: &LLMApiError) -> bool {
+    match error {
+        LLMApiError::Auth { status: 403, message } | LLMApiError::BadRequest { status: 404, message } => message.to_lowercase().contains("cachedcontent"),
+        _ => false,
+    }
+}
+
+// Removes the first `count` non-system turns; system turns stay in place.
+fn without_leading_turns(conversation: &[ChatTurn], count: usize) -> Vec<ChatTurn> {
+    let mut skipped = 0;
+    conversation
+        .iter()
+        .filter(|turn| {
+            if skipped < count && !matches!(turn, ChatTurn::System(_)) {
+                skipped += 1;
+                return false;
+            }
+            true
+        })
+        .cloned()
+        .collect()
+}
+
+/// Creates a `cachedContents` entry holding `prefix` (and `tools`) for `model_name`.
+/// Returns its name, e.g. `cachedContents/abc123`.
+pub fn create_gemini_cached_content(
+    base_url: &str,
+    api_key: &str,
+    model_name: &str,
+    prefix: &[ChatTurn],
+    tools: &[ToolDeclaration],
+    ttl: Duration,
+    retry_policy: &RetryPolicy,
+) -> Result<String> {
+    let prefix_request = GeminiRequest::from_conversation(prefix).with_tools(tools);
+    let request_payload = GeminiCachedContentRequest {
+        model: format!("models/{}", model_name),
+        contents: prefix_request.contents,
+        tools: prefix_request.tools,
+        ttl: format!("{}s", ttl.as_secs()),
+        display_name: "gem project context".to_string(),
+    };
+    let url = format!("{}/cachedContents?key={}", base_url.trim_end_matches('/'), api_key);
+
+    let client = reqwest::blocking::Client::new();
+    let response = send_with_retry(
+        || {
+            client
+                .post(&url)
+                .timeout(Duration::from_secs(120))
+                .header("Content-Type", "application/json")
+                .json(&request_payload)
+        },
+        retry_policy,
+        None,
+    )?;
+    let cached: GeminiCachedContent = serde_json::from_str(&response.text()?)?;
+    Ok(cached.name)
+}
+
+/// Deletes a `cachedContents` entry before its TTL runs out.
+pub fn delete_gemini_cached_content(base_url: &str, api_key: &str, cache_name: &str) -> Result<()> {
+    let url = format!("{}/{}?key={}", base_url.trim_end_matches('/'), cache_name, api_key);
+    let client = reqwest::blocking::Client::new();
+    send_with_retry(|| client.delete(&url).timeout(Duration::from_secs(30)), &RetryPolicy::default(), None)?;
+    Ok(())
+}
+
 // --- Moved from main.rs ---
-pub fn call_real_gemini_api(api_key: &str, prompt_text: &str, model_name: &str) -> Result<String> {
+pub fn call_real_gemini_api(
+    base_url: &str,
+    api_key: &str,
+    model_name: &str,
+    request_payload: &GeminiRequest,
+    retry_policy: &RetryPolicy,
+    progress: Option<&ProgressBar>,
+) -> Result<(String, Option<TokenUsage>)> {
+    let gemini_response = post_gemini_generate_content(base_url, api_key, model_name, request_payload, retry_policy, progress)?;
+
+    let usage = gemini_response.usage_metadata.as_ref().map(TokenUsage::from);
+    check_prompt_feedback(&gemini_response)?;
+    if let Some(candidate) = gemini_response.candidates.first() {
+        let text: String = candidate.content.parts.iter().map(|part| part.text.as_str()).collect();
+        check_finish_reason(candidate.finish_reason.as_deref(), &text, usage, &candidate.safety_ratings)?;
+        if candidate.content.parts.is_empty() {
+            Err("Gemini response missing content part".into())
+        } else {
+            Ok((text, usage))
+        }
+    } else {
+        Err("Gemini response missing candidates".into())
+    }
+}
+
+/// Like `call_real_gemini_api`, but returns every part of the answer, including function calls.
+pub fn call_real_gemini_api_with_tools(
+    base_url: &str,
+    api_key: &str,
+    model_name: &str,
+    request_payload: &GeminiRequest,
+    retry_policy: &RetryPolicy,
+    progress: Option<&ProgressBar>,
+) -> Result<(ModelReply, Option<TokenUsage>)> {
+    let gemini_response = post_gemini_generate_content(base_url, api_key, model_name, request_payload, retry_policy, progress)?;
+
+    let usage = gemini_response.usage_metadata.as_ref().map(TokenUsage::from);
+    check_prompt_feedback(&gemini_response)?;
+    let candidate = gemini_response.candidates.into_iter().next().ok_or("Gemini response missing candidates")?;
+    let mut reply = ModelReply::default();
+    for part in candidate.content.parts {
+        reply.text.push_str(&part.text);
+        if let Some(call) = part.function_call {
+            reply.tool_calls.push(ToolCall { id: None, name: call.name, args: call.args });
+        }
+    }
+    // Tool turns are short; one that still hits the limit is reported rather than continued.
+    check_finish_reason(candidate.finish_reason.as_deref(), &reply.text, usage, &candidate.safety_ratings)?;
+    Ok((reply, usage))
+}
+
+fn post_gemini_generate_content(
+    base_url: &str,
+    api_key: &str,
+    model_name: &str,
+    request_payload: &GeminiRequest,
+    retry_policy: &RetryPolicy,
+    progress: Option<&ProgressBar>,
+) -> Result<GeminiResponse> {
     let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-        model_name, api_key
+        "{}/models/{}:generateContent?key={}",
+        base_url.trim_end_matches('/'), model_name, api_key
     );
 
-    let request_payload = GeminiRequest {
-        contents: vec![GeminiRequestContent {
-            parts: vec![GeminiRequestPart {
-                text: prompt_text.to_string(),
-            }],
-            role: Some("user".to_string()),
-        }],
-    };
+    let client = reqwest::blocking::Client::new();
+    let response = send_with_retry(
+        || {
+            client
+                .post(&url)
+                .timeout(Duration::from_secs(600)) // 10 minutes is the max time on Gemini
+                .header("Content-Type", "application/json")
+                .json(request_payload)
+        },
+        retry_policy,
+        progress,
+    )?;
+
+    let response_body_text = response.text()?;
+    Ok(serde_json::from_str(&response_body_text)?)
+}
+
+/// Calls `streamGenerateContent` with server-sent events and accumulates the text parts.
+/// Partial output is shown on `progress` as it arrives.
+pub fn call_real_gemini_api_streaming(
+    base_url: &str,
+    api_key: &str,
+    model_name: &str,
+    request_payload: &GeminiRequest,
+    retry_policy: &RetryPolicy,
+    progress: Option<&ProgressBar>,
+) -> Result<(String, Option<TokenUsage>)> {
+    let url = format!(
+        "{}/models/{}:streamGenerateContent?alt=sse&key={}",
+        base_url.trim_end_matches('/'), model_name, api_key
+    );
 
     let client = reqwest::blocking::Client::new();
-    let response = client
-        .post(&url)
-        .timeout(Duration::from_secs(600)) // 10 minutes is the max time on Gemini
-        .header("Content-Type", "application/json")
-        .json(&request_payload)
-        .send()?;
-
-    if response.status().is_success() {
-        let response_body_text = response.text()?;
-        let gemini_response: GeminiResponse = serde_json::from_str(&response_body_text)?;
-
-        if let Some(candidate) = gemini_response.candidates.first() {
-            if let Some(part) = candidate.content.parts.first() {
-                Ok(part.text.clone())
-            } else {
-                Err("Gemini response missing content part".into())
+    let response = send_with_retry(
+        || {
+            client
+                .post(&url)
+                .timeout(Duration::from_secs(600)) // 10 minutes is the max time on Gemini
+                .header("Content-Type", "application/json")
+                .json(request_payload)
+        },
+        retry_policy,
+        progress,
+    )?;
+
+    let base_message = progress.map(|pb| pb.message()).unwrap_or_default();
+    let result = read_gemini_sse_stream(std::io::BufReader::new(response), |text_so_far| {
+        if let Some(pb) = progress {
+            pb.set_message(format!("{} [{} chars] {}", base_message, text_so_far.len(), stream_preview(text_so_far)));
+        }
+    });
+    if let Some(pb) = progress {
+        pb.set_message(base_message);
+    }
+    result
+}
+
+/// Reads `data: {...}` events until the stream ends. A stream that finishes without
+/// `finishReason: STOP` is reported as an error instead of returning truncated text
+/// (`LLMApiError::Truncated` for `MAX_TOKENS`, `LLMApiError::Blocked` for safety blocks).
+/// Returns the text together with the usage of the last chunk that reported one (counts are cumulative).
+pub fn read_gemini_sse_stream<R: BufRead>(reader: R, mut on_text: impl FnMut(&str)) -> Result<(String, Option<TokenUsage>)> {
+    let mut text = String::new();
+    let mut finish_reason: Option<String> = None;
+    let mut safety_ratings: Vec<GeminiSafetyRating> = Vec::new();
+    let mut usage: Option<TokenUsage> = None;
+
+    for line in reader.lines() {
+        if is_cancel_requested() {
+            return Err(Box::new(LLMApiError::Cancelled));
+        }
+        let line = line?;
+        let Some(data) = line.strip_prefix("data:") else { continue }; // Skip blank separators and SSE comments
+        let chunk: GeminiResponse = serde_json::from_str(data.trim())
+            .map_err(|e| format!("Failed to parse Gemini stream chunk: {} (chunk: {})", e, data.trim()))?;
+        if let Some(metadata) = &chunk.usage_metadata {
+            usage = Some(TokenUsage::from(metadata));
+        }
+        check_prompt_feedback(&chunk)?;
+
+        if let Some(candidate) = chunk.candidates.first() {
+            for part in &candidate.content.parts {
+                text.push_str(&part.text);
+            }
+            if candidate.finish_reason.is_some() {
+                finish_reason = candidate.finish_reason.clone();
             }
+            if !candidate.safety_ratings.is_empty() {
+                safety_ratings = candidate.safety_ratings.clone();
+            }
+            on_text(&text);
+        }
+    }
+
+    match finish_reason.as_deref() {
+        Some(reason) => {
+            check_finish_reason(Some(reason), &text, usage, &safety_ratings)?;
+            Ok((text, usage))
+        }
+        None => Err(format!(
+            "Gemini stream ended without a finishReason after {} characters; the response is incomplete.",
+            text.len()
+        ).into()),
+    }
+}
+
+// Last line of the partial output, shortened so it fits next to the spinner message.
+fn stream_preview(text: &str) -> String {
+    let last_line = text.trim_end().lines().last().unwrap_or("").trim();
+    let chars: Vec<char> = last_line.chars().collect();
+    if chars.len() > 60 {
+        format!("...{}", chars[chars.len() - 60..].iter().collect::<String>())
+    } else {
+        last_line.to_string()
+    }
+}
+
+pub fn call_openai_chat_completions(
+    base_url: &str,
+    api_key: Option<&str>,
+    prompt_text: &str,
+    model_name: &str,
+    retry_policy: &RetryPolicy,
+) -> Result<(String, Option<TokenUsage>)> {
+    call_openai_chat_conversation(base_url, api_key, &[ChatTurn::User(prompt_text.to_string())], model_name, None, retry_policy)
+}
+
+/// Sends a whole conversation as chat messages and returns the assistant's answer. With a
+/// `response_schema` (in Gemini form) the answer is constrained to JSON matching it.
+pub fn call_openai_chat_conversation(
+    base_url: &str,
+    api_key: Option<&str>,
+    conversation: &[ChatTurn],
+    model_name: &str,
+    response_schema: Option<&serde_json::Value>,
+    retry_policy: &RetryPolicy,
+) -> Result<(String, Option<TokenUsage>)> {
+    let request_payload = OpenAIChatRequest {
+        model: model_name.to_string(),
+        messages: OpenAIChatMessage::from_conversation(conversation),
+        tools: Vec::new(),
+        response_format: response_schema.map(|schema| serde_json::json!({
+            "type": "json_schema",
+            "json_schema": { "name": "response", "schema": to_json_schema(schema) },
+        })),
+    };
+    let chat_response = post_openai_chat_request(base_url, api_key, &request_payload, retry_policy)?;
+    let usage = chat_response.usage.as_ref().map(TokenUsage::from);
+
+    if let Some(choice) = chat_response.choices.into_iter().next() {
+        check_openai_finish_reason(choice.finish_reason.as_deref(), choice.message.content.as_deref().unwrap_or(""), usage)?;
+        if let Some(content) = choice.message.content {
+            Ok((content, usage))
         } else {
-            Err("Gemini response missing candidates".into())
+            Err("Chat completion response missing message content".into())
         }
     } else {
-        let status = response.status();
-        let error_body = response
-            .text()
-            .unwrap_or_else(|_| "Could not read error body".to_string());
-        Err(format!("Gemini API Error ({}): {}", status, error_body).into())
+        Err("Chat completion response missing choices".into())
+    }
+}
+
+// The OpenAI counterparts of `MAX_TOKENS` and `SAFETY`.
+fn check_openai_finish_reason(reason: Option<&str>, text: &str, usage: Option<TokenUsage>) -> Result<()> {
+    match reason {
+        Some("length") => Err(Box::new(LLMApiError::Truncated { partial: text.to_string(), usage })),
+        Some("content_filter") => Err(Box::new(LLMApiError::Blocked {
+            reason: "content_filter".to_string(),
+            in_prompt: false,
+            details: String::new(),
+        })),
+        _ => Ok(()),
+    }
+}
+
+/// Sends a conversation with `tools` declared and returns the assistant's text and tool calls.
+pub fn call_openai_chat_with_tools(
+    base_url: &str,
+    api_key: Option<&str>,
+    conversation: &[ChatTurn],
+    tools: &[ToolDeclaration],
+    model_name: &str,
+    retry_policy: &RetryPolicy,
+) -> Result<(ModelReply, Option<TokenUsage>)> {
+    let request_payload = OpenAIChatRequest {
+        model: model_name.to_string(),
+        messages: OpenAIChatMessage::from_conversation(conversation),
+        tools: tools
+            .iter()
+            .map(|tool| OpenAITool {
+                kind: "function".to_string(),
+                function: serde_json::json!({
+                    "name": tool.name,
+                    "description": tool.description,
+                    "parameters": tool.parameters.as_ref().map(to_json_schema)
+                        .unwrap_or_else(|| serde_json::json!({ "type": "object", "properties": {} })),
+                }),
+            })
+            .collect(),
+        response_format: None,
+    };
+    let chat_response = post_openai_chat_request(base_url, api_key, &request_payload, retry_policy)?;
+    let usage = chat_response.usage.as_ref().map(TokenUsage::from);
+
+    let choice = chat_response.choices.into_iter().next().ok_or("Chat completion response missing choices")?;
+    let tool_calls = choice
+        .message
+        .tool_calls
+        .into_iter()
+        .map(|call| {
+            let args = serde_json::from_str(&call.function.arguments)
+                .map_err(|e| format!("Tool call {} has invalid JSON arguments: {} ({})", call.function.name, e, call.function.arguments))?;
+            Ok(ToolCall { id: Some(call.id).filter(|id| !id.is_empty()), name: call.function.name, args })
+        })
+        .collect::<Result<Vec<ToolCall>>>()?;
+    let text = choice.message.content.unwrap_or_default();
+    check_openai_finish_reason(choice.finish_reason.as_deref(), &text, usage)?;
+    Ok((ModelReply { text, tool_calls }, usage))
+}
+
+fn post_openai_chat_request(
+    base_url: &str,
+    api_key: Option<&str>,
+    request_payload: &OpenAIChatRequest,
+    retry_policy: &RetryPolicy,
+) -> Result<OpenAIChatResponse> {
+    let url = format!("{}/chat/completions", base_url.trim_end_matches('/'));
+
+    let client = reqwest::blocking::Client::new();
+    let response = send_with_retry(
+        || {
+            let request = client
+                .post(&url)
+                .timeout(Duration::from_secs(600)) // Local servers can be slow on large prompts
+                .header("Content-Type", "application/json")
+                .json(request_payload);
+            match api_key {
+                Some(key) => request.bearer_auth(key),
+                None => request,
+            }
+        },
+        retry_policy,
+        None,
+    )?;
+
+    let response_body_text = response.text()?;
+    Ok(serde_json::from_str(&response_body_text)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use std::io::Cursor;
+
+    fn sse(chunks: &[&str]) -> Cursor<String> {
+        Cursor::new(chunks.iter().map(|c| format!("data: {}\r\n\r\n", c)).collect::<String>())
+    }
+
+    #[test]
+    fn test_answer_from_code_blocks_prefers_the_last_json_block() {
+        let blocks = ["{\"sufficient\": false}".to_string(), "let x = 1;".to_string(), "{\"sufficient\": true}".to_string()];
+        assert_eq!(answer_from_code_blocks(&blocks).as_deref(), Some("{\"sufficient\": true}"));
+        assert_eq!(answer_from_code_blocks(&["a".to_string(), "b".to_string()]).as_deref(), Some("a\n\nb"));
+        assert_eq!(answer_from_code_blocks(&[]), None);
+    }
+
+    #[test]
+    #[serial]
+    fn test_read_gemini_sse_stream_accumulates_text() {
+        let stream = sse(&[
+            r#"{"candidates":[{"content":{"parts":[{"text":"{\"needed_"}],"role":"model"}}]}"#,
+            r#"{"candidates":[{"content":{"parts":[{"text":"items\": []}"}],"role":"model"},"finishReason":"STOP"}],"usageMetadata":{"promptTokenCount":120,"candidatesTokenCount":8,"thoughtsTokenCount":30}}"#,
+        ]);
+        let mut updates = Vec::new();
+        let (text, usage) = read_gemini_sse_stream(stream, |t| updates.push(t.to_string())).unwrap();
+        assert_eq!(text, r#"{"needed_items": []}"#);
+        assert_eq!(updates, vec![r#"{"needed_"#.to_string(), text.clone()]);
+        assert_eq!(usage, Some(TokenUsage { prompt_tokens: 120, candidate_tokens: 8, thinking_tokens: 30, cached_tokens: 0 }));
+    }
+
+    #[test]
+    fn test_usage_parsing() {
+        let gemini: GeminiResponse = serde_json::from_str(
+            r#"{"candidates":[],"usageMetadata":{"promptTokenCount":10,"candidatesTokenCount":5,"totalTokenCount":15}}"#,
+        ).unwrap();
+        let usage = TokenUsage::from(gemini.usage_metadata.as_ref().unwrap());
+        assert_eq!(usage, TokenUsage { prompt_tokens: 10, candidate_tokens: 5, thinking_tokens: 0, cached_tokens: 0 });
+        assert_eq!(usage.total(), 15);
+
+        let openai: OpenAIChatResponse = serde_json::from_str(
+            r#"{"choices":[],"usage":{"prompt_tokens":20,"completion_tokens":12,"completion_tokens_details":{"reasoning_tokens":4}}}"#,
+        ).unwrap();
+        let mut total = TokenUsage::from(openai.usage.as_ref().unwrap());
+        assert_eq!(total, TokenUsage { prompt_tokens: 20, candidate_tokens: 8, thinking_tokens: 4, cached_tokens: 0 });
+        total.add(&usage);
+        assert_eq!(total.total(), 47);
+    }
+
+    #[test]
+    #[serial]
+    fn test_read_gemini_sse_stream_detects_early_stop() {
+        let stream = sse(&[
+            r#"{"candidates":[{"content":{"parts":[{"text":"{\"changes\": ["}],"role":"model"},"finishReason":"MAX_TOKENS"}]}"#,
+        ]);
+        let err = read_gemini_sse_stream(stream, |_| {}).unwrap_err();
+        assert!(err.to_string().contains("MAX_TOKENS"), "unexpected error: {}", err);
+
+        let stream = sse(&[r#"{"candidates":[{"content":{"parts":[{"text":"{\"chan"}],"role":"model"}}]}"#]);
+        let err = read_gemini_sse_stream(stream, |_| {}).unwrap_err();
+        assert!(err.to_string().contains("without a finishReason"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    #[serial]
+    fn test_read_gemini_sse_stream_cancelled() {
+        let stream = sse(&[r#"{"candidates":[{"content":{"parts":[{"text":"x"}],"role":"model"},"finishReason":"STOP"}]}"#]);
+        request_cancel();
+        let result = read_gemini_sse_stream(stream, |_| {});
+        clear_cancel_request();
+        assert!(result.unwrap_err().to_string().contains("cancelled"));
+    }
+
+    #[test]
+    fn test_llm_api_error_classification() {
+        assert!(matches!(LLMApiError::from_status(429, None, String::new()), LLMApiError::Quota { .. }));
+        assert!(matches!(LLMApiError::from_status(503, None, String::new()), LLMApiError::Overloaded { .. }));
+        assert!(matches!(LLMApiError::from_status(401, None, String::new()), LLMApiError::Auth { .. }));
+        assert!(matches!(LLMApiError::from_status(403, None, String::new()), LLMApiError::Auth { .. }));
+        assert!(matches!(LLMApiError::from_status(400, None, String::new()), LLMApiError::BadRequest { .. }));
+
+        assert!(LLMApiError::from_status(429, None, String::new()).is_retryable());
+        assert!(LLMApiError::from_status(500, None, String::new()).is_retryable());
+        assert!(LLMApiError::Network("timed out".to_string()).is_retryable());
+        assert!(!LLMApiError::from_status(401, None, String::new()).is_retryable());
+        assert!(!LLMApiError::from_status(404, None, String::new()).is_retryable());
+        assert!(!LLMApiError::Cancelled.is_retryable());
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after(Some("7"), ""), Some(Duration::from_secs(7)));
+        let gemini_body = r#"{"error":{"code":429,"status":"RESOURCE_EXHAUSTED","details":[
+            {"@type":"type.googleapis.com/google.rpc.QuotaFailure"},
+            {"@type":"type.googleapis.com/google.rpc.RetryInfo","retryDelay":"32s"}]}}"#;
+        assert_eq!(parse_retry_after(None, gemini_body), Some(Duration::from_secs(32)));
+        assert_eq!(parse_retry_after(None, "not json"), None);
+        assert_eq!(parse_retry_after(Some("Wed, 21 Oct 2015 07:28:00 GMT"), "{}"), None);
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        };
+        assert_eq!(policy.backoff(0, None), Duration::from_secs(1));
+        assert_eq!(policy.backoff(1, None), Duration::from_secs(2));
+        assert_eq!(policy.backoff(2, None), Duration::from_secs(4));
+        assert_eq!(policy.backoff(10, None), Duration::from_secs(10));
+        assert_eq!(policy.backoff(0, Some(Duration::from_secs(3))), Duration::from_secs(3));
+        assert_eq!(policy.backoff(0, Some(Duration::from_secs(300))), Duration::from_secs(10));
+    }
+
+    // Every serialized field must be declared in the schema, and the schema must not invent fields.
+    fn assert_schema_matches<T: ResponseSchema + Serialize>(example: &T) {
+        let schema = T::response_schema();
+        let mut schema_fields: Vec<String> = schema["properties"].as_object().unwrap().keys().cloned().collect();
+        let mut value_fields: Vec<String> = serde_json::to_value(example).unwrap().as_object().unwrap().keys().cloned().collect();
+        schema_fields.sort();
+        value_fields.sort();
+        assert_eq!(schema_fields, value_fields);
+        for required in schema["required"].as_array().unwrap() {
+            assert!(schema["properties"].get(required.as_str().unwrap()).is_some(), "unknown required field {}", required);
+        }
+    }
+
+    #[test]
+    fn test_response_schemas_match_rust_types() {
+        assert_schema_matches(&GeminiNeededItemsResponse { needed_items: vec![] });
+        assert_schema_matches(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] });
+        assert_schema_matches(&CodeChange { file_path: String::new(), action: CodeChangeAction::CreateFile, content: None });
+        assert_schema_matches(&TestChange { file_path: String::new(), action: String::new(), content: String::new(), test_name: None });
+        assert_schema_matches(&GeminiCodeGenerationResponse { changes: vec![], tests: None, explanation: String::new() });
+    }
+
+    #[test]
+    fn test_code_change_action_schema_lists_every_variant() {
+        let schema = CodeChangeAction::response_schema();
+        for name in schema["enum"].as_array().unwrap() {
+            let parsed: CodeChangeAction = serde_json::from_value(name.clone()).unwrap();
+            assert_eq!(&serde_json::to_value(parsed).unwrap(), name);
+        }
+        // Adding a variant without listing it here breaks this match, reminding you to update the schema.
+        match CodeChangeAction::CreateFile {
+            CodeChangeAction::ReplaceContent
+            | CodeChangeAction::CreateFile
+            | CodeChangeAction::DeleteFile
+            | CodeChangeAction::ApplyDiff
+            | CodeChangeAction::ReplaceLines
+            | CodeChangeAction::InsertAfterLine
+            | CodeChangeAction::ReplaceItemInSection
+            | CodeChangeAction::ProcessMarkdownAndApplyChanges => assert_eq!(schema["enum"].as_array().unwrap().len(), 8),
+        }
+    }
+
+    #[test]
+    fn test_gemini_request_with_response_schema_serialization() {
+        let schema = GeminiSufficiencyResponse::response_schema();
+        let request = serde_json::to_value(GeminiRequest::single_turn("prompt").with_response_schema(&schema)).unwrap();
+        assert_eq!(request["generationConfig"]["responseMimeType"], "application/json");
+        assert_eq!(request["generationConfig"]["responseSchema"], schema);
+
+        let plain = serde_json::to_value(GeminiRequest::single_turn("prompt")).unwrap();
+        assert!(plain.get("generationConfig").is_none());
+    }
+
+    #[test]
+    fn test_split_system_prompt_round_trips_through_flatten() {
+        let prompt = "SYSTEM PROMPT:\nYou are a Rust assistant.\n\nUSER PROMPT:\nAdd a function.";
+        let turns = split_system_prompt(prompt);
+        assert_eq!(turns, vec![ChatTurn::System("You are a Rust assistant.".to_string()), ChatTurn::User("Add a function.".to_string())]);
+        assert_eq!(flatten_conversation(&turns), prompt);
+
+        assert_eq!(split_system_prompt("just a question"), vec![ChatTurn::User("just a question".to_string())]);
+        assert_eq!(flatten_conversation(&split_system_prompt("just a question")), "just a question");
+
+        let mut retry = turns.clone();
+        retry.push(ChatTurn::Model(ModelReply { text: "{}".to_string(), tool_calls: vec![] }));
+        retry.push(ChatTurn::User("That failed.".to_string()));
+        assert!(flatten_conversation(&retry).ends_with("Add a function.\n\nMODEL RESPONSE:\n{}\n\nUSER PROMPT:\nThat failed."));
+    }
+
+    #[test]
+    fn test_gemini_request_sends_system_turns_as_system_instruction() {
+        let request = serde_json::to_value(GeminiRequest::from_conversation(&[
+            ChatTurn::System("Be brief.".to_string()),
+            ChatTurn::User("Hi".to_string()),
+            ChatTurn::Model(ModelReply { text: "Hello".to_string(), tool_calls: vec![] }),
+            ChatTurn::User("Again".to_string()),
+        ]))
+        .unwrap();
+        assert_eq!(request["systemInstruction"], serde_json::json!({ "parts": [{ "text": "Be brief." }] }));
+        let roles: Vec<&str> = request["contents"].as_array().unwrap().iter().map(|c| c["role"].as_str().unwrap()).collect();
+        assert_eq!(roles, vec!["user", "model", "user"]);
+
+        let single = serde_json::to_value(GeminiRequest::single_turn("Hi")).unwrap();
+        assert!(single.get("systemInstruction").is_none());
+    }
+
+    #[test]
+    fn test_to_json_schema_lowercases_types_and_expands_nullable() {
+        let converted = to_json_schema(&TestChange::response_schema());
+        assert_eq!(converted["type"], "object");
+        assert_eq!(converted["properties"]["file_path"]["type"], "string");
+        assert_eq!(converted["properties"]["test_name"]["type"], serde_json::json!(["string", "null"]));
+
+        let nullable = to_json_schema(&serde_json::json!({ "type": "ARRAY", "nullable": true, "items": { "type": "STRING" } }));
+        assert_eq!(nullable, serde_json::json!({ "type": ["array", "null"], "items": { "type": "string" } }));
+    }
+
+    #[test]
+    fn test_stream_preview_shows_tail_of_last_line() {
+        assert_eq!(stream_preview("first line\nsecond line\n"), "second line");
+        let long_line = "a".repeat(100);
+        assert_eq!(stream_preview(&long_line), format!("...{}", "a".repeat(60)));
     }
 }
@@ -0,0 +1,239 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use regex::Regex;
+
+use crate::llm_api::{
+    flatten_conversation, ChatTurn, GeminiCodeGenerationResponse, GeminiNeededItemsResponse, GeminiSufficiencyResponse, LLMApi,
+    ResponseSchema,
+};
+use crate::model_router::AgentPhase;
+use crate::Result;
+
+/// One canned answer of `RuleBasedLLMApi` and the calls it applies to.
+/// Conditions left unset match anything; a rule fires at most `times` times.
+#[derive(Debug, Clone)]
+pub struct MockRule {
+    model: Option<String>,
+    prompt_pattern: Option<Regex>,
+    phase: Option<AgentPhase>,
+    response: std::result::Result<String, String>,
+    max_uses: Option<usize>,
+    required: bool, // Must fire at least once for `assert_all_rules_used`
+}
+
+impl MockRule {
+    /// Answers matching calls with `response`.
+    pub fn respond(response: impl Into<String>) -> Self {
+        Self { model: None, prompt_pattern: None, phase: None, response: Ok(response.into()), max_uses: None, required: true }
+    }
+
+    /// Fails matching calls with `message`.
+    pub fn fail(message: impl Into<String>) -> Self {
+        Self { response: Err(message.into()), ..Self::respond("") }
+    }
+
+    pub fn for_phase(mut self, phase: AgentPhase) -> Self {
+        self.phase = Some(phase);
+        self
+    }
+
+    pub fn for_model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    /// Only matches prompts containing a match of `pattern`. Panics if it is not a valid regex.
+    pub fn for_prompt_matching(mut self, pattern: &str) -> Self {
+        let regex = Regex::new(pattern).unwrap_or_else(|e| panic!("MockRule: invalid prompt pattern `{}`: {}", pattern, e));
+        self.prompt_pattern = Some(regex);
+        self
+    }
+
+    pub fn times(mut self, max_uses: usize) -> Self {
+        self.max_uses = Some(max_uses);
+        self
+    }
+
+    /// The rule may go unused without failing `assert_all_rules_used`.
+    pub fn optional(mut self) -> Self {
+        self.required = false;
+        self
+    }
+
+    fn matches(&self, call: &MockCall) -> bool {
+        self.model.as_ref().is_none_or(|model| *model == call.model)
+            && self.phase.is_none_or(|phase| Some(phase) == call.phase)
+            && self.prompt_pattern.as_ref().is_none_or(|regex| regex.is_match(&call.prompt))
+    }
+}
+
+impl fmt::Display for MockRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut conditions = Vec::new();
+        if let Some(phase) = self.phase { conditions.push(format!("phase={}", phase)); }
+        if let Some(model) = &self.model { conditions.push(format!("model={}", model)); }
+        if let Some(regex) = &self.prompt_pattern { conditions.push(format!("prompt=/{}/", regex)); }
+        if conditions.is_empty() { conditions.push("any call".to_string()); }
+        write!(f, "{}", conditions.join(", "))
+    }
+}
+
+// What a rule is matched against.
+struct MockCall {
+    model: String,
+    phase: Option<AgentPhase>, // Known only for calls that ask for one of the agent's response schemas
+    prompt: String,
+}
+
+/// Mock backend that answers each call with the first rule matching its model, prompt and phase,
+/// so tests don't depend on the number or order of calls. Clones share their rules and counts,
+/// so a test can keep one to call `assert_all_rules_used` after handing the other to the agent.
+#[derive(Clone, Default)]
+pub struct RuleBasedLLMApi {
+    rules: Rc<RefCell<Vec<(MockRule, usize)>>>, // Each rule with how often it fired
+}
+
+impl RuleBasedLLMApi {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rule(self, rule: MockRule) -> Self {
+        self.add_rule(rule);
+        self
+    }
+
+    pub fn add_rule(&self, rule: MockRule) {
+        self.rules.borrow_mut().push((rule, 0));
+    }
+
+    /// How often each rule fired, in the order they were added.
+    pub fn uses(&self) -> Vec<usize> {
+        self.rules.borrow().iter().map(|(_, uses)| *uses).collect()
+    }
+
+    /// Panics listing every required rule that never fired.
+    pub fn assert_all_rules_used(&self) {
+        let rules = self.rules.borrow();
+        let unused: Vec<String> = rules
+            .iter()
+            .enumerate()
+            .filter(|(_, (rule, uses))| rule.required && *uses == 0)
+            .map(|(i, (rule, _))| format!("  rule {}: {}", i + 1, rule))
+            .collect();
+        assert!(unused.is_empty(), "RuleBasedLLMApi: {} rule(s) never matched a call:\n{}", unused.len(), unused.join("\n"));
+    }
+
+    fn answer(&self, call: MockCall) -> Result<String> {
+        let mut rules = self.rules.borrow_mut();
+        let matching = rules
+            .iter_mut()
+            .find(|(rule, uses)| rule.max_uses.is_none_or(|max| *uses < max) && rule.matches(&call));
+        let Some((rule, uses)) = matching else {
+            let phase = call.phase.map_or("unknown".to_string(), |phase| phase.to_string());
+            let excerpt: String = call.prompt.chars().take(200).collect();
+            let listed: Vec<String> = rules
+                .iter()
+                .enumerate()
+                .map(|(i, (rule, uses))| format!("  rule {}: {} (fired {}{})", i + 1, rule, uses, rule.max_uses.map_or(String::new(), |max| format!(" of {}", max))))
+                .collect();
+            return Err(format!(
+                "RuleBasedLLMApi: no rule matches the call to model `{}` (phase: {}). Prompt starts with:\n{}\nRules:\n{}",
+                call.model, phase, excerpt, listed.join("\n")
+            )
+            .into());
+        };
+        *uses += 1;
+        rule.response.clone().map_err(|message| message.into())
+    }
+}
+
+// The phase a call belongs to, from the response schema the agent asks for.
+fn phase_for_schema(response_schema: &serde_json::Value) -> Option<AgentPhase> {
+    if *response_schema == GeminiNeededItemsResponse::response_schema() {
+        Some(AgentPhase::Initial)
+    } else if *response_schema == GeminiSufficiencyResponse::response_schema() {
+        Some(AgentPhase::Sufficient)
+    } else if *response_schema == GeminiCodeGenerationResponse::response_schema() {
+        Some(AgentPhase::Change)
+    } else {
+        None
+    }
+}
+
+impl LLMApi for RuleBasedLLMApi {
+    fn generate_content(&self, prompt_text: &str, model_name: &str) -> Result<String> {
+        self.answer(MockCall { model: model_name.to_string(), phase: None, prompt: prompt_text.to_string() })
+    }
+
+    fn generate_structured(&self, prompt_text: &str, model_name: &str, response_schema: &serde_json::Value) -> Result<String> {
+        self.answer(MockCall { model: model_name.to_string(), phase: phase_for_schema(response_schema), prompt: prompt_text.to_string() })
+    }
+
+    fn generate_conversation(&self, conversation: &[ChatTurn], model_name: &str, response_schema: Option<&serde_json::Value>) -> Result<String> {
+        let mut phase = response_schema.and_then(phase_for_schema);
+        // Code generation that already answered once is a retry after failed verification.
+        if phase == Some(AgentPhase::Change) && conversation.iter().any(|turn| matches!(turn, ChatTurn::Model(_))) {
+            phase = Some(AgentPhase::Retry);
+        }
+        self.answer(MockCall { model: model_name.to_string(), phase, prompt: flatten_conversation(conversation) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_api::ModelReply;
+
+    #[test]
+    fn test_first_matching_rule_answers_until_exhausted() {
+        let api = RuleBasedLLMApi::new()
+            .with_rule(MockRule::respond("pro answer").for_model("gemini-2.5-pro"))
+            .with_rule(MockRule::respond("first").for_prompt_matching(r"^User Request: .*flag").times(1))
+            .with_rule(MockRule::respond("fallback"));
+
+        assert_eq!(api.generate_content("User Request: add a flag", "gemini-2.5-flash").unwrap(), "first");
+        assert_eq!(api.generate_content("User Request: add a flag", "gemini-2.5-flash").unwrap(), "fallback");
+        assert_eq!(api.generate_content("anything", "gemini-2.5-pro").unwrap(), "pro answer");
+        assert_eq!(api.uses(), vec![1, 1, 1]);
+        api.assert_all_rules_used();
+    }
+
+    #[test]
+    fn test_phase_rules_follow_the_response_schema() {
+        let api = RuleBasedLLMApi::new()
+            .with_rule(MockRule::respond(r#"{"needed_items": []}"#).for_phase(AgentPhase::Initial))
+            .with_rule(MockRule::fail("retry failed").for_phase(AgentPhase::Retry))
+            .with_rule(MockRule::respond(r#"{"changes": []}"#).for_phase(AgentPhase::Change));
+
+        let schema = GeminiNeededItemsResponse::response_schema();
+        assert_eq!(api.generate_structured("prompt", "m", &schema).unwrap(), r#"{"needed_items": []}"#);
+
+        let code_gen_schema = GeminiCodeGenerationResponse::response_schema();
+        let mut conversation = vec![ChatTurn::User("write code".to_string())];
+        assert_eq!(api.generate_conversation(&conversation, "m", Some(&code_gen_schema)).unwrap(), r#"{"changes": []}"#);
+        conversation.push(ChatTurn::Model(ModelReply { text: "{}".to_string(), tool_calls: Vec::new() }));
+        conversation.push(ChatTurn::User("it failed".to_string()));
+        assert_eq!(api.generate_conversation(&conversation, "m", Some(&code_gen_schema)).unwrap_err().to_string(), "retry failed");
+
+        // Plain calls have no phase, so no phase rule matches them.
+        let error = api.generate_content("prompt", "m").unwrap_err().to_string();
+        assert!(error.contains("no rule matches the call to model `m` (phase: unknown)"), "{}", error);
+        assert!(error.contains("rule 1: phase=initial (fired 1)"), "{}", error);
+    }
+
+    #[test]
+    #[should_panic(expected = "1 rule(s) never matched a call:\n  rule 2: phase=sufficient, model=gemini-2.5-flash")]
+    fn test_assert_all_rules_used_lists_unused_rules() {
+        let api = RuleBasedLLMApi::new()
+            .with_rule(MockRule::respond("used"))
+            .with_rule(MockRule::respond("unused").for_phase(AgentPhase::Sufficient).for_model("gemini-2.5-flash"))
+            .with_rule(MockRule::respond("also unused").optional());
+        let handle = api.clone();
+        let boxed: Box<dyn LLMApi> = Box::new(api);
+        boxed.generate_content("prompt", "m").unwrap();
+        handle.assert_all_rules_used();
+    }
+}
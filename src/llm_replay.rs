@@ -0,0 +1,320 @@
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::Session;
+use crate::llm_api::{flatten_conversation, ChatTurn, LLMApi, ModelReply, TokenUsage, ToolDeclaration};
+use crate::Result;
+
+// Drift reports show at most this many changed lines.
+const MAX_DIFF_LINES: usize = 40;
+// Above this many differing lines (per side) the diff shows both blocks whole instead of aligning them.
+const MAX_ALIGNED_DIFF_LINES: usize = 1_000;
+
+/// One recorded call. Stored as `<model>-<prompt hash prefix>.json` in the fixture directory.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Fixture {
+    pub prompt_hash: String, // SHA-256 of `prompt`
+    pub model: String,
+    pub kind: FixtureKind,
+    pub prompt: String, // Kept so a drifted prompt can be diffed against it
+    pub response: String, // For `FixtureKind::Tools`, the `ModelReply` as JSON
+    #[serde(default)]
+    pub usage: Option<TokenUsage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FixtureKind {
+    Text,
+    Tools,
+}
+
+impl Fixture {
+    fn file_name(&self) -> String {
+        let model: String = self.model.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).collect();
+        format!("{}-{}.json", model, &self.prompt_hash[..16])
+    }
+}
+
+// The prompt a call is keyed by. Conversations are flattened the way backends without a message list see them.
+fn tools_prompt(conversation: &[ChatTurn], tools: &[ToolDeclaration]) -> String {
+    let names: Vec<&str> = tools.iter().map(|tool| tool.name.as_str()).collect();
+    format!("TOOLS: {}\n\n{}", names.join(", "), flatten_conversation(conversation))
+}
+
+/// Wraps a backend and writes every successful call to `fixture_dir`, for `ReplayLLMApi` to serve later.
+/// Recording a real session this way turns it into a regression test that runs offline.
+pub struct RecordingLLMApi {
+    inner: Box<dyn LLMApi>,
+    fixture_dir: PathBuf,
+    last_usage: Cell<Option<TokenUsage>>,
+}
+
+impl RecordingLLMApi {
+    pub fn new(inner: Box<dyn LLMApi>, fixture_dir: impl Into<PathBuf>) -> Self {
+        Self { inner, fixture_dir: fixture_dir.into(), last_usage: Cell::new(None) }
+    }
+
+    fn record(&self, kind: FixtureKind, prompt: String, model_name: &str, response: String) -> Result<()> {
+        let usage = self.inner.take_last_usage();
+        self.last_usage.set(usage);
+        let fixture = Fixture { prompt_hash: Session::compute_hash(&prompt), model: model_name.to_string(), kind, prompt, response, usage };
+        fs::create_dir_all(&self.fixture_dir)?;
+        let path = self.fixture_dir.join(fixture.file_name());
+        fs::write(&path, serde_json::to_string_pretty(&fixture)?)
+            .map_err(|e| format!("RecordingLLMApi: failed to write fixture {}: {}", path.display(), e))?;
+        Ok(())
+    }
+}
+
+impl LLMApi for RecordingLLMApi {
+    fn generate_content(&self, prompt_text: &str, model_name: &str) -> Result<String> {
+        let response = self.inner.generate_content(prompt_text, model_name)?;
+        self.record(FixtureKind::Text, prompt_text.to_string(), model_name, response.clone())?;
+        Ok(response)
+    }
+
+    fn generate_structured(&self, prompt_text: &str, model_name: &str, response_schema: &serde_json::Value) -> Result<String> {
+        let response = self.inner.generate_structured(prompt_text, model_name, response_schema)?;
+        self.record(FixtureKind::Text, prompt_text.to_string(), model_name, response.clone())?;
+        Ok(response)
+    }
+
+    fn generate_conversation(&self, conversation: &[ChatTurn], model_name: &str, response_schema: Option<&serde_json::Value>) -> Result<String> {
+        let response = self.inner.generate_conversation(conversation, model_name, response_schema)?;
+        self.record(FixtureKind::Text, flatten_conversation(conversation), model_name, response.clone())?;
+        Ok(response)
+    }
+
+    fn set_shared_prefix(&self, prefix: &[ChatTurn]) {
+        self.inner.set_shared_prefix(prefix);
+    }
+
+    fn release_shared_prefix(&self) {
+        self.inner.release_shared_prefix();
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.inner.supports_tools()
+    }
+
+    fn generate_with_tools(&self, conversation: &[ChatTurn], tools: &[ToolDeclaration], model_name: &str) -> Result<ModelReply> {
+        let reply = self.inner.generate_with_tools(conversation, tools, model_name)?;
+        self.record(FixtureKind::Tools, tools_prompt(conversation, tools), model_name, serde_json::to_string(&reply)?)?;
+        Ok(reply)
+    }
+
+    fn take_last_usage(&self) -> Option<TokenUsage> {
+        self.last_usage.take()
+    }
+
+    fn set_progress_bar(&self, pb: Option<ProgressBar>) {
+        self.inner.set_progress_bar(pb);
+    }
+}
+
+/// Answers from fixtures written by `RecordingLLMApi`, looked up by prompt hash and model.
+/// A prompt without a fixture is an error that shows how it differs from the closest recorded one.
+pub struct ReplayLLMApi {
+    fixture_dir: PathBuf,
+    fixtures: Vec<Fixture>,
+    last_usage: Cell<Option<TokenUsage>>,
+}
+
+impl ReplayLLMApi {
+    pub fn new(fixture_dir: impl Into<PathBuf>) -> Result<Self> {
+        let fixture_dir = fixture_dir.into();
+        let mut fixtures = Vec::new();
+        let entries = fs::read_dir(&fixture_dir).map_err(|e| format!("ReplayLLMApi: cannot read fixture directory {}: {}", fixture_dir.display(), e))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let content = fs::read_to_string(&path)?;
+            let fixture: Fixture = serde_json::from_str(&content).map_err(|e| format!("ReplayLLMApi: invalid fixture {}: {}", path.display(), e))?;
+            fixtures.push(fixture);
+        }
+        fixtures.sort_by_key(Fixture::file_name); // Directory order is unspecified; keep drift reports stable
+        Ok(Self { fixture_dir, fixtures, last_usage: Cell::new(None) })
+    }
+
+    fn replay(&self, kind: FixtureKind, prompt: &str, model_name: &str) -> Result<String> {
+        let prompt_hash = Session::compute_hash(prompt);
+        match self.fixtures.iter().find(|f| f.prompt_hash == prompt_hash && f.model == model_name) {
+            Some(fixture) if fixture.kind == kind => {
+                self.last_usage.set(fixture.usage);
+                Ok(fixture.response.clone())
+            }
+            Some(fixture) => Err(format!("ReplayLLMApi: fixture {} was recorded as a {:?} call, not {:?}.", fixture.file_name(), fixture.kind, kind).into()),
+            None => Err(self.describe_miss(prompt, &prompt_hash, model_name).into()),
+        }
+    }
+
+    fn describe_miss(&self, prompt: &str, prompt_hash: &str, model_name: &str) -> String {
+        let mut message = format!(
+            "ReplayLLMApi: no fixture for model `{}` and prompt hash {} in {}.",
+            model_name, &prompt_hash[..16], self.fixture_dir.display()
+        );
+        let Some(closest) = closest_fixture(&self.fixtures, prompt, model_name) else {
+            message.push_str(" The directory holds no fixtures.");
+            return message;
+        };
+        if closest.prompt == prompt {
+            message.push_str(&format!("\nThe prompt matches {}, which was recorded for model `{}` instead.", closest.file_name(), closest.model));
+        } else {
+            message.push_str(&format!(
+                "\nThe prompt has drifted from the closest fixture, {} (model `{}`):\n{}",
+                closest.file_name(), closest.model, line_diff(&closest.prompt, prompt)
+            ));
+        }
+        message.push_str("\nRe-record the fixtures with RecordingLLMApi if the change is intended.");
+        message
+    }
+}
+
+impl LLMApi for ReplayLLMApi {
+    fn generate_content(&self, prompt_text: &str, model_name: &str) -> Result<String> {
+        self.replay(FixtureKind::Text, prompt_text, model_name)
+    }
+
+    fn generate_conversation(&self, conversation: &[ChatTurn], model_name: &str, _response_schema: Option<&serde_json::Value>) -> Result<String> {
+        self.replay(FixtureKind::Text, &flatten_conversation(conversation), model_name)
+    }
+
+    // Tool calling was available while recording if any call used it.
+    fn supports_tools(&self) -> bool {
+        self.fixtures.iter().any(|f| f.kind == FixtureKind::Tools)
+    }
+
+    fn generate_with_tools(&self, conversation: &[ChatTurn], tools: &[ToolDeclaration], model_name: &str) -> Result<ModelReply> {
+        let reply = self.replay(FixtureKind::Tools, &tools_prompt(conversation, tools), model_name)?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    fn take_last_usage(&self) -> Option<TokenUsage> {
+        self.last_usage.take()
+    }
+}
+
+// The fixture whose prompt differs from `prompt` in the fewest lines, preferring fixtures of the same model.
+fn closest_fixture<'a>(fixtures: &'a [Fixture], prompt: &str, model_name: &str) -> Option<&'a Fixture> {
+    let lines: HashSet<&str> = prompt.lines().collect();
+    fixtures.iter().min_by_key(|fixture| {
+        let recorded: HashSet<&str> = fixture.prompt.lines().collect();
+        let changed = lines.symmetric_difference(&recorded).count();
+        (fixture.model != model_name, changed)
+    })
+}
+
+/// Line diff of `recorded` against `actual`: ` ` common, `-` only recorded, `+` only actual.
+/// Common lines are shown only around changes.
+pub fn line_diff(recorded: &str, actual: &str) -> String {
+    let old: Vec<&str> = recorded.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (old_mid, new_mid) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    let mut ops: Vec<(char, &str)> = Vec::new();
+    if old_mid.len() <= MAX_ALIGNED_DIFF_LINES && new_mid.len() <= MAX_ALIGNED_DIFF_LINES {
+        // Longest common subsequence, filled from the end so the walk below goes forward.
+        let mut lcs = vec![vec![0u16; new_mid.len() + 1]; old_mid.len() + 1];
+        for i in (0..old_mid.len()).rev() {
+            for j in (0..new_mid.len()).rev() {
+                lcs[i][j] = if old_mid[i] == new_mid[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < old_mid.len() || j < new_mid.len() {
+            if i < old_mid.len() && j < new_mid.len() && old_mid[i] == new_mid[j] {
+                ops.push((' ', old_mid[i]));
+                i += 1;
+                j += 1;
+            } else if i < old_mid.len() && (j == new_mid.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+                ops.push(('-', old_mid[i]));
+                i += 1;
+            } else {
+                ops.push(('+', new_mid[j]));
+                j += 1;
+            }
+        }
+    } else {
+        ops.extend(old_mid.iter().map(|line| ('-', *line)));
+        ops.extend(new_mid.iter().map(|line| ('+', *line)));
+    }
+
+    let mut diff = format!("@@ line {} @@\n", prefix + 1);
+    if prefix > 0 {
+        diff.push_str(&format!("  {}\n", old[prefix - 1]));
+    }
+    let changed = ops.iter().filter(|(op, _)| *op != ' ').count();
+    for (op, line) in ops.iter().filter(|(op, _)| *op != ' ').take(MAX_DIFF_LINES) {
+        diff.push_str(&format!("{} {}\n", op, line));
+    }
+    if changed > MAX_DIFF_LINES {
+        diff.push_str(&format!("... and {} more changed line(s)\n", changed - MAX_DIFF_LINES));
+    } else if suffix > 0 {
+        diff.push_str(&format!("  {}\n", old[old.len() - suffix]));
+    }
+    diff.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_api::MockLLMApi;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_record_then_replay_by_prompt_and_model() {
+        let dir = tempdir().unwrap();
+        let mut mock = MockLLMApi::new();
+        mock.add_mock_response(Ok("first".to_string()));
+        mock.add_mock_response(Ok("second".to_string()));
+        let recorder = RecordingLLMApi::new(Box::new(mock), dir.path());
+        assert_eq!(recorder.generate_content("prompt one", "model-a").unwrap(), "first");
+        let conversation = vec![ChatTurn::System("Be brief.".to_string()), ChatTurn::User("prompt two".to_string())];
+        assert_eq!(recorder.generate_conversation(&conversation, "org/model-b", None).unwrap(), "second");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        let replay = ReplayLLMApi::new(dir.path()).unwrap();
+        // Order no longer matters, only prompt and model.
+        assert_eq!(replay.generate_conversation(&conversation, "org/model-b", None).unwrap(), "second");
+        assert_eq!(replay.generate_structured("prompt one", "model-a", &serde_json::json!({})).unwrap(), "first");
+        assert!(!replay.supports_tools());
+
+        let wrong_model = replay.generate_content("prompt one", "model-b").unwrap_err().to_string();
+        assert!(wrong_model.contains("recorded for model `model-a` instead"), "{}", wrong_model);
+    }
+
+    #[test]
+    fn test_replay_reports_prompt_drift_as_diff() {
+        let dir = tempdir().unwrap();
+        let fixture = Fixture {
+            prompt_hash: Session::compute_hash("header\nUser Request: \"add a flag\"\nfooter"),
+            model: "model-a".to_string(),
+            kind: FixtureKind::Text,
+            prompt: "header\nUser Request: \"add a flag\"\nfooter".to_string(),
+            response: "{}".to_string(),
+            usage: None,
+        };
+        fs::write(dir.path().join(fixture.file_name()), serde_json::to_string(&fixture).unwrap()).unwrap();
+
+        let replay = ReplayLLMApi::new(dir.path()).unwrap();
+        let error = replay.generate_content("header\nUser Request: \"add two flags\"\nfooter", "model-a").unwrap_err().to_string();
+        assert!(error.contains("The prompt has drifted from the closest fixture"), "{}", error);
+        assert!(error.contains("@@ line 2 @@\n  header\n- User Request: \"add a flag\"\n+ User Request: \"add two flags\"\n  footer"), "{}", error);
+    }
+
+    #[test]
+    fn test_line_diff_aligns_insertions() {
+        assert_eq!(line_diff("a\nb\nc", "a\nx\nb\nc"), "@@ line 2 @@\n  a\n+ x\n  b");
+        assert_eq!(line_diff("a\nb", "a"), "@@ line 2 @@\n  a\n- b");
+    }
+}
@@ -0,0 +1,319 @@
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use indicatif::ProgressBar;
+
+use crate::cli::CustomCliArgs;
+use crate::llm_api::{flatten_conversation, generate_with_continuations, ChatTurn, LLMApi, LLMApiError, TokenUsage};
+use crate::model_router::route_unset_phases_to;
+use crate::Result;
+
+/// Output tokens `LocalLLMApi` allows per answer (continuations included separately).
+pub const DEFAULT_LOCAL_MAX_TOKENS: usize = 2048;
+
+/// What one local generation produced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalGeneration {
+    pub text: String,
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    pub hit_token_limit: bool, // Stopped at `max_tokens` rather than at an end-of-turn token
+}
+
+/// A model that runs in-process: the GGUF CPU backend (`gemma::LocalModel`) or,
+/// with the `mistral_integration` feature, mistral.rs.
+pub trait LocalGenerator {
+    /// The name calls are routed and recorded under.
+    fn model_name(&self) -> &str;
+
+    /// Prompt and answer tokens the model can attend to at once.
+    fn context_window(&self) -> usize;
+
+    /// Answers `prompt` as a single user turn with at most `max_tokens` tokens.
+    fn generate(&self, prompt: &str, max_tokens: usize) -> Result<LocalGeneration>;
+
+    /// Like `generate`, but decoding is constrained to JSON matching `schema` (a Gemini response
+    /// schema, see `llm_api::ResponseSchema`). Generators that cannot constrain decoding answer freely.
+    fn generate_json(&self, prompt: &str, max_tokens: usize, _schema: &serde_json::Value) -> Result<LocalGeneration> {
+        self.generate(prompt, max_tokens)
+    }
+
+    /// Like `generate_json`, but continues `partial`, a constrained answer cut off at the token
+    /// limit, so that `partial` plus the new text matches `schema`.
+    fn generate_json_continuation(&self, prompt: &str, max_tokens: usize, _schema: &serde_json::Value, _partial: &str) -> Result<LocalGeneration> {
+        self.generate(prompt, max_tokens)
+    }
+}
+
+impl<T: LocalGenerator + ?Sized> LocalGenerator for Arc<T> {
+    fn model_name(&self) -> &str {
+        (**self).model_name()
+    }
+
+    fn context_window(&self) -> usize {
+        (**self).context_window()
+    }
+
+    fn generate(&self, prompt: &str, max_tokens: usize) -> Result<LocalGeneration> {
+        (**self).generate(prompt, max_tokens)
+    }
+
+    fn generate_json(&self, prompt: &str, max_tokens: usize, schema: &serde_json::Value) -> Result<LocalGeneration> {
+        (**self).generate_json(prompt, max_tokens, schema)
+    }
+
+    fn generate_json_continuation(&self, prompt: &str, max_tokens: usize, schema: &serde_json::Value, partial: &str) -> Result<LocalGeneration> {
+        (**self).generate_json_continuation(prompt, max_tokens, schema, partial)
+    }
+}
+
+/// What a `--local MODEL` value refers to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LocalModelSource {
+    GgufFile(PathBuf),
+    Directory(PathBuf),
+    ModelId(String), // e.g. "google/gemma-3-1b-it"
+}
+
+impl LocalModelSource {
+    /// Existing files and directories are taken as such (`~/` is expanded); anything else that
+    /// looks like a path is reported missing, and the rest is a model id.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let path = match spec.strip_prefix("~/") {
+            Some(rest) => home_dir().join(rest),
+            None => PathBuf::from(spec),
+        };
+        if path.is_file() {
+            return Ok(LocalModelSource::GgufFile(path));
+        }
+        if path.is_dir() {
+            return Ok(LocalModelSource::Directory(path));
+        }
+        let looks_like_path = path.is_absolute() || spec.starts_with('.') || spec.starts_with('~') || spec.ends_with(".gguf");
+        if looks_like_path {
+            return Err(format!("Local model {} does not exist.", path.display()).into());
+        }
+        Ok(LocalModelSource::ModelId(spec.to_string()))
+    }
+}
+
+fn home_dir() -> PathBuf {
+    PathBuf::from(env::var("HOME").or_else(|_| env::var("USERPROFILE")).unwrap_or_else(|_| ".".to_string()))
+}
+
+/// `~/.gem/models`, where model ids given to `--local` are looked up.
+pub fn models_dir() -> PathBuf {
+    home_dir().join(".gem").join("models")
+}
+
+/// The paths a model id may be stored under in `models_dir`: `<id>/`, `<id>.gguf` and `<name>.gguf`
+/// (the id without its organisation, e.g. `gemma-3-1b-it.gguf`).
+pub fn model_id_candidates(models_dir: &Path, id: &str) -> Vec<PathBuf> {
+    let name = id.rsplit('/').next().unwrap_or(id);
+    let mut candidates = vec![models_dir.join(id), models_dir.join(format!("{}.gguf", id)), models_dir.join(format!("{}.gguf", name))];
+    candidates.dedup();
+    candidates
+}
+
+/// `LLMApi` over a `LocalGenerator`, so the agent runs offline exactly as it does against Gemini.
+/// Conversations are flattened into one prompt; answers cut off at `max_tokens` are continued
+/// like truncated API answers. Answers with a response schema are decoded under a JSON constraint.
+pub struct LocalLLMApi {
+    generator: Box<dyn LocalGenerator>,
+    max_tokens: usize,
+    last_usage: Cell<Option<TokenUsage>>,
+    progress: RefCell<Option<ProgressBar>>,
+}
+
+impl LocalLLMApi {
+    pub fn new(generator: Box<dyn LocalGenerator>) -> Self {
+        Self { generator, max_tokens: DEFAULT_LOCAL_MAX_TOKENS, last_usage: Cell::new(None), progress: RefCell::new(None) }
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    pub fn model_name(&self) -> &str {
+        self.generator.model_name()
+    }
+
+    /// Points every agent phase without a `--model` override at the local model and caps the
+    /// prompt budget to what fits next to an answer.
+    pub fn configure_agent_args(&self, args: &mut CustomCliArgs) {
+        route_unset_phases_to(&mut args.model_overrides, self.model_name());
+        let budget = self.generator.context_window().saturating_sub(self.max_tokens);
+        args.max_prompt_tokens = Some(args.max_prompt_tokens.map_or(budget, |limit| limit.min(budget)));
+    }
+}
+
+impl LLMApi for LocalLLMApi {
+    fn generate_content(&self, prompt_text: &str, model_name: &str) -> Result<String> {
+        self.generate_conversation(&[ChatTurn::User(prompt_text.to_string())], model_name, None)
+    }
+
+    fn generate_conversation(
+        &self,
+        conversation: &[ChatTurn],
+        _model_name: &str,
+        response_schema: Option<&serde_json::Value>,
+    ) -> Result<String> {
+        let progress = self.progress.borrow();
+        let asked = conversation.len();
+        let (text, usage) = generate_with_continuations(conversation, progress.as_ref(), |conversation, continuing| {
+            let prompt = flatten_conversation(conversation);
+            let generation = match (response_schema, continuing) {
+                (Some(schema), false) => self.generator.generate_json(&prompt, self.max_tokens, schema)?,
+                // The constraint carries on from the cut-off parts, which follow the original conversation.
+                (Some(schema), true) => {
+                    let partial: String = conversation[asked..]
+                        .iter()
+                        .filter_map(|turn| match turn {
+                            ChatTurn::Model(reply) => Some(reply.text.as_str()),
+                            _ => None,
+                        })
+                        .collect();
+                    self.generator.generate_json_continuation(&prompt, self.max_tokens, schema, &partial)?
+                }
+                (None, _) => self.generator.generate(&prompt, self.max_tokens)?,
+            };
+            let usage = Some(TokenUsage {
+                prompt_tokens: generation.prompt_tokens as u64,
+                candidate_tokens: generation.generated_tokens as u64,
+                ..TokenUsage::default()
+            });
+            if generation.hit_token_limit {
+                return Err(Box::new(LLMApiError::Truncated { partial: generation.text, usage }));
+            }
+            Ok((generation.text, usage))
+        })?;
+        self.last_usage.set(usage);
+        Ok(text)
+    }
+
+    fn take_last_usage(&self) -> Option<TokenUsage> {
+        self.last_usage.take()
+    }
+
+    fn set_progress_bar(&self, pb: Option<ProgressBar>) {
+        *self.progress.borrow_mut() = pb;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gemma::LocalModel;
+    use crate::model_router::AgentPhase;
+    use crate::llm_api::{GeminiNeededItemsResponse, ResponseSchema};
+    use clap::Parser;
+    use std::rc::Rc;
+
+    // Answers with fixed texts, one "token" per character; an answer that fills `max_tokens` counts as cut off.
+    struct ScriptedGenerator {
+        answers: RefCell<Vec<&'static str>>,
+        prompts: Rc<RefCell<Vec<(String, usize)>>>, // Each prompt with the `max_tokens` it was given
+        json_calls: Rc<Cell<usize>>,
+        continued: Rc<RefCell<Vec<String>>>, // The partial answer of each constrained continuation
+    }
+
+    impl LocalGenerator for ScriptedGenerator {
+        fn model_name(&self) -> &str {
+            "scripted-local"
+        }
+
+        fn context_window(&self) -> usize {
+            8_192
+        }
+
+        fn generate(&self, prompt: &str, max_tokens: usize) -> Result<LocalGeneration> {
+            self.prompts.borrow_mut().push((prompt.to_string(), max_tokens));
+            let answer = self.answers.borrow_mut().remove(0);
+            let text: String = answer.chars().take(max_tokens).collect();
+            Ok(LocalGeneration {
+                prompt_tokens: prompt.len(),
+                generated_tokens: text.chars().count(),
+                hit_token_limit: text.chars().count() == max_tokens,
+                text,
+            })
+        }
+
+        fn generate_json(&self, prompt: &str, max_tokens: usize, _schema: &serde_json::Value) -> Result<LocalGeneration> {
+            self.json_calls.set(self.json_calls.get() + 1);
+            self.generate(prompt, max_tokens)
+        }
+
+        fn generate_json_continuation(&self, prompt: &str, max_tokens: usize, _schema: &serde_json::Value, partial: &str) -> Result<LocalGeneration> {
+            self.continued.borrow_mut().push(partial.to_string());
+            self.generate(prompt, max_tokens)
+        }
+    }
+
+    #[test]
+    fn test_answers_cut_off_at_max_tokens_are_continued() {
+        let prompts = Rc::new(RefCell::new(Vec::new()));
+        let generator = ScriptedGenerator {
+            answers: RefCell::new(vec!["{\"needed_items\"", ": [\"fn main\", \"", "fn run\"]}"]),
+            prompts: prompts.clone(),
+            json_calls: Rc::default(),
+            continued: Rc::default(),
+        };
+        let (json_calls, continued) = (generator.json_calls.clone(), generator.continued.clone());
+        let api = LocalLLMApi::new(Box::new(generator)).with_max_tokens(15);
+        let conversation = [ChatTurn::System("Answer in JSON.".to_string()), ChatTurn::User("What do you need?".to_string())];
+        let schema = GeminiNeededItemsResponse::response_schema();
+
+        assert_eq!(api.generate_conversation(&conversation, "scripted-local", Some(&schema)).unwrap(), "{\"needed_items\": [\"fn main\", \"fn run\"]}");
+        assert_eq!(json_calls.get(), 1);
+        // Each continuation stays constrained, carrying on from everything cut off so far.
+        assert_eq!(*continued.borrow(), vec!["{\"needed_items\"", "{\"needed_items\": [\"fn main\", \""]);
+        assert_eq!(api.take_last_usage().unwrap().candidate_tokens, 15 + 15 + 9);
+        let prompts = prompts.borrow();
+        assert_eq!(prompts.iter().map(|(_, max_tokens)| *max_tokens).collect::<Vec<_>>(), vec![15, 15, 15]);
+        assert!(prompts[1].0.contains("MODEL RESPONSE:\n{\"needed_items\"") && prompts[1].0.contains("was cut off"), "{}", prompts[1].0);
+    }
+
+    #[test]
+    fn test_configure_agent_args_routes_phases_to_the_local_model() {
+        let generator = ScriptedGenerator { answers: RefCell::new(Vec::new()), prompts: Rc::default(), json_calls: Rc::default(), continued: Rc::default() };
+        let api = LocalLLMApi::new(Box::new(generator)).with_max_tokens(1_000);
+        let mut args = CustomCliArgs::try_parse_from(["gem", "--model", "retry=other-local", "request"]).unwrap();
+
+        api.configure_agent_args(&mut args);
+        let router = crate::model_router::ModelRouter::with_overrides(&args.model_overrides);
+        assert_eq!(router.chain(AgentPhase::Initial), ["scripted-local"]);
+        assert_eq!(router.chain(AgentPhase::Change), ["scripted-local"]);
+        assert_eq!(router.chain(AgentPhase::Retry), ["other-local"]);
+        assert_eq!(args.max_prompt_tokens, Some(7_192));
+    }
+
+    #[test]
+    fn test_local_model_source_parse() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/local_models");
+        let file = fixtures.join("tiny-llama.gguf");
+        assert_eq!(LocalModelSource::parse(file.to_str().unwrap()).unwrap(), LocalModelSource::GgufFile(file));
+        assert_eq!(LocalModelSource::parse(fixtures.to_str().unwrap()).unwrap(), LocalModelSource::Directory(fixtures));
+        assert_eq!(LocalModelSource::parse("google/gemma-3-1b-it").unwrap(), LocalModelSource::ModelId("google/gemma-3-1b-it".to_string()));
+
+        let error = LocalModelSource::parse("./models/missing.gguf").unwrap_err().to_string();
+        assert_eq!(error, "Local model ./models/missing.gguf does not exist.");
+        assert!(LocalModelSource::parse("missing.gguf").is_err());
+    }
+
+    #[test]
+    fn test_gguf_model_answers_through_llm_api() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/local_models/tiny-llama.gguf");
+        let model = LocalModel::load(&fixture).unwrap();
+        let generation = LocalGenerator::generate(&model, "hello world", 3).unwrap();
+        assert_eq!((generation.generated_tokens, generation.hit_token_limit), (3, true));
+
+        let api = LocalLLMApi::new(Box::new(model)).with_max_tokens(2);
+        assert_eq!(api.model_name(), "tiny-llama");
+        // The random model never finishes, and the continuation request no longer fits its 128-token context.
+        let error = api.generate_content("hello world", "tiny-llama").unwrap_err().to_string();
+        assert!(error.contains("the local model's context holds only 128"), "{}", error);
+    }
+}
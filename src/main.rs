@@ -1,30 +1,27 @@
 use anyhow::Result;
 use clap::Parser;
 use gem::cli::CustomCliArgs; // Assuming your CLI args are defined here
-use std::io::{self, IsTerminal, Read};
-
-// Modules for model loading and inference logic
-mod gemma;
-mod inference;
+use std::io::{self, IsTerminal};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse command line arguments using CustomCliArgs
-    let args = CustomCliArgs::parse();
+    let mut args = CustomCliArgs::parse();
+
+    if args.usage_report {
+        let calls = gem::usage::load_all_call_records(&gem::cache::sessions_dir());
+        println!("{}", gem::usage::format_usage_report(&calls));
+        return Ok(());
+    }
 
     // Determine the user's request from arguments or stdin
-    let user_request = if !args.user_request_parts.is_empty() {
-        args.user_request_parts.join(" ")
-    } else if !io::stdin().is_terminal() {
-        // Check if stdin is being piped
-        let mut buffer = String::new();
-        io::stdin().read_to_string(&mut buffer)?;
-        buffer.trim().to_string() // Trim whitespace, especially trailing newlines
-    } else {
+    if args.user_request_parts.is_empty() && io::stdin().is_terminal() {
         // No user request provided via argument or stdin pipe
         eprintln!("Error: No user request provided. Please provide a request via arguments or pipe it through stdin.");
         std::process::exit(1);
-    };
+    }
+    let request_from_stdin = args.read_request_from_stdin(io::stdin())?;
+    let user_request = args.user_request_parts.join(" ");
 
     if user_request.is_empty() {
         eprintln!("Error: User request is empty.");
@@ -47,78 +44,67 @@ async fn main() -> Result<()> {
         println!("--auto-tool-selection recognized. Tools will be selected automatically (Not yet fully implemented).");
     }
 
-    if args.local {
+    let llm_api: Box<dyn gem::llm_api::LLMApi> = if let Some(model) = args.local.clone() {
+        // Local model on this machine; the agent runs offline.
         #[cfg(feature = "mistral_integration")]
-        {
-            // Mistral local inference path
-            match inference::InferenceEngine::initialize().await {
-                Ok(_) => {
-                    println!("Mistral Inference engine initialized.");
-                    match inference::InferenceEngine::generate_text(&user_request, 100).await {
-                        Ok(response) => {
-                            println!("\nLLM Response (Mistral):\n{}", response);
-                        }
-                        Err(e) => {
-                            eprintln!("Error generating LLM response from Mistral: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error initializing Mistral inference engine: {}", e);
-                    std::process::exit(1);
-                }
+        let generator: Box<dyn gem::local_llm::LocalGenerator> = match gem::inference::MistralGenerator::new(&model).await {
+            Ok(generator) => Box::new(generator),
+            Err(e) => {
+                eprintln!("Error initializing Mistral inference engine: {}", e);
+                std::process::exit(1);
             }
-        }
+        };
         #[cfg(not(feature = "mistral_integration"))]
-        {
-            // Gemma local inference path
-            println!("Mistral integration not built. Using local Gemma model.");
-            match gemma::run_local_gemma(&user_request) {
-                Ok(response) => {
-                    println!("\nLLM Response (Gemma):\n{}", response);
-                }
+        let generator: Box<dyn gem::local_llm::LocalGenerator> = match gem::gemma::load_cached(&model) {
+            Ok(model) => Box::new(model),
+            Err(e) => {
+                eprintln!("Error loading local model: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let local = gem::local_llm::LocalLLMApi::new(generator);
+        println!("Using local model {} via run_gem_agent.", local.model_name());
+        local.configure_agent_args(&mut args);
+        Box::new(local)
+    } else if let Some(url) = &args.browser {
+        // Web chat in a browser driven through WebDriver; each prompt is typed into the page.
+        let selectors = gem::browser_interaction::ChatSelectors::new(
+            args.input_selector.as_deref(),
+            args.codeblock_selector.as_deref(),
+            args.finished_selector.as_deref(),
+        );
+        let browser = gem::browser_interaction::BrowserLLMApi::new(&args.webdriver_url, url, selectors);
+        println!("Using the web chat at {} via run_gem_agent.", url);
+        browser.configure_agent_args(&mut args);
+        Box::new(browser)
+    } else if args.manual {
+        // Copy-paste through any chat UI. A request piped on stdin used it up, so answers are read from the terminal.
+        let input: Box<dyn io::BufRead> = if request_from_stdin {
+            match std::fs::File::open("/dev/tty") {
+                Ok(tty) => Box::new(io::BufReader::new(tty)),
                 Err(e) => {
-                    eprintln!("Error generating LLM response from Gemma: {}", e);
+                    eprintln!("Error: --manual needs a terminal to paste answers into ({}). Pass the request as an argument instead.", e);
                     std::process::exit(1);
                 }
             }
+        } else {
+            Box::new(io::BufReader::new(io::stdin()))
+        };
+        let mut manual = gem::manual_llm::ManualLLMApi::new(input, Box::new(io::stdout()), &args.end_marker).with_clipboard(args.clipboard);
+        if let Some(path) = &args.prompt_file {
+            manual = manual.with_prompt_file(path.clone());
         }
-    } else if args.browser.is_some() {
-        // Browser interaction path
-        println!("Browser URL specified. Initiating browser interaction.");
-
-        // Extract necessary arguments for handle_browser_request
-        // The URL is guaranteed to be Some by the if condition.
-        let url = args.browser.as_ref().unwrap(); // Safe due to the if condition
-        let input_selector = args.input_selector.as_deref();
-        let codeblock_selector = args.codeblock_selector.as_deref();
-        let finished_selector = args.finished_selector.as_deref();
-
-        match gem::browser_interaction::execute_browser_interaction_task(
-            url,
-            input_selector,
-            codeblock_selector,
-            finished_selector,
-            &user_request, // user_request is already prepared
-        ) {
-            Ok(code_blocks) => {
-                if code_blocks.is_empty() {
-                    println!("Browser interaction completed. No code blocks were extracted.");
-                } else {
-                    println!("Browser interaction completed. Extracted code blocks:");
-                    for (i, block) in code_blocks.iter().enumerate() {
-                        println!("\n--- Code Block {} ---", i + 1);
-                        println!("{}", block);
-                        println!("--- End Code Block {} ---", i + 1);
-                    }
-                }
-            }
-            Err(e) => {
-                eprintln!("Browser interaction failed: {}", e);
-                std::process::exit(1);
-            }
-        }
+        println!("Using manual copy-paste mode via run_gem_agent.");
+        manual.configure_agent_args(&mut args);
+        Box::new(manual)
+    } else if let Some(base_url) = &args.openai_base_url {
+        // OpenAI-compatible chat completions server (llama.cpp server, vLLM, LiteLLM, ...)
+        println!("Using OpenAI-compatible API at {} via run_gem_agent.", base_url);
+        let api_key = std::env::var("OPENAI_API_KEY").ok().filter(|key| !key.is_empty());
+        Box::new(
+            gem::llm_api::OpenAICompatibleLLMApi::new(base_url.clone(), args.openai_model.clone(), api_key)
+                .with_retry_policy(gem::llm_api::RetryPolicy::with_max_retries(args.max_api_retries)),
+        )
     } else {
         // Default to Gemini HTTP API via run_gem_agent
         println!("Using Gemini HTTP API via run_gem_agent.");
@@ -136,24 +122,53 @@ async fn main() -> Result<()> {
             std::process::exit(1);
         }
 
-        let llm_api = gem::llm_api::RealLLMApi::new(gemini_api_key);
+        // Stream by default so partial output shows up under the spinner as it arrives.
+        let mut gemini = gem::llm_api::RealLLMApi::new(gemini_api_key)
+            .with_streaming(!args.no_stream)
+            .with_retry_policy(gem::llm_api::RetryPolicy::with_max_retries(args.max_api_retries));
+        if args.context_cache_ttl > 0 {
+            gemini = gemini.with_context_cache(gem::llm_api::ContextCacheConfig {
+                ttl: std::time::Duration::from_secs(args.context_cache_ttl),
+                min_tokens: args.context_cache_min_tokens,
+            });
+        }
+        Box::new(gemini)
+    };
 
-        // Create a session ID based on the user request.
-        let session_id_str = format!("gem_session_{}", user_request.chars().take(20).collect::<String>());
-        let session_id = gem::cache::Session::compute_hash(&session_id_str);
-        let mut session = gem::cache::Session::new(&session_id);
+    // First Ctrl-C cancels the running request and lets the agent save its session;
+    // a second one quits immediately.
+    tokio::spawn(async {
+        let mut interrupted = false;
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if interrupted {
+                eprintln!("\ngem: Interrupted again, exiting.");
+                std::process::exit(130);
+            }
+            interrupted = true;
+            eprintln!("\ngem: Cancelling... (press Ctrl-C again to quit immediately)");
+            gem::llm_api::request_cancel();
+        }
+    });
 
-        let is_interactive = io::stdout().is_terminal();
+    // Create a session ID based on the user request.
+    let session_id_str = format!("gem_session_{}", user_request.chars().take(20).collect::<String>());
+    let session_id = gem::cache::Session::compute_hash(&session_id_str);
+    let mut session = gem::cache::Session::new(&session_id);
 
-        // `args.project_root` is already a PathBuf.
-        // Clone project_root before moving args.
-        let project_root_clone = args.project_root.clone();
-        match gem::run_gem_agent(args, &mut session, Box::new(llm_api), is_interactive, project_root_clone) {
-            Ok(_) => println!("Gem agent finished successfully."),
-            Err(e) => {
-                eprintln!("Gem agent failed: {}", e);
-                std::process::exit(1);
-            }
+    let is_interactive = io::stdout().is_terminal();
+
+    // `args.project_root` is already a PathBuf.
+    // Clone project_root before moving args.
+    let project_root_clone = args.project_root.clone();
+    // The LLM backends use blocking HTTP clients, which must not run directly on an async worker.
+    let agent_result = tokio::task::block_in_place(|| {
+        gem::run_gem_agent(args, &mut session, llm_api, is_interactive, project_root_clone)
+    });
+    match agent_result {
+        Ok(_) => println!("Gem agent finished successfully."),
+        Err(e) => {
+            eprintln!("Gem agent failed: {}", e);
+            std::process::exit(1);
         }
     }
     Ok(())
@@ -0,0 +1,230 @@
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use indicatif::ProgressBar;
+use regex::Regex;
+
+use crate::cli::CustomCliArgs;
+use crate::llm_api::{answer_from_code_blocks, flatten_conversation, ChatTurn, LLMApi, JSON_CODE_BLOCK_INSTRUCTION};
+use crate::model_router::route_unset_phases_to;
+use crate::Result;
+
+/// Name calls are routed and recorded under.
+pub const MANUAL_MODEL_NAME: &str = "manual";
+
+// Tried in order; the first one that runs receives the prompt on stdin.
+const CLIPBOARD_COMMANDS: &[&[&str]] = &[
+    &["pbcopy"],
+    &["wl-copy"],
+    &["xclip", "-selection", "clipboard"],
+    &["xsel", "--clipboard", "--input"],
+    &["clip"],
+];
+
+/// `LLMApi` where the user is the transport: each prompt is shown in the terminal (or written to a
+/// file, optionally also copied to the clipboard), the user pastes it into any chat UI and pastes
+/// the answer back, ending it with the end marker line or EOF. Every prompt holds the whole
+/// conversation, so it can go into a new chat or the same one.
+pub struct ManualLLMApi {
+    input: RefCell<Box<dyn BufRead>>,
+    output: RefCell<Box<dyn Write>>,
+    prompt_file: Option<PathBuf>,
+    clipboard: bool,
+    end_marker: String,
+    exchanges: Cell<usize>,
+    progress: RefCell<Option<ProgressBar>>,
+}
+
+impl ManualLLMApi {
+    /// Reads pasted answers from `input` and shows prompts and instructions on `output`.
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>, end_marker: &str) -> Self {
+        Self {
+            input: RefCell::new(input),
+            output: RefCell::new(output),
+            prompt_file: None,
+            clipboard: false,
+            end_marker: end_marker.to_string(),
+            exchanges: Cell::new(0),
+            progress: RefCell::new(None),
+        }
+    }
+
+    /// Writes each prompt to `path` (replacing the previous one) instead of showing it.
+    pub fn with_prompt_file(mut self, path: PathBuf) -> Self {
+        self.prompt_file = Some(path);
+        self
+    }
+
+    /// Also copies each prompt to the system clipboard.
+    pub fn with_clipboard(mut self, clipboard: bool) -> Self {
+        self.clipboard = clipboard;
+        self
+    }
+
+    pub fn model_name(&self) -> &str {
+        MANUAL_MODEL_NAME
+    }
+
+    /// Points every agent phase without a `--model` override at the manual backend.
+    pub fn configure_agent_args(&self, args: &mut CustomCliArgs) {
+        route_unset_phases_to(&mut args.model_overrides, MANUAL_MODEL_NAME);
+    }
+
+    fn exchange(&self, prompt: &str) -> Result<String> {
+        let exchange = self.exchanges.get() + 1;
+        self.exchanges.set(exchange);
+        let mut output = self.output.borrow_mut();
+
+        match &self.prompt_file {
+            Some(path) => {
+                fs::write(path, prompt).map_err(|e| format!("Failed to write the prompt to {}: {}", path.display(), e))?;
+                writeln!(output, "\n=== gem prompt {} written to {} ===", exchange, path.display())?;
+            }
+            None => {
+                writeln!(output, "\n=== gem prompt {} (copy everything up to the closing line) ===", exchange)?;
+                writeln!(output, "{}", prompt)?;
+                writeln!(output, "=== end of gem prompt {} ===", exchange)?;
+            }
+        }
+        if self.clipboard {
+            match copy_to_clipboard(prompt) {
+                Ok(()) => writeln!(output, "The prompt is on the clipboard.")?,
+                Err(e) => writeln!(output, "gem: WARN: {}", e)?,
+            }
+        }
+        writeln!(output, "Paste the model's answer, then a line with {} (or Ctrl-D):", self.end_marker)?;
+        output.flush()?;
+
+        let answer = read_answer(&mut **self.input.borrow_mut(), &self.end_marker)?;
+        if answer.trim().is_empty() {
+            return Err(format!("No answer was pasted for gem prompt {}.", exchange).into());
+        }
+        Ok(answer_from_pasted_text(&answer))
+    }
+}
+
+impl LLMApi for ManualLLMApi {
+    fn generate_content(&self, prompt_text: &str, model_name: &str) -> Result<String> {
+        self.generate_conversation(&[ChatTurn::User(prompt_text.to_string())], model_name, None)
+    }
+
+    fn generate_conversation(
+        &self,
+        conversation: &[ChatTurn],
+        _model_name: &str,
+        response_schema: Option<&serde_json::Value>,
+    ) -> Result<String> {
+        let mut prompt = flatten_conversation(conversation);
+        if response_schema.is_some() {
+            prompt.push_str(JSON_CODE_BLOCK_INSTRUCTION);
+        }
+        // The spinner would redraw over the prompt and the pasted answer.
+        match self.progress.borrow().as_ref() {
+            Some(pb) => pb.suspend(|| self.exchange(&prompt)),
+            None => self.exchange(&prompt),
+        }
+    }
+
+    fn set_progress_bar(&self, pb: Option<ProgressBar>) {
+        *self.progress.borrow_mut() = pb;
+    }
+}
+
+// Lines up to the end marker line or EOF, without the marker.
+fn read_answer(input: &mut dyn BufRead, end_marker: &str) -> Result<String> {
+    let mut answer = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 || line.trim() == end_marker {
+            return Ok(answer);
+        }
+        answer.push_str(&line);
+    }
+}
+
+// Chat UIs' "copy" buttons give the whole answer as Markdown; the answer is in its fenced code
+// blocks when it has any, and the pasted text otherwise.
+fn answer_from_pasted_text(text: &str) -> String {
+    let fence = Regex::new(r"(?ms)^[ \t]*```[^\n`]*\n(.*?)\n[ \t]*```[ \t]*$").expect("valid code fence regex");
+    let blocks: Vec<String> = fence.captures_iter(text).map(|block| block[1].to_string()).collect();
+    answer_from_code_blocks(&blocks).unwrap_or_else(|| text.trim().to_string())
+}
+
+fn copy_to_clipboard(text: &str) -> Result<()> {
+    for command in CLIPBOARD_COMMANDS {
+        let Ok(mut child) = Command::new(command[0]).args(&command[1..]).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null()).spawn() else {
+            continue;
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        }
+        if child.wait()?.success() {
+            return Ok(());
+        }
+    }
+    let tried: Vec<&str> = CLIPBOARD_COMMANDS.iter().map(|command| command[0]).collect();
+    Err(format!("Could not copy the prompt to the clipboard (tried {}).", tried.join(", ")).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedOutput {
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.borrow()).into_owned()
+        }
+    }
+
+    #[test]
+    fn test_answers_end_at_the_marker_or_eof() {
+        let output = SharedOutput::default();
+        let pasted = "first answer\nover two lines\n---END---\nsecond answer\n";
+        let api = ManualLLMApi::new(Box::new(Cursor::new(pasted)), Box::new(output.clone()), "---END---");
+
+        assert_eq!(api.generate_content("Question one?", "manual").unwrap(), "first answer\nover two lines");
+        assert_eq!(api.generate_content("Question two?", "manual").unwrap(), "second answer");
+        let error = api.generate_content("Question three?", "manual").unwrap_err().to_string();
+        assert_eq!(error, "No answer was pasted for gem prompt 3.");
+
+        let shown = output.text();
+        assert!(shown.contains("=== gem prompt 1 (copy everything up to the closing line) ===\nQuestion one?\n=== end of gem prompt 1 ==="), "{}", shown);
+        assert!(shown.contains("then a line with ---END--- (or Ctrl-D)"), "{}", shown);
+    }
+
+    #[test]
+    fn test_json_prompts_ask_for_a_code_block_and_answers_are_read_from_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let prompt_file = dir.path().join("prompt.md");
+        let output = SharedOutput::default();
+        let pasted = "Sure! Here is an example:\n```json\n{\"sufficient\": false}\n```\nActually, this one:\n```json\n{\"sufficient\": true}\n```\nHope that helps.\n";
+        let api = ManualLLMApi::new(Box::new(Cursor::new(pasted)), Box::new(output.clone()), "---END---").with_prompt_file(prompt_file.clone());
+
+        let conversation = [ChatTurn::User("Is this enough?".to_string())];
+        let answer = api.generate_conversation(&conversation, "manual", Some(&serde_json::json!({ "type": "OBJECT" }))).unwrap();
+
+        assert_eq!(answer, "{\"sufficient\": true}");
+        assert_eq!(fs::read_to_string(&prompt_file).unwrap(), format!("Is this enough?{}", JSON_CODE_BLOCK_INSTRUCTION));
+        assert!(output.text().contains(&format!("gem prompt 1 written to {}", prompt_file.display())));
+        assert!(!output.text().contains("Is this enough?"));
+    }
+}
@@ -0,0 +1,250 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::llm_api::LLMApiError;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// The agent phases that talk to the model. Each one has its own ordered model chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AgentPhase {
+    Initial,
+    Sufficient,
+    Change,
+    Retry, // Code generation after a failed verification
+}
+
+impl AgentPhase {
+    pub const ALL: [AgentPhase; 4] = [AgentPhase::Initial, AgentPhase::Sufficient, AgentPhase::Change, AgentPhase::Retry];
+}
+
+impl fmt::Display for AgentPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            AgentPhase::Initial => "initial",
+            AgentPhase::Sufficient => "sufficient",
+            AgentPhase::Change => "change",
+            AgentPhase::Retry => "retry",
+        };
+        write!(f, "{s}")
+    }
+}
+
+impl std::str::FromStr for AgentPhase {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "initial" => Ok(AgentPhase::Initial),
+            "sufficient" => Ok(AgentPhase::Sufficient),
+            "change" | "changes" => Ok(AgentPhase::Change),
+            "retry" => Ok(AgentPhase::Retry),
+            _ => Err(format!("invalid agent phase: {} (expected initial, sufficient, change or retry)", s)),
+        }
+    }
+}
+
+/// A `--model PHASE=MODEL[,MODEL...]` command line override.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelChainOverride {
+    pub phase: AgentPhase,
+    pub models: Vec<String>,
+}
+
+pub fn parse_model_chain_override(s: &str) -> std::result::Result<ModelChainOverride, String> {
+    let (phase, models) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid model override: {} (expected PHASE=MODEL[,MODEL...])", s))?;
+    let models: Vec<String> = models
+        .split(',')
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+        .map(str::to_string)
+        .collect();
+    if models.is_empty() {
+        return Err(format!("invalid model override: {} (no models given)", s));
+    }
+    Ok(ModelChainOverride { phase: phase.parse()?, models })
+}
+
+/// Adds an override sending every phase without one to `model` alone, for backends that are a
+/// single model (a local model, a web chat) where the Gemini fallback chains mean nothing.
+pub fn route_unset_phases_to(overrides: &mut Vec<ModelChainOverride>, model: &str) {
+    for phase in AgentPhase::ALL {
+        if !overrides.iter().any(|o| o.phase == phase) {
+            overrides.push(ModelChainOverride { phase, models: vec![model.to_string()] });
+        }
+    }
+}
+
+/// Picks the model for each agent phase and falls back along the phase's chain when a model
+/// runs out of quota or fails. Models that hit their quota are skipped for the rest of the run.
+pub struct ModelRouter {
+    chains: HashMap<AgentPhase, Vec<String>>,
+    exhausted: RefCell<HashSet<String>>,
+}
+
+impl Default for ModelRouter {
+    fn default() -> Self {
+        let fast = vec!["gemini-2.5-flash".to_string(), "gemini-2.5-flash-lite".to_string()];
+        let capable = vec!["gemini-2.5-pro".to_string(), "gemini-2.5-flash".to_string()];
+        let mut chains = HashMap::new();
+        chains.insert(AgentPhase::Initial, fast.clone());
+        chains.insert(AgentPhase::Sufficient, fast);
+        chains.insert(AgentPhase::Change, capable.clone());
+        chains.insert(AgentPhase::Retry, capable);
+        Self { chains, exhausted: RefCell::new(HashSet::new()) }
+    }
+}
+
+impl ModelRouter {
+    pub fn with_overrides(overrides: &[ModelChainOverride]) -> Self {
+        let mut router = Self::default();
+        for o in overrides {
+            router.set_chain(o.phase, o.models.clone());
+        }
+        router
+    }
+
+    pub fn set_chain(&mut self, phase: AgentPhase, models: Vec<String>) {
+        self.chains.insert(phase, models);
+    }
+
+    pub fn chain(&self, phase: AgentPhase) -> &[String] {
+        self.chains.get(&phase).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Runs `call` with the first usable model of the phase's chain, falling back along the chain
+    /// on failure. Returns the result together with the model that produced it.
+    pub fn route<T>(&self, phase: AgentPhase, mut call: impl FnMut(&str) -> Result<T>) -> Result<(T, String)> {
+        let chain = self.chain(phase);
+        let candidates: Vec<&String> = chain
+            .iter()
+            .filter(|m| !self.exhausted.borrow().contains(*m))
+            .collect();
+        // If every model is marked exhausted, try the whole chain again rather than failing outright.
+        let candidates = if candidates.is_empty() { chain.iter().collect() } else { candidates };
+        if candidates.is_empty() {
+            return Err(format!("No models configured for the {} phase.", phase).into());
+        }
+
+        let mut last_error = None;
+        for (i, model) in candidates.iter().enumerate() {
+            match call(model) {
+                Ok(response) => return Ok((response, model.to_string())),
+                Err(e) => {
+                    let api_error = e.downcast_ref::<LLMApiError>();
+                    if matches!(api_error, Some(LLMApiError::Auth { .. }) | Some(LLMApiError::Cancelled)) {
+                        return Err(e); // Another model will not fix a bad key or a Ctrl-C
+                    }
+                    if matches!(api_error, Some(LLMApiError::Quota { .. })) {
+                        self.exhausted.borrow_mut().insert(model.to_string());
+                    }
+                    if let Some(next) = candidates.get(i + 1) {
+                        eprintln!("gem: WARN: Model {} failed for the {} phase ({}). Falling back to {}.", model, phase, e, next);
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("at least one model was tried"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_api::{ChatTurn, LLMApi};
+    use std::cell::RefCell;
+
+    // Fails with the configured error for the listed models and records every model it was asked for.
+    struct FlakyModels {
+        failing: HashMap<String, fn() -> LLMApiError>,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl LLMApi for FlakyModels {
+        fn generate_content(&self, _prompt_text: &str, model_name: &str) -> Result<String> {
+            self.calls.borrow_mut().push(model_name.to_string());
+            match self.failing.get(model_name) {
+                Some(make_error) => Err(Box::new(make_error())),
+                None => Ok(format!("answer from {}", model_name)),
+            }
+        }
+    }
+
+    fn quota() -> LLMApiError {
+        LLMApiError::Quota { status: 429, message: "quota".to_string(), retry_after: None }
+    }
+
+    fn auth() -> LLMApiError {
+        LLMApiError::Auth { status: 401, message: "bad key".to_string() }
+    }
+
+    fn router(models: &[&str]) -> ModelRouter {
+        let mut router = ModelRouter::default();
+        router.set_chain(AgentPhase::Change, models.iter().map(|m| m.to_string()).collect());
+        router
+    }
+
+    // Routes a code generation call the way the agent does.
+    fn change(router: &ModelRouter, api: &FlakyModels) -> Result<(String, String)> {
+        let conversation = [ChatTurn::User("prompt".to_string())];
+        router.route(AgentPhase::Change, |model| api.generate_conversation(&conversation, model, None))
+    }
+
+    #[test]
+    fn test_parse_model_chain_override() {
+        let o = parse_model_chain_override("change=gemini-2.5-pro, gemini-2.5-flash").unwrap();
+        assert_eq!(o.phase, AgentPhase::Change);
+        assert_eq!(o.models, vec!["gemini-2.5-pro", "gemini-2.5-flash"]);
+
+        assert!(parse_model_chain_override("gemini-2.5-pro").is_err());
+        assert!(parse_model_chain_override("deploy=gemini-2.5-pro").is_err());
+        assert!(parse_model_chain_override("retry=").is_err());
+    }
+
+    #[test]
+    fn test_with_overrides_replaces_only_given_phases() {
+        let router = ModelRouter::with_overrides(&[parse_model_chain_override("retry=big-model").unwrap()]);
+        assert_eq!(router.chain(AgentPhase::Retry), ["big-model".to_string()]);
+        assert_eq!(router.chain(AgentPhase::Initial), ModelRouter::default().chain(AgentPhase::Initial));
+    }
+
+    #[test]
+    fn test_route_falls_back_on_quota_and_skips_exhausted_model() {
+        let api = FlakyModels { failing: HashMap::from([("pro".to_string(), quota as fn() -> LLMApiError)]), calls: RefCell::new(vec![]) };
+        let router = router(&["pro", "flash"]);
+
+        let (response, model) = change(&router, &api).unwrap();
+        assert_eq!((response.as_str(), model.as_str()), ("answer from flash", "flash"));
+
+        change(&router, &api).unwrap();
+        assert_eq!(*api.calls.borrow(), vec!["pro", "flash", "flash"]);
+    }
+
+    #[test]
+    fn test_route_does_not_fall_back_on_auth_error() {
+        let api = FlakyModels { failing: HashMap::from([("pro".to_string(), auth as fn() -> LLMApiError)]), calls: RefCell::new(vec![]) };
+        let router = router(&["pro", "flash"]);
+
+        let err = change(&router, &api).unwrap_err();
+        assert!(matches!(err.downcast_ref::<LLMApiError>(), Some(LLMApiError::Auth { .. })));
+        assert_eq!(*api.calls.borrow(), vec!["pro"]);
+    }
+
+    #[test]
+    fn test_route_returns_last_error_when_chain_is_exhausted() {
+        let api = FlakyModels {
+            failing: HashMap::from([("pro".to_string(), quota as fn() -> LLMApiError), ("flash".to_string(), quota as fn() -> LLMApiError)]),
+            calls: RefCell::new(vec![]),
+        };
+        let router = router(&["pro", "flash"]);
+
+        assert!(change(&router, &api).is_err());
+        // Both are exhausted now, so the next call tries the full chain again.
+        assert!(change(&router, &api).is_err());
+        assert_eq!(*api.calls.borrow(), vec!["pro", "flash", "pro", "flash"]);
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::verification::VerificationStage;
+use crate::Result;
+
+/// Per-project settings, read from this file in the project root.
+pub const PROJECT_CONFIG_FILE: &str = "gem.toml";
+
+/// Settings from `gem.toml`, e.g.
+///
+/// ```toml
+/// [[verify]]
+/// command = "cargo fmt --check"
+/// on_failure = "warn"
+///
+/// [[verify]]
+/// command = "cargo test"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    #[serde(default)]
+    pub verify: Vec<VerificationStage>, // Verification pipeline, used when `--verify-with` is not given
+}
+
+impl ProjectConfig {
+    /// The project's config, or the defaults when it has no config file.
+    pub fn load(project_root: &Path) -> Result<Self> {
+        let path = project_root.join(PROJECT_CONFIG_FILE);
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(&path)?;
+        toml::from_str(&content).map_err(|e| format!("Invalid project config {}: {}", path.display(), e).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verification::OnFailure;
+
+    #[test]
+    fn test_loads_verification_stages() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(ProjectConfig::load(dir.path()).unwrap().verify.is_empty());
+
+        fs::write(
+            dir.path().join(PROJECT_CONFIG_FILE),
+            "[[verify]]\ncommand = \"cargo fmt --check\"\non_failure = \"warn\"\n\n[[verify]]\ncommand = \"cargo test\"\n",
+        )
+        .unwrap();
+        let stages = ProjectConfig::load(dir.path()).unwrap().verify;
+        assert_eq!(
+            stages,
+            vec![
+                VerificationStage { command: "cargo fmt --check".to_string(), on_failure: OnFailure::Warn },
+                VerificationStage { command: "cargo test".to_string(), on_failure: OnFailure::Stop },
+            ]
+        );
+
+        fs::write(dir.path().join(PROJECT_CONFIG_FILE), "[[verify]]\ncommand = \"cargo test\"\non_failure = \"ignore\"\n").unwrap();
+        let error = ProjectConfig::load(dir.path()).unwrap_err().to_string();
+        assert!(error.starts_with("Invalid project config "), "{}", error);
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use syn::visit::Visit;
+
+use crate::llm_api::{flatten_conversation, ChatTurn};
+
+// Gemini averages roughly four characters per token for English text and Rust code.
+// Erring on the high side is fine: the budget only has to keep prompts inside the window.
+const CHARS_PER_TOKEN: usize = 4;
+
+// Used for models we know nothing about (e.g. whatever an OpenAI-compatible server serves).
+const UNKNOWN_MODEL_LIMITS: ModelLimits = ModelLimits { context_window: 32_768, max_output: 8_192 };
+
+/// Marks gathered items whose retrieval failed; they are the first to go when trimming.
+pub const GATHER_ERROR_PREFIX: &str = "// GEM_NOTE: Error querying";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelLimits {
+    pub context_window: usize,
+    pub max_output: usize,
+}
+
+impl ModelLimits {
+    /// Known context windows, matched by model name prefix (most specific first).
+    pub fn for_model(model_name: &str) -> Self {
+        const KNOWN: &[(&str, ModelLimits)] = &[
+            ("gemini-1.5-pro", ModelLimits { context_window: 2_097_152, max_output: 8_192 }),
+            ("gemini-1.5-flash", ModelLimits { context_window: 1_048_576, max_output: 8_192 }),
+            ("gemini-2.0", ModelLimits { context_window: 1_048_576, max_output: 8_192 }),
+            ("gemini-2.5", ModelLimits { context_window: 1_048_576, max_output: 65_536 }),
+            ("gemma", ModelLimits { context_window: 8_192, max_output: 2_048 }),
+        ];
+        let name = model_name.rsplit('/').next().unwrap_or(model_name); // "models/gemini-..." or "google/gemma-..."
+        KNOWN
+            .iter()
+            .find(|(prefix, _)| name.starts_with(prefix))
+            .map(|(_, limits)| *limits)
+            .unwrap_or(UNKNOWN_MODEL_LIMITS)
+    }
+
+    /// Tokens left for the prompt once room for the answer is reserved.
+    pub fn prompt_budget(&self) -> usize {
+        self.context_window.saturating_sub(self.max_output)
+    }
+}
+
+/// Cheap local token estimate, good enough to decide whether a prompt fits.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// Prompt budget for a phase: the smallest budget among the models it may fall back to,
+/// optionally capped by the user (`--max-prompt-tokens`).
+pub fn prompt_budget_for_models(models: &[String], user_limit: Option<usize>) -> usize {
+    let model_budget = models
+        .iter()
+        .map(|m| ModelLimits::for_model(m).prompt_budget())
+        .min()
+        .unwrap_or_else(|| UNKNOWN_MODEL_LIMITS.prompt_budget());
+    match user_limit {
+        Some(limit) => model_budget.min(limit),
+        None => model_budget,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrimAction {
+    /// Function bodies were replaced by `{ ... }`, keeping signatures and type definitions.
+    Summarised { item: String, from_tokens: usize, to_tokens: usize },
+    Dropped { item: String, tokens: usize },
+    Truncated { item: String, from_tokens: usize, to_tokens: usize },
+}
+
+impl fmt::Display for TrimAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrimAction::Summarised { item, from_tokens, to_tokens } => write!(f, "summarised {} (~{} -> ~{} tokens)", item, from_tokens, to_tokens),
+            TrimAction::Dropped { item, tokens } => write!(f, "dropped {} (~{} tokens)", item, tokens),
+            TrimAction::Truncated { item, from_tokens, to_tokens } => write!(f, "truncated {} (~{} -> ~{} tokens)", item, from_tokens, to_tokens),
+        }
+    }
+}
+
+// Lower sorts first, i.e. is cut first: failed lookups, then whole files, then specific items.
+// Within a class the biggest items go first, since they free the most room.
+fn trim_priority(item: &str, content: &str) -> (u8, std::cmp::Reverse<usize>) {
+    let class = if content.starts_with(GATHER_ERROR_PREFIX) {
+        0
+    } else if item.contains('/') || item.contains('\\') || item.ends_with(".rs") {
+        1
+    } else {
+        2
+    };
+    (class, std::cmp::Reverse(content.len()))
+}
+
+// What an item costs in the prompt, including the "// Item: ..." header the prompt builders add.
+fn entry_tokens(item: &str, content: &str) -> usize {
+    estimate_tokens(item) + estimate_tokens(content) + 8
+}
+
+fn dropped_note(tokens: usize) -> String {
+    format!("// GEM_NOTE: Omitted to fit the prompt token budget (~{} tokens). Request a more specific item if you need it.", tokens)
+}
+
+/// Shrinks `gathered_data` until its entries fit in `budget_tokens`: first by summarising
+/// Rust sources to their signatures, then by dropping items (leaving a short note so the
+/// model knows they exist). Returns the fitted data and what was cut.
+pub fn fit_gathered_data(gathered_data: &HashMap<String, String>, budget_tokens: usize) -> (HashMap<String, String>, Vec<TrimAction>) {
+    let mut fitted = gathered_data.clone();
+    let mut actions = Vec::new();
+    let mut total: usize = fitted.iter().map(|(item, c)| entry_tokens(item, c)).sum();
+    if total <= budget_tokens {
+        return (fitted, actions);
+    }
+
+    let mut order: Vec<&String> = gathered_data.keys().collect();
+    order.sort_by(|a, b| trim_priority(a, &gathered_data[*a]).cmp(&trim_priority(b, &gathered_data[*b])).then(a.cmp(b)));
+
+    for item in &order {
+        if total <= budget_tokens { break; }
+        let content = &gathered_data[*item];
+        if let Some(summary) = summarise_rust_source(content) {
+            let (from_tokens, to_tokens) = (estimate_tokens(content), estimate_tokens(&summary));
+            if to_tokens < from_tokens {
+                total = total - from_tokens + to_tokens;
+                fitted.insert(item.to_string(), summary);
+                actions.push(TrimAction::Summarised { item: item.to_string(), from_tokens, to_tokens });
+            }
+        }
+    }
+
+    for item in &order {
+        if total <= budget_tokens { break; }
+        let current_tokens = estimate_tokens(&fitted[*item]);
+        let tokens = estimate_tokens(&gathered_data[*item]);
+        let note = dropped_note(tokens);
+        let note_tokens = estimate_tokens(&note);
+        if note_tokens >= current_tokens { continue; }
+        total = total - current_tokens + note_tokens;
+        fitted.insert(item.to_string(), note);
+        // A summarised-then-dropped item is reported once, as dropped.
+        actions.retain(|a| !matches!(a, TrimAction::Summarised { item: s, .. } if s == *item));
+        actions.push(TrimAction::Dropped { item: item.to_string(), tokens });
+    }
+    (fitted, actions)
+}
+
+/// Cuts `text` at a line boundary so it fits in `max_tokens`, noting how many lines were left out.
+pub fn truncate_to_tokens(text: &str, max_tokens: usize) -> String {
+    if estimate_tokens(text) <= max_tokens {
+        return text.to_string();
+    }
+    let total_lines = text.lines().count();
+    let omitted_note = |omitted: usize| format!("// GEM_NOTE: {} more lines omitted to fit the prompt token budget.\n", omitted);
+    let max_tokens = max_tokens.saturating_sub(estimate_tokens(&omitted_note(total_lines)));
+    let mut kept = String::new();
+    let mut kept_lines = 0;
+    for line in text.lines() {
+        if estimate_tokens(&kept) + estimate_tokens(line) + 1 > max_tokens { break; }
+        kept.push_str(line);
+        kept.push('\n');
+        kept_lines += 1;
+    }
+    kept.push_str(&omitted_note(total_lines - kept_lines));
+    kept
+}
+
+/// Shrinks the named entries of `context`, in the given order, until all of them together fit in
+/// `budget_tokens`. Used for the large parts of the initial prompt (project symbols, cargo tree).
+pub fn fit_context_entries(context: &mut HashMap<String, String>, keys: &[&str], budget_tokens: usize) -> Vec<TrimAction> {
+    let mut actions = Vec::new();
+    let total: usize = keys.iter().filter_map(|k| context.get(*k)).map(|c| estimate_tokens(c)).sum();
+    let mut excess = total.saturating_sub(budget_tokens);
+    for key in keys {
+        if excess == 0 { break; }
+        let Some(content) = context.get(*key) else { continue };
+        let from_tokens = estimate_tokens(content);
+        let truncated = truncate_to_tokens(content, from_tokens.saturating_sub(excess));
+        let to_tokens = estimate_tokens(&truncated);
+        if to_tokens >= from_tokens { continue; }
+        excess = excess.saturating_sub(from_tokens - to_tokens);
+        context.insert(key.to_string(), truncated);
+        actions.push(TrimAction::Truncated { item: key.to_string(), from_tokens, to_tokens });
+    }
+    actions
+}
+
+/// Drops the oldest follow-up exchanges (a model answer and the user turn after it) until the
+/// conversation fits in `budget_tokens`. The first `keep_leading` turns and the latest exchange are
+/// always kept. Returns how many exchanges were dropped and whether the conversation fits now.
+pub fn fit_conversation(conversation: &mut Vec<ChatTurn>, keep_leading: usize, budget_tokens: usize) -> (usize, bool) {
+    let mut dropped = 0;
+    while estimate_tokens(&flatten_conversation(conversation)) > budget_tokens {
+        if conversation.len() < keep_leading + 4 {
+            return (dropped, false);
+        }
+        conversation.drain(keep_leading..keep_leading + 2);
+        dropped += 1;
+    }
+    (dropped, true)
+}
+
+// Collects the byte ranges of function bodies, without descending into them.
+struct FnBodyCollector {
+    line_starts: Vec<usize>,
+    source: String,
+    bodies: Vec<(usize, usize)>,
+}
+
+impl FnBodyCollector {
+    fn offset(&self, location: proc_macro2::LineColumn) -> usize {
+        // Lines are 1-based, columns count chars.
+        let line_start = self.line_starts[location.line - 1];
+        self.source[line_start..]
+            .char_indices()
+            .nth(location.column)
+            .map(|(i, _)| line_start + i)
+            .unwrap_or(self.source.len())
+    }
+
+    fn add_body(&mut self, block: &syn::Block) {
+        let span = block.brace_token.span;
+        let start = self.offset(span.open().start());
+        let end = self.offset(span.close().end());
+        self.bodies.push((start, end));
+    }
+}
+
+impl<'ast> Visit<'ast> for FnBodyCollector {
+    fn visit_item_fn(&mut self, node: &'ast syn::ItemFn) {
+        self.add_body(&node.block);
+    }
+
+    fn visit_impl_item_fn(&mut self, node: &'ast syn::ImplItemFn) {
+        self.add_body(&node.block);
+    }
+
+    fn visit_trait_item_fn(&mut self, node: &'ast syn::TraitItemFn) {
+        if let Some(block) = &node.default {
+            self.add_body(block);
+        }
+    }
+}
+
+/// Outline of a Rust source file or item: everything except function bodies, which become `{ ... }`.
+/// Returns `None` if the text does not parse as Rust.
+pub fn summarise_rust_source(source: &str) -> Option<String> {
+    let file = syn::parse_file(source).ok()?;
+    let mut line_starts = vec![0];
+    line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+    let mut collector = FnBodyCollector { line_starts, source: source.to_string(), bodies: Vec::new() };
+    collector.visit_file(&file);
+    if collector.bodies.is_empty() {
+        return None;
+    }
+
+    collector.bodies.sort();
+    let mut summary = String::new();
+    let mut pos = 0;
+    for (start, end) in collector.bodies {
+        if start < pos { continue; } // Nested in a body we already cut
+        summary.push_str(&source[pos..start]);
+        summary.push_str("{ ... }");
+        pos = end;
+    }
+    summary.push_str(&source[pos..]);
+    Some(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = r#"/// A point.
+pub struct Point { pub x: i32, pub y: i32 }
+
+impl Point {
+    pub fn new(x: i32, y: i32) -> Self {
+        let p = Point { x, y };
+        p
+    }
+}
+
+pub trait Shape {
+    fn area(&self) -> f64 { 0.0 }
+    fn name(&self) -> &str;
+}
+
+fn helper(s: &str) -> usize {
+    fn inner() -> usize { 1 }
+    s.chars().count() + inner()
+}
+"#;
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("abcde"), 2);
+    }
+
+    #[test]
+    fn test_model_limits_and_phase_budget() {
+        assert_eq!(ModelLimits::for_model("gemini-2.5-pro").context_window, 1_048_576);
+        assert_eq!(ModelLimits::for_model("models/gemini-1.5-pro-latest").context_window, 2_097_152);
+        assert_eq!(ModelLimits::for_model("google/gemma-3-1b-it").context_window, 8_192);
+        assert_eq!(ModelLimits::for_model("some-local-model"), UNKNOWN_MODEL_LIMITS);
+
+        let chain = vec!["gemini-2.5-pro".to_string(), "gemma-3".to_string()];
+        assert_eq!(prompt_budget_for_models(&chain, None), 8_192 - 2_048);
+        assert_eq!(prompt_budget_for_models(&chain, Some(1_000)), 1_000);
+    }
+
+    #[test]
+    fn test_summarise_rust_source_keeps_signatures() {
+        let summary = summarise_rust_source(SOURCE).unwrap();
+        assert!(summary.contains("pub struct Point { pub x: i32, pub y: i32 }"));
+        assert!(summary.contains("pub fn new(x: i32, y: i32) -> Self { ... }"));
+        assert!(summary.contains("fn area(&self) -> f64 { ... }"));
+        assert!(summary.contains("fn name(&self) -> &str;"));
+        assert!(summary.contains("fn helper(s: &str) -> usize { ... }"));
+        assert!(!summary.contains("let p ="));
+        assert!(!summary.contains("fn inner"));
+
+        assert!(summarise_rust_source("not rust at all {").is_none());
+        assert!(summarise_rust_source("pub struct OnlyData;").is_none());
+    }
+
+    #[test]
+    fn test_fit_gathered_data_within_budget_is_untouched() {
+        let data: HashMap<String, String> = [("src/lib.rs".to_string(), SOURCE.to_string())].into_iter().collect();
+        let (fitted, actions) = fit_gathered_data(&data, 10_000);
+        assert_eq!(fitted, data);
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn test_fit_gathered_data_summarises_then_drops_lowest_priority() {
+        let error_note = format!("{} missing::Item: not found", GATHER_ERROR_PREFIX);
+        let data: HashMap<String, String> = [
+            ("src/lib.rs".to_string(), SOURCE.to_string()),
+            ("crate::Point".to_string(), "pub struct Point { pub x: i32, pub y: i32 }".to_string()),
+            ("missing::Item".to_string(), error_note.clone()),
+            ("docs/notes.txt".to_string(), "plain text ".repeat(40)),
+        ]
+        .into_iter()
+        .collect();
+
+        // Summarising src/lib.rs is not enough; the text file and then the source file go.
+        let budget = entry_tokens("missing::Item", &error_note) + entry_tokens("crate::Point", &data["crate::Point"]) + 100;
+        let (fitted, actions) = fit_gathered_data(&data, budget);
+        let total: usize = fitted.iter().map(|(item, c)| entry_tokens(item, c)).sum();
+        assert!(total <= budget, "{} > {}: {:?}", total, budget, actions);
+
+        // The specific item survives untouched; the failed lookup is too small to be worth dropping.
+        assert_eq!(fitted["crate::Point"], data["crate::Point"]);
+        assert_eq!(fitted["missing::Item"], error_note);
+        assert!(fitted["docs/notes.txt"].starts_with("// GEM_NOTE: Omitted"));
+        assert!(fitted["src/lib.rs"].starts_with("// GEM_NOTE: Omitted"));
+        assert_eq!(actions.len(), 2, "{:?}", actions); // src/lib.rs was summarised first, but is only reported as dropped
+        assert!(actions.iter().any(|a| matches!(a, TrimAction::Dropped { item, .. } if item == "docs/notes.txt")));
+        assert!(!actions.iter().any(|a| matches!(a, TrimAction::Dropped { item, .. } if item == "crate::Point")));
+    }
+
+    #[test]
+    fn test_fit_context_entries_truncates_in_order() {
+        let symbols: String = (0..200).map(|i| format!("crate::module::item_{}\n", i)).collect();
+        let mut context: HashMap<String, String> = [
+            ("project_symbols".to_string(), symbols.clone()),
+            ("dependencies".to_string(), "gem v0.1.0\n".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        let actions = fit_context_entries(&mut context, &["project_symbols", "dependencies"], 300);
+        assert_eq!(actions.len(), 1);
+        assert!(matches!(&actions[0], TrimAction::Truncated { item, .. } if item == "project_symbols"));
+        assert_eq!(context["dependencies"], "gem v0.1.0\n");
+        assert!(context["project_symbols"].contains("more lines omitted"));
+        assert!(context["project_symbols"].starts_with("crate::module::item_0\n"));
+        let total: usize = context.values().map(|c| estimate_tokens(c)).sum();
+        assert!(total <= 300, "total {}", total);
+    }
+
+    #[test]
+    fn test_fit_conversation_drops_oldest_exchanges() {
+        let reply = |text: &str| ChatTurn::Model(crate::llm_api::ModelReply { text: text.to_string(), tool_calls: vec![] });
+        let mut conversation = vec![
+            ChatTurn::System("system".to_string()),
+            ChatTurn::User("context ".repeat(50)),
+            reply(&"first answer ".repeat(100)),
+            ChatTurn::User("first failure".to_string()),
+            reply("second answer"),
+            ChatTurn::User("second failure".to_string()),
+        ];
+        let full_tokens = estimate_tokens(&flatten_conversation(&conversation));
+        assert_eq!(fit_conversation(&mut conversation, 2, full_tokens), (0, true));
+        assert_eq!(conversation.len(), 6);
+
+        assert_eq!(fit_conversation(&mut conversation, 2, full_tokens - 100), (1, true));
+        assert_eq!(conversation[2], reply("second answer"));
+        assert_eq!(conversation.len(), 4);
+
+        // The leading turns and the latest exchange are never dropped.
+        assert_eq!(fit_conversation(&mut conversation, 2, 10), (0, false));
+        assert_eq!(conversation.len(), 4);
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::cache::{load_call_records, CallRecord};
+use crate::llm_api::TokenUsage;
+
+/// Token totals over a set of calls.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageTotals {
+    pub calls: usize,
+    pub calls_without_usage: usize, // Calls whose backend reported no token counts
+    pub usage: TokenUsage,
+}
+
+impl UsageTotals {
+    pub fn add_call(&mut self, record: &CallRecord) {
+        self.calls += 1;
+        match &record.usage {
+            Some(usage) => self.usage.add(usage),
+            None => self.calls_without_usage += 1,
+        }
+    }
+}
+
+/// Totals per model, in model name order.
+pub fn totals_by_model(calls: &[CallRecord]) -> BTreeMap<String, UsageTotals> {
+    let mut totals: BTreeMap<String, UsageTotals> = BTreeMap::new();
+    for call in calls {
+        totals.entry(call.model.clone()).or_default().add_call(call);
+    }
+    totals
+}
+
+/// Totals per (UTC day, model), oldest day first.
+pub fn totals_by_day_and_model(calls: &[CallRecord]) -> BTreeMap<(String, String), UsageTotals> {
+    let mut totals: BTreeMap<(String, String), UsageTotals> = BTreeMap::new();
+    for call in calls {
+        totals.entry((utc_day(call.timestamp), call.model.clone())).or_default().add_call(call);
+    }
+    totals
+}
+
+/// Every call recorded in every session under `sessions_dir`.
+pub fn load_all_call_records(sessions_dir: &Path) -> Vec<CallRecord> {
+    let Ok(entries) = fs::read_dir(sessions_dir) else { return Vec::new() };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .flat_map(|entry| load_call_records(&entry.path()))
+        .collect()
+}
+
+/// Short summary printed at the end of a run, one line per model.
+pub fn format_run_summary(calls: &[CallRecord]) -> String {
+    let mut summary = String::from("gem: Token usage for this run:\n");
+    let mut overall = UsageTotals::default();
+    for (model, totals) in totals_by_model(calls) {
+        summary.push_str(&format!("  {}: {}\n", model, describe_totals(&totals)));
+        overall.calls += totals.calls;
+        overall.calls_without_usage += totals.calls_without_usage;
+        overall.usage.add(&totals.usage);
+    }
+    summary.push_str(&format!("  total: {}", describe_totals(&overall)));
+    summary
+}
+
+fn describe_totals(totals: &UsageTotals) -> String {
+    let mut line = format!(
+        "{} call{}, {} prompt + {} output + {} thinking = {} tokens",
+        totals.calls,
+        if totals.calls == 1 { "" } else { "s" },
+        totals.usage.prompt_tokens,
+        totals.usage.candidate_tokens,
+        totals.usage.thinking_tokens,
+        totals.usage.total()
+    );
+    if totals.usage.cached_tokens > 0 {
+        line.push_str(&format!(" ({} prompt tokens from cache)", totals.usage.cached_tokens));
+    }
+    if totals.calls_without_usage > 0 {
+        line.push_str(&format!(" ({} without usage data)", totals.calls_without_usage));
+    }
+    line
+}
+
+/// Table of token totals across sessions, by day and model (`gem --usage-report`).
+pub fn format_usage_report(calls: &[CallRecord]) -> String {
+    if calls.is_empty() {
+        return "No LLM calls recorded yet.".to_string();
+    }
+    let rows = totals_by_day_and_model(calls);
+    let model_width = rows.keys().map(|(_, model)| model.len()).max().unwrap_or(0).max("Model".len());
+    let mut report = format!(
+        "{:<10}  {:<mw$}  {:>6}  {:>12}  {:>12}  {:>12}  {:>12}  {:>12}\n",
+        "Day", "Model", "Calls", "Prompt", "Cached", "Output", "Thinking", "Total", mw = model_width
+    );
+    let mut overall = UsageTotals::default();
+    for ((day, model), totals) in &rows {
+        report.push_str(&format!(
+            "{:<10}  {:<mw$}  {:>6}  {:>12}  {:>12}  {:>12}  {:>12}  {:>12}\n",
+            day, model, totals.calls, totals.usage.prompt_tokens, totals.usage.cached_tokens, totals.usage.candidate_tokens,
+            totals.usage.thinking_tokens, totals.usage.total(), mw = model_width
+        ));
+        overall.calls += totals.calls;
+        overall.calls_without_usage += totals.calls_without_usage;
+        overall.usage.add(&totals.usage);
+    }
+    report.push_str(&format!(
+        "{:<10}  {:<mw$}  {:>6}  {:>12}  {:>12}  {:>12}  {:>12}  {:>12}",
+        "Total", "", overall.calls, overall.usage.prompt_tokens, overall.usage.cached_tokens, overall.usage.candidate_tokens,
+        overall.usage.thinking_tokens, overall.usage.total(), mw = model_width
+    ));
+    if overall.usage.cached_tokens > 0 {
+        report.push_str(&format!(
+            "\n{} of {} prompt tokens ({:.0}%) came from context caches and were billed at the reduced cached rate.",
+            overall.usage.cached_tokens,
+            overall.usage.prompt_tokens,
+            overall.usage.cached_tokens as f64 * 100.0 / overall.usage.prompt_tokens.max(1) as f64
+        ));
+    }
+    if overall.calls_without_usage > 0 {
+        report.push_str(&format!("\n{} call(s) have no usage data (older sessions or backends that do not report it).", overall.calls_without_usage));
+    }
+    report
+}
+
+// "YYYY-MM-DD" for a Unix timestamp, in UTC (Howard Hinnant's civil-from-days algorithm).
+fn utc_day(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153; // March-based
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn call(model: &str, timestamp: u64, usage: Option<TokenUsage>) -> CallRecord {
+        CallRecord {
+            prompt_type: "change".to_string(),
+            prompt_hash: "hash".to_string(),
+            model: model.to_string(),
+            timestamp,
+            usage,
+        }
+    }
+
+    fn usage(prompt_tokens: u64, candidate_tokens: u64, thinking_tokens: u64) -> Option<TokenUsage> {
+        Some(TokenUsage { prompt_tokens, candidate_tokens, thinking_tokens, cached_tokens: 0 })
+    }
+
+    #[test]
+    fn test_utc_day() {
+        assert_eq!(utc_day(0), "1970-01-01");
+        assert_eq!(utc_day(951_782_400), "2000-02-29");
+        assert_eq!(utc_day(1_735_689_599), "2024-12-31");
+        assert_eq!(utc_day(1_735_689_600), "2025-01-01");
+    }
+
+    #[test]
+    fn test_totals_by_day_and_model() {
+        let day_one = 1_735_689_600; // 2025-01-01
+        let day_two = day_one + 86_400;
+        let calls = vec![
+            call("gemini-2.5-flash", day_one, usage(100, 10, 5)),
+            call("gemini-2.5-flash", day_one + 60, usage(200, 20, 0)),
+            call("gemini-2.5-pro", day_one, None),
+            call("gemini-2.5-flash", day_two, usage(1, 1, 1)),
+        ];
+
+        let totals = totals_by_day_and_model(&calls);
+        let flash_day_one = &totals[&("2025-01-01".to_string(), "gemini-2.5-flash".to_string())];
+        assert_eq!(flash_day_one.calls, 2);
+        assert_eq!(flash_day_one.usage, TokenUsage { prompt_tokens: 300, candidate_tokens: 30, thinking_tokens: 5, cached_tokens: 0 });
+        let pro_day_one = &totals[&("2025-01-01".to_string(), "gemini-2.5-pro".to_string())];
+        assert_eq!(pro_day_one.calls_without_usage, 1);
+        assert_eq!(totals.len(), 3);
+
+        let report = format_usage_report(&calls);
+        assert!(report.contains("2025-01-02"));
+        assert!(report.lines().last().unwrap().contains("1 call(s) have no usage data"));
+
+        let summary = format_run_summary(&calls);
+        assert!(summary.contains("gemini-2.5-flash: 3 calls, 301 prompt + 31 output + 6 thinking = 338 tokens"));
+        assert!(summary.contains("gemini-2.5-pro: 1 call, 0 prompt + 0 output + 0 thinking = 0 tokens (1 without usage data)"));
+    }
+
+    #[test]
+    fn test_cached_prompt_tokens_are_reported() {
+        let mut cached = usage(1_000, 10, 0).unwrap();
+        cached.cached_tokens = 800;
+        let calls = vec![call("gemini-2.5-flash", 0, Some(cached)), call("gemini-2.5-flash", 60, usage(1_000, 10, 0))];
+
+        let summary = format_run_summary(&calls);
+        assert!(summary.contains("2 calls, 2000 prompt + 20 output + 0 thinking = 2020 tokens (800 prompt tokens from cache)"), "{}", summary);
+
+        let report = format_usage_report(&calls);
+        assert!(report.lines().next().unwrap().contains("Cached"));
+        assert!(report.ends_with("800 of 2000 prompt tokens (40%) came from context caches and were billed at the reduced cached rate."), "{}", report);
+    }
+
+    #[test]
+    fn test_load_all_call_records_across_sessions() {
+        let sessions = tempdir().unwrap();
+        for (session, model) in [("one", "gemini-2.5-flash"), ("two", "gemini-2.5-pro")] {
+            let dir = sessions.path().join(session);
+            fs::create_dir_all(&dir).unwrap();
+            let line = serde_json::to_string(&call(model, 0, usage(1, 2, 3))).unwrap();
+            fs::write(dir.join("calls.jsonl"), format!("{}\nnot json\n", line)).unwrap();
+        }
+        fs::write(sessions.path().join("stray-file.txt"), "ignored").unwrap();
+
+        let mut models: Vec<String> = load_all_call_records(sessions.path()).into_iter().map(|c| c.model).collect();
+        models.sort();
+        assert_eq!(models, vec!["gemini-2.5-flash", "gemini-2.5-pro"]);
+        assert_eq!(format_usage_report(&[]), "No LLM calls recorded yet.");
+    }
+}
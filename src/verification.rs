@@ -0,0 +1,255 @@
+use std::io::Read;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::cli::DEFAULT_VERIFY_COMMAND;
+use crate::llm_api::{is_cancel_requested, LLMApiError};
+use crate::project_config::ProjectConfig;
+use crate::Result;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Prefix of a `--verify-with` command whose failure only warns.
+const WARN_PREFIX: &str = "warn:";
+
+/// What a failing verification stage does to the pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    #[default]
+    Stop, // The changes fail verification; later stages are skipped
+    Warn, // Reported, but the pipeline goes on
+}
+
+/// One command of the verification pipeline.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VerificationStage {
+    pub command: String,
+    #[serde(default)]
+    pub on_failure: OnFailure,
+}
+
+impl VerificationStage {
+    /// A `--verify-with` value: the command, prefixed with `warn:` if its failure should only warn.
+    pub fn parse(spec: &str) -> Self {
+        match spec.trim().strip_prefix(WARN_PREFIX) {
+            Some(command) => Self { command: command.trim().to_string(), on_failure: OnFailure::Warn },
+            None => Self { command: spec.trim().to_string(), on_failure: OnFailure::Stop },
+        }
+    }
+}
+
+/// The pipeline given with `--verify-with`, else the project config's, else `cargo build`.
+pub fn verification_stages(verify_with: &[String], project_root: &Path) -> Result<Vec<VerificationStage>> {
+    if !verify_with.is_empty() {
+        return Ok(verify_with.iter().map(|spec| VerificationStage::parse(spec)).collect());
+    }
+    let configured = ProjectConfig::load(project_root)?.verify;
+    if !configured.is_empty() {
+        return Ok(configured);
+    }
+    Ok(vec![VerificationStage::parse(DEFAULT_VERIFY_COMMAND)])
+}
+
+/// Names the stage at `index` (0-based) of `count` in messages and prompts, e.g.
+/// `the verification command "cargo test"` or `verification stage 2 of 3 ("cargo test")`.
+pub fn describe_stage(stage: &VerificationStage, index: usize, count: usize) -> String {
+    if count == 1 {
+        format!("the verification command \"{}\"", stage.command)
+    } else {
+        format!("verification stage {} of {} (\"{}\")", index + 1, count, stage.command)
+    }
+}
+
+/// Outcome of one run of the verification command.
+#[derive(Debug, Clone)]
+pub struct VerificationResult {
+    pub command: String,
+    pub exit_code: Option<i32>, // None when the command was killed (timeout) or ended by a signal
+    pub timed_out: bool,
+    pub duration: Duration,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl VerificationResult {
+    pub fn success(&self) -> bool {
+        !self.timed_out && self.exit_code == Some(0)
+    }
+
+    /// How the command ended, e.g. `exited with status 101 after 3.2s`.
+    pub fn status_line(&self) -> String {
+        let secs = self.duration.as_secs_f64();
+        match self.exit_code {
+            _ if self.timed_out => format!("timed out after {:.1}s and was killed", secs),
+            Some(code) => format!("exited with status {} after {:.1}s", code, secs),
+            None => format!("was terminated by a signal after {:.1}s", secs),
+        }
+    }
+
+    /// The status and both output streams, as shown to the user and fed back to the model.
+    pub fn report(&self) -> String {
+        let mut report = format!("`{}` {}.", self.command, self.status_line());
+        for (name, output) in [("stderr", &self.stderr), ("stdout", &self.stdout)] {
+            if !output.trim().is_empty() {
+                report.push_str(&format!("\n--- {} ---\n{}", name, output.trim_end()));
+            }
+        }
+        report
+    }
+}
+
+/// Runs `command` through the shell in `project_root`. After `timeout` the command and every
+/// process it started are killed. Errors only when the command cannot be started or the run is
+/// cancelled; a failing command is a result that is not `success()`.
+pub fn run_verification(project_root: &Path, command: &str, timeout: Duration) -> Result<VerificationResult> {
+    let started = Instant::now();
+    let mut child = shell(command)
+        .current_dir(project_root)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to start verification command `{}`: {}", command, e))?;
+    // Both pipes are drained while waiting, so a chatty command cannot block on a full pipe.
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if is_cancel_requested() {
+            kill_process_tree(&mut child);
+            return Err(Box::new(LLMApiError::Cancelled));
+        }
+        if started.elapsed() >= timeout {
+            timed_out = true;
+            kill_process_tree(&mut child);
+            break child.wait()?;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    Ok(VerificationResult {
+        command: command.to_string(),
+        exit_code: if timed_out { None } else { status.code() },
+        timed_out,
+        duration: started.elapsed(),
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    use std::os::unix::process::CommandExt;
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell.process_group(0); // Its own group, so a timeout can kill everything it started
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(unix)]
+fn kill_process_tree(child: &mut Child) {
+    let group = format!("-{}", child.id());
+    let killed = Command::new("kill").args(["-KILL", "--", &group]).stderr(Stdio::null()).status().is_ok_and(|status| status.success());
+    if !killed {
+        let _ = child.kill();
+    }
+}
+
+#[cfg(windows)]
+fn kill_process_tree(child: &mut Child) {
+    let pid = child.id().to_string();
+    let killed = Command::new("taskkill").args(["/T", "/F", "/PID", &pid]).stdout(Stdio::null()).stderr(Stdio::null()).status().is_ok_and(|status| status.success());
+    if !killed {
+        let _ = child.kill();
+    }
+}
+
+fn read_in_background(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    #[serial] // A cancel request from another test would stop the run
+    fn test_captures_stdout_and_stderr_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("marker.txt"), "here").unwrap();
+
+        let result = run_verification(dir.path(), "cat marker.txt; echo oops >&2; exit 3", Duration::from_secs(10)).unwrap();
+
+        assert!(!result.success());
+        assert_eq!((result.exit_code, result.timed_out), (Some(3), false));
+        assert_eq!(result.stdout, "here");
+        assert_eq!(result.stderr, "oops\n");
+        assert!(result.report().starts_with("`cat marker.txt; echo oops >&2; exit 3` exited with status 3 after "), "{}", result.report());
+        assert!(result.report().ends_with("s.\n--- stderr ---\noops\n--- stdout ---\nhere"), "{}", result.report());
+
+        let result = run_verification(dir.path(), "true", Duration::from_secs(10)).unwrap();
+        assert!(result.success());
+    }
+
+    #[test]
+    #[serial]
+    fn test_timeout_kills_the_whole_process_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let started = Instant::now();
+
+        // The background subshell would create the file after the timeout if it survived.
+        let result = run_verification(dir.path(), "(sleep 1; touch late.txt) & sleep 30", Duration::from_millis(200)).unwrap();
+
+        assert!(result.timed_out && !result.success());
+        assert_eq!(result.exit_code, None);
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+        assert!(result.status_line().starts_with("timed out after 0."), "{}", result.status_line());
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!dir.path().join("late.txt").exists());
+    }
+
+    #[test]
+    fn test_stages_come_from_the_cli_then_the_project_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let stop = |command: &str| VerificationStage { command: command.to_string(), on_failure: OnFailure::Stop };
+        assert_eq!(verification_stages(&[], dir.path()).unwrap(), vec![stop(DEFAULT_VERIFY_COMMAND)]);
+
+        std::fs::write(dir.path().join(crate::project_config::PROJECT_CONFIG_FILE), "[[verify]]\ncommand = \"cargo test\"\n").unwrap();
+        assert_eq!(verification_stages(&[], dir.path()).unwrap(), vec![stop("cargo test")]);
+
+        let cli = ["warn: cargo fmt --check".to_string(), "cargo clippy -- -D warnings".to_string()];
+        let stages = verification_stages(&cli, dir.path()).unwrap();
+        assert_eq!(stages, vec![VerificationStage { command: "cargo fmt --check".to_string(), on_failure: OnFailure::Warn }, stop("cargo clippy -- -D warnings")]);
+        assert_eq!(describe_stage(&stages[1], 1, 2), "verification stage 2 of 2 (\"cargo clippy -- -D warnings\")");
+        assert_eq!(describe_stage(&stages[1], 0, 1), "the verification command \"cargo clippy -- -D warnings\"");
+    }
+
+    #[test]
+    fn test_reports_commands_that_cannot_start() {
+        let error = run_verification(Path::new("/nonexistent/project"), "true", Duration::from_secs(1)).unwrap_err().to_string();
+        assert!(error.starts_with("Failed to start verification command `true`: "), "{}", error);
+    }
+}
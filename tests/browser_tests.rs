@@ -0,0 +1,219 @@
+use gem::browser_interaction::{execute_browser_interaction_task, BrowserChat, BrowserLLMApi, ChatSelectors};
+use gem::cache::Session;
+use gem::llm_api::{ChatTurn, CodeChange, CodeChangeAction, GeminiCodeGenerationResponse, GeminiNeededItemsResponse, GeminiSufficiencyResponse, LLMApi, ModelReply};
+use gem::run_gem_agent;
+use serial_test::serial;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+mod common;
+use common::webdriver::StubWebDriver;
+use common::{common_test_args, setup_test_env};
+
+fn chat_page_url() -> String {
+    format!("file://{}", Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/browser/chat.html").display())
+}
+
+fn fast_chat(driver: &StubWebDriver, selectors: ChatSelectors) -> Result<BrowserChat, Box<dyn Error>> {
+    Ok(BrowserChat::open(&driver.base_url, &chat_page_url(), selectors)?.with_timing(Duration::from_secs(5), Duration::from_millis(5)))
+}
+
+#[test]
+fn test_browser_task_types_request_and_returns_answer_code_blocks() -> Result<(), Box<dyn Error>> {
+    let driver = StubWebDriver::start(2, |_| {
+        concat!(
+            "<div class=\"answer\"><p>Here you go:</p>",
+            "<pre class=\"code\">fn main() {\n    println!(&quot;hi&quot;);\n}</pre>",
+            "<p>and a check:</p><pre class=\"code\"><span class=\"kw\">let</span> ok = 1 &lt; 2;</pre>",
+            "<span class=\"done\">Done</span></div>"
+        )
+        .to_string()
+    });
+
+    let blocks = execute_browser_interaction_task(
+        &driver.base_url,
+        &chat_page_url(),
+        Some("#prompt"),
+        Some("pre.code"),
+        Some(".done"),
+        "Print a greeting.\nKeep it short.",
+    )?;
+
+    assert_eq!(blocks, vec!["fn main() {\n    println!(\"hi\");\n}", "let ok = 1 < 2;"]);
+    let state = driver.state();
+    assert_eq!(state.url.as_deref(), Some(chat_page_url().as_str()));
+    assert_eq!(state.prompts, vec!["Print a greeting.\nKeep it short."]);
+    assert!(state.session_deleted);
+    Ok(())
+}
+
+#[test]
+fn test_browser_chat_returns_only_the_latest_answer() -> Result<(), Box<dyn Error>> {
+    let driver = StubWebDriver::start(3, |prompt| format!("<pre>// answer to: {}</pre>", prompt));
+    // Without a finished selector, an answer is complete once its code blocks stop changing.
+    let chat = fast_chat(&driver, ChatSelectors::new(None, None, None))?;
+
+    assert_eq!(chat.ask("first")?, vec!["// answer to: first"]);
+    assert_eq!(chat.ask("second")?, vec!["// answer to: second"]);
+    assert_eq!(driver.state().prompts, vec!["first", "second"]);
+    Ok(())
+}
+
+#[test]
+fn test_browser_chat_polls_again_when_a_code_block_goes_stale() -> Result<(), Box<dyn Error>> {
+    let driver = StubWebDriver::start(2, |prompt| format!("<pre>// answer to: {}</pre><span class=\"done\">Done</span>", prompt));
+    let chat = fast_chat(&driver, ChatSelectors::new(None, None, Some(".done")))?;
+    driver.state().stale_text_reads = 1;
+
+    assert_eq!(chat.ask("streaming")?, vec!["// answer to: streaming"]);
+    assert_eq!(driver.state().stale_text_reads, 0);
+    Ok(())
+}
+
+#[test]
+fn test_browser_chat_reports_missing_elements() -> Result<(), Box<dyn Error>> {
+    let driver = StubWebDriver::start(0, |_| "<p>No code today.</p>".to_string());
+
+    let chat = fast_chat(&driver, ChatSelectors::new(Some("#missing-input"), None, None))?;
+    let error = chat.ask("hello").unwrap_err().to_string();
+    assert_eq!(error, "No input field matches `#missing-input` on the chat page.");
+
+    let chat = fast_chat(&driver, ChatSelectors::new(None, None, Some(".done")))?.with_timing(Duration::from_secs(1), Duration::from_millis(5));
+    let error = chat.ask("hello").unwrap_err().to_string();
+    assert_eq!(error, "No `.done` element appeared within 1s of sending the prompt.");
+    Ok(())
+}
+
+#[test]
+fn test_browser_task_reports_unreachable_webdriver() {
+    let error = execute_browser_interaction_task("http://127.0.0.1:9", &chat_page_url(), None, None, None, "hello").unwrap_err().to_string();
+    assert!(error.starts_with("Cannot reach the WebDriver server at http://127.0.0.1:9"), "{}", error);
+    assert!(error.contains("--webdriver"), "{}", error);
+}
+
+// Wraps JSON the way a chat UI renders it: HTML-escaped inside a code block, with some prose around it.
+fn chat_answer_with_json(json: &str) -> String {
+    let escaped = json.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;");
+    format!("<div class=\"answer\"><p>Sure, here it is:</p><pre>{}</pre></div>", escaped)
+}
+
+fn browser_api(driver: &StubWebDriver) -> BrowserLLMApi {
+    BrowserLLMApi::new(&driver.base_url, &chat_page_url(), ChatSelectors::new(None, None, None)).with_timing(Duration::from_secs(5), Duration::from_millis(5))
+}
+
+#[test]
+#[serial]
+fn test_web_chat_drives_full_agent_loop_with_session_caching() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("browser_agent");
+    fs::write(project_root.join("src").join("lib.rs"), "const OLD_CONST: i32 = 1;\n")?;
+
+    let code_gen_response = GeminiCodeGenerationResponse {
+        changes: vec![CodeChange {
+            file_path: "src/lib.rs::OLD_CONST".to_string(),
+            action: CodeChangeAction::ReplaceItemInSection,
+            content: Some("const NEW_CONST: i32 = 2; // 1 < 2".to_string()),
+        }],
+        tests: None,
+        explanation: "Renamed the constant.".to_string(),
+    };
+    let answers = [
+        ("identify the specific Rust files", serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec!["src/lib.rs".to_string()] })?),
+        ("You previously requested specific code elements", serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?),
+        ("generate the precise code changes", serde_json::to_string(&code_gen_response)?),
+    ];
+    let driver = StubWebDriver::start(2, move |prompt| {
+        let (_, answer) = answers.iter().find(|(marker, _)| prompt.contains(marker)).expect("no scripted answer for prompt");
+        chat_answer_with_json(answer)
+    });
+
+    let api = browser_api(&driver);
+    let model_name = format!("browser:{}", chat_page_url());
+    assert_eq!(api.model_name(), model_name);
+    let args = || {
+        let mut args = common_test_args(project_root.clone(), "rename OLD_CONST in the browser");
+        api.configure_agent_args(&mut args);
+        args
+    };
+    let mut session = Session::new(&Session::compute_hash("browser_agent"));
+    run_gem_agent(args(), &mut session, Box::new(browser_api(&driver)), false, project_root.clone())?;
+
+    assert_eq!(fs::read_to_string(project_root.join("src").join("lib.rs"))?.trim(), "const NEW_CONST: i32 = 2; // 1 < 2");
+    let calls: Vec<&str> = session.calls().iter().map(|c| c.prompt_type.as_str()).collect();
+    assert_eq!(calls, vec!["initial", "sufficient", "change"]);
+    assert!(session.calls().iter().all(|c| c.model == model_name));
+    let prompts = driver.state().prompts.clone();
+    assert_eq!(prompts.len(), 3);
+    assert!(prompts.iter().all(|prompt| prompt.ends_with("Reply with the JSON in a single code block.")));
+
+    // A second run of the same request is answered from the session cache without typing anything.
+    fs::write(project_root.join("src").join("lib.rs"), "const OLD_CONST: i32 = 1;\n")?;
+    run_gem_agent(args(), &mut session, Box::new(browser_api(&driver)), false, project_root.clone())?;
+    assert_eq!(fs::read_to_string(project_root.join("src").join("lib.rs"))?.trim(), "const NEW_CONST: i32 = 2; // 1 < 2");
+    assert_eq!(driver.state().prompts.len(), 3);
+    Ok(())
+}
+
+#[test]
+fn test_web_chat_continues_conversations_and_starts_new_ones() -> Result<(), Box<dyn Error>> {
+    let driver = StubWebDriver::start(0, |prompt| format!("<pre>answer {}</pre>", prompt.len()));
+    let api = browser_api(&driver);
+
+    let conversation = vec![ChatTurn::System("Project context".to_string()), ChatTurn::User("Change it".to_string())];
+    let first = api.generate_conversation(&conversation, "ignored", None)?;
+    let mut retry = conversation.clone();
+    retry.push(ChatTurn::Model(ModelReply { text: first, tool_calls: vec![] }));
+    retry.push(ChatTurn::User("cargo check failed".to_string()));
+    api.generate_conversation(&retry, "ignored", None)?;
+    api.generate_content("Something else", "ignored")?;
+
+    let state = driver.state();
+    assert!(state.prompts[0].contains("Project context") && state.prompts[0].contains("Change it"), "{}", state.prompts[0]);
+    assert_eq!(state.prompts[1], "cargo check failed"); // Only the new turn; the chat remembers the rest
+    assert_eq!(state.prompts[2], "Something else");
+    assert_eq!(state.navigations, 2); // The unrelated prompt started a new chat
+    Ok(())
+}
+
+#[test]
+fn test_gem_binary_sends_a_request_piped_on_stdin_to_the_web_chat() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, home_dir_guard) = setup_test_env("browser_stdin");
+    fs::write(project_root.join("src").join("lib.rs"), "const OLD_CONST: i32 = 1;\n")?;
+    let code_gen_response = GeminiCodeGenerationResponse {
+        changes: vec![CodeChange { file_path: "src/lib.rs::OLD_CONST".to_string(), action: CodeChangeAction::ReplaceItemInSection, content: Some("const NEW_CONST: i32 = 2;".to_string()) }],
+        tests: None,
+        explanation: "Renamed the constant.".to_string(),
+    };
+    let answers = [
+        ("identify the specific Rust files", serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec!["src/lib.rs".to_string()] })?),
+        ("You previously requested specific code elements", serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?),
+        ("generate the precise code changes", serde_json::to_string(&code_gen_response)?),
+    ];
+    let driver = StubWebDriver::start(0, move |prompt| {
+        let (_, answer) = answers.iter().find(|(marker, _)| prompt.contains(marker)).expect("no scripted answer for prompt");
+        format!("{}<span class=\"done\">Done</span>", chat_answer_with_json(answer))
+    });
+
+    // As for `echo "rename OLD_CONST from stdin" | gem --browser ...`
+    let mut gem = Command::new(env!("CARGO_BIN_EXE_gem"))
+        .args(["--browser", &chat_page_url(), "--webdriver", &driver.base_url, "--finished", ".done", "--verify-with", "true"])
+        .arg("--project-root")
+        .arg(&project_root)
+        .env("HOME", home_dir_guard.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    gem.stdin.take().expect("piped stdin").write_all(b"rename OLD_CONST from stdin\n")?;
+    let output = gem.wait_with_output()?;
+
+    assert!(output.status.success(), "{}\n{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    let prompts = driver.state().prompts.clone();
+    assert_eq!(prompts.len(), 3);
+    assert!(prompts[0].contains("User Request: \"rename OLD_CONST from stdin\""), "{}", prompts[0]);
+    assert_eq!(fs::read_to_string(project_root.join("src").join("lib.rs"))?.trim(), "const NEW_CONST: i32 = 2;");
+    Ok(())
+}
@@ -0,0 +1,199 @@
+// Shared helpers for the integration tests.
+// Not every test crate uses every helper.
+#![allow(dead_code)]
+
+use gem::cli::{CustomCliArgs, MAX_DATA_GATHERING_ITERATIONS_DEFAULT, MAX_VERIFICATION_RETRIES_DEFAULT, VERIFY_TIMEOUT_SECS_DEFAULT, MAX_API_RETRIES_DEFAULT, CONTEXT_CACHE_TTL_SECS_DEFAULT, CONTEXT_CACHE_MIN_TOKENS_DEFAULT, DEFAULT_WEBDRIVER_URL, DEFAULT_END_MARKER};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::{tempdir, TempDir};
+
+pub mod webdriver;
+
+pub fn common_test_args(project_root: PathBuf, user_request: &str) -> CustomCliArgs {
+    CustomCliArgs {
+        user_request_parts: vec![user_request.to_string()],
+        project_root,
+        verify_with: vec!["cargo check".to_string()],
+        no_test: false,
+        project_file: None,
+        max_data_loops: MAX_DATA_GATHERING_ITERATIONS_DEFAULT,
+        max_verify_retries: MAX_VERIFICATION_RETRIES_DEFAULT,
+        verify_timeout: VERIFY_TIMEOUT_SECS_DEFAULT,
+        stash: false,
+        commit_author: None,
+        max_api_retries: MAX_API_RETRIES_DEFAULT,
+        max_prompt_tokens: None,
+        debug_mode: None,
+        no_explanation: false,
+        no_code: false,
+        no_readme: false,
+        auto_tool_selection: false,
+        browser: None,
+        input_selector: None,
+        codeblock_selector: None,
+        finished_selector: None,
+        webdriver_url: DEFAULT_WEBDRIVER_URL.to_string(),
+        local: None,
+        manual: false,
+        prompt_file: None,
+        clipboard: false,
+        end_marker: DEFAULT_END_MARKER.to_string(),
+        openai_base_url: None,
+        openai_model: None,
+        model_overrides: vec![],
+        no_stream: false,
+        usage_report: false,
+        no_tools: false,
+        context_cache_ttl: CONTEXT_CACHE_TTL_SECS_DEFAULT,
+        context_cache_min_tokens: CONTEXT_CACHE_MIN_TOKENS_DEFAULT,
+    }
+}
+
+// Helper function to setup a test environment
+// Returns project_root, a TempDir guard for project_root, and a TempDir guard for home_path
+pub fn setup_test_env(session_id_prefix: &str) -> (PathBuf, TempDir, TempDir) {
+    let temp_project_dir_guard = tempdir().unwrap(); // Guard for project root
+    let project_root = temp_project_dir_guard.path().to_path_buf();
+
+    let src_dir = project_root.join("src");
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("lib.rs"), "pub fn hello() {} \n pub struct SomeStruct;").unwrap();
+    fs::write(project_root.join("Cargo.toml"), "[package]\nname = \"test_project\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n").unwrap();
+
+    let unique_session_id = format!("{}_{}", session_id_prefix, uuid::Uuid::new_v4());
+
+    let temp_home_dir = tempdir().unwrap();
+    let home_path_str = temp_home_dir.path().to_str().unwrap().to_string();
+    std::env::set_var("HOME", &home_path_str);
+
+    let session_dir = PathBuf::from(&home_path_str).join(".gem").join("session").join(unique_session_id);
+    fs::create_dir_all(&session_dir).unwrap();
+
+    (project_root, temp_project_dir_guard, temp_home_dir)
+}
+
+// `StubServer` is a minimal stand-in HTTP server: it answers each incoming request with the next
+// canned response and records what it received, so tests can assert on paths, headers and bodies.
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>, // Header names are lowercased
+    pub body: String,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers.iter().find(|(k, _)| *k == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn json_body(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).expect("request body is not valid JSON")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StubResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl StubResponse {
+    pub fn json(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: body.to_string(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+pub struct StubServer {
+    pub base_url: String, // e.g. "http://127.0.0.1:12345"
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl StubServer {
+    /// Starts the server on an ephemeral port. Requests beyond the canned responses get a 500.
+    pub fn start(responses: Vec<StubResponse>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind stub server");
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&requests);
+
+        thread::spawn(move || {
+            let mut responses = responses.into_iter();
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let Some(request) = read_request(&mut stream) else { continue };
+                recorded.lock().unwrap().push(request);
+
+                let response = responses
+                    .next()
+                    .unwrap_or_else(|| StubResponse::json(500, r#"{"error":"stub server has no more responses"}"#));
+                write_response(&mut stream, &response);
+            }
+        });
+
+        Self { base_url, requests }
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn write_response(stream: &mut std::net::TcpStream, response: &StubResponse) {
+    let mut raw = format!("HTTP/1.1 {} Stub\r\n", response.status);
+    for (name, value) in &response.headers {
+        raw.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    raw.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", response.body.len()));
+    raw.push_str(&response.body);
+    let _ = stream.write_all(raw.as_bytes());
+    let _ = stream.flush();
+}
+
+fn read_request(stream: &mut std::net::TcpStream) -> Option<RecordedRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    let content_length = headers
+        .iter()
+        .find(|(k, _)| k == "content-length")
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(RecordedRequest { method, path, headers, body: String::from_utf8_lossy(&body).to_string() })
+}
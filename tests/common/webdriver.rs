@@ -0,0 +1,183 @@
+// `StubWebDriver` stands in for chromedriver plus a browser: it implements the few W3C WebDriver
+// commands `gem` uses against a static HTML page loaded from a `file://` URL. Pressing Enter in a
+// field "sends" the typed prompt; the scripted reply is appended to the page a few polls later,
+// like a chat answer that takes a while to generate.
+
+use std::fs;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use regex::Regex;
+use serde_json::{json, Value};
+
+use super::{read_request, write_response, StubResponse};
+
+const ELEMENT_KEY: &str = "element-6066-11e4-a52e-4f735466cecf";
+const SESSION_ID: &str = "stub-session";
+
+type Reply = Box<dyn Fn(&str) -> String + Send>;
+
+#[derive(Default)]
+pub struct BrowserState {
+    pub url: Option<String>,
+    pub navigations: usize,
+    pub prompts: Vec<String>, // What was sent, with Shift+Enter turned back into newlines
+    pub session_deleted: bool,
+    pub stale_text_reads: usize, // Element text reads still to answer with `stale element reference`
+    html: String,
+    pending: Option<(String, usize)>, // Reply HTML and the element lookups left before it appears
+}
+
+pub struct StubWebDriver {
+    pub base_url: String,
+    state: Arc<Mutex<BrowserState>>,
+}
+
+impl StubWebDriver {
+    /// `reply` maps each sent prompt to the HTML appended to the page's body, after `delay_polls` element lookups.
+    pub fn start(delay_polls: usize, reply: impl Fn(&str) -> String + Send + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind stub WebDriver");
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(BrowserState::default()));
+        let browser = Arc::clone(&state);
+        let reply: Reply = Box::new(reply);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let Some(request) = read_request(&mut stream) else { continue };
+                let body = if request.body.is_empty() { Value::Null } else { request.json_body() };
+                let (status, value) = handle(&mut browser.lock().unwrap(), &request.method, &request.path, &body, delay_polls, &reply);
+                write_response(&mut stream, &StubResponse::json(status, &json!({ "value": value }).to_string()));
+            }
+        });
+
+        Self { base_url, state }
+    }
+
+    pub fn state(&self) -> std::sync::MutexGuard<'_, BrowserState> {
+        self.state.lock().unwrap()
+    }
+}
+
+fn handle(browser: &mut BrowserState, method: &str, path: &str, body: &Value, delay_polls: usize, reply: &Reply) -> (u16, Value) {
+    let session_path = format!("/session/{}", SESSION_ID);
+    let command = path.strip_prefix(&session_path);
+    match (method, path, command) {
+        ("POST", "/session", _) => (200, json!({ "sessionId": SESSION_ID, "capabilities": {} })),
+        ("DELETE", _, Some("")) => {
+            browser.session_deleted = true;
+            (200, Value::Null)
+        }
+        ("POST", _, Some("/url")) => {
+            let url = body["url"].as_str().unwrap_or_default().to_string();
+            match url.strip_prefix("file://").and_then(|path| fs::read_to_string(path).ok()) {
+                Some(html) => {
+                    browser.html = html;
+                    browser.url = Some(url);
+                    browser.navigations += 1;
+                    (200, Value::Null)
+                }
+                None => error(400, "unknown error", &format!("cannot load {}", url)),
+            }
+        }
+        ("POST", _, Some("/elements")) => {
+            if let Some((html, polls_left)) = browser.pending.take() {
+                if polls_left == 0 {
+                    let end = browser.html.rfind("</body>").unwrap_or(browser.html.len());
+                    browser.html.insert_str(end, &html);
+                } else {
+                    browser.pending = Some((html, polls_left - 1));
+                }
+            }
+            let selector = body["value"].as_str().unwrap_or_default();
+            let found: Vec<Value> = elements(&browser.html)
+                .into_iter()
+                .filter(|element| element.matches(selector))
+                .map(|element| json!({ ELEMENT_KEY: element.start.to_string() }))
+                .collect();
+            (200, Value::Array(found))
+        }
+        (_, _, Some(command)) if command.starts_with("/element/") => {
+            let mut parts = command["/element/".len()..].splitn(2, '/');
+            let start: usize = parts.next().and_then(|id| id.parse().ok()).unwrap_or(usize::MAX);
+            let Some(element) = elements(&browser.html).into_iter().find(|element| element.start == start) else {
+                return error(404, "no such element", "stale element reference");
+            };
+            match (method, parts.next()) {
+                ("GET", Some("text")) if browser.stale_text_reads > 0 => {
+                    browser.stale_text_reads -= 1;
+                    error(404, "stale element reference", "the element was replaced")
+                }
+                ("GET", Some("text")) => (200, json!(element.text)),
+                ("POST", Some("value")) => {
+                    let typed = body["text"].as_str().unwrap_or_default().replace("\u{E008}\u{E007}\u{E000}", "\n");
+                    if let Some(prompt) = typed.strip_suffix('\u{E007}') {
+                        browser.prompts.push(prompt.to_string());
+                        browser.pending = Some((reply(prompt), delay_polls));
+                    }
+                    (200, Value::Null)
+                }
+                _ => error(404, "unknown command", command),
+            }
+        }
+        _ => error(404, "unknown command", path),
+    }
+}
+
+fn error(status: u16, error: &str, message: &str) -> (u16, Value) {
+    (status, json!({ "error": error, "message": message }))
+}
+
+struct Element {
+    start: usize, // Byte offset of the opening tag, used as the element id
+    tag: String,
+    id: Option<String>,
+    classes: Vec<String>,
+    text: String,
+}
+
+impl Element {
+    // Supports `tag`, `#id`, `.class` and combinations such as `pre.code`.
+    fn matches(&self, selector: &str) -> bool {
+        regex(r"([#.]?)([\w-]+)").captures_iter(selector).all(|part| match &part[1] {
+            "#" => self.id.as_deref() == Some(&part[2]),
+            "." => self.classes.iter().any(|class| class == &part[2]),
+            _ => self.tag == part[2],
+        })
+    }
+}
+
+// The page's elements; an element's text runs to the next closing tag of the same name (no nesting).
+fn elements(html: &str) -> Vec<Element> {
+    let attribute = |attributes: &str, pattern: &'static str| regex(pattern).captures(attributes).map(|c| c[1].to_string());
+    regex(r"<([a-zA-Z][a-zA-Z0-9]*)([^>]*)>")
+        .captures_iter(html)
+        .map(|tag| {
+            let whole = tag.get(0).unwrap();
+            let name = tag[1].to_lowercase();
+            let inner = html[whole.end()..].find(&format!("</{}>", name)).map(|end| &html[whole.end()..whole.end() + end]).unwrap_or_default();
+            let text = regex(r"<[^>]*>").replace_all(inner, "").replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&amp;", "&");
+            Element {
+                start: whole.start(),
+                tag: name,
+                id: attribute(&tag[2], r#"\bid="([^"]*)""#),
+                classes: attribute(&tag[2], r#"\bclass="([^"]*)""#).map(|c| c.split_whitespace().map(str::to_string).collect()).unwrap_or_default(),
+                text,
+            }
+        })
+        .collect()
+}
+
+// Compiles each pattern once; the page is re-parsed on every command.
+fn regex(pattern: &'static str) -> &'static Regex {
+    static COMPILED: OnceLock<Mutex<Vec<(&'static str, &'static Regex)>>> = OnceLock::new();
+    let mut compiled = COMPILED.get_or_init(Default::default).lock().unwrap();
+    if let Some((_, regex)) = compiled.iter().find(|(p, _)| *p == pattern) {
+        return regex;
+    }
+    let regex: &'static Regex = Box::leak(Box::new(Regex::new(pattern).unwrap()));
+    compiled.push((pattern, regex));
+    regex
+}
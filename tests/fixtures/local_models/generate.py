@@ -0,0 +1,432 @@
+#!/usr/bin/env python3
+"""Generates the tiny randomly-initialised GGUF models used by the tests in src/gemma.rs,
+and expected.json with what an independent reference implementation (below) predicts for them.
+
+Pure Python (no numpy), so it runs anywhere:
+
+    python3 tests/fixtures/local_models/generate.py
+
+Quantized tensors are written as random blocks rather than quantized from floats; the
+reference dequantizes them itself, so both sides see exactly the same weights.
+"""
+import json
+import math
+import random
+import struct
+from pathlib import Path
+
+OUT = Path(__file__).resolve().parent
+ALIGNMENT = 32
+
+F32, F16, Q4_0, Q4_1, Q5_0, Q5_1, Q8_0, Q4_K, Q5_K, Q6_K, BF16 = 0, 1, 2, 3, 6, 7, 8, 12, 13, 14, 30
+BLOCK = {F32: (1, 4), F16: (1, 2), BF16: (1, 2), Q4_0: (32, 18), Q4_1: (32, 20), Q5_0: (32, 22), Q5_1: (32, 24),
+         Q8_0: (32, 34), Q4_K: (256, 144), Q5_K: (256, 176), Q6_K: (256, 210)}
+
+CONTROL, BYTE, NORMAL = 3, 6, 1
+SPACE = "▁"
+
+
+# --- GGUF writing -------------------------------------------------------------------------------
+
+def gguf_string(s):
+    b = s.encode()
+    return struct.pack("<Q", len(b)) + b
+
+
+def gguf_value(kind, value):
+    if kind == "u32":
+        return struct.pack("<I", 4) + struct.pack("<I", value)
+    if kind == "f32":
+        return struct.pack("<I", 6) + struct.pack("<f", value)
+    if kind == "bool":
+        return struct.pack("<I", 7) + struct.pack("<B", value)
+    if kind == "str":
+        return struct.pack("<I", 8) + gguf_string(value)
+    element_kind, values = kind.split(":")[1], value
+    element_type, element_format = {"str": (8, None), "f32": (6, "<f"), "i32": (5, "<i")}[element_kind]
+    body = struct.pack("<IIQ", 9, element_type, len(values))
+    for v in values:
+        body += gguf_string(v) if element_format is None else struct.pack(element_format, v)
+    return body
+
+
+def write_gguf(path, metadata, tensors):
+    header = b"GGUF" + struct.pack("<IQQ", 3, len(tensors), len(metadata))
+    for key, kind, value in metadata:
+        header += gguf_string(key) + gguf_value(kind, value)
+    data, offsets = b"", []
+    for _, _, _, raw in tensors:
+        data += b"\0" * (-len(data) % ALIGNMENT)
+        offsets.append(len(data))
+        data += raw
+    for (name, dims, ggml_type, _), offset in zip(tensors, offsets):
+        header += gguf_string(name) + struct.pack("<I", len(dims)) + b"".join(struct.pack("<Q", d) for d in dims)
+        header += struct.pack("<IQ", ggml_type, offset)
+    header += b"\0" * (-len(header) % ALIGNMENT)
+    path.write_bytes(header + data)
+
+
+# --- Random tensors and their dequantization -------------------------------------------------------
+
+def f16(x):
+    return struct.pack("<e", x)
+
+
+def random_tensor(rng, ggml_type, rows, cols, scale):
+    """Random raw bytes of a rows x cols tensor whose values are roughly of magnitude `scale`."""
+    block_elements, _ = BLOCK[ggml_type]
+    out = b""
+    for _ in range(rows * cols // block_elements):
+        noise = lambda n: bytes(rng.randrange(256) for _ in range(n))
+        d = scale * rng.uniform(0.5, 1.5)
+        if ggml_type == F32:
+            out += struct.pack("<f", rng.gauss(0, scale))
+        elif ggml_type == F16:
+            out += f16(rng.gauss(0, scale))
+        elif ggml_type == BF16:
+            out += struct.pack("<f", rng.gauss(0, scale))[2:]
+        elif ggml_type == Q4_0:
+            out += f16(d / 4) + noise(16)
+        elif ggml_type == Q4_1:
+            out += f16(d / 4) + f16(-2 * d) + noise(16)
+        elif ggml_type == Q5_0:
+            out += f16(d / 8) + noise(20)
+        elif ggml_type == Q5_1:
+            out += f16(d / 8) + f16(-2 * d) + noise(20)
+        elif ggml_type == Q8_0:
+            out += f16(d / 64) + noise(32)
+        elif ggml_type == Q4_K:
+            out += f16(d / 200) + f16(7.5 * d / 200) + noise(12 + 128)
+        elif ggml_type == Q5_K:
+            out += f16(d / 400) + f16(15.5 * d / 400) + noise(12 + 32 + 128)
+        elif ggml_type == Q6_K:
+            out += noise(128 + 64) + bytes(rng.randrange(-60, 61) & 0xFF for _ in range(16)) + f16(d / 500)
+    return out
+
+
+def scale_min_k4(j, q):
+    if j < 4:
+        return q[j] & 63, q[j + 4] & 63
+    return (q[j + 4] & 0xF) | ((q[j - 4] >> 6) << 4), (q[j + 4] >> 4) | ((q[j] >> 6) << 4)
+
+
+def dequantize_block(ggml_type, b):
+    """The values of one block, following ggml's reference dequantize_row_* functions."""
+    h = lambda at: struct.unpack_from("<e", b, at)[0]
+    if ggml_type == F32:
+        return [struct.unpack("<f", b)[0]]
+    if ggml_type == F16:
+        return [h(0)]
+    if ggml_type == BF16:
+        return [struct.unpack("<f", b"\0\0" + b)[0]]
+    if ggml_type == Q8_0:
+        return [h(0) * struct.unpack("b", bytes([q]))[0] for q in b[2:34]]
+    if ggml_type in (Q4_0, Q4_1):
+        d, qs = h(0), b[2:18] if ggml_type == Q4_0 else b[4:20]
+        if ggml_type == Q4_0:
+            return [((q & 0xF) - 8) * d for q in qs] + [((q >> 4) - 8) * d for q in qs]
+        m = h(2)
+        return [(q & 0xF) * d + m for q in qs] + [(q >> 4) * d + m for q in qs]
+    if ggml_type in (Q5_0, Q5_1):
+        at = 2 if ggml_type == Q5_0 else 4
+        d, qh, qs = h(0), struct.unpack_from("<I", b, at)[0], b[at + 4:at + 20]
+        lo = [(qs[j] & 0xF) | (((qh >> j) & 1) << 4) for j in range(16)]
+        hi = [(qs[j] >> 4) | (((qh >> (j + 16)) & 1) << 4) for j in range(16)]
+        if ggml_type == Q5_0:
+            return [(x - 16) * d for x in lo + hi]
+        m = h(2)
+        return [x * d + m for x in lo + hi]
+    if ggml_type in (Q4_K, Q5_K):
+        d, dmin, scales = h(0), h(2), b[4:16]
+        qh, qs = (b[16:48], b[48:176]) if ggml_type == Q5_K else (None, b[16:144])
+        y = []
+        for chunk in range(4):
+            (sc1, m1), (sc2, m2) = scale_min_k4(2 * chunk, scales), scale_min_k4(2 * chunk + 1, scales)
+            q = qs[32 * chunk:32 * chunk + 32]
+            high = lambda l, bit: 16 * ((qh[l] >> bit) & 1) if qh else 0
+            y += [d * sc1 * ((q[l] & 0xF) + high(l, 2 * chunk)) - dmin * m1 for l in range(32)]
+            y += [d * sc2 * ((q[l] >> 4) + high(l, 2 * chunk + 1)) - dmin * m2 for l in range(32)]
+        return y
+    if ggml_type == Q6_K:
+        ql, qh, sc, d = b[0:128], b[128:192], struct.unpack("16b", b[192:208]), h(208)
+        y = [0.0] * 256
+        for n in range(2):
+            for l in range(32):
+                lo, hb, s, base = ql[64 * n:], qh[32 * n:], sc[8 * n:], 128 * n
+                is_ = l // 16
+                y[base + l] = d * s[is_] * (((lo[l] & 0xF) | ((hb[l] & 3) << 4)) - 32)
+                y[base + l + 32] = d * s[is_ + 2] * (((lo[l + 32] & 0xF) | (((hb[l] >> 2) & 3) << 4)) - 32)
+                y[base + l + 64] = d * s[is_ + 4] * (((lo[l] >> 4) | (((hb[l] >> 4) & 3) << 4)) - 32)
+                y[base + l + 96] = d * s[is_ + 6] * (((lo[l + 32] >> 4) | (((hb[l] >> 6) & 3) << 4)) - 32)
+        return y
+    raise ValueError(ggml_type)
+
+
+def dequantize(ggml_type, raw, rows, cols):
+    block_elements, block_bytes = BLOCK[ggml_type]
+    values = []
+    for i in range(0, len(raw), block_bytes):
+        values += dequantize_block(ggml_type, raw[i:i + block_bytes])
+    return [values[r * cols:(r + 1) * cols] for r in range(rows)]
+
+
+# --- SentencePiece tokenizer -------------------------------------------------------------------------
+
+def build_vocab(rng, specials, words):
+    """Specials, the 256 byte tokens, single characters, then every prefix of every word."""
+    tokens = [t for t, _ in specials] + ["<0x%02X>" % b for b in range(256)]
+    types = [k for _, k in specials] + [BYTE] * 256
+    pieces = list(SPACE + "abcdefghijklmnopqrstuvwxyz0123456789.,!?(){};:'\"_=\n") + ["ll", "lo", "in", "er", "he", "or", "()"]
+    for word in words:
+        pieces += [word[:n] for n in range(2, len(word) + 1)]
+    for piece in pieces:
+        if piece not in tokens:
+            tokens.append(piece)
+            types.append(NORMAL)
+    # Longer pieces score higher; the random part breaks ties between pieces of the same length.
+    scores = [0.0 if k != NORMAL else len(t) + rng.random() for t, k in zip(tokens, types)]
+    return tokens, [float(struct.unpack("<f", struct.pack("<f", s))[0]) for s in scores], types
+
+
+def spm_encode(text, tokens, scores, add_space_prefix):
+    ids = {t: i for i, t in enumerate(tokens)}
+    text = ((" " if add_space_prefix else "") + text).replace(" ", SPACE)
+    symbols = list(text)
+    while True:
+        best = None
+        for i in range(len(symbols) - 1):
+            merged = symbols[i] + symbols[i + 1]
+            if merged in ids and (best is None or scores[ids[merged]] > best[0]):
+                best = (scores[ids[merged]], i)
+        if best is None:
+            break
+        i = best[1]
+        symbols[i:i + 2] = [symbols[i] + symbols[i + 1]]
+    out = []
+    for s in symbols:
+        out += [ids[s]] if s in ids else [ids["<0x%02X>" % b] for b in s.encode()]
+    return out
+
+
+def spm_decode(ids, tokens, types):
+    out = b""
+    for i in ids:
+        if types[i] == CONTROL:
+            continue
+        out += bytes([int(tokens[i][3:5], 16)]) if types[i] == BYTE else tokens[i].replace(SPACE, " ").encode()
+    return out
+
+
+# --- Reference forward pass ----------------------------------------------------------------------------
+
+def rms_norm(x, w, eps):
+    scale = 1.0 / math.sqrt(sum(v * v for v in x) / len(x) + eps)
+    return [v * scale * wi for v, wi in zip(x, w)]
+
+
+def matvec(rows, x):
+    return [sum(w * v for w, v in zip(row, x)) for row in rows]
+
+
+def rope(vec, pos, head_dim, base, neox):
+    out = list(vec)
+    for h in range(0, len(vec), head_dim):
+        for i in range(head_dim // 2):
+            angle = pos * base ** (-2.0 * i / head_dim)
+            a, b = (h + i, h + i + head_dim // 2) if neox else (h + 2 * i, h + 2 * i + 1)
+            out[a] = vec[a] * math.cos(angle) - vec[b] * math.sin(angle)
+            out[b] = vec[a] * math.sin(angle) + vec[b] * math.cos(angle)
+    return out
+
+
+def gelu(x):
+    return 0.5 * x * (1 + math.tanh(math.sqrt(2 / math.pi) * (x + 0.044715 * x ** 3)))
+
+
+def silu(x):
+    return x / (1 + math.exp(-x))
+
+
+def reference_logits(cfg, w, tokens):
+    """Logits after the last of `tokens`, recomputing the whole sequence (no cache)."""
+    dim, hd, nh, nkv = cfg["dim"], cfg["head_dim"], cfg["heads"], cfg["kv_heads"]
+    gemma = cfg["arch"].startswith("gemma")
+    x = [[v * (math.sqrt(dim) if gemma else 1.0) for v in w["token_embd.weight"][t]] for t in tokens]
+    for layer in range(cfg["layers"]):
+        p = lambda name: w.get("blk.%d.%s.weight" % (layer, name))
+        local = cfg.get("window") is not None and (layer + 1) % 6 != 0
+        base = 10000.0 if local and cfg["arch"] == "gemma3" else cfg["rope_base"]
+        qs, ks, vs = [], [], []
+        for pos, xt in enumerate(x):
+            h = rms_norm(xt, p("attn_norm"), cfg["eps"])
+            q, k, v = matvec(p("attn_q"), h), matvec(p("attn_k"), h), matvec(p("attn_v"), h)
+            if p("attn_q_norm"):
+                q = sum((rms_norm(q[i:i + hd], p("attn_q_norm"), cfg["eps"]) for i in range(0, len(q), hd)), [])
+                k = sum((rms_norm(k[i:i + hd], p("attn_k_norm"), cfg["eps"]) for i in range(0, len(k), hd)), [])
+            qs.append(rope(q, pos, hd, base, gemma))
+            ks.append(rope(k, pos, hd, base, gemma))
+            vs.append(v)
+        new_x = []
+        for pos, xt in enumerate(x):
+            attn = []
+            for head in range(nh):
+                kv = head // (nh // nkv)
+                q = qs[pos][head * hd:(head + 1) * hd]
+                first = max(0, pos + 1 - cfg["window"]) if local else 0
+                scores = [sum(a * b for a, b in zip(q, ks[j][kv * hd:(kv + 1) * hd])) / math.sqrt(hd) for j in range(first, pos + 1)]
+                top = max(scores)
+                exps = [math.exp(s - top) for s in scores]
+                total = sum(exps)
+                out = [0.0] * hd
+                for j, e in zip(range(first, pos + 1), exps):
+                    for i in range(hd):
+                        out[i] += e / total * vs[j][kv * hd + i]
+                attn += out
+            o = matvec(p("attn_output"), attn)
+            if p("post_attention_norm"):
+                o = rms_norm(o, p("post_attention_norm"), cfg["eps"])
+            xt = [a + b for a, b in zip(xt, o)]
+            h = rms_norm(xt, p("ffn_norm"), cfg["eps"])
+            act = gelu if gemma else silu
+            g = [act(a) * b for a, b in zip(matvec(p("ffn_gate"), h), matvec(p("ffn_up"), h))]
+            d = matvec(p("ffn_down"), g)
+            if p("post_ffw_norm"):
+                d = rms_norm(d, p("post_ffw_norm"), cfg["eps"])
+            new_x.append([a + b for a, b in zip(xt, d)])
+        x = new_x
+    last = rms_norm(x[-1], w["output_norm.weight"], cfg["eps"])
+    return matvec(w.get("output.weight", w["token_embd.weight"]), last)
+
+
+# --- The fixtures --------------------------------------------------------------------------------------
+
+def build(cfg, seed):
+    rng = random.Random(seed)
+    tokens, scores, types = build_vocab(rng, cfg["specials"], cfg["words"])
+    vocab, dim, hd, ff = len(tokens), cfg["dim"], cfg["head_dim"], cfg["ff"]
+    shapes = {"attn_q": (cfg["heads"] * hd, dim), "attn_k": (cfg["kv_heads"] * hd, dim), "attn_v": (cfg["kv_heads"] * hd, dim),
+              "attn_output": (dim, cfg["heads"] * hd), "ffn_gate": (ff, dim), "ffn_up": (ff, dim), "ffn_down": (dim, ff)}
+    tensors, weights = [], {}
+
+    def add(name, rows, cols, ggml_type, scale):
+        raw = random_tensor(rng, ggml_type, rows, cols, scale)
+        tensors.append((name, [cols, rows] if rows > 1 else [cols], ggml_type, raw))
+        weights[name] = dequantize(ggml_type, raw, rows, cols)
+        if rows == 1:
+            weights[name] = weights[name][0]
+
+    def add_norm(name, size):
+        raw = b"".join(struct.pack("<f", rng.gauss(1.0, 0.1)) for _ in range(size))
+        tensors.append((name, [size], F32, raw))
+        weights[name] = dequantize(F32, raw, 1, size)[0]
+
+    add("token_embd.weight", vocab, dim, cfg["types"]["token_embd"], cfg.get("embd_scale", 1.0))
+    for layer in range(cfg["layers"]):
+        for name, ggml_type in cfg["types"]["layers"][layer].items():
+            rows, cols = shapes[name]
+            add("blk.%d.%s.weight" % (layer, name), rows, cols, ggml_type, 1.0 / math.sqrt(cols))
+        for norm in cfg["norms"]:
+            add_norm("blk.%d.%s.weight" % (layer, norm), hd if norm in ("attn_q_norm", "attn_k_norm") else dim)
+    add_norm("output_norm.weight", dim)
+    if "output" in cfg["types"]:
+        add("output.weight", vocab, dim, cfg["types"]["output"], 1.0 / math.sqrt(dim))
+
+    arch = cfg["arch"]
+    metadata = [("general.architecture", "str", arch), ("general.name", "str", "tiny random " + arch),
+                (arch + ".context_length", "u32", cfg["context"]), (arch + ".embedding_length", "u32", dim),
+                (arch + ".block_count", "u32", cfg["layers"]), (arch + ".feed_forward_length", "u32", ff),
+                (arch + ".attention.head_count", "u32", cfg["heads"]), (arch + ".attention.head_count_kv", "u32", cfg["kv_heads"]),
+                (arch + ".attention.key_length", "u32", hd), (arch + ".attention.value_length", "u32", hd),
+                (arch + ".attention.layer_norm_rms_epsilon", "f32", cfg["eps"]), (arch + ".rope.freq_base", "f32", cfg["rope_base"]),
+                ("tokenizer.ggml.model", "str", "llama"), ("tokenizer.ggml.tokens", "arr:str", tokens),
+                ("tokenizer.ggml.scores", "arr:f32", scores), ("tokenizer.ggml.token_type", "arr:i32", types),
+                ("tokenizer.ggml.bos_token_id", "u32", tokens.index(cfg["bos"])), ("tokenizer.ggml.eos_token_id", "u32", tokens.index(cfg["eos"]))]
+    if cfg.get("window"):
+        metadata.append((arch + ".attention.sliding_window", "u32", cfg["window"]))
+    if cfg["add_space_prefix"] is not None:
+        metadata.append(("tokenizer.ggml.add_space_prefix", "bool", cfg["add_space_prefix"]))
+    return tokens, scores, types, metadata, tensors, weights
+
+
+def chat_tokens(cfg, tokens, scores, prompt):
+    ids = {t: i for i, t in enumerate(tokens)}
+    enc = lambda text, at_start: spm_encode(text, tokens, scores, cfg["add_space_prefix"] is not False and at_start)
+    if "<start_of_turn>" in ids:
+        start, end = ids["<start_of_turn>"], ids["<end_of_turn>"]
+        return [ids[cfg["bos"]], start] + enc("user\n" + prompt, False) + [end] + enc("\n", False) + [start] + enc("model\n", False)
+    return [ids[cfg["bos"]]] + enc(prompt, True)
+
+
+def generate(cfg, weights, prompt_tokens, stop, max_tokens):
+    """Greedy decoding; also returns the smallest top-1/top-2 logit gap seen, to reject seeds with near ties."""
+    sequence, generated, first_logits, margin = list(prompt_tokens), [], None, float("inf")
+    while len(generated) < max_tokens:
+        logits = reference_logits(cfg, weights, sequence)
+        first_logits = first_logits or logits
+        ranked = sorted(range(len(logits)), key=lambda i: -logits[i])
+        margin = min(margin, logits[ranked[0]] - logits[ranked[1]])
+        if ranked[0] in stop:
+            break
+        generated.append(ranked[0])
+        sequence.append(ranked[0])
+    return generated, first_logits, margin
+
+
+LLAMA = {
+    "file": "tiny-llama.gguf", "arch": "llama", "dim": 32, "heads": 4, "kv_heads": 2, "head_dim": 8, "ff": 256, "layers": 3,
+    "context": 128, "eps": 1e-5, "rope_base": 10000.0, "add_space_prefix": None, "bos": "<s>", "eos": "</s>",
+    "specials": [("<unk>", 2), ("<s>", CONTROL), ("</s>", CONTROL)],
+    "words": [SPACE + w for w in ["hello", "world", "the", "fn", "main", "println", "let", "use", "mod", "a", "in", "on"]],
+    "norms": ["attn_norm", "ffn_norm"],
+    "types": {"token_embd": Q8_0, "output": Q8_0, "layers": [
+        {"attn_q": Q4_0, "attn_k": Q4_1, "attn_v": Q5_0, "attn_output": Q5_1, "ffn_gate": F16, "ffn_up": BF16, "ffn_down": Q4_K},
+        {"attn_q": F32, "attn_k": Q8_0, "attn_v": F16, "attn_output": Q4_0, "ffn_gate": Q8_0, "ffn_up": Q5_0, "ffn_down": Q5_K},
+        {"attn_q": Q8_0, "attn_k": Q8_0, "attn_v": Q8_0, "attn_output": Q8_0, "ffn_gate": Q4_1, "ffn_up": Q5_1, "ffn_down": Q6_K},
+    ]},
+    "prompt": "hello world, fn main() { println!(\"hi\"); }",
+}
+
+GEMMA3 = {
+    "file": "tiny-gemma3.gguf", "arch": "gemma3", "dim": 32, "heads": 2, "kv_heads": 1, "head_dim": 16, "ff": 64, "layers": 6,
+    "context": 256, "eps": 1e-6, "embd_scale": 0.15, "rope_base": 1000000.0, "window": 4, "add_space_prefix": False, "bos": "<bos>", "eos": "<eos>",
+    "specials": [("<pad>", CONTROL), ("<eos>", CONTROL), ("<bos>", CONTROL), ("<unk>", 2),
+                 ("<start_of_turn>", CONTROL), ("<end_of_turn>", CONTROL)],
+    "words": ["user", "model"] + [SPACE + w for w in ["hello", "world", "the", "code", "a"]],
+    "norms": ["attn_norm", "attn_q_norm", "attn_k_norm", "post_attention_norm", "ffn_norm", "post_ffw_norm"],
+    "types": {"token_embd": Q8_0, "layers": [
+        {"attn_q": [F32, F16, Q8_0][i % 3], "attn_k": Q8_0, "attn_v": F16, "attn_output": Q4_0, "ffn_gate": Q8_0, "ffn_up": F16, "ffn_down": Q8_0}
+        for i in range(6)]},
+    "prompt": "hello world, the code",
+}
+
+MAX_TOKENS = 6
+
+
+def main():
+    expected = {}
+    for cfg in (LLAMA, GEMMA3):
+        for seed in range(100):
+            tokens, scores, types, metadata, tensors, weights = build(cfg, seed)
+            ids = {t: i for i, t in enumerate(tokens)}
+            prompt_tokens = chat_tokens(cfg, tokens, scores, cfg["prompt"])
+            stop = {ids[cfg["eos"]]} | ({ids["<end_of_turn>"]} if "<end_of_turn>" in ids else set())
+            generated, logits, margin = generate(cfg, weights, prompt_tokens, stop, MAX_TOKENS)
+            try:
+                text = spm_decode(generated, tokens, types).decode("utf-8")
+            except UnicodeDecodeError:
+                continue
+            if margin > 0.01 and len(generated) == MAX_TOKENS and len(set(generated)) >= 3:
+                break
+        else:
+            raise SystemExit("no seed gave an unambiguous greedy decode for " + cfg["file"])
+        if cfg is LLAMA:
+            assert spm_encode("hello world", tokens, scores, True) == [ids[SPACE + "hello"], ids[SPACE + "world"]]
+        write_gguf(OUT / cfg["file"], metadata, tensors)
+        expected[cfg["file"]] = {"seed": seed, "prompt": cfg["prompt"], "prompt_tokens": prompt_tokens, "max_tokens": MAX_TOKENS,
+                                 "generated_tokens": generated, "generated_text": text, "logits": [round(l, 6) for l in logits]}
+        print("%s: seed %d, %d prompt tokens, generated %r" % (cfg["file"], seed, len(prompt_tokens), text))
+    (OUT / "expected.json").write_text(json.dumps(expected, indent=1) + "\n")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,848 @@
+use gem::cache::Session;
+use gem::llm_api::{ChatTurn, ContextCacheConfig, LLMApi, LLMApiError, ModelReply, ResponseSchema, TokenUsage, ToolCall, ToolResult, OpenAICompatibleLLMApi, RealLLMApi, RetryPolicy, GeminiNeededItemsResponse, GeminiSufficiencyResponse, GeminiCodeGenerationResponse, CodeChange, CodeChangeAction};
+use gem::run_gem_agent;
+use serial_test::serial;
+use std::error::Error;
+use std::fs;
+use std::time::Duration;
+
+mod common;
+use common::{common_test_args, setup_test_env, StubResponse, StubServer};
+
+fn openai_chat_response(content: &str) -> StubResponse {
+    let body = serde_json::json!({
+        "id": "chatcmpl-stub",
+        "object": "chat.completion",
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": "stop"
+        }]
+    });
+    StubResponse::json(200, &body.to_string())
+}
+
+fn gemini_sse_response(chunks: &[&str]) -> StubResponse {
+    let body: String = chunks
+        .iter()
+        .map(|text| {
+            let chunk = serde_json::json!({
+                "candidates": [{ "content": { "parts": [{ "text": text }], "role": "model" } }]
+            });
+            format!("data: {}\r\n\r\n", chunk)
+        })
+        .collect();
+    let last = serde_json::json!({
+        "candidates": [{ "content": { "parts": [{ "text": "" }], "role": "model" }, "finishReason": "STOP" }]
+    });
+    StubResponse {
+        status: 200,
+        headers: vec![("Content-Type".to_string(), "text/event-stream".to_string())],
+        body: format!("{}data: {}\r\n\r\n", body, last),
+    }
+}
+
+fn gemini_json_response(text: &str) -> StubResponse {
+    let body = serde_json::json!({
+        "candidates": [{ "content": { "parts": [{ "text": text }], "role": "model" }, "finishReason": "STOP" }]
+    });
+    StubResponse::json(200, &body.to_string())
+}
+
+fn fast_retry_policy(max_retries: usize) -> RetryPolicy {
+    RetryPolicy {
+        max_retries,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(50),
+    }
+}
+
+#[test]
+fn test_gemini_generate_content_against_base_url() -> Result<(), Box<dyn Error>> {
+    let server = StubServer::start(vec![gemini_json_response("plain answer")]);
+    let api = RealLLMApi::new("test-key".to_string()).with_base_url(server.base_url.clone());
+
+    assert_eq!(api.generate_content("prompt", "gemini-test")?, "plain answer");
+    assert_eq!(server.requests()[0].path, "/models/gemini-test:generateContent?key=test-key");
+    Ok(())
+}
+
+#[test]
+fn test_gemini_streaming_accumulates_sse_chunks() -> Result<(), Box<dyn Error>> {
+    let server = StubServer::start(vec![gemini_sse_response(&["{\"needed_items\": ", "[\"src/lib.rs\"]}"])]);
+    let api = RealLLMApi::new("test-key".to_string())
+        .with_base_url(server.base_url.clone())
+        .with_streaming(true);
+
+    let response = api.generate_content("what do you need?", "gemini-test")?;
+    assert_eq!(response, r#"{"needed_items": ["src/lib.rs"]}"#);
+
+    let request = &server.requests()[0];
+    assert_eq!(request.path, "/models/gemini-test:streamGenerateContent?alt=sse&key=test-key");
+    assert_eq!(request.json_body()["contents"][0]["parts"][0]["text"], "what do you need?");
+    Ok(())
+}
+
+fn gemini_sse_finished(text: &str, finish_reason: &str) -> StubResponse {
+    let chunk = serde_json::json!({
+        "candidates": [{ "content": { "parts": [{ "text": text }], "role": "model" }, "finishReason": finish_reason }]
+    });
+    StubResponse {
+        status: 200,
+        headers: vec![("Content-Type".to_string(), "text/event-stream".to_string())],
+        body: format!("data: {}\r\n\r\n", chunk),
+    }
+}
+
+#[test]
+fn test_gemini_continues_truncated_response() -> Result<(), Box<dyn Error>> {
+    let server = StubServer::start(vec![
+        gemini_sse_finished("{\"sufficient\": ", "MAX_TOKENS"),
+        gemini_sse_finished("true}", "STOP"),
+    ]);
+    let api = RealLLMApi::new("test-key".to_string())
+        .with_base_url(server.base_url.clone())
+        .with_streaming(true);
+
+    let schema = GeminiSufficiencyResponse::response_schema();
+    assert_eq!(api.generate_structured("enough?", "gemini-test", &schema)?, "{\"sufficient\": true}");
+
+    let requests = server.requests();
+    assert_eq!(requests[0].json_body()["generationConfig"]["responseSchema"], schema);
+    let continuation = requests[1].json_body();
+    assert_eq!(continuation["contents"][1], serde_json::json!({ "role": "model", "parts": [{ "text": "{\"sufficient\": " }] }));
+    assert!(continuation["contents"][2]["parts"][0]["text"].as_str().unwrap().contains("Continue exactly where it stopped"));
+    // A schema would make the model start a new JSON document instead of finishing this one.
+    assert!(continuation.get("generationConfig").is_none());
+    Ok(())
+}
+
+#[test]
+fn test_gemini_gives_up_on_endlessly_truncated_response() {
+    let truncated = || gemini_json_response_finished("abc", "MAX_TOKENS");
+    let server = StubServer::start(vec![truncated(), truncated(), truncated(), truncated()]);
+    let api = RealLLMApi::new("test-key".to_string()).with_base_url(server.base_url.clone());
+
+    let err = api.generate_content("write a novel", "gemini-test").unwrap_err();
+    match err.downcast_ref::<LLMApiError>() {
+        Some(LLMApiError::Truncated { partial, .. }) => assert_eq!(partial, "abcabcabcabc"),
+        other => panic!("expected a truncation error, got {:?}", other),
+    }
+    assert!(err.to_string().contains("MAX_TOKENS"), "unexpected error: {}", err);
+    assert_eq!(server.requests().len(), 4); // The answer and three continuations
+}
+
+fn gemini_json_response_finished(text: &str, finish_reason: &str) -> StubResponse {
+    let body = serde_json::json!({
+        "candidates": [{ "content": { "parts": [{ "text": text }], "role": "model" }, "finishReason": finish_reason }]
+    });
+    StubResponse::json(200, &body.to_string())
+}
+
+#[test]
+fn test_gemini_safety_and_recitation_blocks_are_explained() {
+    let safety = serde_json::json!({
+        "candidates": [{
+            "finishReason": "SAFETY",
+            "safetyRatings": [
+                { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "probability": "HIGH", "blocked": true },
+                { "category": "HARM_CATEGORY_HARASSMENT", "probability": "NEGLIGIBLE" }
+            ]
+        }]
+    });
+    let prompt_blocked = serde_json::json!({ "promptFeedback": { "blockReason": "PROHIBITED_CONTENT" } });
+    let server = StubServer::start(vec![
+        StubResponse::json(200, &safety.to_string()),
+        StubResponse::json(200, &prompt_blocked.to_string()),
+        gemini_sse_finished("pub fn copied_verbatim", "RECITATION"),
+    ]);
+    let api = RealLLMApi::new("test-key".to_string()).with_base_url(server.base_url.clone());
+
+    let err = api.generate_content("do something dangerous", "gemini-test").unwrap_err();
+    assert!(matches!(err.downcast_ref::<LLMApiError>(), Some(LLMApiError::Blocked { in_prompt: false, .. })));
+    let message = err.to_string();
+    assert!(message.contains("blocked the response (SAFETY: HARM_CATEGORY_DANGEROUS_CONTENT HIGH)"), "unexpected error: {}", message);
+    assert!(!message.contains("HARASSMENT"));
+    assert!(message.contains("Rephrase the request"));
+
+    let err = api.generate_content("something prohibited", "gemini-test").unwrap_err();
+    assert!(err.to_string().contains("blocked the prompt (PROHIBITED_CONTENT)"), "unexpected error: {}", err);
+
+    let api = api.with_streaming(true);
+    let err = api.generate_content("copy a crate", "gemini-test").unwrap_err();
+    assert!(err.to_string().contains("(RECITATION)"), "unexpected error: {}", err);
+    assert!(err.to_string().contains("reproducing existing material"));
+}
+
+#[test]
+fn test_gemini_retries_rate_limit_and_overload() -> Result<(), Box<dyn Error>> {
+    let server = StubServer::start(vec![
+        StubResponse::json(429, r#"{"error":{"code":429,"status":"RESOURCE_EXHAUSTED"}}"#).with_header("Retry-After", "0"),
+        StubResponse::json(503, r#"{"error":{"code":503,"status":"UNAVAILABLE"}}"#),
+        gemini_json_response("answer after retries"),
+    ]);
+    let api = RealLLMApi::new("test-key".to_string())
+        .with_base_url(server.base_url.clone())
+        .with_retry_policy(fast_retry_policy(3));
+
+    assert_eq!(api.generate_content("prompt", "gemini-test")?, "answer after retries");
+    assert_eq!(server.requests().len(), 3);
+    Ok(())
+}
+
+#[test]
+fn test_gemini_gives_up_after_max_retries() {
+    let overloaded = StubResponse::json(503, r#"{"error":{"code":503,"status":"UNAVAILABLE"}}"#);
+    let server = StubServer::start(vec![overloaded.clone(), overloaded.clone(), overloaded]);
+    let api = RealLLMApi::new("test-key".to_string())
+        .with_base_url(server.base_url.clone())
+        .with_retry_policy(fast_retry_policy(2));
+
+    let err = api.generate_content("prompt", "gemini-test").unwrap_err();
+    assert!(matches!(err.downcast_ref::<LLMApiError>(), Some(LLMApiError::Overloaded { status: 503, .. })), "unexpected error: {}", err);
+    assert_eq!(server.requests().len(), 3);
+}
+
+#[test]
+fn test_gemini_auth_and_bad_request_fail_fast() {
+    let server = StubServer::start(vec![
+        StubResponse::json(403, r#"{"error":{"code":403,"status":"PERMISSION_DENIED"}}"#),
+        StubResponse::json(400, r#"{"error":{"code":400,"status":"INVALID_ARGUMENT"}}"#),
+    ]);
+    let api = RealLLMApi::new("bad-key".to_string())
+        .with_base_url(server.base_url.clone())
+        .with_retry_policy(fast_retry_policy(3));
+
+    let err = api.generate_content("prompt", "gemini-test").unwrap_err();
+    assert!(matches!(err.downcast_ref::<LLMApiError>(), Some(LLMApiError::Auth { status: 403, .. })), "unexpected error: {}", err);
+    assert_eq!(server.requests().len(), 1);
+
+    let err = api.generate_content("prompt", "gemini-test").unwrap_err();
+    assert!(matches!(err.downcast_ref::<LLMApiError>(), Some(LLMApiError::BadRequest { status: 400, .. })), "unexpected error: {}", err);
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[test]
+fn test_openai_backend_sends_chat_completion_request() -> Result<(), Box<dyn Error>> {
+    let server = StubServer::start(vec![openai_chat_response("hello from the stub")]);
+    let api = OpenAICompatibleLLMApi::new(
+        format!("{}/v1/", server.base_url),
+        Some("local-model".to_string()),
+        Some("secret-token".to_string()),
+    );
+
+    let response = api.generate_content("say hello", "gemini-model-ignored")?;
+    assert_eq!(response, "hello from the stub");
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    let request = &requests[0];
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/v1/chat/completions");
+    assert_eq!(request.header("authorization"), Some("Bearer secret-token"));
+
+    let body = request.json_body();
+    assert_eq!(body["model"], "local-model");
+    assert_eq!(body["messages"][0]["role"], "user");
+    assert_eq!(body["messages"][0]["content"], "say hello");
+    Ok(())
+}
+
+#[test]
+fn test_openai_backend_uses_agent_model_without_override_or_token() -> Result<(), Box<dyn Error>> {
+    let server = StubServer::start(vec![openai_chat_response("ok")]);
+    let api = OpenAICompatibleLLMApi::new(format!("{}/v1", server.base_url), None, None);
+
+    api.generate_content("prompt", "requested-model")?;
+
+    let request = &server.requests()[0];
+    assert_eq!(request.header("authorization"), None);
+    assert_eq!(request.json_body()["model"], "requested-model");
+    Ok(())
+}
+
+#[test]
+fn test_openai_backend_reports_http_errors() {
+    let server = StubServer::start(vec![StubResponse::json(404, r#"{"error":"model not found"}"#)]);
+    let api = OpenAICompatibleLLMApi::new(format!("{}/v1", server.base_url), None, None);
+
+    let err = api.generate_content("prompt", "missing-model").unwrap_err();
+    assert!(matches!(err.downcast_ref::<LLMApiError>(), Some(LLMApiError::BadRequest { status: 404, .. })), "unexpected error: {}", err);
+    assert!(err.to_string().contains("404"), "unexpected error: {}", err);
+    assert!(err.to_string().contains("model not found"), "unexpected error: {}", err);
+}
+
+#[test]
+#[serial]
+fn test_run_gem_agent_with_openai_backend() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("openai_backend_agent");
+
+    let code_gen_response = GeminiCodeGenerationResponse {
+        changes: vec![CodeChange {
+            file_path: "src/from_openai.txt".to_string(),
+            action: CodeChangeAction::CreateFile,
+            content: Some("written via chat completions".to_string()),
+        }],
+        tests: None,
+        explanation: "Created a file through the OpenAI-compatible backend.".to_string(),
+    };
+    let server = StubServer::start(vec![
+        openai_chat_response(&serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?),
+        openai_chat_response(&serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?),
+        openai_chat_response(&serde_json::to_string(&code_gen_response)?),
+    ]);
+
+    let mut args = common_test_args(project_root.clone(), "create a file through the openai backend");
+    args.no_tools = true; // Exercise the `needed_items` flow
+    let mut session = Session::new(&Session::compute_hash(&format!("{:?}", args)));
+    let llm_api = Box::new(OpenAICompatibleLLMApi::new(format!("{}/v1", server.base_url), Some("local-model".to_string()), None));
+
+    run_gem_agent(args, &mut session, llm_api, false, project_root.clone())?;
+
+    assert_eq!(fs::read_to_string(project_root.join("src/from_openai.txt"))?, "written via chat completions");
+    assert_eq!(server.requests().len(), 3);
+    Ok(())
+}
+
+#[test]
+fn test_gemini_structured_request_sends_response_schema() -> Result<(), Box<dyn Error>> {
+    let server = StubServer::start(vec![
+        gemini_json_response(r#"{"sufficient": true}"#),
+        gemini_sse_response(&[r#"{"sufficient": true}"#]),
+    ]);
+    let schema = GeminiSufficiencyResponse::response_schema();
+    let api = RealLLMApi::new("test-key".to_string()).with_base_url(server.base_url.clone());
+    api.generate_structured("enough?", "gemini-test", &schema)?;
+    let api = api.with_streaming(true);
+    api.generate_structured("enough?", "gemini-test", &schema)?;
+
+    for request in server.requests() {
+        let body = request.json_body();
+        assert_eq!(body["generationConfig"]["responseMimeType"], "application/json");
+        assert_eq!(body["generationConfig"]["responseSchema"], schema);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_openai_structured_request_sends_json_schema_response_format() -> Result<(), Box<dyn Error>> {
+    let server = StubServer::start(vec![openai_chat_response(r#"{"sufficient": true}"#), openai_chat_response("plain")]);
+    let schema = GeminiSufficiencyResponse::response_schema();
+    let api = OpenAICompatibleLLMApi::new(format!("{}/v1", server.base_url), None, None);
+    api.generate_structured("enough?", "local-model", &schema)?;
+    api.generate_content("no schema", "local-model")?;
+
+    let requests = server.requests();
+    let response_format = &requests[0].json_body()["response_format"];
+    assert_eq!(response_format["type"], "json_schema");
+    assert_eq!(response_format["json_schema"]["name"], "response");
+    assert_eq!(response_format["json_schema"]["schema"], gem::llm_api::to_json_schema(&schema));
+    assert_eq!(response_format["json_schema"]["schema"]["properties"]["sufficient"]["type"], "boolean");
+    assert!(requests[1].json_body().get("response_format").is_none());
+    Ok(())
+}
+
+#[test]
+fn test_openai_backend_drops_rejected_response_format() -> Result<(), Box<dyn Error>> {
+    let server = StubServer::start(vec![
+        StubResponse::json(400, r#"{"error": {"message": "response_format json_schema is not supported"}}"#),
+        openai_chat_response(r#"{"sufficient": true}"#),
+        openai_chat_response(r#"{"sufficient": false}"#),
+    ]);
+    let schema = GeminiSufficiencyResponse::response_schema();
+    let api = OpenAICompatibleLLMApi::new(format!("{}/v1", server.base_url), None, None);
+    assert_eq!(api.generate_structured("enough?", "local-model", &schema)?, r#"{"sufficient": true}"#);
+    // The backend is remembered as not supporting it.
+    assert_eq!(api.generate_structured("enough now?", "local-model", &schema)?, r#"{"sufficient": false}"#);
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[0].json_body()["response_format"]["type"], "json_schema");
+    assert!(requests[1].json_body().get("response_format").is_none());
+    assert!(requests[2].json_body().get("response_format").is_none());
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_run_gem_agent_requests_schema_per_phase() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("gemini_schema_agent");
+
+    let code_gen_response = GeminiCodeGenerationResponse {
+        changes: vec![CodeChange {
+            file_path: "src/structured.txt".to_string(),
+            action: CodeChangeAction::CreateFile,
+            content: Some("schema-constrained".to_string()),
+        }],
+        tests: None,
+        explanation: "Created a file from a schema-constrained response.".to_string(),
+    };
+    let server = StubServer::start(vec![
+        gemini_json_response(&serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?),
+        gemini_json_response(&serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?),
+        gemini_json_response(&serde_json::to_string(&code_gen_response)?),
+    ]);
+
+    let mut args = common_test_args(project_root.clone(), "create a file from a structured response");
+    args.no_tools = true; // Exercise the `needed_items` flow
+    let mut session = Session::new(&Session::compute_hash(&format!("{:?}", args)));
+    let llm_api = Box::new(RealLLMApi::new("test-key".to_string()).with_base_url(server.base_url.clone()));
+
+    run_gem_agent(args, &mut session, llm_api, false, project_root.clone())?;
+
+    assert_eq!(fs::read_to_string(project_root.join("src/structured.txt"))?, "schema-constrained");
+    let schemas: Vec<serde_json::Value> = server
+        .requests()
+        .iter()
+        .map(|r| r.json_body()["generationConfig"]["responseSchema"].clone())
+        .collect();
+    assert_eq!(schemas, vec![
+        GeminiNeededItemsResponse::response_schema(),
+        GeminiSufficiencyResponse::response_schema(),
+        GeminiCodeGenerationResponse::response_schema(),
+    ]);
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_run_gem_agent_trims_gathered_data_to_prompt_budget() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("prompt_budget_agent");
+    let big_source: String = (0..200)
+        .map(|i| format!("pub fn function_{i}(input: u32) -> u32 {{\n    let value_{i} = input * {i};\n    let doubled = value_{i} * 2;\n    let shifted = doubled << 1;\n    shifted + value_{i} + 1\n}}\n\n"))
+        .collect();
+    fs::write(project_root.join("src/big.rs"), &big_source)?;
+
+    let server = StubServer::start(vec![
+        gemini_json_response(&serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec!["src/big.rs".to_string()] })?),
+        gemini_json_response(&serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?),
+        gemini_json_response(&serde_json::to_string(&GeminiCodeGenerationResponse { changes: vec![], tests: None, explanation: "Nothing to do.".to_string() })?),
+    ]);
+
+    let mut args = common_test_args(project_root.clone(), "look at a file that does not fit");
+    args.max_prompt_tokens = Some(5_000);
+    args.no_tools = true;
+    let mut session = Session::new(&Session::compute_hash(&format!("{:?}", args)));
+    let llm_api = Box::new(RealLLMApi::new("test-key".to_string()).with_base_url(server.base_url.clone()));
+
+    run_gem_agent(args, &mut session, llm_api, false, project_root.clone())?;
+
+    let requests = server.requests();
+    for request in &requests {
+        let prompt = gemini_request_text(&request.json_body());
+        assert!(prompt.len() / 4 <= 5_000, "prompt of {} bytes exceeds the budget", prompt.len());
+    }
+    // The file is summarised to its signatures rather than dropped.
+    let sufficiency_prompt = gemini_request_text(&requests[1].json_body());
+    assert!(sufficiency_prompt.contains("pub fn function_199(input: u32) -> u32 { ... }"));
+    assert!(!sufficiency_prompt.contains("let value_0"));
+    // The full file is still what the session gathered.
+    assert_eq!(session.gathered_data["src/big.rs"], big_source);
+    Ok(())
+}
+
+// All text a Gemini request sends: the system instruction and every text part of every turn.
+fn gemini_request_text(body: &serde_json::Value) -> String {
+    let turns = body.get("systemInstruction").into_iter().chain(body["contents"].as_array().unwrap());
+    turns
+        .flat_map(|turn| turn["parts"].as_array().unwrap().iter().filter_map(|part| part["text"].as_str()))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn gemini_json_response_with_usage(text: &str, prompt_tokens: u64, candidate_tokens: u64, thinking_tokens: u64) -> StubResponse {
+    let body = serde_json::json!({
+        "candidates": [{ "content": { "parts": [{ "text": text }], "role": "model" }, "finishReason": "STOP" }],
+        "usageMetadata": {
+            "promptTokenCount": prompt_tokens,
+            "candidatesTokenCount": candidate_tokens,
+            "thoughtsTokenCount": thinking_tokens,
+            "totalTokenCount": prompt_tokens + candidate_tokens + thinking_tokens
+        }
+    });
+    StubResponse::json(200, &body.to_string())
+}
+
+#[test]
+#[serial]
+fn test_run_gem_agent_records_token_usage_per_call() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("usage_agent");
+
+    let server = StubServer::start(vec![
+        gemini_json_response_with_usage(&serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?, 1000, 10, 50),
+        gemini_json_response_with_usage(&serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?, 1200, 5, 0),
+        gemini_json_response(&serde_json::to_string(&GeminiCodeGenerationResponse { changes: vec![], tests: None, explanation: "Nothing to do.".to_string() })?),
+    ]);
+
+    let mut args = common_test_args(project_root.clone(), "count the tokens of this run");
+    args.no_tools = true; // Exercise the `needed_items` flow
+    let mut session = Session::new(&Session::compute_hash(&format!("{:?}", args)));
+    let llm_api = Box::new(RealLLMApi::new("test-key".to_string()).with_base_url(server.base_url.clone()));
+
+    run_gem_agent(args, &mut session, llm_api, false, project_root.clone())?;
+
+    let usages: Vec<Option<TokenUsage>> = session.calls().iter().map(|c| c.usage).collect();
+    assert_eq!(usages, vec![
+        Some(TokenUsage { prompt_tokens: 1000, candidate_tokens: 10, thinking_tokens: 50, cached_tokens: 0 }),
+        Some(TokenUsage { prompt_tokens: 1200, candidate_tokens: 5, thinking_tokens: 0, cached_tokens: 0 }),
+        None, // The code generation answer carried no usageMetadata
+    ]);
+
+    // The report across sessions sees this run's calls.
+    let all_calls = gem::usage::load_all_call_records(&gem::cache::sessions_dir());
+    assert_eq!(all_calls.len(), 3);
+    let report = gem::usage::format_usage_report(&all_calls);
+    assert!(report.contains("2265"), "unexpected report:\n{}", report);
+    Ok(())
+}
+
+fn gemini_cached_response(text: &str, prompt_tokens: u64, cached_tokens: u64) -> StubResponse {
+    let body = serde_json::json!({
+        "candidates": [{ "content": { "parts": [{ "text": text }], "role": "model" }, "finishReason": "STOP" }],
+        "usageMetadata": { "promptTokenCount": prompt_tokens, "candidatesTokenCount": 5, "cachedContentTokenCount": cached_tokens }
+    });
+    StubResponse::json(200, &body.to_string())
+}
+
+#[test]
+#[serial]
+fn test_run_gem_agent_caches_project_context_per_model() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("context_cache_agent");
+
+    let server = StubServer::start(vec![
+        StubResponse::json(200, r#"{"name": "cachedContents/flash-context"}"#),
+        gemini_cached_response(&serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?, 1000, 800),
+        gemini_cached_response(&serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?, 1000, 800),
+        // Code generation runs on another model, which needs its own cache.
+        StubResponse::json(200, r#"{"name": "cachedContents/pro-context"}"#),
+        gemini_cached_response(&serde_json::to_string(&GeminiCodeGenerationResponse { changes: vec![], tests: None, explanation: "Nothing to do.".to_string() })?, 1000, 800),
+        StubResponse::json(200, "{}"),
+        StubResponse::json(200, "{}"),
+    ]);
+
+    let mut args = common_test_args(project_root.clone(), "reuse the cached project context");
+    args.no_tools = true;
+    let mut session = Session::new(&Session::compute_hash(&format!("{:?}", args)));
+    let llm_api = Box::new(
+        RealLLMApi::new("test-key".to_string())
+            .with_base_url(server.base_url.clone())
+            .with_context_cache(ContextCacheConfig { ttl: Duration::from_secs(300), min_tokens: 0 }),
+    );
+
+    run_gem_agent(args, &mut session, llm_api, false, project_root.clone())?;
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 7);
+    let create = requests[0].json_body();
+    assert!(requests[0].path.starts_with("/cachedContents?"));
+    assert_eq!(create["model"], "models/gemini-2.5-flash");
+    assert_eq!(create["ttl"], "300s");
+    assert!(gemini_request_text(&create).contains("Project Symbols"));
+    assert_eq!(requests[3].json_body()["model"], "models/gemini-2.5-pro");
+
+    for (index, cache_name) in [(1, "cachedContents/flash-context"), (2, "cachedContents/flash-context"), (4, "cachedContents/pro-context")] {
+        let body = requests[index].json_body();
+        assert_eq!(body["cachedContent"], cache_name);
+        // Neither the project context nor a system instruction is sent next to the cache.
+        assert!(body.get("systemInstruction").is_none());
+        assert!(!gemini_request_text(&body).contains("Project Symbols"));
+        assert!(gemini_request_text(&body).starts_with("SYSTEM PROMPT:\n"));
+    }
+
+    // Both caches are deleted when the run ends.
+    let mut deleted: Vec<&str> = requests[5..].iter().map(|r| { assert_eq!(r.method, "DELETE"); r.path.split('?').next().unwrap() }).collect();
+    deleted.sort();
+    assert_eq!(deleted, vec!["/cachedContents/flash-context", "/cachedContents/pro-context"]);
+
+    let cached: u64 = session.calls().iter().filter_map(|c| c.usage).map(|u| u.cached_tokens).sum();
+    assert_eq!(cached, 2400);
+    Ok(())
+}
+
+#[test]
+fn test_context_cache_skips_small_prefixes_and_recovers_from_expiry() -> Result<(), Box<dyn Error>> {
+    let context = ChatTurn::User("Project Context: a tiny crate.".to_string());
+    let conversation = vec![ChatTurn::System("You write Rust.".to_string()), context.clone(), ChatTurn::User("Add a function.".to_string())];
+
+    // Below min_tokens nothing is cached.
+    let server = StubServer::start(vec![gemini_json_response("done")]);
+    let api = RealLLMApi::new("test-key".to_string())
+        .with_base_url(server.base_url.clone())
+        .with_context_cache(ContextCacheConfig { ttl: Duration::from_secs(60), min_tokens: 1_000 });
+    api.set_shared_prefix(std::slice::from_ref(&context));
+    assert_eq!(api.generate_conversation(&conversation, "gemini-test", None)?, "done");
+    api.release_shared_prefix();
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].json_body().get("cachedContent").is_none());
+
+    // A cache that expired early is dropped and the conversation is sent in full.
+    let server = StubServer::start(vec![
+        StubResponse::json(200, r#"{"name": "cachedContents/short-lived"}"#),
+        StubResponse::json(403, r#"{"error": {"code": 403, "message": "CachedContent not found (or permission denied)"}}"#),
+        gemini_json_response("done"),
+        gemini_json_response("done again"),
+    ]);
+    let api = RealLLMApi::new("test-key".to_string())
+        .with_base_url(server.base_url.clone())
+        .with_context_cache(ContextCacheConfig { ttl: Duration::from_secs(60), min_tokens: 0 })
+        .with_retry_policy(fast_retry_policy(0));
+    api.set_shared_prefix(std::slice::from_ref(&context));
+    assert_eq!(api.generate_conversation(&conversation, "gemini-test", None)?, "done");
+    assert_eq!(api.generate_conversation(&conversation, "gemini-test", None)?, "done again");
+    api.release_shared_prefix(); // Nothing left to delete
+    let requests = server.requests();
+    assert_eq!(requests.len(), 4);
+    for request in &requests[2..] {
+        assert!(request.json_body().get("cachedContent").is_none());
+        assert!(gemini_request_text(&request.json_body()).contains("a tiny crate"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_context_cache_is_kept_on_errors_unrelated_to_it() -> Result<(), Box<dyn Error>> {
+    let context = ChatTurn::User("Project Context: a tiny crate.".to_string());
+    let conversation = vec![context.clone(), ChatTurn::User("Add a function.".to_string())];
+    let server = StubServer::start(vec![
+        StubResponse::json(200, r#"{"name": "cachedContents/kept"}"#),
+        StubResponse::json(400, r#"{"error": {"code": 400, "message": "Invalid JSON payload received."}}"#),
+        StubResponse::json(403, r#"{"error": {"code": 403, "message": "API key not valid."}}"#),
+        gemini_json_response("done"),
+    ]);
+    let api = RealLLMApi::new("test-key".to_string())
+        .with_base_url(server.base_url.clone())
+        .with_context_cache(ContextCacheConfig { ttl: Duration::from_secs(60), min_tokens: 0 })
+        .with_retry_policy(fast_retry_policy(0));
+    api.set_shared_prefix(std::slice::from_ref(&context));
+
+    let error = api.generate_conversation(&conversation, "gemini-test", None).unwrap_err();
+    assert!(matches!(error.downcast_ref::<LLMApiError>(), Some(LLMApiError::BadRequest { status: 400, .. })), "{}", error);
+    let error = api.generate_conversation(&conversation, "gemini-test", None).unwrap_err();
+    assert!(matches!(error.downcast_ref::<LLMApiError>(), Some(LLMApiError::Auth { status: 403, .. })), "{}", error);
+    assert_eq!(api.generate_conversation(&conversation, "gemini-test", None)?, "done");
+
+    // Each request was sent once, and all of them still use the cache.
+    let requests = server.requests();
+    assert_eq!(requests.len(), 4);
+    for request in &requests[1..] {
+        assert_eq!(request.json_body()["cachedContent"], "cachedContents/kept");
+    }
+    Ok(())
+}
+
+fn gemini_function_call_response(name: &str, args: serde_json::Value) -> StubResponse {
+    let body = serde_json::json!({
+        "candidates": [{ "content": { "parts": [{ "functionCall": { "name": name, "args": args } }], "role": "model" }, "finishReason": "STOP" }]
+    });
+    StubResponse::json(200, &body.to_string())
+}
+
+#[test]
+fn test_gemini_tool_calls_round_trip() -> Result<(), Box<dyn Error>> {
+    let server = StubServer::start(vec![
+        gemini_function_call_response("retrieve_item_source", serde_json::json!({ "item": "src/lib.rs" })),
+        gemini_json_response("I have what I need."),
+    ]);
+    let api = RealLLMApi::new("test-key".to_string()).with_base_url(server.base_url.clone()).with_streaming(true);
+    let tools = gem::agent_tools::tool_declarations();
+    assert!(api.supports_tools());
+
+    let mut conversation = vec![ChatTurn::User("what is in lib.rs?".to_string())];
+    let reply = api.generate_with_tools(&conversation, &tools, "gemini-test")?;
+    assert_eq!(reply.tool_calls, vec![ToolCall { id: None, name: "retrieve_item_source".to_string(), args: serde_json::json!({ "item": "src/lib.rs" }) }]);
+
+    let call = reply.tool_calls[0].clone();
+    conversation.push(ChatTurn::Model(reply));
+    conversation.push(ChatTurn::ToolResults(vec![ToolResult { call, output: "pub fn hello() {}".to_string() }]));
+    let reply = api.generate_with_tools(&conversation, &tools, "gemini-test")?;
+    assert_eq!(reply, ModelReply { text: "I have what I need.".to_string(), tool_calls: vec![] });
+
+    let requests = server.requests();
+    // Tool turns are never streamed.
+    assert!(requests.iter().all(|r| r.path.contains(":generateContent")));
+    let first = requests[0].json_body();
+    let declarations = first["tools"][0]["functionDeclarations"].as_array().unwrap();
+    assert_eq!(declarations.len(), tools.len());
+    assert_eq!(declarations[0]["name"], "retrieve_item_source");
+    assert!(declarations.iter().any(|d| d["name"] == "cargo_check" && d.get("parameters").is_none()));
+
+    let second = requests[1].json_body();
+    assert_eq!(second["contents"][1]["role"], "model");
+    assert_eq!(second["contents"][1]["parts"][0]["functionCall"]["name"], "retrieve_item_source");
+    assert_eq!(second["contents"][2]["parts"][0]["functionResponse"], serde_json::json!({
+        "name": "retrieve_item_source",
+        "response": { "output": "pub fn hello() {}" }
+    }));
+    Ok(())
+}
+
+#[test]
+fn test_openai_tool_calls_round_trip() -> Result<(), Box<dyn Error>> {
+    let tool_call_body = serde_json::json!({
+        "choices": [{
+            "index": 0,
+            "message": {
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{ "id": "call_abc", "type": "function", "function": { "name": "search_symbols", "arguments": "{\"query\":\"Session\"}" } }]
+            },
+            "finish_reason": "tool_calls"
+        }]
+    });
+    let server = StubServer::start(vec![
+        StubResponse::json(200, &tool_call_body.to_string()),
+        openai_chat_response("Done."),
+    ]);
+    let api = OpenAICompatibleLLMApi::new(format!("{}/v1", server.base_url), None, None);
+    let tools = gem::agent_tools::tool_declarations();
+
+    let mut conversation = vec![ChatTurn::User("find the session type".to_string())];
+    let reply = api.generate_with_tools(&conversation, &tools, "local-model")?;
+    let call = reply.tool_calls[0].clone();
+    assert_eq!(call, ToolCall { id: Some("call_abc".to_string()), name: "search_symbols".to_string(), args: serde_json::json!({ "query": "Session" }) });
+
+    conversation.push(ChatTurn::Model(reply));
+    conversation.push(ChatTurn::ToolResults(vec![ToolResult { call, output: "gem::cache::Session (Struct)".to_string() }]));
+    assert_eq!(api.generate_with_tools(&conversation, &tools, "local-model")?.text, "Done.");
+
+    let requests = server.requests();
+    let first = requests[0].json_body();
+    assert_eq!(first["tools"][0]["type"], "function");
+    assert_eq!(first["tools"][0]["function"]["parameters"]["properties"]["item"]["type"], "string");
+    // Parameterless tools still get an (empty) object schema.
+    assert_eq!(first["tools"][3]["function"]["parameters"], serde_json::json!({ "type": "object", "properties": {} }));
+
+    let messages = requests[1].json_body()["messages"].clone();
+    assert_eq!(messages[1]["tool_calls"][0]["id"], "call_abc");
+    assert_eq!(messages[2], serde_json::json!({ "role": "tool", "content": "gem::cache::Session (Struct)", "tool_call_id": "call_abc" }));
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_run_gem_agent_gathers_context_with_tool_calls() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("tool_calling_agent");
+
+    let code_gen_response = GeminiCodeGenerationResponse {
+        changes: vec![CodeChange {
+            file_path: "src/from_tools.txt".to_string(),
+            action: CodeChangeAction::CreateFile,
+            content: Some("gathered with tools".to_string()),
+        }],
+        tests: None,
+        explanation: "Created a file after looking at lib.rs.".to_string(),
+    };
+    let server = StubServer::start(vec![
+        gemini_function_call_response("retrieve_item_source", serde_json::json!({ "item": "src/lib.rs" })),
+        gemini_function_call_response("file_outline", serde_json::json!({ "path": "src/lib.rs" })),
+        gemini_json_response("lib.rs defines `hello` and `SomeStruct`."),
+        gemini_json_response(&serde_json::to_string(&code_gen_response)?),
+    ]);
+
+    let args = common_test_args(project_root.clone(), "create a file after reading lib.rs");
+    let mut session = Session::new(&Session::compute_hash(&format!("{:?}", args)));
+    let llm_api = Box::new(RealLLMApi::new("test-key".to_string()).with_base_url(server.base_url.clone()));
+
+    run_gem_agent(args, &mut session, llm_api, false, project_root.clone())?;
+
+    assert_eq!(fs::read_to_string(project_root.join("src/from_tools.txt"))?, "gathered with tools");
+    let requests = server.requests();
+    assert_eq!(requests.len(), 4);
+    // All gathering turns are one conversation: project context, prompt, call, result, call, result.
+    let last_gathering_turn = requests[2].json_body();
+    assert_eq!(last_gathering_turn["contents"].as_array().unwrap().len(), 6);
+    let retrieved = last_gathering_turn["contents"][3]["parts"][0]["functionResponse"]["response"]["output"].as_str().unwrap();
+    assert!(retrieved.contains("pub struct SomeStruct"));
+    // The retrieved source reaches the code generation prompt; the outline is not kept.
+    let code_gen_prompt = gemini_request_text(&requests[3].json_body());
+    assert!(code_gen_prompt.contains("pub struct SomeStruct"));
+    assert_eq!(session.gathered_data.keys().collect::<Vec<_>>(), vec!["src/lib.rs"]);
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_run_gem_agent_caps_tool_calls_at_max_data_loops() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("tool_call_limit_agent");
+
+    let server = StubServer::start(vec![
+        gemini_function_call_response("search_symbols", serde_json::json!({ "query": "hello" })),
+        gemini_function_call_response("search_symbols", serde_json::json!({ "query": "again" })),
+        gemini_json_response(&serde_json::to_string(&GeminiCodeGenerationResponse { changes: vec![], tests: None, explanation: "Nothing to do.".to_string() })?),
+    ]);
+
+    let mut args = common_test_args(project_root.clone(), "keep searching forever");
+    args.max_data_loops = 1;
+    let mut session = Session::new(&Session::compute_hash(&format!("{:?}", args)));
+    let llm_api = Box::new(RealLLMApi::new("test-key".to_string()).with_base_url(server.base_url.clone()));
+
+    run_gem_agent(args, &mut session, llm_api, false, project_root.clone())?;
+
+    // The second tool call is not executed; the agent moves on to code generation.
+    let requests = server.requests();
+    assert_eq!(requests.len(), 3);
+    assert!(requests[0].json_body()["contents"][1]["parts"][0]["text"].as_str().unwrap().contains("at most 1 tool calls"));
+    assert!(requests[2].json_body().get("tools").is_none());
+    Ok(())
+}
+
+#[test]
+fn test_conversation_turns_reach_both_backends() -> Result<(), Box<dyn Error>> {
+    let conversation = vec![
+        ChatTurn::System("You write Rust.".to_string()),
+        ChatTurn::User("Add a function.".to_string()),
+        ChatTurn::Model(ModelReply { text: r#"{"changes": []}"#.to_string(), tool_calls: vec![] }),
+        ChatTurn::User("`cargo build` failed: E0425.".to_string()),
+    ];
+    let schema = GeminiCodeGenerationResponse::response_schema();
+
+    let gemini = StubServer::start(vec![gemini_json_response("fixed")]);
+    let api = RealLLMApi::new("test-key".to_string()).with_base_url(gemini.base_url.clone());
+    assert_eq!(api.generate_conversation(&conversation, "gemini-test", Some(&schema))?, "fixed");
+    let body = gemini.requests()[0].json_body();
+    assert_eq!(body["systemInstruction"]["parts"][0]["text"], "You write Rust.");
+    assert_eq!(body["contents"].as_array().unwrap().len(), 3);
+    assert_eq!(body["contents"][1], serde_json::json!({ "role": "model", "parts": [{ "text": r#"{"changes": []}"# }] }));
+    assert_eq!(body["contents"][2]["parts"][0]["text"], "`cargo build` failed: E0425.");
+    assert_eq!(body["generationConfig"]["responseSchema"], schema);
+
+    let openai = StubServer::start(vec![openai_chat_response("fixed")]);
+    let api = OpenAICompatibleLLMApi::new(format!("{}/v1", openai.base_url), None, None);
+    assert_eq!(api.generate_conversation(&conversation, "local-model", None)?, "fixed");
+    let roles: Vec<String> = openai.requests()[0].json_body()["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["role"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+    Ok(())
+}
+
+#[test]
+fn test_openai_backend_continues_length_cut_off_and_reports_content_filter() -> Result<(), Box<dyn Error>> {
+    let finished = |content: &str, finish_reason: &str| {
+        let body = serde_json::json!({
+            "choices": [{ "index": 0, "message": { "role": "assistant", "content": content }, "finish_reason": finish_reason }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 5 }
+        });
+        StubResponse::json(200, &body.to_string())
+    };
+    let server = StubServer::start(vec![
+        finished("Hello, ", "length"),
+        finished("world.", "stop"),
+        finished("", "content_filter"),
+    ]);
+    let api = OpenAICompatibleLLMApi::new(format!("{}/v1", server.base_url), None, None);
+
+    assert_eq!(api.generate_content("greet", "local-model")?, "Hello, world.");
+    // Usage covers both requests.
+    assert_eq!(api.take_last_usage(), Some(TokenUsage { prompt_tokens: 20, candidate_tokens: 10, thinking_tokens: 0, cached_tokens: 0 }));
+    let messages = server.requests()[1].json_body()["messages"].clone();
+    assert_eq!(messages[1], serde_json::json!({ "role": "assistant", "content": "Hello, " }));
+
+    let err = api.generate_content("something filtered", "local-model").unwrap_err();
+    assert!(matches!(err.downcast_ref::<LLMApiError>(), Some(LLMApiError::Blocked { .. })));
+    Ok(())
+}
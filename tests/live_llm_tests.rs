@@ -1,7 +1,7 @@
 #[cfg(test)]
 mod tests {
     use gem::run_gem_agent;
-    use gem::cli::{CustomCliArgs, MAX_DATA_GATHERING_ITERATIONS_DEFAULT, MAX_VERIFICATION_RETRIES_DEFAULT}; // Added more imports
+    use gem::cli::{CustomCliArgs, MAX_DATA_GATHERING_ITERATIONS_DEFAULT, MAX_VERIFICATION_RETRIES_DEFAULT, VERIFY_TIMEOUT_SECS_DEFAULT, MAX_API_RETRIES_DEFAULT, CONTEXT_CACHE_TTL_SECS_DEFAULT, CONTEXT_CACHE_MIN_TOKENS_DEFAULT, DEFAULT_WEBDRIVER_URL, DEFAULT_END_MARKER}; // Added more imports
     use gem::cache::Session;
     use gem::llm_api::RealLLMApi; // LLMApi removed as it's unused
 
@@ -46,11 +46,16 @@ mod tests {
         let args = CustomCliArgs {
             user_request_parts: vec!["add a public function named `say_hello` to `src/lib.rs` that takes no arguments and returns the string \"hello live\". Also add a test for it.".to_string()],
             project_root: project_root.clone(),
-            verify_with: "cargo test".to_string(),
+            verify_with: vec!["cargo test".to_string()],
             no_test: false,
             project_file: None,
             max_data_loops: MAX_DATA_GATHERING_ITERATIONS_DEFAULT,
             max_verify_retries: MAX_VERIFICATION_RETRIES_DEFAULT,
+            verify_timeout: VERIFY_TIMEOUT_SECS_DEFAULT,
+            stash: false,
+            commit_author: None,
+            max_api_retries: MAX_API_RETRIES_DEFAULT,
+            max_prompt_tokens: None,
             debug_mode: None,
             no_explanation: false,
             no_code: false,
@@ -60,7 +65,20 @@ mod tests {
             input_selector: None,
             codeblock_selector: None,
             finished_selector: None,
-            local: false,
+            webdriver_url: DEFAULT_WEBDRIVER_URL.to_string(),
+            local: None,
+            manual: false,
+            prompt_file: None,
+            clipboard: false,
+            end_marker: DEFAULT_END_MARKER.to_string(),
+            openai_base_url: None,
+            openai_model: None,
+            model_overrides: vec![],
+            no_stream: false,
+            usage_report: false,
+            no_tools: false,
+            context_cache_ttl: CONTEXT_CACHE_TTL_SECS_DEFAULT,
+            context_cache_min_tokens: CONTEXT_CACHE_MIN_TOKENS_DEFAULT,
         };
         // args.max_data_loops = 1; // Potentially limit loops for a simple task
         // args.max_verify_retries = 1;
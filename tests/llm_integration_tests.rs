@@ -1,73 +1,37 @@
-use gem::llm_api::{MockLLMApi, LLMApi, GeminiNeededItemsResponse, GeminiSufficiencyResponse, GeminiCodeGenerationResponse, CodeChange, CodeChangeAction};
+use clap::Parser;
+use gem::llm_api::{GeminiNeededItemsResponse, GeminiSufficiencyResponse, GeminiCodeGenerationResponse, CodeChange, CodeChangeAction};
+use gem::llm_mock::{MockRule, RuleBasedLLMApi};
+use gem::llm_replay::{RecordingLLMApi, ReplayLLMApi};
+use gem::local_llm::{LocalGeneration, LocalGenerator, LocalLLMApi};
+use gem::manual_llm::ManualLLMApi;
+use gem::model_router::AgentPhase;
 use gem::cache::Session;
-use gem::cli::{CustomCliArgs, MAX_DATA_GATHERING_ITERATIONS_DEFAULT, MAX_VERIFICATION_RETRIES_DEFAULT};
+use gem::cli::{CustomCliArgs, DEFAULT_END_MARKER};
 use gem::run_gem_agent;
-use std::path::PathBuf;
-use tempfile::{tempdir, TempDir};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
 use std::fs;
 use std::error::Error;
 use serial_test::serial;
 
-fn common_test_args(project_root: PathBuf, user_request: &str) -> CustomCliArgs {
-    CustomCliArgs {
-        user_request_parts: vec![user_request.to_string()],
-        project_root,
-        verify_with: "cargo check".to_string(),
-        no_test: false,
-        project_file: None,
-        max_data_loops: MAX_DATA_GATHERING_ITERATIONS_DEFAULT,
-        max_verify_retries: MAX_VERIFICATION_RETRIES_DEFAULT,
-        debug_mode: None,
-        no_explanation: false,
-        no_code: false,
-        no_readme: false,
-        auto_tool_selection: false,
-        browser: None,
-        input_selector: None,
-        codeblock_selector: None,
-        finished_selector: None,
-        local: false,
-    }
-}
-
-// Helper function to setup a test environment
-// Returns project_root, a TempDir guard for project_root, and a TempDir guard for home_path
-fn setup_test_env(session_id_prefix: &str) -> (PathBuf, TempDir, TempDir) {
-    let temp_project_dir_guard = tempdir().unwrap(); // Guard for project root
-    let project_root = temp_project_dir_guard.path().to_path_buf();
-
-    let src_dir = project_root.join("src");
-    fs::create_dir_all(&src_dir).unwrap();
-    fs::write(src_dir.join("lib.rs"), "pub fn hello() {} \n pub struct SomeStruct;").unwrap();
-    fs::write(project_root.join("Cargo.toml"), "[package]\nname = \"test_project\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n").unwrap();
-
-    let unique_session_id = format!("{}_{}", session_id_prefix, uuid::Uuid::new_v4());
-
-    let temp_home_dir = tempdir().unwrap();
-    let home_path_str = temp_home_dir.path().to_str().unwrap().to_string();
-    std::env::set_var("HOME", &home_path_str);
-
-    let session_dir = PathBuf::from(&home_path_str).join(".gem").join("session").join(unique_session_id);
-    fs::create_dir_all(&session_dir).unwrap();
-
-    (project_root, temp_project_dir_guard, temp_home_dir)
-}
+mod common;
+use common::{common_test_args, setup_test_env};
 
 fn run_gem_logic_with_mock_api_owned(
     args: CustomCliArgs,
-    mock_api: MockLLMApi,
+    mock_api: RuleBasedLLMApi,
     project_root: PathBuf,
 ) -> Result<Session, Box<dyn Error>> {
     let session_id_str = format!("{:?}_{:?}", args, std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_nanos());
     let session_id = Session::compute_hash(&session_id_str);
     let mut session = Session::new(&session_id);
 
-    let llm_api_boxed: Box<dyn LLMApi> = Box::new(mock_api);
-
-    match run_gem_agent(args, &mut session, llm_api_boxed, false, project_root) {
-        Ok(_) => Ok(session),
-        Err(e) => Err(e),
-    }
+    // The agent gets a clone; both share the rules, so usage can be checked afterwards.
+    run_gem_agent(args, &mut session, Box::new(mock_api.clone()), false, project_root)?;
+    mock_api.assert_all_rules_used();
+    Ok(session)
 }
 
 
@@ -78,23 +42,23 @@ fn test_initial_prompt_flow() -> Result<(), Box<dyn Error>> {
 
     let args = common_test_args(project_root.clone(), "test request for initial items");
 
-    let mut mock_api = MockLLMApi::new();
+    let mock_api = RuleBasedLLMApi::new();
     let expected_needed_items = vec!["src/lib.rs".to_string(), "test_project::SomeStruct".to_string()];
 
     let response_json = serde_json::to_string(&GeminiNeededItemsResponse {
         needed_items: expected_needed_items.clone(),
     })?;
-    mock_api.add_mock_response(Ok(response_json));
+    mock_api.add_rule(MockRule::respond(response_json).for_phase(AgentPhase::Initial));
 
     let sufficient_response = GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&sufficient_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&sufficient_response)?).for_phase(AgentPhase::Sufficient));
 
     let code_gen_response = GeminiCodeGenerationResponse {
         changes: vec![],
         tests: None,
         explanation: "Mocked code generation response for initial_prompt_flow test.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result_session = run_gem_logic_with_mock_api_owned(args, mock_api, project_root)?;
 
@@ -105,6 +69,62 @@ fn test_initial_prompt_flow() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn test_session_records_model_per_phase() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("model_per_phase");
+
+    let mut args = common_test_args(project_root.clone(), "test which models answer each phase");
+    args.model_overrides = vec![gem::model_router::parse_model_chain_override("change=big-model,small-model")?];
+
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiCodeGenerationResponse {
+        changes: vec![],
+        tests: None,
+        explanation: "No changes needed.".to_string(),
+    })?).for_phase(AgentPhase::Change).for_model("big-model"));
+
+    let session = run_gem_logic_with_mock_api_owned(args, mock_api, project_root)?;
+
+    let default_router = gem::model_router::ModelRouter::default();
+    let default_fast_model = &default_router.chain(gem::model_router::AgentPhase::Initial)[0];
+    let calls: Vec<(&str, &str)> = session.calls().iter().map(|c| (c.prompt_type.as_str(), c.model.as_str())).collect();
+    assert_eq!(calls, vec![
+        ("initial", default_fast_model.as_str()),
+        ("sufficient", default_fast_model.as_str()),
+        ("change", "big-model"),
+    ]);
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_failed_model_falls_back_along_the_phase_chain() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("model_fallback");
+
+    let mut args = common_test_args(project_root.clone(), "test falling back to the next model");
+    args.model_overrides = vec![gem::model_router::parse_model_chain_override("change=big-model,small-model")?];
+
+    let mock_api = RuleBasedLLMApi::new()
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial))
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient))
+        .with_rule(MockRule::fail("503 overloaded").for_phase(AgentPhase::Change).for_model("big-model").times(1))
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiCodeGenerationResponse {
+            changes: vec![],
+            tests: None,
+            explanation: "No changes needed.".to_string(),
+        })?).for_phase(AgentPhase::Change).for_model("small-model").times(1));
+
+    let session = run_gem_logic_with_mock_api_owned(args, mock_api, project_root)?;
+
+    // The call is recorded once, under the model that answered.
+    let calls: Vec<(&str, &str)> = session.calls().iter().skip(2).map(|c| (c.prompt_type.as_str(), c.model.as_str())).collect();
+    assert_eq!(calls, vec![("change", "small-model")]);
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn test_markdown_remove_function_expect_whole_file_replace() -> Result<(), Box<dyn Error>> {
@@ -124,9 +144,9 @@ pub fn function_to_keep() -> String {
 
     let args = common_test_args(project_root.clone(), "Remove function_to_remove via Markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 Explanation: `function_to_remove` has been removed. The file now only contains `function_to_keep`.
@@ -159,7 +179,7 @@ pub fn function_to_keep() -> String {
         tests: None,
         explanation: "High-level: Removed a function via Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&llm_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&llm_response)?).for_phase(AgentPhase::Change));
 
     run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone())?;
 
@@ -185,9 +205,9 @@ pub fn existing_function() {}
 
     let args = common_test_args(project_root.clone(), "Add a new function new_function_to_add via Markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 Adding a new function `new_function_to_add`.
@@ -216,7 +236,7 @@ pub fn new_function_to_add() -> bool {
         tests: None,
         explanation: "High-level: Added a new function via Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&llm_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&llm_response)?).for_phase(AgentPhase::Change));
 
     run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone())?;
 
@@ -243,9 +263,9 @@ pub fn some_other_function() {}
 
     let args = common_test_args(project_root.clone(), "Replace CONST_TO_REPLACE via Markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 The constant `CONST_TO_REPLACE` needs an update.
@@ -267,7 +287,7 @@ The constant has been changed to a string type and new value.
         tests: None,
         explanation: "High-level: Updated a const via Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&llm_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&llm_response)?).for_phase(AgentPhase::Change));
 
     run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone())?;
 
@@ -297,9 +317,9 @@ const MY_CONST: bool = true; // Should remain
 
     let args = common_test_args(project_root.clone(), "Replace EnumToReplace via Markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 This Markdown explains that `EnumToReplace` will be updated.
@@ -324,7 +344,7 @@ The enum has been changed to have new variants.
         tests: None,
         explanation: "High-level: Updated an enum via Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&llm_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&llm_response)?).for_phase(AgentPhase::Change));
 
     run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone())?;
 
@@ -357,9 +377,9 @@ fn another_item() {}
 
     let args = common_test_args(project_root.clone(), "Replace StructToReplace via Markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 Replacing `StructToReplace`.
@@ -384,7 +404,7 @@ The struct has been updated with new fields.
         tests: None,
         explanation: "High-level: Updated a struct via Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&llm_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&llm_response)?).for_phase(AgentPhase::Change));
 
     run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone())?;
 
@@ -413,9 +433,9 @@ pub fn old_func() {}
 
     let args = common_test_args(project_root.clone(), "Replace lib.rs with multiple items from markdown, expecting fallback");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 src/lib.rs
@@ -441,7 +461,7 @@ pub struct StructB {}
         tests: None,
         explanation: "Markdown block not a single item, expecting fallback to whole file.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -462,9 +482,9 @@ fn test_markdown_item_replacement_target_file_does_not_exist_fallback() -> Resul
 
     let args = common_test_args(project_root.clone(), "Create new_file_for_item.rs with a function via markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 src/new_file_for_item.rs
@@ -484,7 +504,7 @@ pub fn func_in_new_file() {}
         tests: None,
         explanation: "Attempted item replacement in non-existent file, expecting fallback to create file.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -508,9 +528,9 @@ pub fn existing_func() {}
 
     let args = common_test_args(project_root.clone(), "Add new_func_from_markdown to lib.rs, expecting fallback");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 src/lib.rs
@@ -530,7 +550,7 @@ pub fn new_func_from_markdown() { /* new function */ }
         tests: None,
         explanation: "Attempted item replacement, expecting fallback to whole file.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -556,9 +576,9 @@ pub fn some_func_after_struct() {} // Should remain
 
     let args = common_test_args(project_root.clone(), "Replace StructToReplace via markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 src/lib.rs
@@ -576,7 +596,7 @@ pub struct StructToReplace { new_field: String, another_field: bool }
         tests: None,
         explanation: "Replaced item StructToReplace from Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -597,9 +617,9 @@ fn test_markdown_processing_filename_heuristics() -> Result<(), Box<dyn Error>>
 
     let args = common_test_args(project_root.clone(), "Create files from markdown with various filename styles");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 File: src/file1_explicit.rs
@@ -631,7 +651,7 @@ pub fn f3() {}
         tests: None,
         explanation: "Applied changes from Markdown with varied filename styles.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -660,9 +680,9 @@ pub fn another_func() {} // Should remain untouched
 
     let args = common_test_args(project_root.clone(), "Replace func_to_replace via markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 src/lib.rs
@@ -680,7 +700,7 @@ pub fn func_to_replace() -> String { "replaced".to_string() }
         tests: None,
         explanation: "Replaced item func_to_replace from Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -702,9 +722,9 @@ fn test_markdown_processing_no_valid_blocks() -> Result<(), Box<dyn Error>> {
 
     let args = common_test_args(project_root.clone(), "Process markdown with no valid blocks");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = "This is some Markdown text but it does not contain any valid file code blocks.";
     let change = CodeChange {
@@ -717,7 +737,7 @@ fn test_markdown_processing_no_valid_blocks() -> Result<(), Box<dyn Error>> {
         tests: None,
         explanation: "Processed Markdown with no valid blocks.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed for markdown with no blocks: {:?}", result.err());
@@ -746,9 +766,9 @@ fn test_markdown_processing_empty_markdown() -> Result<(), Box<dyn Error>> {
 
     let args = common_test_args(project_root.clone(), "Process empty markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = "";
     let change = CodeChange {
@@ -761,7 +781,7 @@ fn test_markdown_processing_empty_markdown() -> Result<(), Box<dyn Error>> {
         tests: None,
         explanation: "Processed empty Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed for empty markdown: {:?}", result.err());
@@ -789,9 +809,9 @@ fn test_markdown_processing_with_directory_creation() -> Result<(), Box<dyn Erro
 
     let args = common_test_args(project_root.clone(), "Create deeply nested file from markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 File: src/deep/nested/module.rs
@@ -809,7 +829,7 @@ pub fn deep_func() {}
         tests: None,
         explanation: "Created a deeply nested module from Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -832,9 +852,9 @@ fn test_markdown_processing_mixed_create_and_overwrite() -> Result<(), Box<dyn E
 
     let args = common_test_args(project_root.clone(), "Mixed create and overwrite from markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 src/lib.rs
@@ -858,7 +878,7 @@ pub const VALUE: i32 = 42;
         tests: None,
         explanation: "Applied mixed changes from Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -884,9 +904,9 @@ fn test_markdown_processing_overwrite_existing_files() -> Result<(), Box<dyn Err
 
     let args = common_test_args(project_root.clone(), "Overwrite files from markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 src/lib.rs
@@ -911,7 +931,7 @@ pub fn new_mod_func(x: i32) -> i32 { x * 2 }
         tests: None,
         explanation: "Applied overwrites from Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -937,9 +957,9 @@ fn test_markdown_processing_create_new_files() -> Result<(), Box<dyn Error>> {
 
     let args = common_test_args(project_root.clone(), "Create files from markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 File: src/new_module_from_md.rs
@@ -962,7 +982,7 @@ pub struct Data { value: i32 }
         tests: None,
         explanation: "Applied changes from Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -996,9 +1016,9 @@ fn test_delete_file() -> Result<(), Box<dyn Error>> {
 
     let args = common_test_args(project_root.clone(), "delete to_be_deleted.rs");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let change = CodeChange {
         file_path: file_to_delete_path_str.to_string(),
@@ -1010,7 +1030,7 @@ fn test_delete_file() -> Result<(), Box<dyn Error>> {
         tests: None,
         explanation: "Deleted the specified file.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -1032,9 +1052,9 @@ fn test_create_file() -> Result<(), Box<dyn Error>> {
 
     let args = common_test_args(project_root.clone(), "create a new module");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let new_module_path = "src/new_module.rs";
     let new_module_content = "pub fn newly_created_func() {\n    // Content of new module\n}";
@@ -1048,7 +1068,7 @@ fn test_create_file() -> Result<(), Box<dyn Error>> {
         tests: None,
         explanation: "Created a new module file.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -1078,9 +1098,9 @@ fn old_function_to_be_wiped() {
 
     let args = common_test_args(project_root.clone(), "replace entire lib.rs");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let new_total_content = "// Entirely new content\npub fn brand_new_function() {}";
     let change = CodeChange {
@@ -1093,7 +1113,7 @@ fn old_function_to_be_wiped() {
         tests: None,
         explanation: "Replaced entire content of src/lib.rs.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -1119,9 +1139,9 @@ fn some_func() {
 
     let args = common_test_args(project_root.clone(), "update non_existent_func");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let change = CodeChange {
         file_path: "src/lib.rs::non_existent_func".to_string(),
@@ -1133,7 +1153,7 @@ fn some_func() {
         tests: None,
         explanation: "Attempting to update a non-existent function.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
 
@@ -1170,9 +1190,9 @@ pub fn lib_func() {}
 
     let args = common_test_args(project_root.clone(), "update func_in_mod");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let new_func_content = "pub fn changed_func_in_mod() -> i32 {\n    100\n}";
     let change = CodeChange {
@@ -1185,7 +1205,7 @@ pub fn lib_func() {}
         tests: None,
         explanation: "Updated func_in_mod.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -1220,9 +1240,9 @@ const SOME_CONST: i32 = 42;
 
     let args = common_test_args(project_root.clone(), "update MyEnum");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let new_enum_content = "enum MyEnumUpdated {\n    NewVariantA,\n    NewVariantB,\n}";
     let change = CodeChange {
@@ -1235,7 +1255,7 @@ const SOME_CONST: i32 = 42;
         tests: None,
         explanation: "Updated MyEnum.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -1270,9 +1290,9 @@ fn another_func() {}
 
     let args = common_test_args(project_root.clone(), "update MyStruct");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let new_struct_content = "struct MyStructUpdated {\n    new_field: String,\n}";
     let change = CodeChange {
@@ -1285,7 +1305,7 @@ fn another_func() {}
         tests: None,
         explanation: "Updated MyStruct.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -1311,13 +1331,13 @@ fn test_code_generation_flow_applies_changes() -> Result<(), Box<dyn Error>> {
 
     let args = common_test_args(project_root.clone(), "create a new file");
 
-    let mut mock_api = MockLLMApi::new();
+    let mock_api = RuleBasedLLMApi::new();
 
     let initial_response = GeminiNeededItemsResponse { needed_items: vec![] };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&initial_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&initial_response)?).for_phase(AgentPhase::Initial));
 
     let sufficiency_response = GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&sufficiency_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&sufficiency_response)?).for_phase(AgentPhase::Sufficient));
 
     let new_file_path = "src/new_file_from_test.txt";
     let new_file_content = "hello from test";
@@ -1331,7 +1351,7 @@ fn test_code_generation_flow_applies_changes() -> Result<(), Box<dyn Error>> {
         tests: None,
         explanation: "Test creating a file".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -1350,20 +1370,20 @@ fn test_sufficiency_loop_sufficient_case() -> Result<(), Box<dyn Error>> {
 
     let args = common_test_args(project_root.clone(), "test sufficiency: sufficient case");
 
-    let mut mock_api = MockLLMApi::new();
+    let mock_api = RuleBasedLLMApi::new();
 
     let initial_response = GeminiNeededItemsResponse { needed_items: vec!["src/lib.rs".to_string()] };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&initial_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&initial_response)?).for_phase(AgentPhase::Initial));
 
     let sufficiency_response = GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&sufficiency_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&sufficiency_response)?).for_phase(AgentPhase::Sufficient));
 
     let change_response = gem::llm_api::GeminiCodeGenerationResponse {
         changes: vec![],
         tests: None,
         explanation: "Proceeded to code generation as data was sufficient.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&change_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&change_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root);
 
@@ -1372,6 +1392,29 @@ fn test_sufficiency_loop_sufficient_case() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn test_sufficiency_loop_requests_missing_item() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("sufficiency_missing_item");
+
+    let args = common_test_args(project_root.clone(), "test sufficiency: one more item needed");
+
+    let more_needed = GeminiSufficiencyResponse { sufficient: false, needed_items: vec!["test_project::SomeStruct".to_string()] };
+    let sufficient = GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] };
+    let mock_api = RuleBasedLLMApi::new()
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec!["src/lib.rs".to_string()] })?).for_phase(AgentPhase::Initial).times(1))
+        // Once the struct has been gathered, the data is sufficient; until then it is asked for once.
+        .with_rule(MockRule::respond(serde_json::to_string(&sufficient)?).for_phase(AgentPhase::Sufficient).for_prompt_matching(r"// Item: test_project::SomeStruct\n"))
+        .with_rule(MockRule::respond(serde_json::to_string(&more_needed)?).for_phase(AgentPhase::Sufficient).times(1))
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiCodeGenerationResponse { changes: vec![], tests: None, explanation: "Done.".to_string() })?).for_phase(AgentPhase::Change));
+
+    let session = run_gem_logic_with_mock_api_owned(args, mock_api.clone(), project_root)?;
+
+    assert_eq!(mock_api.uses(), vec![1, 1, 1, 1]);
+    assert!(session.gathered_data.contains_key("test_project::SomeStruct"));
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn test_replace_item_in_section_const() -> Result<(), Box<dyn Error>> {
@@ -1385,9 +1428,9 @@ fn some_func_for_context() {}
 
     let args = common_test_args(project_root.clone(), "update OLD_CONST");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let new_const_content = "const NEW_CONST: &str = \"hello\";";
     let change = CodeChange {
@@ -1400,7 +1443,7 @@ fn some_func_for_context() {}
         tests: None,
         explanation: "Updated OLD_CONST.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -1433,10 +1476,10 @@ fn old_func() -> i32 {
 
     let args = common_test_args(project_root.clone(), "replace old_func with new_func");
 
-    let mut mock_api = MockLLMApi::new();
+    let mock_api = RuleBasedLLMApi::new();
 
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let new_function_content = "fn new_func() -> i32 {\n    2\n}";
     let change = CodeChange {
@@ -1449,7 +1492,7 @@ fn old_func() -> i32 {
         tests: None,
         explanation: "Replaced old_func with new_func.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&code_gen_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
 
     let result = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone());
     assert!(result.is_ok(), "run_gem_logic_with_mock_api_owned failed: {:?}", result.err());
@@ -1487,9 +1530,9 @@ pub fn another_function() {
 
     let args = common_test_args(project_root.clone(), "Replace function_to_replace with new content via Markdown");
 
-    let mut mock_api = MockLLMApi::new();
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?));
-    mock_api.add_mock_response(Ok(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?));
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec![] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
 
     let markdown_content = r#"
 This is an explanation of the change.
@@ -1515,7 +1558,7 @@ The function signature and body have been updated.
         tests: None,
         explanation: "High-level: Updated a function via Markdown.".to_string(),
     };
-    mock_api.add_mock_response(Ok(serde_json::to_string(&llm_response)?));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&llm_response)?).for_phase(AgentPhase::Change));
 
     let _session = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone())?;
 
@@ -1529,3 +1572,406 @@ The function signature and body have been updated.
 
     Ok(())
 }
+
+#[test]
+#[serial]
+fn test_recorded_session_replays_without_backend() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("record_replay");
+    let fixture_dir = tempfile::tempdir()?;
+
+    let mock_api = RuleBasedLLMApi::new();
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec!["src/lib.rs".to_string()] })?).for_phase(AgentPhase::Initial));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient));
+    mock_api.add_rule(MockRule::respond(serde_json::to_string(&GeminiCodeGenerationResponse {
+        changes: vec![CodeChange { file_path: "src/recorded.txt".to_string(), action: CodeChangeAction::CreateFile, content: Some("recorded".to_string()) }],
+        tests: None,
+        explanation: "Created a file.".to_string(),
+    })?).for_phase(AgentPhase::Change));
+
+    let args = common_test_args(project_root.clone(), "record this session");
+    let mut session = Session::new(&Session::compute_hash("record_replay_recording"));
+    run_gem_agent(args, &mut session, Box::new(RecordingLLMApi::new(Box::new(mock_api), fixture_dir.path())), false, project_root.clone())?;
+    assert_eq!(fs::read_dir(fixture_dir.path())?.count(), 3);
+
+    // A fresh session has no cached responses, so every answer comes from the fixtures.
+    fs::remove_file(project_root.join("src/recorded.txt"))?;
+    let mut session = Session::new(&Session::compute_hash("record_replay_replaying"));
+    let args = common_test_args(project_root.clone(), "record this session");
+    run_gem_agent(args, &mut session, Box::new(ReplayLLMApi::new(fixture_dir.path())?), false, project_root.clone())?;
+    assert_eq!(fs::read_to_string(project_root.join("src/recorded.txt"))?, "recorded");
+
+    // A changed request is a prompt the fixtures have not seen.
+    fs::remove_file(project_root.join("src/recorded.txt"))?;
+    let mut session = Session::new(&Session::compute_hash("record_replay_drifted"));
+    let drifted = common_test_args(project_root.clone(), "record this other session");
+    let error = run_gem_agent(drifted, &mut session, Box::new(ReplayLLMApi::new(fixture_dir.path())?), false, project_root)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("- User Request: \"record this session\"\n+ User Request: \"record this other session\""), "{}", error);
+    Ok(())
+}
+
+// Stands in for a local model: sees each call as one flattened prompt, like a GGUF model does,
+// and answers by recognising the phase's prompt.
+struct PhaseScriptedGenerator {
+    answers: Vec<(&'static str, String)>, // Prompt marker and answer
+    max_tokens_seen: Rc<RefCell<Vec<usize>>>,
+}
+
+impl LocalGenerator for PhaseScriptedGenerator {
+    fn model_name(&self) -> &str {
+        "scripted-local"
+    }
+
+    fn context_window(&self) -> usize {
+        16_384
+    }
+
+    fn generate(&self, prompt: &str, max_tokens: usize) -> Result<LocalGeneration, Box<dyn Error>> {
+        self.max_tokens_seen.borrow_mut().push(max_tokens);
+        let (_, answer) = self.answers.iter().find(|(marker, _)| prompt.contains(marker)).ok_or("no scripted answer for prompt")?;
+        Ok(LocalGeneration { text: answer.clone(), prompt_tokens: prompt.len() / 4, generated_tokens: answer.len() / 4, hit_token_limit: false })
+    }
+}
+
+#[test]
+#[serial]
+fn test_local_model_drives_full_agent_loop() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("local_model_agent");
+    fs::write(project_root.join("src").join("lib.rs"), "const OLD_CONST: i32 = 1;\n")?;
+    let mut args = common_test_args(project_root.clone(), "rename OLD_CONST offline");
+
+    let code_gen_response = GeminiCodeGenerationResponse {
+        changes: vec![CodeChange {
+            file_path: "src/lib.rs::OLD_CONST".to_string(),
+            action: CodeChangeAction::ReplaceItemInSection,
+            content: Some("const NEW_CONST: i32 = 2;".to_string()),
+        }],
+        tests: None,
+        explanation: "Renamed the constant.".to_string(),
+    };
+    let max_tokens_seen = Rc::new(RefCell::new(Vec::new()));
+    let generator = PhaseScriptedGenerator {
+        answers: vec![
+            ("identify the specific Rust files", serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec!["src/lib.rs".to_string()] })?),
+            ("You previously requested specific code elements", serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?),
+            ("generate the precise code changes", serde_json::to_string(&code_gen_response)?),
+        ],
+        max_tokens_seen: max_tokens_seen.clone(),
+    };
+    let local = LocalLLMApi::new(Box::new(generator)).with_max_tokens(1_500);
+    local.configure_agent_args(&mut args);
+
+    let mut session = Session::new(&Session::compute_hash("local_model_agent"));
+    run_gem_agent(args, &mut session, Box::new(local), false, project_root.clone())?;
+
+    assert_eq!(fs::read_to_string(project_root.join("src").join("lib.rs"))?.trim(), "const NEW_CONST: i32 = 2;");
+    let calls: Vec<(&str, &str)> = session.calls().iter().map(|c| (c.prompt_type.as_str(), c.model.as_str())).collect();
+    assert_eq!(calls, vec![("initial", "scripted-local"), ("sufficient", "scripted-local"), ("change", "scripted-local")]);
+    assert_eq!(*max_tokens_seen.borrow(), vec![1_500; 3]);
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_local_model_gets_a_request_piped_on_stdin() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("local_model_stdin");
+    fs::write(project_root.join("src").join("lib.rs"), "const OLD_CONST: i32 = 1;\n")?;
+    // As for `echo "rename OLD_CONST offline" | gem --local ...`
+    let mut args = common_test_args(project_root.clone(), "");
+    args.user_request_parts.clear();
+    assert!(args.read_request_from_stdin("rename OLD_CONST offline\n".as_bytes())?);
+
+    let code_gen_response = GeminiCodeGenerationResponse {
+        changes: vec![CodeChange { file_path: "src/lib.rs::OLD_CONST".to_string(), action: CodeChangeAction::ReplaceItemInSection, content: Some("const NEW_CONST: i32 = 2;".to_string()) }],
+        tests: None,
+        explanation: "Renamed the constant.".to_string(),
+    };
+    // Each phase is only answered when its prompt carries the piped request.
+    let generator = PhaseScriptedGenerator {
+        answers: vec![
+            ("Original User Request: \"rename OLD_CONST offline\"\n\nProvided Source Code", serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?),
+            ("Original User Request: \"rename OLD_CONST offline\"\n\nFull Context", serde_json::to_string(&code_gen_response)?),
+            ("\nUser Request: \"rename OLD_CONST offline\"", serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec!["src/lib.rs".to_string()] })?),
+        ],
+        max_tokens_seen: Rc::new(RefCell::new(Vec::new())),
+    };
+    let local = LocalLLMApi::new(Box::new(generator));
+    local.configure_agent_args(&mut args);
+
+    let mut session = Session::new(&Session::compute_hash("local_model_stdin"));
+    run_gem_agent(args, &mut session, Box::new(local), false, project_root.clone())?;
+
+    assert_eq!(fs::read_to_string(project_root.join("src").join("lib.rs"))?.trim(), "const NEW_CONST: i32 = 2;");
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_failed_verification_is_retried_in_the_same_conversation() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("verification_retry");
+    fs::write(project_root.join("src").join("lib.rs"), "pub const OLD_CONST: i32 = 1;\n")?;
+    let args = common_test_args(project_root.clone(), "rename OLD_CONST to NEW_CONST");
+
+    let replace_item = |item: &str, content: &str| -> Result<String, Box<dyn Error>> {
+        Ok(serde_json::to_string(&GeminiCodeGenerationResponse {
+            changes: vec![CodeChange {
+                file_path: format!("src/lib.rs::{}", item),
+                action: CodeChangeAction::ReplaceItemInSection,
+                content: Some(content.to_string()),
+            }],
+            tests: None,
+            explanation: format!("Replaced {}.", item),
+        })?)
+    };
+    let mock_api = RuleBasedLLMApi::new()
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec!["src/lib.rs".to_string()] })?).for_phase(AgentPhase::Initial))
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient))
+        .with_rule(MockRule::respond(replace_item("OLD_CONST", "pub const NEW_CONST: i32 = \"two\";")?).for_phase(AgentPhase::Change).times(1))
+        // The retry turn carries the real `cargo check` failure: its exit status and the compiler errors, listed once each.
+        .with_rule(
+            MockRule::respond(replace_item("NEW_CONST", "pub const NEW_CONST: i32 = 2;")?)
+                .for_phase(AgentPhase::Retry)
+                .for_prompt_matching(r#"(?s)but they failed the verification command "cargo check"\..*`cargo check --message-format=json` exited with status 101 after .*Compiler errors \(1, duplicates removed\):\n\n1\. error\[E0308\] in src/lib\.rs line 1: mismatched types\nerror\[E0308\]: mismatched types\n --> src/lib\.rs:1:28"#)
+                .times(1),
+        );
+
+    let session = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone())?;
+
+    assert_eq!(fs::read_to_string(project_root.join("src").join("lib.rs"))?.trim(), "pub const NEW_CONST: i32 = 2;");
+    let calls: Vec<&str> = session.calls().iter().map(|c| c.prompt_type.as_str()).collect();
+    assert_eq!(calls, vec!["initial", "sufficient", "change", "change"]);
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_verification_pipeline_reports_only_the_failed_stage() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("verification_pipeline");
+    fs::write(project_root.join("src").join("lib.rs"), "pub const OLD_CONST: i32 = 1;\n")?;
+    let mut args = common_test_args(project_root.clone(), "rename OLD_CONST to NEW_CONST with value 2");
+    args.verify_with = vec![
+        "warn: echo style problems >&2; exit 1".to_string(),
+        "grep -q 'NEW_CONST: i32 = 2' src/lib.rs || { echo 'NEW_CONST must be 2' >&2; exit 1; }".to_string(),
+        "echo ran >> stage3.log".to_string(),
+    ];
+
+    let replace_item = |item: &str, content: &str| -> Result<String, Box<dyn Error>> {
+        Ok(serde_json::to_string(&GeminiCodeGenerationResponse {
+            changes: vec![CodeChange { file_path: format!("src/lib.rs::{}", item), action: CodeChangeAction::ReplaceItemInSection, content: Some(content.to_string()) }],
+            tests: None,
+            explanation: format!("Replaced {}.", item),
+        })?)
+    };
+    let mock_api = RuleBasedLLMApi::new()
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec!["src/lib.rs".to_string()] })?).for_phase(AgentPhase::Initial))
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient))
+        .with_rule(MockRule::respond(replace_item("OLD_CONST", "pub const NEW_CONST: i32 = 3;")?).for_phase(AgentPhase::Change).times(1))
+        // The warning-only stage's output must not reach the model.
+        .with_rule(MockRule::fail("the retry prompt includes the warning-only stage").for_phase(AgentPhase::Retry).for_prompt_matching("style problems").optional())
+        .with_rule(
+            MockRule::respond(replace_item("NEW_CONST", "pub const NEW_CONST: i32 = 2;")?)
+                .for_phase(AgentPhase::Retry)
+                .for_prompt_matching(r#"(?s)but they failed verification stage 2 of 3 \("grep -q .*--- stderr ---\nNEW_CONST must be 2"#)
+                .times(1),
+        );
+
+    let session = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone())?;
+
+    assert_eq!(fs::read_to_string(project_root.join("src").join("lib.rs"))?.trim(), "pub const NEW_CONST: i32 = 2;");
+    let calls: Vec<&str> = session.calls().iter().map(|c| c.prompt_type.as_str()).collect();
+    assert_eq!(calls, vec!["initial", "sufficient", "change", "change"]);
+    // The last stage only ran once the blocking stage before it passed.
+    assert_eq!(fs::read_to_string(project_root.join("stage3.log"))?, "ran\n");
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_machine_applicable_fixes_are_applied_without_asking_the_model() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("auto_fix");
+    fs::write(project_root.join("src").join("lib.rs"), "#![deny(unused_mut)]\npub const OLD_CONST: i32 = 1;\n")?;
+    let args = common_test_args(project_root.clone(), "replace OLD_CONST with a function");
+
+    let code_gen_response = GeminiCodeGenerationResponse {
+        changes: vec![CodeChange {
+            file_path: "src/lib.rs::OLD_CONST".to_string(),
+            action: CodeChangeAction::ReplaceItemInSection,
+            content: Some("pub fn value() -> i32 { let mut x = 2; x }".to_string()),
+        }],
+        tests: None,
+        explanation: "Replaced the constant with a function.".to_string(),
+    };
+    // No rule for the retry phase: the unused `mut` must be fixed without another model call.
+    let mock_api = RuleBasedLLMApi::new()
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec!["src/lib.rs".to_string()] })?).for_phase(AgentPhase::Initial))
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient))
+        .with_rule(MockRule::respond(serde_json::to_string(&code_gen_response)?).for_phase(AgentPhase::Change));
+
+    let session = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone())?;
+
+    assert_eq!(fs::read_to_string(project_root.join("src").join("lib.rs"))?, "#![deny(unused_mut)]\npub fn value() -> i32 { let x = 2; x }\n");
+    let calls: Vec<&str> = session.calls().iter().map(|c| c.prompt_type.as_str()).collect();
+    assert_eq!(calls, vec!["initial", "sufficient", "change"]);
+    let fixes: Vec<(&str, usize, &str)> = session.auto_fixes().iter().map(|f| (f.file.as_str(), f.line, f.message.as_str())).collect();
+    assert_eq!(fixes, vec![("src/lib.rs", 2, "remove this `mut`")]);
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_manual_copy_paste_drives_full_agent_loop_with_session_caching() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("manual_agent");
+    fs::write(project_root.join("src").join("lib.rs"), "const OLD_CONST: i32 = 1;\n")?;
+    let prompt_dir = tempfile::tempdir()?;
+    let prompt_file = prompt_dir.path().join("prompt.md");
+
+    let code_gen_response = GeminiCodeGenerationResponse {
+        changes: vec![CodeChange {
+            file_path: "src/lib.rs::OLD_CONST".to_string(),
+            action: CodeChangeAction::ReplaceItemInSection,
+            content: Some("const NEW_CONST: i32 = 2;".to_string()),
+        }],
+        tests: None,
+        explanation: "Renamed the constant.".to_string(),
+    };
+    // What a user copies out of a chat UI: prose around a fenced JSON block, one answer per prompt.
+    let pasted: String = [
+        serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec!["src/lib.rs".to_string()] })?,
+        serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?,
+        serde_json::to_string(&code_gen_response)?,
+    ]
+    .iter()
+    .map(|json| format!("Here you go:\n\n```json\n{}\n```\n{}\n", json, DEFAULT_END_MARKER))
+    .collect();
+
+    let manual = |input: String| {
+        ManualLLMApi::new(Box::new(std::io::Cursor::new(input)), Box::new(std::io::sink()), DEFAULT_END_MARKER).with_prompt_file(prompt_file.clone())
+    };
+    let args = || {
+        let mut args = common_test_args(project_root.clone(), "rename OLD_CONST by copy and paste");
+        manual(String::new()).configure_agent_args(&mut args);
+        args
+    };
+    let mut session = Session::new(&Session::compute_hash("manual_agent"));
+    run_gem_agent(args(), &mut session, Box::new(manual(pasted)), false, project_root.clone())?;
+
+    assert_eq!(fs::read_to_string(project_root.join("src").join("lib.rs"))?.trim(), "const NEW_CONST: i32 = 2;");
+    let calls: Vec<(&str, &str)> = session.calls().iter().map(|c| (c.prompt_type.as_str(), c.model.as_str())).collect();
+    assert_eq!(calls, vec![("initial", "manual"), ("sufficient", "manual"), ("change", "manual")]);
+    let last_prompt = fs::read_to_string(&prompt_file)?;
+    assert!(last_prompt.contains("generate the precise code changes"), "{}", last_prompt);
+
+    // A second run of the same request is answered from the session cache; nothing is pasted.
+    fs::write(project_root.join("src").join("lib.rs"), "const OLD_CONST: i32 = 1;\n")?;
+    fs::remove_file(&prompt_file)?;
+    run_gem_agent(args(), &mut session, Box::new(manual(String::new())), false, project_root.clone())?;
+    assert_eq!(fs::read_to_string(project_root.join("src").join("lib.rs"))?.trim(), "const NEW_CONST: i32 = 2;");
+    assert!(!prompt_file.exists());
+    Ok(())
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("git").args(args).current_dir(dir).output()?;
+    if !output.status.success() {
+        return Err(format!("git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr)).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Turns the test project into a repository with everything committed.
+fn init_git_repo(project_root: &Path) -> Result<(), Box<dyn Error>> {
+    fs::write(project_root.join(".gitignore"), "/target\nCargo.lock\n")?;
+    git(project_root, &["init", "--quiet"])?;
+    git(project_root, &["config", "user.name", "Dev"])?;
+    git(project_root, &["config", "user.email", "dev@example.com"])?;
+    git(project_root, &["add", "."])?;
+    git(project_root, &["commit", "--quiet", "-m", "initial"])?;
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_retries_are_committed_once_with_only_the_touched_files() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("git_commit");
+    fs::write(project_root.join("src").join("lib.rs"), "pub const OLD_CONST: i32 = 1;\n")?;
+    fs::write(project_root.join("README.md"), "# Test project\n")?;
+    init_git_repo(&project_root)?;
+    // The user's uncommitted edit goes onto the stash; their untracked notes stay where they are.
+    fs::write(project_root.join("README.md"), "# Test project, edited\n")?;
+    fs::write(project_root.join("notes.txt"), "not for gem")?;
+
+    let mut args = common_test_args(project_root.clone(), "rename OLD_CONST to NEW_CONST");
+    args.stash = true;
+    args.commit_author = Some("gem bot <gem@example.com>".to_string());
+    let first_attempt = GeminiCodeGenerationResponse {
+        changes: vec![
+            CodeChange { file_path: "src/lib.rs::OLD_CONST".to_string(), action: CodeChangeAction::ReplaceItemInSection, content: Some("pub const NEW_CONST: i32 = \"two\";".to_string()) },
+            CodeChange { file_path: "src/consts.rs".to_string(), action: CodeChangeAction::CreateFile, content: Some("pub const OTHER: i32 = 3;\n".to_string()) },
+        ],
+        tests: None,
+        explanation: "Renamed the constant.".to_string(),
+    };
+    let retry = GeminiCodeGenerationResponse {
+        changes: vec![CodeChange { file_path: "src/lib.rs::NEW_CONST".to_string(), action: CodeChangeAction::ReplaceItemInSection, content: Some("pub const NEW_CONST: i32 = 2;".to_string()) }],
+        tests: None,
+        explanation: "Renamed the constant and fixed its type.".to_string(),
+    };
+    let mock_api = RuleBasedLLMApi::new()
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiNeededItemsResponse { needed_items: vec!["src/lib.rs".to_string()] })?).for_phase(AgentPhase::Initial))
+        .with_rule(MockRule::respond(serde_json::to_string(&GeminiSufficiencyResponse { sufficient: true, needed_items: vec![] })?).for_phase(AgentPhase::Sufficient))
+        .with_rule(MockRule::respond(serde_json::to_string(&first_attempt)?).for_phase(AgentPhase::Change).times(1))
+        .with_rule(MockRule::respond(serde_json::to_string(&retry)?).for_phase(AgentPhase::Retry).times(1));
+
+    let session = run_gem_logic_with_mock_api_owned(args, mock_api, project_root.clone())?;
+
+    assert_eq!(git(&project_root, &["rev-list", "--count", "HEAD"])?.trim(), "2");
+    // src/consts.rs only came from the first attempt, but is part of the squashed result.
+    assert_eq!(git(&project_root, &["show", "--name-status", "--format=", "HEAD"])?, "A\tsrc/consts.rs\nM\tsrc/lib.rs\n");
+    assert_eq!(git(&project_root, &["log", "-1", "--format=%an <%ae>"])?.trim(), "gem bot <gem@example.com>");
+    let message = git(&project_root, &["log", "-1", "--format=%B"])?;
+    assert_eq!(message.trim(), format!("gem: Automated change for \"rename OLD_CONST to NEW_CONST\"\n\nRenamed the constant and fixed its type.\n\nGem-Session: {}", session.id()));
+    assert_eq!(git(&project_root, &["status", "--porcelain"])?, "?? notes.txt\n");
+    assert!(git(&project_root, &["stash", "list"])?.contains("gem: before \"rename OLD_CONST to NEW_CONST\""));
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_refuses_to_run_on_a_dirty_work_tree() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("git_dirty");
+    init_git_repo(&project_root)?;
+    fs::write(project_root.join("src").join("lib.rs"), "pub fn hello() { /* work in progress */ }\n")?;
+    let args = common_test_args(project_root.clone(), "change hello");
+    let mut session = Session::new(&Session::compute_hash("git_dirty"));
+
+    let error = run_gem_agent(args, &mut session, Box::new(RuleBasedLLMApi::new()), false, project_root.clone()).unwrap_err().to_string();
+
+    assert_eq!(error, "The working tree has uncommitted changes:\n M src/lib.rs\nCommit them, or run again with --stash to stash them first.");
+    assert!(session.calls().is_empty());
+    assert_eq!(fs::read_to_string(project_root.join("src").join("lib.rs"))?, "pub fn hello() { /* work in progress */ }\n");
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_manual_mode_prompts_with_a_request_piped_on_stdin() -> Result<(), Box<dyn Error>> {
+    let (project_root, _project_dir_guard, _home_dir_guard) = setup_test_env("manual_stdin");
+    let prompt_dir = tempfile::tempdir()?;
+    let prompt_file = prompt_dir.path().join("prompt.md");
+    // As for `echo "rename OLD_CONST by copy and paste" | gem --manual --prompt-file prompt.md`
+    let mut args = CustomCliArgs::try_parse_from(["gem", "--manual", "--verify-with", "true"])?;
+    args.project_root = project_root.clone();
+    assert!(args.read_request_from_stdin("rename OLD_CONST by copy and paste\n".as_bytes())?);
+    let manual = ManualLLMApi::new(Box::new(std::io::Cursor::new(String::new())), Box::new(std::io::sink()), DEFAULT_END_MARKER).with_prompt_file(prompt_file.clone());
+    manual.configure_agent_args(&mut args);
+
+    // Nothing is pasted back, so the run stops after showing the first prompt.
+    let mut session = Session::new(&Session::compute_hash("manual_stdin"));
+    let error = run_gem_agent(args, &mut session, Box::new(manual), false, project_root).unwrap_err().to_string();
+
+    assert_eq!(error, "No answer was pasted for gem prompt 1.");
+    let first_prompt = fs::read_to_string(&prompt_file)?;
+    assert!(first_prompt.contains("User Request: \"rename OLD_CONST by copy and paste\""), "{}", first_prompt);
+    Ok(())
+}